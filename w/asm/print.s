@@ -1,41 +1,198 @@
+  .text
   .globl print #标记为全局，才能被其他模块访问
-  
+  .globl print_int
+  .globl print_str
+  .globl print_char
+  .globl print_float
+  .globl print_bool
+
+# 与 W 函数的调用约定相同：调用者把 %rbp 移到新栈帧，第一个参数位于 (%rbp)
+
+# 打印有符号 64 位整数并换行（单参数的旧接口）
 print:
-  # 分配栈空间 32 字节用于存储整数的字节表示
-  movq $0, %rdi
-  movq $32, %rsi
-  movq $3, %rdx
-  movq $34, %r10
-  movq $-1, %r8  
-  movq $0, %r9
-  movq $9, %rax                # 存放栈底指针
-  syscall
+  call print_int
+  movq $10, (%rbp)
+  call print_char
+  ret
 
-  # 转换函数
-  movq %rax, %rdi              # rdi 指向字符串存储的起始位置
-  movl (%rbp), %eax            # 将要转换的整数
-  movl %eax, %ecx              # 把整数复制到 ecx，用作除法的被除数
-  addq $34, %rdi               # 移动指针到字符串的末尾（假设最多32位加上终止符和换行符）
-  movb $0, (%rdi)              # 设置字符串终止符
-  decq %rdi                    # 指针回退到终止符前的位置
-  movb $'\n', (%rdi)           # 添加换行符
-  decq %rdi                    # 指针回退到换行符前的位置
-convert_loop:
-  xorl %edx, %edx              # 清除 edx 以供 div 使用
-  movl $10, %ebx               # 除数设置为10
-  divl %ebx                    # eax /= 10, edx = eax % 10
+# 打印有符号 64 位整数，不换行
+print_int:
+  movq (%rbp), %rax
+  subq $32, %rsp               # 在 %rsp 栈上开 32 字节缓冲区
+  leaq 32(%rsp), %rsi          # rsi 从缓冲区末尾向前写
+  movq %rax, %r8               # 保存原值，用于判断符号
+  testq %rax, %rax
+  jns print_int_loop
+  negq %rax
+print_int_loop:
+  xorq %rdx, %rdx              # 清除 rdx 以供 div 使用
+  movq $10, %rcx               # 除数设置为10
+  divq %rcx                    # rax /= 10, rdx = rax % 10
   addb $'0', %dl               # 将余数转换为ASCII
-  movb %dl, (%rdi)             # 存储字符
-  decq %rdi                    # 移动字符串指针
-  testl %eax, %eax             # 检查是否还有数字需要处理
-  jnz convert_loop             # 如果不为0，继续循环
-  # 现在 %rdi 指向字符串的第一个字符，可以进行打印
-  # 调用字符串打印系统服务
-  mov %rdi, %rsi          # 消息的地址
-  mov $1, %rax            # 系统调用号 1 (sys_write)
-  mov $1, %rdi            # 文件描述符 1 (stdout)
-  # mov $len, %rdx        # 消息的长度
-  mov $100, %rdx          # 消息的长度
-  syscall                 # 调用系统服务
+  decq %rsi
+  movb %dl, (%rsi)             # 存储字符
+  testq %rax, %rax             # 检查是否还有数字需要处理
+  jnz print_int_loop
+  testq %r8, %r8
+  jns print_int_write
+  decq %rsi
+  movb $'-', (%rsi)
+print_int_write:
+  leaq 32(%rsp), %rdx
+  subq %rsi, %rdx              # 消息的长度
+  movq $1, %rax                # 系统调用号 1 (sys_write)
+  movq $1, %rdi                # 文件描述符 1 (stdout)
+  syscall
+  addq $32, %rsp
   ret
-  
\ No newline at end of file
+
+# 打印 (%rbp) 指向的以 0 结尾的字符串
+print_str:
+  movq (%rbp), %rsi
+  xorq %rdx, %rdx
+print_str_len:
+  cmpb $0, (%rsi,%rdx)
+  je print_str_write
+  incq %rdx
+  jmp print_str_len
+print_str_write:
+  movq $1, %rax
+  movq $1, %rdi
+  syscall
+  ret
+
+# 打印 (%rbp) 低字节中的字符
+print_char:
+  movq %rbp, %rsi
+  movq $1, %rdx
+  movq $1, %rax
+  movq $1, %rdi
+  syscall
+  ret
+
+# 打印 (%rbp) 中的双精度浮点数，保留 6 位小数；NaN 打印为 nan，无穷大打印为 inf
+# 被调用者的栈帧从 (%rbp) 开始，8(%rbp) 之后的位置可以作为临时空间
+print_float:
+  movq (%rbp), %rax
+  btrq $63, %rax               # 取绝对值
+  movq %rax, 8(%rbp)
+  movabsq $0x7FF0000000000000, %rcx
+  cmpq %rcx, %rax
+  ja print_float_nan           # 指数全为 1 且尾数不为 0
+  cmpq $0, (%rbp)              # 符号位为 1 时打印负号
+  jge print_float_abs
+  movq $'-', (%rbp)
+  call print_char
+print_float_abs:
+  movq 8(%rbp), %rax
+  movabsq $0x7FF0000000000000, %rcx
+  cmpq %rcx, %rax
+  je print_float_inf
+  movsd 8(%rbp), %xmm0
+  movabsq $0x43E0000000000000, %rax
+  movq %rax, %xmm2             # 2^63
+  ucomisd %xmm2, %xmm0
+  jae print_float_large
+  cvttsd2siq %xmm0, %rax       # 整数部分
+  movq %rax, 16(%rbp)
+  cvtsi2sdq %rax, %xmm1
+  subsd %xmm1, %xmm0           # 小数部分
+  movabsq $0x412E848000000000, %rax
+  movq %rax, %xmm1             # 1e6
+  mulsd %xmm1, %xmm0
+  cvtsd2siq %xmm0, %rax        # 四舍五入到 6 位小数
+  cmpq $1000000, %rax
+  jl print_float_int
+  incq 16(%rbp)                # 进位到整数部分
+  xorq %rax, %rax
+print_float_int:
+  movq %rax, 24(%rbp)
+  movq 16(%rbp), %rax
+  movq %rax, (%rbp)
+  call print_int
+  movq $'.', (%rbp)
+  call print_char
+  movq 24(%rbp), %rax          # 小数部分固定输出 6 位，不足补 0
+  leaq 38(%rbp), %rsi
+  movq $6, %r8
+  movq $10, %rcx
+print_float_frac:
+  xorq %rdx, %rdx
+  divq %rcx
+  addb $'0', %dl
+  decq %rsi
+  movb %dl, (%rsi)
+  decq %r8
+  jnz print_float_frac
+  movq $6, %rdx
+  movq $1, %rax
+  movq $1, %rdi
+  syscall
+  ret
+
+# 超出 64 位整数范围的数：缩小到 17 位有效数字，24(%rbp) 记录缩小的次数，
+# 打印时在整数部分之后补上同样个数的 0，小数部分全为 0
+print_float_large:
+  movq $0, 24(%rbp)
+  movabsq $0x46C8A6E32246C99C, %rax
+  movq %rax, %xmm2             # 1e33
+  movabsq $0x4341C37937E08000, %rax
+  movq %rax, %xmm3             # 1e16，整段缩小以减少舍入误差的累积
+print_float_scale_16:
+  ucomisd %xmm2, %xmm0
+  jb print_float_scale_1
+  divsd %xmm3, %xmm0
+  addq $16, 24(%rbp)
+  jmp print_float_scale_16
+print_float_scale_1:
+  movabsq $0x4376345785D8A000, %rax
+  movq %rax, %xmm2             # 1e17
+  movabsq $0x4024000000000000, %rax
+  movq %rax, %xmm3             # 10
+print_float_scale:
+  divsd %xmm3, %xmm0
+  incq 24(%rbp)
+  ucomisd %xmm2, %xmm0
+  jae print_float_scale
+  cvttsd2siq %xmm0, %rax
+  movq %rax, (%rbp)
+  call print_int
+print_float_zeros:
+  movq $'0', (%rbp)
+  call print_char
+  decq 24(%rbp)
+  jnz print_float_zeros
+  leaq float_zero_frac(%rip), %rax
+  movq %rax, (%rbp)
+  jmp print_str
+
+print_float_nan:
+  leaq float_nan(%rip), %rax
+  movq %rax, (%rbp)
+  jmp print_str
+
+print_float_inf:
+  leaq float_inf(%rip), %rax
+  movq %rax, (%rbp)
+  jmp print_str
+
+# 打印 (%rbp) 中的布尔值：true 或 false
+print_bool:
+  leaq bool_true(%rip), %rax
+  leaq bool_false(%rip), %rcx
+  cmpq $0, (%rbp)
+  cmoveq %rcx, %rax
+  movq %rax, (%rbp)
+  jmp print_str
+
+  .section .rodata
+bool_true:
+  .asciz "true"
+bool_false:
+  .asciz "false"
+float_nan:
+  .asciz "nan"
+float_inf:
+  .asciz "inf"
+float_zero_frac:
+  .asciz ".000000"
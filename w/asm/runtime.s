@@ -0,0 +1,12 @@
+  .text
+  .globl runtime_error
+
+# 运行时错误：%rsi 指向错误消息，%rdx 为消息长度
+# 把消息写到标准错误，然后以退出码 101 结束进程
+runtime_error:
+  movq $1, %rax                # 系统调用号 1 (sys_write)
+  movq $2, %rdi                # 文件描述符 2 (stderr)
+  syscall
+  movq $60, %rax               # 系统调用号 60 (sys_exit)
+  movq $101, %rdi
+  syscall
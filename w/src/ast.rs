@@ -7,11 +7,19 @@
  * @Description: 这是默认设置,请设置`customMade`, 打开koroFileHeader查看配置 进行设置: https://github.com/OBKoro1/koro1FileHeader/wiki/%E9%85%8D%E7%BD%AE
  */
 
+use crate::lexer::Span;
+use crate::types::Type;
+use std::ops::Deref;
+
 /// 抽象语法树
+#[derive(Clone)]
 pub struct AST {
   pub value: String,
   pub children: Vec<AST>,
-  pub register: String,
+  /// 节点在源码中的区间
+  pub span: Span,
+  /// 类型检查得到的类型；运算符节点记录的是操作数的类型
+  pub ty: Option<Type>,
 }
 
 impl AST {
@@ -19,16 +27,22 @@ impl AST {
     Self {
       value,
       children,
-      register: String::new(),
+      span: Span::default(),
+      ty: None,
     }
   }
 
+  pub fn with_span(mut self, span: Span) -> Self {
+    self.span = span;
+    self
+  }
+
   /// Returns a vector of expressions from the AST.
   ///
   /// # Examples
   ///
   /// ```
-  /// use crate::ast::AST;
+  /// use w::ast::AST;
   ///
   /// let tree = AST::new(
   ///     "Expr".to_string(),
@@ -50,11 +64,8 @@ impl AST {
     if self.value == "FnCall" {
       expression.push(self.children[0].value.clone());
       expression.push("op(".to_string());
-      let mut i = 1;
-      let len = self.children.len();
-      while i < len {
-        expression.extend(self.children[i].get_expression());
-        i += 1;
+      for arg in &self.children[1..] {
+        expression.extend(arg.get_expression());
       }
       expression.push("op)".to_string());
       return expression;
@@ -65,23 +76,43 @@ impl AST {
     expression
   }
 
+  /// 函数节点上的属性 `@name(args)`，每个是一个 Attr[name, args...] 节点
+  pub fn fn_attrs(&self) -> &[AST] {
+    self.children.get(4).map_or(&[], |attrs| &attrs.children)
+  }
+
+  /// 语句节点上的属性，没有属性的语句只有一个子节点
+  pub fn stmt_attrs(&self) -> &[AST] {
+    self.children.get(1).map_or(&[], |attrs| &attrs.children)
+  }
+
+  /// 函数或语句是否带有属性 `@name`
+  pub fn has_attr(&self, name: &str) -> bool {
+    let attrs = match self.value.as_str() {
+      "Stmt" => self.stmt_attrs(),
+      _ => self.fn_attrs(),
+    };
+    attrs.iter().any(|attr| attr.children[0].value == name)
+  }
+
   pub fn print(&self, depth: usize, path: &mut Vec<bool>) {
-    for d in 0..depth {
+    for (d, &has_next) in path.iter().enumerate().take(depth) {
       if d == depth - 1 {
-        if path[d] {
+        if has_next {
           print!("├─ ");
         } else {
           print!("└─ ");
         }
+      } else if has_next {
+        print!("|  ");
       } else {
-        if path[d] {
-          print!("|  ");
-        } else {
-          print!("   ");
-        }
+        print!("   ");
       }
     }
-    println!("{}", self.value);
+    match &self.ty {
+      Some(ty) => println!("{}: {}", self.value, ty),
+      None => println!("{}", self.value),
+    }
 
     if !self.children.is_empty() {
       for i in 0..self.children.len() {
@@ -93,6 +124,73 @@ impl AST {
   }
 }
 
+/// 生成把右递归的 Expr / Term 链展开成操作数（Factor）和运算符两个序列的函数；
+/// 只读和可变两个版本共用同一段遍历
+macro_rules! chain_collector {
+  ($name:ident, $iter:ident $(, $mut:tt)?) => {
+    pub fn $name<'a>(
+      ast: &'a $($mut)? AST,
+      operands: &mut Vec<&'a $($mut)? AST>,
+      ops: &mut Vec<&'a $($mut)? AST>,
+    ) {
+      if ast.value == "Factor" {
+        operands.push(ast);
+        return;
+      }
+      for (i, child) in ast.children.$iter().enumerate() {
+        if i == 1 {
+          ops.push(child);
+        } else {
+          $name(child, operands, ops);
+        }
+      }
+    }
+  };
+}
+
+chain_collector!(collect_chain, iter);
+chain_collector!(collect_chain_mut, iter_mut, mut);
+
+/// 运算符优先级，数值越大结合越紧
+pub fn priority(op: &str) -> i8 {
+  match op {
+    ">" | "<" | ">=" | "<=" | "==" | "!=" => 9,
+    "+" | "-" => 11,
+    "*" | "/" => 12,
+    _ => 0,
+  }
+}
+
+/// 按运算符优先级组合 `collect_chain` 展开的链（同级左结合）。
+/// `operands` 是从左到右已经处理好的操作数，`apply(op, lhs, rhs)` 组合一个运算符两侧的结果
+pub fn fold_chain<O, T>(ops: Vec<O>, operands: Vec<T>, mut apply: impl FnMut(O, T, T) -> T) -> T
+where
+  O: Deref<Target = AST>,
+{
+  let mut reduce = |op: O, stack: &mut Vec<T>| {
+    let rhs = stack.pop().unwrap();
+    let lhs = stack.pop().unwrap();
+    stack.push(apply(op, lhs, rhs));
+  };
+  let mut operands = operands.into_iter();
+  let mut stack = vec![operands.next().unwrap()];
+  let mut pending: Vec<O> = vec![];
+  for (op, operand) in ops.into_iter().zip(operands) {
+    while pending
+      .last()
+      .is_some_and(|top| priority(&top.value) >= priority(&op.value))
+    {
+      reduce(pending.pop().unwrap(), &mut stack);
+    }
+    pending.push(op);
+    stack.push(operand);
+  }
+  while let Some(op) = pending.pop() {
+    reduce(op, &mut stack);
+  }
+  stack.pop().unwrap()
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -135,4 +233,53 @@ mod tests {
     let expression = tree.get_expression();
     assert_eq!(expression, vec!["1", "+", "2"]);
   }
+
+  #[test]
+  fn test_collect_chain() {
+    let factor = |value: &str| {
+      AST::new(
+        "Factor".to_string(),
+        vec![AST::new(value.to_string(), vec![])],
+      )
+    };
+    let op = |value: &str| AST::new(value.to_string(), vec![]);
+    // a * b + c：Expr[Term[a, *, Term[b]], +, Expr[Term[c]]]
+    let term = |children| AST::new("Term".to_string(), children);
+    let mut tree = AST::new(
+      "Expr".to_string(),
+      vec![
+        term(vec![factor("a"), op("*"), term(vec![factor("b")])]),
+        op("+"),
+        AST::new("Expr".to_string(), vec![term(vec![factor("c")])]),
+      ],
+    );
+    let (mut operands, mut ops) = (vec![], vec![]);
+    collect_chain(&tree, &mut operands, &mut ops);
+    let operands: Vec<String> = operands.iter().flat_map(|f| f.get_expression()).collect();
+    let ops: Vec<&str> = ops.iter().map(|op| op.value.as_str()).collect();
+    assert_eq!(
+      (operands, ops),
+      (
+        vec!["a".to_string(), "b".into(), "c".into()],
+        vec!["*", "+"]
+      )
+    );
+    let (mut operands, mut ops) = (vec![], vec![]);
+    collect_chain_mut(&mut tree, &mut operands, &mut ops);
+    ops[1].value = "-".to_string();
+    assert_eq!(tree.get_expression(), vec!["a", "*", "b", "-", "c"]);
+  }
+
+  #[test]
+  fn test_fold_chain() {
+    let ops: Vec<AST> = ["-", "*", "+", "<", "-"]
+      .iter()
+      .map(|op| AST::new(op.to_string(), vec![]))
+      .collect();
+    let operands = ["a", "b", "c", "d", "e", "f"].map(String::from).to_vec();
+    let folded = fold_chain(ops.iter().collect(), operands, |op, lhs, rhs| {
+      format!("({} {} {})", lhs, op.value, rhs)
+    });
+    assert_eq!(folded, "(((a - (b * c)) + d) < (e - f))");
+  }
 }
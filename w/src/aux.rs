@@ -9,5 +9,34 @@
 pub fn is_identifier(token: &str) -> bool {
   token
     .chars()
-    .all(|c| (c <= 'z' && c >= 'a') || c == '_' || (c <= 'Z' && c >= 'A'))
+    .all(|c| c.is_ascii_lowercase() || c == '_' || c.is_ascii_uppercase())
+}
+
+/// 两个字符串的编辑距离（Levenshtein distance）
+pub fn edit_distance(a: &str, b: &str) -> usize {
+  let b: Vec<char> = b.chars().collect();
+  let mut prev: Vec<usize> = (0..=b.len()).collect();
+  for (i, ca) in a.chars().enumerate() {
+    let mut cur = vec![i + 1];
+    for (j, cb) in b.iter().enumerate() {
+      let cost = if ca == *cb { 0 } else { 1 };
+      cur.push((prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1));
+    }
+    prev = cur;
+  }
+  prev[b.len()]
+}
+
+/// 从候选名字中找出与 name 最接近的一个，差别太大时返回 None
+pub fn suggest_name<'a>(
+  name: &str,
+  candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+  let limit = (name.chars().count() / 3).max(1);
+  candidates
+    .into_iter()
+    .map(|candidate| (edit_distance(name, candidate), candidate))
+    .filter(|(distance, _)| *distance <= limit)
+    .min()
+    .map(|(_, candidate)| candidate)
 }
@@ -0,0 +1,264 @@
+//! 控制流图与支配关系
+//!
+//! 支配树用 Cooper、Harvey、Kennedy 的迭代算法计算（按逆后序反复求 idom 直到不动点），
+//! 支配边界按每个汇合点向上走到其 idom 的方法求得。从入口不可达的块不参与计算。
+
+use crate::ir::{BlockId, Function};
+
+pub struct Cfg {
+  /// 后继和前驱，下标是块的编号；同一条边只记一次
+  pub succs: Vec<Vec<BlockId>>,
+  pub preds: Vec<Vec<BlockId>>,
+  /// 从入口出发的逆后序，只包含可达的块
+  pub rpo: Vec<BlockId>,
+}
+
+impl Cfg {
+  pub fn new(function: &Function) -> Cfg {
+    let n = function.blocks.len();
+    let mut succs = vec![vec![]; n];
+    let mut preds: Vec<Vec<BlockId>> = vec![vec![]; n];
+    for block in &function.blocks {
+      for succ in block.term.successors() {
+        let from = &mut succs[block.id.0 as usize];
+        if !from.contains(&succ) {
+          from.push(succ);
+          preds[succ.0 as usize].push(block.id);
+        }
+      }
+    }
+    let mut rpo = vec![];
+    if n > 0 {
+      // 非递归的后序遍历：栈中保存 (块, 下一个要访问的后继)
+      let mut visited = vec![false; n];
+      let mut stack = vec![(BlockId(0), 0)];
+      visited[0] = true;
+      while let Some((block, next)) = stack.last_mut() {
+        let block = *block;
+        if let Some(&succ) = succs[block.0 as usize].get(*next) {
+          *next += 1;
+          if !visited[succ.0 as usize] {
+            visited[succ.0 as usize] = true;
+            stack.push((succ, 0));
+          }
+        } else {
+          rpo.push(block);
+          stack.pop();
+        }
+      }
+      rpo.reverse();
+    }
+    Cfg { succs, preds, rpo }
+  }
+
+  pub fn succs(&self, block: BlockId) -> &[BlockId] {
+    &self.succs[block.0 as usize]
+  }
+
+  pub fn preds(&self, block: BlockId) -> &[BlockId] {
+    &self.preds[block.0 as usize]
+  }
+}
+
+pub struct Dominators {
+  /// 直接支配者；入口是它自己，不可达的块是 None
+  idom: Vec<Option<BlockId>>,
+  children: Vec<Vec<BlockId>>,
+  frontiers: Vec<Vec<BlockId>>,
+}
+
+impl Dominators {
+  pub fn new(cfg: &Cfg) -> Dominators {
+    let n = cfg.succs.len();
+    let mut order = vec![usize::MAX; n];
+    for (i, block) in cfg.rpo.iter().enumerate() {
+      order[block.0 as usize] = i;
+    }
+    let mut idom: Vec<Option<BlockId>> = vec![None; n];
+    if let Some(&entry) = cfg.rpo.first() {
+      idom[entry.0 as usize] = Some(entry);
+    }
+    let intersect = |idom: &[Option<BlockId>], mut a: BlockId, mut b: BlockId| {
+      while a != b {
+        while order[a.0 as usize] > order[b.0 as usize] {
+          a = idom[a.0 as usize].unwrap();
+        }
+        while order[b.0 as usize] > order[a.0 as usize] {
+          b = idom[b.0 as usize].unwrap();
+        }
+      }
+      a
+    };
+    let mut changed = true;
+    while changed {
+      changed = false;
+      for &block in cfg.rpo.iter().skip(1) {
+        let mut new_idom = None;
+        for &pred in cfg.preds(block) {
+          if idom[pred.0 as usize].is_none() {
+            continue;
+          }
+          new_idom = Some(match new_idom {
+            None => pred,
+            Some(other) => intersect(&idom, pred, other),
+          });
+        }
+        if idom[block.0 as usize] != new_idom {
+          idom[block.0 as usize] = new_idom;
+          changed = true;
+        }
+      }
+    }
+
+    let mut children = vec![vec![]; n];
+    for &block in cfg.rpo.iter().skip(1) {
+      children[idom[block.0 as usize].unwrap().0 as usize].push(block);
+    }
+    let mut frontiers: Vec<Vec<BlockId>> = vec![vec![]; n];
+    for &block in &cfg.rpo {
+      let preds = cfg.preds(block);
+      if preds.len() < 2 {
+        continue;
+      }
+      let stop = idom[block.0 as usize].unwrap();
+      for &pred in preds {
+        if idom[pred.0 as usize].is_none() {
+          continue;
+        }
+        let mut runner = pred;
+        while runner != stop {
+          let frontier = &mut frontiers[runner.0 as usize];
+          if !frontier.contains(&block) {
+            frontier.push(block);
+          }
+          runner = idom[runner.0 as usize].unwrap();
+        }
+      }
+    }
+    for list in children.iter_mut().chain(&mut frontiers) {
+      list.sort();
+    }
+    Dominators {
+      idom,
+      children,
+      frontiers,
+    }
+  }
+
+  pub fn is_reachable(&self, block: BlockId) -> bool {
+    self.idom[block.0 as usize].is_some()
+  }
+
+  /// 直接支配者，入口和不可达的块没有
+  pub fn idom(&self, block: BlockId) -> Option<BlockId> {
+    self.idom[block.0 as usize].filter(|&idom| idom != block)
+  }
+
+  /// a 是否支配 b（每个可达的块都支配它自己）
+  pub fn dominates(&self, a: BlockId, b: BlockId) -> bool {
+    if !self.is_reachable(a) || !self.is_reachable(b) {
+      return false;
+    }
+    let mut block = b;
+    loop {
+      if block == a {
+        return true;
+      }
+      match self.idom(block) {
+        Some(idom) => block = idom,
+        None => return false,
+      }
+    }
+  }
+
+  /// 支配树中的子节点
+  pub fn children(&self, block: BlockId) -> &[BlockId] {
+    &self.children[block.0 as usize]
+  }
+
+  pub fn frontier(&self, block: BlockId) -> &[BlockId] {
+    &self.frontiers[block.0 as usize]
+  }
+
+  /// 支配树的先序遍历：每个块都排在它支配的块之前
+  pub fn preorder(&self) -> Vec<BlockId> {
+    let mut order = vec![];
+    if self.idom.first().is_some_and(Option::is_some) {
+      let mut stack = vec![BlockId(0)];
+      while let Some(block) = stack.pop() {
+        order.push(block);
+        stack.extend(self.children(block).iter().rev());
+      }
+    }
+    order
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ir::parse_module;
+
+  fn function(text: &str) -> Function {
+    parse_module(text).unwrap().functions.remove(0)
+  }
+
+  // b0 -> b1 (循环头) -> b2 -> b3 / b4 -> b5 -> b1，b1 -> b6
+  const LOOP: &str = "fn f() -> void {
+b0:
+  jmp b1
+b1:
+  br true, b2, b6
+b2:
+  br false, b3, b4
+b3:
+  jmp b5
+b4:
+  jmp b5
+b5:
+  jmp b1
+b6:
+  ret
+}
+";
+
+  #[test]
+  fn test_cfg() {
+    let f = function(LOOP);
+    let cfg = Cfg::new(&f);
+    assert_eq!(cfg.preds(BlockId(1)), &[BlockId(0), BlockId(5)]);
+    assert_eq!(cfg.succs(BlockId(2)), &[BlockId(3), BlockId(4)]);
+    assert_eq!(cfg.rpo[0], BlockId(0));
+    assert_eq!(cfg.rpo.len(), 7);
+    let position = |id| cfg.rpo.iter().position(|&b| b == BlockId(id)).unwrap();
+    assert!(position(2) < position(3) && position(3) < position(5));
+  }
+
+  #[test]
+  fn test_dominators_and_frontiers() {
+    let f = function(LOOP);
+    let dom = Dominators::new(&Cfg::new(&f));
+    assert_eq!(dom.idom(BlockId(0)), None);
+    assert_eq!(dom.idom(BlockId(5)), Some(BlockId(2)));
+    assert_eq!(dom.idom(BlockId(6)), Some(BlockId(1)));
+    assert!(dom.dominates(BlockId(1), BlockId(4)));
+    assert!(!dom.dominates(BlockId(3), BlockId(5)));
+    assert_eq!(dom.frontier(BlockId(3)), &[BlockId(5)]);
+    assert_eq!(dom.frontier(BlockId(5)), &[BlockId(1)]);
+    assert_eq!(dom.frontier(BlockId(2)), &[BlockId(1)]);
+    assert_eq!(dom.frontier(BlockId(1)), &[BlockId(1)]);
+    assert!(dom.frontier(BlockId(6)).is_empty());
+    assert_eq!(dom.preorder(), [0, 1, 2, 3, 4, 5, 6].map(BlockId).to_vec());
+  }
+
+  #[test]
+  fn test_unreachable_blocks() {
+    let f = function("fn f() -> void {\nb0:\n  jmp b2\nb1:\n  jmp b2\nb2:\n  ret\n}\n");
+    let cfg = Cfg::new(&f);
+    let dom = Dominators::new(&cfg);
+    assert_eq!(cfg.rpo, vec![BlockId(0), BlockId(2)]);
+    assert!(!dom.is_reachable(BlockId(1)));
+    assert_eq!(dom.idom(BlockId(2)), Some(BlockId(0)));
+    assert!(dom.frontier(BlockId(0)).is_empty());
+  }
+}
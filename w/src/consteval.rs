@@ -0,0 +1,952 @@
+//! 编译期常量求值
+//!
+//! 求值器直接在 AST 上解释执行，用于：
+//! - 全局常量 `const int N = ...` 的初始值
+//! - `static_assert(cond, "message")`
+//! - 数组类型的长度 `[int; N * 2]`，求值后替换成整数字面量
+//! - 函数中实参都是常量的 `const` 函数调用（如 `fibonacci(10)`）和常量的使用，结果替换到 AST 中
+//!
+//! const 函数只能调用 const 函数和 `wrapping_add` 等回绕运算的内建函数。每执行一条语句或一次调用消耗一步，超过预算就停止求值。
+//! 除以零、整数溢出等错误报告在发起求值的位置（常量的初始值、函数中的调用处），
+//! 并注明出错时所在的函数。
+
+use crate::ast::{collect_chain, fold_chain, AST};
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::resolve::{collect_consts, source_name};
+use crate::signature::{param_nodes, BUILTIN_FUNCTIONS, WRAPPING_FUNCTIONS};
+use crate::types::Type;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
+
+/// 每次求值（一个常量、一处调用）最多执行的步数
+pub const STEP_BUDGET: usize = 1_000_000;
+/// const 函数调用的最大嵌套深度
+const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+  Int(i64),
+  Float(f64),
+  Bool(bool),
+  /// 带引号的字符串字面量
+  Str(String),
+}
+
+impl Value {
+  pub fn ty(&self) -> Type {
+    match self {
+      Value::Int(_) => Type::Int,
+      Value::Float(_) => Type::Float,
+      Value::Bool(_) => Type::Bool,
+      Value::Str(_) => Type::Str,
+    }
+  }
+}
+
+impl fmt::Display for Value {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Value::Int(n) => write!(f, "{}", n),
+      Value::Float(x) => write!(f, "{:?}", x),
+      Value::Bool(b) => write!(f, "{}", b),
+      Value::Str(s) => write!(f, "{}", s),
+    }
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EvalError {
+  /// 表达式依赖运行时的值，不能在编译期求值
+  NotConstant { message: String, span: Span },
+  /// 求值出错；`trace` 是出错时正在执行的 const 函数，最内层在最后
+  Failed {
+    message: String,
+    span: Span,
+    trace: Vec<String>,
+  },
+  /// 超出步数预算
+  OutOfBudget,
+}
+
+enum Flow {
+  Next,
+  Break,
+  Continue,
+  Return(Option<Value>),
+}
+
+pub struct ConstEval {
+  fns: HashMap<String, Rc<AST>>,
+  consts: HashMap<String, Rc<AST>>,
+  values: HashMap<String, Value>,
+  /// 求值失败的常量，之后的使用不再重复报告原因
+  failed: HashSet<String>,
+  /// 正在求值的常量，用于发现循环定义
+  evaluating: Vec<String>,
+  /// 每个调用帧是一组嵌套的块作用域；None 表示已声明但未赋值
+  frames: Vec<Vec<HashMap<String, Option<Value>>>>,
+  trace: Vec<String>,
+  steps: usize,
+  pub budget: usize,
+}
+
+/// 求值程序中的常量、`static_assert` 和数组长度。需要在名字解析和类型检查之前运行，
+/// 因为类型中的数组长度在之后的阶段都应该是整数字面量
+pub fn eval_constants(ast: &mut AST) -> (ConstEval, Vec<Diagnostic>) {
+  let mut eval = ConstEval::new(ast);
+  let mut diagnostics = eval.check_const_fns();
+  let consts: Vec<(String, Span)> = collect_consts(ast)
+    .into_iter()
+    .map(|def| (def.children[1].value.clone(), def.children[2].span))
+    .collect();
+  for (name, span) in consts {
+    eval.begin();
+    if let Err(err) = eval.eval_const(&name, span) {
+      diagnostics.push(eval.diagnostic(err, span));
+    }
+  }
+  eval.check_static_asserts(ast, &mut diagnostics);
+  eval.eval_array_lengths(ast, &mut diagnostics);
+  (eval, diagnostics)
+}
+
+/// 在函数中用常量的值替换常量的使用，并在编译期执行实参都是常量的 const 函数调用。
+/// 需要在名字解析之后运行，此时与常量同名的局部变量已经被改名
+pub fn fold_constants(ast: &mut AST, eval: &mut ConstEval) -> Vec<Diagnostic> {
+  let mut diagnostics = vec![];
+  match ast.value.as_str() {
+    "Fn" => eval.fold(ast, &mut diagnostics),
+    "Pg" | "FnList" => {
+      for child in &mut ast.children {
+        diagnostics.extend(fold_constants(child, eval));
+      }
+    }
+    _ => {}
+  }
+  diagnostics
+}
+
+/// 只由一个函数调用构成的表达式语句
+fn lone_call(expr: &AST) -> Option<&AST> {
+  let mut node = expr;
+  while node.children.len() == 1 {
+    node = &node.children[0];
+    if node.value == "FnCall" {
+      return Some(node);
+    }
+  }
+  None
+}
+
+impl ConstEval {
+  pub fn new(ast: &AST) -> Self {
+    let mut fns = HashMap::new();
+    collect_const_fns(ast, &mut fns);
+    let consts = collect_consts(ast)
+      .into_iter()
+      .map(|def| (def.children[1].value.clone(), Rc::new(def.clone())))
+      .collect();
+    ConstEval {
+      fns,
+      consts,
+      values: HashMap::new(),
+      failed: HashSet::new(),
+      evaluating: vec![],
+      frames: vec![],
+      trace: vec![],
+      steps: 0,
+      budget: STEP_BUDGET,
+    }
+  }
+
+  /// 求值一个不含局部变量的表达式（Expr、Factor、FnCall 或字面量）
+  pub fn eval(&mut self, ast: &AST) -> Result<Value, EvalError> {
+    self.begin();
+    match ast.value.as_str() {
+      "Expr" | "Term" => self.eval_expr(ast),
+      "Factor" => self.eval_factor(ast),
+      "FnCall" => self.call_value(ast),
+      _ => self.eval_leaf(ast),
+    }
+  }
+
+  /// 开始一次新的求值：重新计算步数
+  fn begin(&mut self) {
+    self.steps = 0;
+    self.frames.clear();
+    self.trace.clear();
+  }
+
+  fn step(&mut self) -> Result<(), EvalError> {
+    self.steps += 1;
+    if self.steps > self.budget {
+      return Err(EvalError::OutOfBudget);
+    }
+    Ok(())
+  }
+
+  fn fail(&self, message: String, span: Span) -> EvalError {
+    EvalError::Failed {
+      message,
+      span,
+      trace: self.trace.clone(),
+    }
+  }
+
+  /// 把求值错误转换成诊断信息；错误发生在 const 函数内部时报告在发起求值的位置 `site`
+  pub fn diagnostic(&self, err: EvalError, site: Span) -> Diagnostic {
+    match err {
+      EvalError::NotConstant { message, span } => Diagnostic::error(message, span),
+      EvalError::Failed {
+        message,
+        span,
+        trace,
+      } => {
+        let message = format!("constant evaluation failed: {}", message);
+        if trace.is_empty() {
+          return Diagnostic::error(message, span);
+        }
+        let frames: Vec<String> = trace.iter().rev().map(|f| format!("`{}`", f)).collect();
+        Diagnostic::error(message, site).with_note(format!(
+          "the error occurred inside {}",
+          frames.join(", called from ")
+        ))
+      }
+      EvalError::OutOfBudget => Diagnostic::error(
+        "constant evaluation exceeded the step budget".to_string(),
+        site,
+      )
+      .with_note(format!(
+        "evaluation is stopped after {} steps; this may be an infinite loop",
+        self.budget
+      )),
+    }
+  }
+
+  /// const 函数只能调用 const 函数
+  fn check_const_fns(&self) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut names: Vec<&String> = self.fns.keys().collect();
+    names.sort_by_key(|name| self.fns[*name].span.start);
+    for name in names {
+      self.check_calls_in(&self.fns[name].children[3], name, &mut diagnostics);
+    }
+    diagnostics
+  }
+
+  fn check_calls_in(&self, ast: &AST, caller: &str, diagnostics: &mut Vec<Diagnostic>) {
+    if ast.value == "FnCall" {
+      let callee = &ast.children[0];
+      let wrapping = WRAPPING_FUNCTIONS.iter().any(|(f, _)| *f == callee.value);
+      if !self.fns.contains_key(&callee.value) && !wrapping {
+        let diagnostic = Diagnostic::error(
+          format!(
+            "cannot call non-const function `{}` in const function `{}`",
+            callee.value, caller
+          ),
+          callee.span,
+        );
+        let builtin = BUILTIN_FUNCTIONS.iter().any(|(b, _)| *b == callee.value);
+        diagnostics.push(if builtin {
+          diagnostic.with_note(format!(
+            "`{}` depends on the program's input and output",
+            callee.value
+          ))
+        } else {
+          diagnostic.with_note(format!(
+            "mark `{}` as `const` to call it at compile time",
+            callee.value
+          ))
+        });
+      }
+    }
+    for child in &ast.children {
+      self.check_calls_in(child, caller, diagnostics);
+    }
+  }
+
+  fn check_static_asserts(&mut self, ast: &AST, diagnostics: &mut Vec<Diagnostic>) {
+    match ast.value.as_str() {
+      "StaticAssert" => {
+        let cond = &ast.children[0];
+        match self.eval(cond) {
+          Ok(Value::Bool(true)) => {}
+          Ok(Value::Bool(false)) => {
+            let message = match ast.children.get(1) {
+              Some(message) => format!(
+                "static assertion failed: {}",
+                message.value.trim_matches('"')
+              ),
+              None => "static assertion failed".to_string(),
+            };
+            diagnostics.push(Diagnostic::error(message, cond.span));
+          }
+          Ok(value) => diagnostics.push(Diagnostic::error(
+            format!(
+              "mismatched types: `static_assert` condition must be `bool`, found `{}`",
+              value.ty()
+            ),
+            cond.span,
+          )),
+          Err(err) => diagnostics.push(self.diagnostic(err, cond.span)),
+        }
+      }
+      "Pg" | "FnList" => {
+        for child in &ast.children {
+          self.check_static_asserts(child, diagnostics);
+        }
+      }
+      _ => {}
+    }
+  }
+
+  fn eval_array_lengths(&mut self, ast: &mut AST, diagnostics: &mut Vec<Diagnostic>) {
+    if ast.value == "ArrayType" && !ast.children[1].children.is_empty() {
+      let len = &ast.children[1];
+      let value = match self.eval(len) {
+        Ok(Value::Int(n)) if n >= 0 => n,
+        Ok(value) => {
+          diagnostics.push(Diagnostic::error(
+            format!(
+              "array length must be a non-negative `int`, found `{}`",
+              value
+            ),
+            len.span,
+          ));
+          0
+        }
+        Err(err) => {
+          diagnostics.push(self.diagnostic(err, len.span));
+          0
+        }
+      };
+      ast.children[1] = AST::new(value.to_string(), vec![]).with_span(len.span);
+    }
+    for child in &mut ast.children {
+      self.eval_array_lengths(child, diagnostics);
+    }
+  }
+
+  /// 自底向上替换函数中可以在编译期求值的常量使用和 const 函数调用
+  fn fold(&mut self, ast: &mut AST, diagnostics: &mut Vec<Diagnostic>) {
+    for child in &mut ast.children {
+      self.fold(child, diagnostics);
+    }
+    if ast.value != "Factor" || ast.children.len() != 1 {
+      return;
+    }
+    let inner = &ast.children[0];
+    let callee = match inner.value.as_str() {
+      "FnCall" if self.fns.contains_key(&inner.children[0].value) => {
+        Some(inner.children[0].value.clone())
+      }
+      name if inner.children.is_empty() && self.consts.contains_key(name) => None,
+      _ => return,
+    };
+    match self.eval(inner) {
+      // 非有限的浮点数没有对应的字面量，留到运行时计算
+      Ok(Value::Float(x)) if !x.is_finite() => {}
+      Ok(value) => {
+        let mut leaf = AST::new(value.to_string(), vec![]).with_span(inner.span);
+        leaf.ty = Some(value.ty());
+        ast.children[0] = leaf;
+      }
+      Err(EvalError::NotConstant { .. }) => {}
+      Err(EvalError::OutOfBudget) => {
+        // 不是必须在编译期求值的调用，超出预算时留到运行时执行
+        diagnostics.push(
+          Diagnostic::warning(
+            format!(
+              "call to `{}` is not evaluated at compile time",
+              callee.unwrap_or_default()
+            ),
+            inner.span,
+          )
+          .with_note(format!(
+            "constant evaluation is stopped after {} steps",
+            self.budget
+          )),
+        );
+      }
+      Err(err) => {
+        let span = inner.span;
+        let mut diagnostic = self.diagnostic(err, span);
+        diagnostic.span = span;
+        if let Some(callee) = callee {
+          diagnostic = diagnostic.with_note(format!(
+            "`{}` is a const function and all arguments are constants, so the call is evaluated at compile time",
+            callee
+          ));
+        }
+        diagnostics.push(diagnostic);
+      }
+    }
+  }
+
+  fn eval_const(&mut self, name: &str, span: Span) -> Result<Value, EvalError> {
+    if let Some(value) = self.values.get(name) {
+      return Ok(value.clone());
+    }
+    if self.failed.contains(name) {
+      return Err(self.fail(format!("constant `{}` could not be evaluated", name), span));
+    }
+    if self.evaluating.iter().any(|c| c == name) {
+      return Err(EvalError::Failed {
+        message: format!("constant `{}` depends on itself", name),
+        span,
+        trace: vec![],
+      });
+    }
+    let def = self.consts[name].clone();
+    // 常量的初始值在全局作用域中求值
+    let frames = std::mem::take(&mut self.frames);
+    let trace = std::mem::take(&mut self.trace);
+    self.evaluating.push(name.to_string());
+    let result = self.eval_expr(&def.children[2]);
+    self.evaluating.pop();
+    self.frames = frames;
+    self.trace = trace;
+    match &result {
+      Ok(value) => {
+        self.values.insert(name.to_string(), value.clone());
+      }
+      Err(EvalError::Failed { .. }) | Err(EvalError::NotConstant { .. }) => {
+        self.failed.insert(name.to_string());
+      }
+      Err(EvalError::OutOfBudget) => {}
+    }
+    result
+  }
+
+  /// 表达式：按运算符优先级求值
+  fn eval_expr(&mut self, ast: &AST) -> Result<Value, EvalError> {
+    let mut operands = vec![];
+    let mut ops = vec![];
+    collect_chain(ast, &mut operands, &mut ops);
+    let mut values = vec![];
+    for operand in operands {
+      values.push(Ok(self.eval_factor(operand)?));
+    }
+    // 左侧的运算总是先于右侧完成，因此返回的是最先出现的错误
+    fold_chain(ops, values, |op, lhs, rhs| self.binary(op, lhs?, rhs?))
+  }
+
+  fn binary(&self, op: &AST, lhs: Value, rhs: Value) -> Result<Value, EvalError> {
+    let overflow = |verb: &str| self.fail(format!("attempt to {} with overflow", verb), op.span);
+    let value = match (&lhs, &rhs) {
+      (Value::Int(a), Value::Int(b)) => match op.value.as_str() {
+        "+" => Value::Int(a.checked_add(*b).ok_or_else(|| overflow("add"))?),
+        "-" => Value::Int(a.checked_sub(*b).ok_or_else(|| overflow("subtract"))?),
+        "*" => Value::Int(a.checked_mul(*b).ok_or_else(|| overflow("multiply"))?),
+        "/" if *b == 0 => return Err(self.fail("attempt to divide by zero".to_string(), op.span)),
+        "/" => Value::Int(a.checked_div(*b).ok_or_else(|| overflow("divide"))?),
+        "<" => Value::Bool(a < b),
+        ">" => Value::Bool(a > b),
+        "<=" => Value::Bool(a <= b),
+        ">=" => Value::Bool(a >= b),
+        "==" => Value::Bool(a == b),
+        "!=" => Value::Bool(a != b),
+        _ => return Err(self.mismatch(op, &lhs, &rhs)),
+      },
+      (Value::Float(a), Value::Float(b)) => match op.value.as_str() {
+        "+" => Value::Float(a + b),
+        "-" => Value::Float(a - b),
+        "*" => Value::Float(a * b),
+        "/" => Value::Float(a / b),
+        "<" => Value::Bool(a < b),
+        ">" => Value::Bool(a > b),
+        "<=" => Value::Bool(a <= b),
+        ">=" => Value::Bool(a >= b),
+        "==" => Value::Bool(a == b),
+        "!=" => Value::Bool(a != b),
+        _ => return Err(self.mismatch(op, &lhs, &rhs)),
+      },
+      (Value::Bool(a), Value::Bool(b)) if op.value == "==" => Value::Bool(a == b),
+      (Value::Bool(a), Value::Bool(b)) if op.value == "!=" => Value::Bool(a != b),
+      _ => return Err(self.mismatch(op, &lhs, &rhs)),
+    };
+    Ok(value)
+  }
+
+  fn mismatch(&self, op: &AST, lhs: &Value, rhs: &Value) -> EvalError {
+    self.fail(
+      format!(
+        "cannot apply `{}` to `{}` and `{}`",
+        op.value,
+        lhs.ty(),
+        rhs.ty()
+      ),
+      op.span,
+    )
+  }
+
+  fn eval_factor(&mut self, ast: &AST) -> Result<Value, EvalError> {
+    if ast.children.len() == 3 {
+      // ( Expr )
+      return self.eval_expr(&ast.children[1]);
+    }
+    let inner = &ast.children[0];
+    match inner.value.as_str() {
+      "FnCall" => self.call_value(inner),
+      "Index" => Err(EvalError::NotConstant {
+        message: "indexing cannot be evaluated at compile time".to_string(),
+        span: inner.span,
+      }),
+      _ => self.eval_leaf(inner),
+    }
+  }
+
+  fn eval_leaf(&mut self, ast: &AST) -> Result<Value, EvalError> {
+    let token = ast.value.as_str();
+    if token.starts_with('"') {
+      return Ok(Value::Str(token.to_string()));
+    }
+    if token == "true" || token == "false" {
+      return Ok(Value::Bool(token == "true"));
+    }
+    // 常量折叠产生的字面量可能是负数
+    if token.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+      if let Ok(n) = token.parse::<i64>() {
+        return Ok(Value::Int(n));
+      }
+      if token.contains(['.', 'e']) {
+        if let Ok(x) = token.parse::<f64>() {
+          return Ok(Value::Float(x));
+        }
+      }
+      return Err(self.fail(
+        format!("integer literal `{}` is too large", token),
+        ast.span,
+      ));
+    }
+    let local = self
+      .frames
+      .last()
+      .and_then(|scopes| scopes.iter().rev().find_map(|scope| scope.get(token)))
+      .cloned();
+    match local {
+      Some(Some(value)) => Ok(value),
+      Some(None) => Err(self.fail(
+        format!(
+          "variable `{}` is used before being assigned",
+          source_name(token)
+        ),
+        ast.span,
+      )),
+      None if self.consts.contains_key(token) => self.eval_const(token, ast.span),
+      None => Err(EvalError::NotConstant {
+        message: format!("`{}` is not a constant", source_name(token)),
+        span: ast.span,
+      }),
+    }
+  }
+
+  /// 调用必须有返回值
+  fn call_value(&mut self, call: &AST) -> Result<Value, EvalError> {
+    match self.call(call)? {
+      Some(value) => Ok(value),
+      None => Err(self.fail(
+        format!("`{}` does not return a value", call.children[0].value),
+        call.span,
+      )),
+    }
+  }
+
+  fn call(&mut self, call: &AST) -> Result<Option<Value>, EvalError> {
+    let name = &call.children[0].value;
+    if let Some((_, op)) = WRAPPING_FUNCTIONS.iter().find(|(f, _)| f == name) {
+      return self.wrapping(call, op).map(Some);
+    }
+    let Some(f) = self.fns.get(name).cloned() else {
+      let builtin = BUILTIN_FUNCTIONS.iter().any(|(b, _)| b == name);
+      let message = if builtin {
+        format!("`{}` cannot be called in a constant expression", name)
+      } else {
+        format!(
+          "cannot call non-const function `{}` in a constant expression",
+          name
+        )
+      };
+      return Err(EvalError::NotConstant {
+        message,
+        span: call.span,
+      });
+    };
+    let params = param_nodes(&f.children[2]);
+    if params
+      .iter()
+      .any(|p| p.children[0].children[0].value == "Variadic")
+    {
+      return Err(EvalError::NotConstant {
+        message: format!(
+          "variadic function `{}` cannot be evaluated at compile time",
+          name
+        ),
+        span: call.span,
+      });
+    }
+    // 实参在调用方求值，缺省的参数在全局作用域中求默认值
+    let mut args: Vec<Option<Value>> = vec![None; params.len()];
+    let mut position = 0;
+    for arg in &call.children[1..] {
+      let index = if arg.value == "NamedArg" {
+        let key = &arg.children[0].value;
+        params
+          .iter()
+          .position(|p| source_name(&p.children[1].value) == key)
+          .ok_or_else(|| {
+            self.fail(
+              format!("`{}` has no parameter named `{}`", name, key),
+              arg.span,
+            )
+          })?
+      } else {
+        position += 1;
+        position - 1
+      };
+      if index >= params.len() {
+        return Err(self.fail(
+          format!("too many arguments in call to `{}`", name),
+          arg.span,
+        ));
+      }
+      let expr = if arg.value == "NamedArg" {
+        &arg.children[1]
+      } else {
+        arg
+      };
+      args[index] = Some(self.eval_expr(expr)?);
+    }
+    let mut scope = HashMap::new();
+    for (param, arg) in params.iter().zip(args) {
+      let value = match arg {
+        Some(value) => value,
+        None => {
+          let default = &param.children[2].children[0];
+          if default.value == "ε" {
+            return Err(self.fail(
+              format!(
+                "missing argument `{}` in call to `{}`",
+                param.children[1].value, name
+              ),
+              call.span,
+            ));
+          }
+          let frames = std::mem::take(&mut self.frames);
+          let value = self.eval_expr(default);
+          self.frames = frames;
+          value?
+        }
+      };
+      scope.insert(param.children[1].value.clone(), Some(value));
+    }
+
+    self.step()?;
+    if self.frames.len() >= MAX_DEPTH {
+      return Err(self.fail(
+        format!("recursion limit of {} nested calls reached", MAX_DEPTH),
+        call.span,
+      ));
+    }
+    self.trace.push(name.clone());
+    self.frames.push(vec![scope]);
+    // 函数体最外层与参数共用一个作用域
+    let flow = self.exec_list(&f.children[3].children[0])?;
+    self.frames.pop();
+    self.trace.pop();
+    Ok(match flow {
+      Flow::Return(value) => value,
+      _ => None,
+    })
+  }
+
+  /// 内建的 `wrapping_add` 等函数：整数运算溢出时回绕
+  fn wrapping(&mut self, call: &AST, op: &str) -> Result<Value, EvalError> {
+    let mut args = vec![];
+    for arg in &call.children[1..] {
+      match self.eval_expr(arg)? {
+        Value::Int(n) => args.push(n),
+        value => {
+          return Err(self.fail(
+            format!(
+              "`{}` expects `int` arguments, found `{}`",
+              call.children[0].value,
+              value.ty()
+            ),
+            arg.span,
+          ))
+        }
+      }
+    }
+    let [a, b] = args[..] else {
+      return Err(self.fail(
+        format!("`{}` takes 2 arguments", call.children[0].value),
+        call.span,
+      ));
+    };
+    Ok(Value::Int(match op {
+      "+" => a.wrapping_add(b),
+      "-" => a.wrapping_sub(b),
+      _ => a.wrapping_mul(b),
+    }))
+  }
+
+  /// 执行 `if` / `while` 的语句块，块中声明的变量在块结束时丢弃
+  fn exec_block(&mut self, ast: &AST) -> Result<Flow, EvalError> {
+    self.frames.last_mut().unwrap().push(HashMap::new());
+    let flow = self.exec_list(ast);
+    self.frames.last_mut().unwrap().pop();
+    flow
+  }
+
+  fn exec_list(&mut self, ast: &AST) -> Result<Flow, EvalError> {
+    let mut stmt_list = ast;
+    while stmt_list.children.len() == 2 {
+      let flow = self.exec_stmt(&stmt_list.children[0].children[0])?;
+      if !matches!(flow, Flow::Next) {
+        return Ok(flow);
+      }
+      stmt_list = &stmt_list.children[1];
+    }
+    Ok(Flow::Next)
+  }
+
+  fn exec_stmt(&mut self, ast: &AST) -> Result<Flow, EvalError> {
+    self.step()?;
+    match ast.value.as_str() {
+      "VarDecl" => self.declare(&ast.children[1].value, None),
+      "VarDef" => {
+        let value = self.eval_expr(&ast.children[2])?;
+        self.declare(&ast.children[1].value, Some(value));
+      }
+      "Assign" => {
+        let value = self.eval_expr(&ast.children[1])?;
+        let target = &ast.children[0];
+        let slot = self.frames.last_mut().and_then(|scopes| {
+          scopes
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.get_mut(&target.value))
+        });
+        match slot {
+          Some(slot) => *slot = Some(value),
+          None => {
+            return Err(self.fail(
+              format!("cannot assign to `{}` at compile time", target.value),
+              target.span,
+            ))
+          }
+        }
+      }
+      "Return" => {
+        let value = match ast.children.first() {
+          Some(expr) => Some(self.eval_expr(expr)?),
+          None => None,
+        };
+        return Ok(Flow::Return(value));
+      }
+      "BranchStmt" => {
+        let branch = if self.condition(&ast.children[0], "if")? {
+          &ast.children[1]
+        } else {
+          &ast.children[2]
+        };
+        return self.exec_block(branch);
+      }
+      "LoopStmt" => {
+        while self.condition(&ast.children[0], "while")? {
+          match self.exec_block(&ast.children[1])? {
+            Flow::Break => break,
+            Flow::Return(value) => return Ok(Flow::Return(value)),
+            Flow::Next | Flow::Continue => self.step()?,
+          }
+        }
+      }
+      "Expr" => match lone_call(ast) {
+        Some(call) => {
+          self.call(call)?;
+        }
+        None => {
+          self.eval_expr(ast)?;
+        }
+      },
+      "break" => return Ok(Flow::Break),
+      "continue" => return Ok(Flow::Continue),
+      _ => {}
+    }
+    Ok(Flow::Next)
+  }
+
+  fn condition(&mut self, cond: &AST, keyword: &str) -> Result<bool, EvalError> {
+    match self.eval_expr(cond)? {
+      Value::Bool(b) => Ok(b),
+      value => Err(self.fail(
+        format!(
+          "mismatched types: `{}` condition must be `bool`, found `{}`",
+          keyword,
+          value.ty()
+        ),
+        cond.span,
+      )),
+    }
+  }
+
+  fn declare(&mut self, name: &str, value: Option<Value>) {
+    let scopes = self.frames.last_mut().unwrap();
+    scopes.last_mut().unwrap().insert(name.to_string(), value);
+  }
+}
+
+fn collect_const_fns(ast: &AST, fns: &mut HashMap<String, Rc<AST>>) {
+  match ast.value.as_str() {
+    "Fn" if ast.has_attr("const") => {
+      fns.insert(ast.children[1].value.clone(), Rc::new(ast.clone()));
+    }
+    "Pg" | "FnList" => {
+      for child in &ast.children {
+        collect_const_fns(child, fns);
+      }
+    }
+    _ => {}
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::parser::Parser;
+  use crate::resolve::resolve;
+
+  const FIB: &str = "const int fibonacci(int n)\n  if n < 2\n    return n\n  else\n    return fibonacci(n - 1) + fibonacci(n - 2)\n";
+
+  fn parse(source: &str) -> AST {
+    Parser::new(Lexer::new(source)).parse()
+  }
+
+  fn messages(source: &str) -> Vec<String> {
+    let mut ast = parse(source);
+    eval_constants(&mut ast)
+      .1
+      .into_iter()
+      .map(|d| d.message)
+      .collect()
+  }
+
+  #[test]
+  fn test_constants_and_static_assert() {
+    let source = format!(
+      "const int N = fibonacci(10) * 2\nconst int M = N / 4\nstatic_assert(M == 27)\nstatic_assert(N < 100, \"N is too large\")\nint main()\n  return 0\n\n{}",
+      FIB
+    );
+    let mut ast = parse(&source);
+    let (mut eval, diagnostics) = eval_constants(&mut ast);
+    assert_eq!(
+      diagnostics
+        .iter()
+        .map(|d| d.message.as_str())
+        .collect::<Vec<_>>(),
+      vec!["static assertion failed: N is too large"]
+    );
+    assert_eq!(eval.values["N"], Value::Int(110));
+    assert_eq!(
+      eval.eval(&AST::new("M".to_string(), vec![])),
+      Ok(Value::Int(27))
+    );
+  }
+
+  #[test]
+  fn test_array_length() {
+    let mut ast =
+      parse("const int N = 3\nint main()\n  [int; N * 2] xs\n  [int; 1 - 2] ys\n  return 0\n");
+    let (_, diagnostics) = eval_constants(&mut ast);
+    assert_eq!(
+      diagnostics[0].message,
+      "array length must be a non-negative `int`, found `-1`"
+    );
+    let ty = crate::types::Type::from_ast(
+      &ast.children[1].children[0].children[3].children[0].children[0].children[0].children[0],
+    );
+    assert_eq!(ty, Type::Array(Box::new(Type::Int), 6));
+  }
+
+  #[test]
+  fn test_errors_are_reported_at_the_call_site() {
+    let source = "const int N = div(1, 0)\nconst int M = N + 1\nconst int big = 9223372036854775807 + 1\n\nconst int div(int a, int b)\n  return a / b\n";
+    let mut ast = parse(source);
+    let diagnostics = eval_constants(&mut ast).1;
+    assert_eq!(
+      diagnostics[0].message,
+      "constant evaluation failed: attempt to divide by zero"
+    );
+    assert_eq!(
+      &source[diagnostics[0].span.start..diagnostics[0].span.end],
+      "div(1, 0)"
+    );
+    assert_eq!(
+      diagnostics[0].notes,
+      vec!["the error occurred inside `div`"]
+    );
+    assert_eq!(
+      diagnostics[1].message,
+      "constant evaluation failed: constant `N` could not be evaluated"
+    );
+    assert_eq!(
+      diagnostics[2].message,
+      "constant evaluation failed: attempt to add with overflow"
+    );
+  }
+
+  #[test]
+  fn test_const_fns_only_call_const_fns() {
+    assert_eq!(
+      messages("const int f()\n  return g() + scan()\n\nint g()\n  return 1\n"),
+      vec![
+        "cannot call non-const function `g` in const function `f`",
+        "cannot call non-const function `scan` in const function `f`"
+      ]
+    );
+    let mut ast =
+      parse("const int N = f()\nconst int f()\n  return wrapping_add(9223372036854775807, 1)\n");
+    let (eval, diagnostics) = eval_constants(&mut ast);
+    assert!(diagnostics.is_empty());
+    assert_eq!(eval.values["N"], Value::Int(i64::MIN));
+    assert_eq!(
+      messages("const int A = B\nconst int B = A\n"),
+      vec![
+        "constant evaluation failed: constant `A` depends on itself",
+        "constant evaluation failed: constant `B` could not be evaluated"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_step_budget() {
+    let source = "const int N = spin()\n\nconst int spin()\n  while true\n    pass\n  return 0\n";
+    let mut ast = parse(source);
+    let diagnostics = eval_constants(&mut ast).1;
+    assert_eq!(
+      diagnostics[0].message,
+      "constant evaluation exceeded the step budget"
+    );
+  }
+
+  #[test]
+  fn test_fold_calls_in_functions() {
+    let source = format!(
+      "const int N = 5\nint main()\n  int N = scan()\n  print(fibonacci(N), fibonacci(10), N)\n  return fibonacci(N)\n\n{}",
+      FIB
+    );
+    let mut ast = parse(&source);
+    let (mut eval, diagnostics) = eval_constants(&mut ast);
+    assert!(diagnostics.is_empty());
+    assert!(resolve(&mut ast).is_empty());
+    assert!(fold_constants(&mut ast, &mut eval).is_empty());
+    let main = ast.children[1].children[0].get_expression();
+    // 局部变量 N 遮蔽了常量 N，只有 fibonacci(10) 被折叠
+    assert!(main.contains(&"55".to_string()));
+    assert_eq!(main.iter().filter(|t| *t == "fibonacci").count(), 2);
+  }
+}
@@ -0,0 +1,293 @@
+//! 常量折叠与常量传播，在 SSA 形式上进行
+//!
+//! 反复执行以下化简直到不再变化：
+//! - `copy` 的结果直接替换为它的源操作数，常量由此经过局部变量传播到使用处
+//! - 操作数都是常量的运算在编译期求值；会触发溢出检查或除以零的运算留到运行时报错
+//! - 各来源的值都相同的 phi 替换为这个值
+//! - 条件为常量的 br 改为 jmp，删除因此不可达的块
+
+use crate::ir::{BinOp, BlockId, Function, Imm, Inst, Operand, Term, VReg};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// 在编译期计算 `lhs op rhs`；`trap` 表示整数溢出时需要在运行时报错，这时不折叠
+pub fn fold_binary(op: BinOp, lhs: Imm, rhs: Imm, trap: bool) -> Option<Imm> {
+  match (lhs, rhs) {
+    (Imm::Int(a), Imm::Int(b)) => {
+      let (value, overflow) = match op {
+        BinOp::Add => a.overflowing_add(b),
+        BinOp::Sub => a.overflowing_sub(b),
+        BinOp::Mul => a.overflowing_mul(b),
+        // 除以零和 i64::MIN / -1 在运行时都会出错
+        BinOp::Div => return a.checked_div(b).map(Imm::Int),
+        _ => return Some(Imm::Bool(compare(op, a.cmp(&b)))),
+      };
+      (!overflow || !trap).then_some(Imm::Int(value))
+    }
+    (Imm::Float(a), Imm::Float(b)) => match op {
+      BinOp::Add => Some(Imm::Float(a + b)),
+      BinOp::Sub => Some(Imm::Float(a - b)),
+      BinOp::Mul => Some(Imm::Float(a * b)),
+      BinOp::Div => Some(Imm::Float(a / b)),
+      // ucomisd 对 NaN 的比较结果与 IEEE 754 不完全相同，留给运行时
+      _ => a
+        .partial_cmp(&b)
+        .map(|ordering| Imm::Bool(compare(op, ordering))),
+    },
+    (Imm::Bool(a), Imm::Bool(b)) if op.is_comparison() => Some(Imm::Bool(compare(op, a.cmp(&b)))),
+    _ => None,
+  }
+}
+
+fn compare(op: BinOp, ordering: Ordering) -> bool {
+  match op {
+    BinOp::Lt => ordering == Ordering::Less,
+    BinOp::Gt => ordering == Ordering::Greater,
+    BinOp::Le => ordering != Ordering::Greater,
+    BinOp::Ge => ordering != Ordering::Less,
+    BinOp::Eq => ordering == Ordering::Equal,
+    BinOp::Ne => ordering != Ordering::Equal,
+    _ => panic!("{} is not a comparison", op.name()),
+  }
+}
+
+/// 指令的结果在编译期已知时返回它
+fn simplify(inst: &Inst) -> Option<Operand> {
+  match inst {
+    Inst::Copy { src, .. } => Some(src.clone()),
+    Inst::Bin {
+      op,
+      lhs: Operand::Imm(lhs),
+      rhs: Operand::Imm(rhs),
+      trap,
+      ..
+    } => fold_binary(*op, *lhs, *rhs, trap.is_some()).map(Operand::Imm),
+    Inst::Phi { dst, incoming, .. } => {
+      // 来自自身的值（循环中没有改变的变量）不影响结果
+      let mut values = incoming
+        .iter()
+        .map(|(_, value)| value)
+        .filter(|value| **value != Operand::Reg(*dst));
+      let first = values.next()?;
+      values.all(|value| value == first).then(|| first.clone())
+    }
+    _ => None,
+  }
+}
+
+/// 沿替换表找到操作数最终的值
+pub fn resolve(replace: &HashMap<VReg, Operand>, operand: &mut Operand) {
+  while let Operand::Reg(reg) = operand {
+    match replace.get(reg) {
+      Some(value) => *operand = value.clone(),
+      None => break,
+    }
+  }
+}
+
+pub fn propagate_constants(function: &mut Function) {
+  let mut replace: HashMap<VReg, Operand> = HashMap::new();
+  loop {
+    let mut changed = false;
+    for block in &mut function.blocks {
+      let mut kept = vec![];
+      for mut inst in std::mem::take(&mut block.insts) {
+        for operand in inst.operands_mut() {
+          resolve(&replace, operand);
+        }
+        match (inst.dst(), simplify(&inst)) {
+          (Some(dst), Some(value)) => {
+            replace.insert(dst, value);
+            changed = true;
+          }
+          _ => kept.push(inst),
+        }
+      }
+      block.insts = kept;
+      for operand in block.term.operands_mut() {
+        resolve(&replace, operand);
+      }
+    }
+
+    // 条件已知的分支：删去不会走的边，以及目标块中 phi 来自这条边的值
+    let mut removed: Vec<(BlockId, BlockId)> = vec![];
+    for block in &mut function.blocks {
+      let Term::Branch { cond, then, els } = &block.term else {
+        continue;
+      };
+      let taken = match cond {
+        _ if then == els => *then,
+        Operand::Imm(Imm::Bool(true)) => *then,
+        Operand::Imm(Imm::Bool(false)) => *els,
+        _ => continue,
+      };
+      let not_taken = if taken == *then { *els } else { *then };
+      if not_taken != taken {
+        removed.push((block.id, not_taken));
+      }
+      block.term = Term::Jump(taken);
+      changed = true;
+    }
+    for (from, to) in &removed {
+      for inst in &mut function.blocks[to.0 as usize].insts {
+        if let Inst::Phi { incoming, .. } = inst {
+          incoming.retain(|(block, _)| block != from);
+        }
+      }
+    }
+    if !removed.is_empty() {
+      function.remove_unreachable_blocks();
+    }
+    if !changed {
+      break;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ir::parse_module;
+  use crate::ssa::{into_ssa, verify};
+
+  fn optimize(text: &str) -> String {
+    let mut function = parse_module(text).unwrap().functions.remove(0);
+    into_ssa(&mut function);
+    propagate_constants(&mut function);
+    assert_eq!(verify(&function), Ok(()));
+    function.to_string()
+  }
+
+  #[test]
+  fn test_fold_binary() {
+    use Imm::*;
+    assert_eq!(fold_binary(BinOp::Add, Int(2), Int(3), true), Some(Int(5)));
+    assert_eq!(
+      fold_binary(BinOp::Div, Int(-7), Int(2), true),
+      Some(Int(-3))
+    );
+    assert_eq!(fold_binary(BinOp::Div, Int(1), Int(0), false), None);
+    assert_eq!(fold_binary(BinOp::Add, Int(i64::MAX), Int(1), true), None);
+    assert_eq!(
+      fold_binary(BinOp::Add, Int(i64::MAX), Int(1), false),
+      Some(Int(i64::MIN))
+    );
+    assert_eq!(
+      fold_binary(BinOp::Ge, Int(3), Int(3), true),
+      Some(Bool(true))
+    );
+    assert_eq!(
+      fold_binary(BinOp::Mul, Float(1.5), Float(2.0), true),
+      Some(Float(3.0))
+    );
+    assert_eq!(
+      fold_binary(BinOp::Lt, Float(f64::NAN), Float(1.0), true),
+      None
+    );
+    assert_eq!(
+      fold_binary(BinOp::Ne, Bool(true), Bool(false), true),
+      Some(Bool(true))
+    );
+  }
+
+  #[test]
+  fn test_propagate_through_locals_and_fold_branch() {
+    assert_eq!(
+      optimize(
+        "fn main() -> int {
+  slot x: int
+  slot y: int
+b0:
+  v0 = copy int 2
+  v1 = copy int 3
+  v2 = add int v0, v1 trap 1
+  store [x], v2
+  v3 = load int [x]
+  v4 = gt int v3, 4
+  br v4, b1, b2
+b1:
+  store [y], 10
+  jmp b3
+b2:
+  store [y], 20
+  jmp b3
+b3:
+  v5 = load int [y]
+  v6 = load int [x]
+  v7 = mul int v5, v6 trap 5
+  call void print_int(v7)
+  ret 0
+}
+"
+      ),
+      "fn main() -> int {
+b0:
+  jmp b1
+b1:
+  jmp b2
+b2:
+  call void print_int(50)
+  ret 0
+}
+"
+    );
+  }
+
+  #[test]
+  fn test_loop_variables_are_not_constant() {
+    assert_eq!(
+      optimize(
+        "fn f(n: int) -> int {
+  slot i: int
+  slot k: int
+b0:
+  store [i], 0
+  store [k], 7
+  jmp b1
+b1:
+  v0 = load int [i]
+  v1 = load int [n]
+  v2 = lt int v0, v1
+  br v2, b2, b3
+b2:
+  v3 = load int [k]
+  v4 = add int v0, v3 trap 3
+  store [i], v4
+  store [k], 7
+  jmp b1
+b3:
+  v5 = load int [i]
+  v6 = div int v5, 0
+  ret v6
+}
+"
+      ),
+      "fn f(n: int) -> int {
+b0:
+  v9 = load int [n]
+  jmp b1
+b1:
+  v7 = phi int [b0: 0], [b2: v4]
+  v2 = lt int v7, v9
+  br v2, b2, b3
+b2:
+  v4 = add int v7, 7 trap 3
+  jmp b1
+b3:
+  v6 = div int v7, 0
+  ret v6
+}
+"
+    );
+  }
+
+  #[test]
+  fn test_overflow_is_left_to_runtime() {
+    assert_eq!(
+      optimize(
+        "fn f() -> int {\nb0:\n  v0 = mul int 9223372036854775807, 2 trap 1\n  v1 = mul int 9223372036854775807, 2\n  v2 = add int v0, v1\n  ret v2\n}\n"
+      ),
+      "fn f() -> int {\nb0:\n  v0 = mul int 9223372036854775807, 2 trap 1\n  v2 = add int v0, -2\n  ret v2\n}\n"
+    );
+  }
+}
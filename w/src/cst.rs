@@ -0,0 +1,643 @@
+//! 无损具体语法树（CST）
+//!
+//! 仿照 rowan 的 green/red 双层结构：
+//! - green 树是不可变、可共享的，只记录节点种类、子节点和 token 文本，不含位置信息；
+//! - red 树（`SyntaxNode` / `SyntaxToken`）在 green 树之上按需构建，带有父节点指针和源码偏移。
+//!
+//! 空白、换行和注释作为 trivia token 保留在树中，因此 `root.text()` 与源码逐字节相同。
+//! 缩进产生的 `{` / `}` 是零宽 token，不影响还原出的文本。
+//! 节点种类与 `Parser` 产生的 AST 节点名一致（`Fn`、`StmtList`、`Expr` ...）。
+
+use crate::lexer::{Span, TokenKind};
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct GreenToken {
+  kind: TokenKind,
+  text: String,
+}
+
+impl GreenToken {
+  pub fn new(kind: TokenKind, text: &str) -> Self {
+    GreenToken {
+      kind,
+      text: text.to_string(),
+    }
+  }
+
+  pub fn kind(&self) -> TokenKind {
+    self.kind
+  }
+
+  pub fn text(&self) -> &str {
+    &self.text
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GreenElement {
+  Node(Rc<GreenNode>),
+  Token(Rc<GreenToken>),
+}
+
+impl GreenElement {
+  pub fn text_len(&self) -> usize {
+    match self {
+      GreenElement::Node(node) => node.text_len,
+      GreenElement::Token(token) => token.text.len(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GreenNode {
+  kind: String,
+  text_len: usize,
+  children: Vec<GreenElement>,
+}
+
+impl GreenNode {
+  pub fn new(kind: &str, children: Vec<GreenElement>) -> Self {
+    GreenNode {
+      kind: kind.to_string(),
+      text_len: children.iter().map(GreenElement::text_len).sum(),
+      children,
+    }
+  }
+
+  pub fn kind(&self) -> &str {
+    &self.kind
+  }
+
+  pub fn text_len(&self) -> usize {
+    self.text_len
+  }
+
+  pub fn children(&self) -> &[GreenElement] {
+    &self.children
+  }
+
+  fn write_text(&self, out: &mut String) {
+    for child in &self.children {
+      match child {
+        GreenElement::Node(node) => node.write_text(out),
+        GreenElement::Token(token) => out.push_str(&token.text),
+      }
+    }
+  }
+}
+
+/// 记录构建过程中某个位置，之后可以用 `start_node_at` 把该位置之后的元素包进一个新节点
+#[derive(Debug, Clone, Copy)]
+pub struct Checkpoint(usize);
+
+/// 自底向上构建 green 树
+#[derive(Default)]
+pub struct GreenNodeBuilder {
+  parents: Vec<(String, usize)>,
+  children: Vec<GreenElement>,
+}
+
+impl GreenNodeBuilder {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn start_node(&mut self, kind: &str) {
+    self.parents.push((kind.to_string(), self.children.len()));
+  }
+
+  pub fn checkpoint(&self) -> Checkpoint {
+    Checkpoint(self.children.len())
+  }
+
+  pub fn start_node_at(&mut self, checkpoint: Checkpoint, kind: &str) {
+    let Checkpoint(index) = checkpoint;
+    assert!(
+      index <= self.children.len(),
+      "checkpoint no longer valid, was finish_node called early?"
+    );
+    if let Some(&(_, first_child)) = self.parents.last() {
+      assert!(
+        index >= first_child,
+        "checkpoint no longer valid, was an unmatched start_node called?"
+      );
+    }
+    self.parents.push((kind.to_string(), index));
+  }
+
+  pub fn token(&mut self, kind: TokenKind, text: &str) {
+    self
+      .children
+      .push(GreenElement::Token(Rc::new(GreenToken::new(kind, text))));
+  }
+
+  pub fn finish_node(&mut self) {
+    let (kind, first_child) = self.parents.pop().expect("finish_node without start_node");
+    let children = self.children.split_off(first_child);
+    self
+      .children
+      .push(GreenElement::Node(Rc::new(GreenNode::new(&kind, children))));
+  }
+
+  pub fn finish(mut self) -> GreenNode {
+    assert!(
+      self.parents.is_empty(),
+      "unfinished nodes in GreenNodeBuilder"
+    );
+    assert_eq!(
+      self.children.len(),
+      1,
+      "GreenNodeBuilder must have exactly one root"
+    );
+    match self.children.pop() {
+      Some(GreenElement::Node(node)) => Rc::try_unwrap(node).unwrap_or_else(|rc| (*rc).clone()),
+      _ => panic!("root of GreenNodeBuilder must be a node"),
+    }
+  }
+}
+
+struct NodeData {
+  green: Rc<GreenNode>,
+  parent: Option<SyntaxNode>,
+  offset: usize,
+}
+
+/// red 树节点：green 节点加上父指针和源码偏移
+#[derive(Clone)]
+pub struct SyntaxNode(Rc<NodeData>);
+
+#[derive(Clone)]
+pub struct SyntaxToken {
+  green: Rc<GreenToken>,
+  parent: SyntaxNode,
+  offset: usize,
+}
+
+#[derive(Clone)]
+pub enum SyntaxElement {
+  Node(SyntaxNode),
+  Token(SyntaxToken),
+}
+
+impl SyntaxNode {
+  pub fn new_root(green: GreenNode) -> Self {
+    SyntaxNode(Rc::new(NodeData {
+      green: Rc::new(green),
+      parent: None,
+      offset: 0,
+    }))
+  }
+
+  pub fn kind(&self) -> &str {
+    self.0.green.kind()
+  }
+
+  pub fn green(&self) -> &GreenNode {
+    &self.0.green
+  }
+
+  pub fn parent(&self) -> Option<SyntaxNode> {
+    self.0.parent.clone()
+  }
+
+  pub fn text_range(&self) -> Span {
+    Span::new(self.0.offset, self.0.offset + self.0.green.text_len())
+  }
+
+  /// 还原该节点覆盖的源码文本（含 trivia）
+  pub fn text(&self) -> String {
+    let mut out = String::with_capacity(self.0.green.text_len());
+    self.0.green.write_text(&mut out);
+    out
+  }
+
+  pub fn children_with_tokens(&self) -> Vec<SyntaxElement> {
+    let mut offset = self.0.offset;
+    let mut elements = vec![];
+    for child in self.0.green.children() {
+      match child {
+        GreenElement::Node(node) => {
+          elements.push(SyntaxElement::Node(SyntaxNode(Rc::new(NodeData {
+            green: node.clone(),
+            parent: Some(self.clone()),
+            offset,
+          }))))
+        }
+        GreenElement::Token(token) => elements.push(SyntaxElement::Token(SyntaxToken {
+          green: token.clone(),
+          parent: self.clone(),
+          offset,
+        })),
+      }
+      offset += child.text_len();
+    }
+    elements
+  }
+
+  pub fn children(&self) -> Vec<SyntaxNode> {
+    self
+      .children_with_tokens()
+      .into_iter()
+      .filter_map(|element| match element {
+        SyntaxElement::Node(node) => Some(node),
+        SyntaxElement::Token(_) => None,
+      })
+      .collect()
+  }
+
+  /// 直接子 token（不含 trivia）
+  pub fn tokens(&self) -> Vec<SyntaxToken> {
+    self
+      .children_with_tokens()
+      .into_iter()
+      .filter_map(|element| match element {
+        SyntaxElement::Token(token) if !token.is_trivia() => Some(token),
+        _ => None,
+      })
+      .collect()
+  }
+
+  pub fn first_child(&self, kind: &str) -> Option<SyntaxNode> {
+    self
+      .children()
+      .into_iter()
+      .find(|child| child.kind() == kind)
+  }
+
+  /// 先序遍历所有后代节点（包括自身）
+  pub fn descendants(&self) -> Vec<SyntaxNode> {
+    let mut nodes = vec![self.clone()];
+    for child in self.children() {
+      nodes.extend(child.descendants());
+    }
+    nodes
+  }
+
+  /// 先序遍历所有 token（包括 trivia）
+  pub fn descendant_tokens(&self) -> Vec<SyntaxToken> {
+    let mut tokens = vec![];
+    for element in self.children_with_tokens() {
+      match element {
+        SyntaxElement::Node(node) => tokens.extend(node.descendant_tokens()),
+        SyntaxElement::Token(token) => tokens.push(token),
+      }
+    }
+    tokens
+  }
+
+  pub fn print(&self, depth: usize) {
+    println!(
+      "{}{}@{}..{}",
+      "  ".repeat(depth),
+      self.kind(),
+      self.text_range().start,
+      self.text_range().end
+    );
+    for element in self.children_with_tokens() {
+      match element {
+        SyntaxElement::Node(node) => node.print(depth + 1),
+        SyntaxElement::Token(token) => println!("{}{:?}", "  ".repeat(depth + 1), token),
+      }
+    }
+  }
+}
+
+impl fmt::Display for SyntaxNode {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.text())
+  }
+}
+
+impl fmt::Debug for SyntaxNode {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let range = self.text_range();
+    write!(f, "{}@{}..{}", self.kind(), range.start, range.end)
+  }
+}
+
+impl SyntaxToken {
+  pub fn kind(&self) -> TokenKind {
+    self.green.kind()
+  }
+
+  pub fn text(&self) -> &str {
+    self.green.text()
+  }
+
+  pub fn parent(&self) -> SyntaxNode {
+    self.parent.clone()
+  }
+
+  pub fn text_range(&self) -> Span {
+    Span::new(self.offset, self.offset + self.green.text.len())
+  }
+
+  pub fn is_trivia(&self) -> bool {
+    matches!(self.kind(), TokenKind::Whitespace | TokenKind::Comment)
+  }
+}
+
+impl fmt::Debug for SyntaxToken {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let range = self.text_range();
+    write!(
+      f,
+      "{:?}@{}..{} {:?}",
+      self.kind(),
+      range.start,
+      range.end,
+      self.text()
+    )
+  }
+}
+
+/// 把一段 trivia 文本切分成空白和注释 token
+pub fn split_trivia(text: &str) -> Vec<(TokenKind, &str)> {
+  let mut pieces = vec![];
+  let mut rest = text;
+  while !rest.is_empty() {
+    let len = if rest.starts_with('#') {
+      rest.find('\n').unwrap_or(rest.len())
+    } else {
+      rest.find('#').unwrap_or(rest.len())
+    };
+    let kind = if rest.starts_with('#') {
+      TokenKind::Comment
+    } else {
+      TokenKind::Whitespace
+    };
+    pieces.push((kind, &rest[..len]));
+    rest = &rest[len..];
+  }
+  pieces
+}
+
+// 类型化的 AST 视图：对 `SyntaxNode` 的零开销包装，编辑工具通过它们访问语法结构。
+
+pub trait AstNode: Sized {
+  fn cast(node: SyntaxNode) -> Option<Self>;
+  fn syntax(&self) -> &SyntaxNode;
+}
+
+macro_rules! ast_node {
+  ($name:ident, $kind:literal) => {
+    #[derive(Clone, Debug)]
+    pub struct $name(SyntaxNode);
+
+    impl AstNode for $name {
+      fn cast(node: SyntaxNode) -> Option<Self> {
+        if node.kind() == $kind {
+          Some($name(node))
+        } else {
+          None
+        }
+      }
+
+      fn syntax(&self) -> &SyntaxNode {
+        &self.0
+      }
+    }
+  };
+}
+
+ast_node!(SourceFile, "Pg");
+ast_node!(FnDef, "Fn");
+ast_node!(TypeDef, "TypeDef");
+ast_node!(TypeRef, "Type");
+ast_node!(ParamList, "ParamList");
+ast_node!(StmtList, "StmtList");
+ast_node!(Stmt, "Stmt");
+ast_node!(VarDecl, "VarDecl");
+ast_node!(VarDef, "VarDef");
+ast_node!(Assign, "Assign");
+ast_node!(Return, "Return");
+ast_node!(BranchStmt, "BranchStmt");
+ast_node!(LoopStmt, "LoopStmt");
+ast_node!(Expr, "Expr");
+ast_node!(FnCall, "FnCall");
+
+/// `Fn FnList` 和 `StmtList` 等右递归结构在视图中展开成列表
+fn flatten_list<T: AstNode>(node: &SyntaxNode, list_kind: &str) -> Vec<T> {
+  let mut items = vec![];
+  let mut current = Some(node.clone());
+  while let Some(list) = current {
+    current = None;
+    for child in list.children() {
+      if child.kind() == list_kind {
+        current = Some(child);
+      } else if let Some(item) = T::cast(child) {
+        items.push(item);
+      }
+    }
+  }
+  items
+}
+
+fn ident_tokens(node: &SyntaxNode) -> Vec<SyntaxToken> {
+  node
+    .tokens()
+    .into_iter()
+    .filter(|token| token.kind() == TokenKind::Ident)
+    .collect()
+}
+
+impl SourceFile {
+  pub fn functions(&self) -> Vec<FnDef> {
+    flatten_list(&self.0, "FnList")
+  }
+
+  pub fn type_defs(&self) -> Vec<TypeDef> {
+    flatten_list(&self.0, "FnList")
+  }
+}
+
+impl TypeDef {
+  pub fn name(&self) -> Option<SyntaxToken> {
+    ident_tokens(&self.0).into_iter().next()
+  }
+
+  pub fn ty(&self) -> Option<TypeRef> {
+    self.0.children().into_iter().find_map(TypeRef::cast)
+  }
+}
+
+impl FnDef {
+  pub fn ret_type(&self) -> Option<TypeRef> {
+    self.0.children().into_iter().find_map(TypeRef::cast)
+  }
+
+  pub fn name(&self) -> Option<SyntaxToken> {
+    ident_tokens(&self.0).into_iter().next()
+  }
+
+  pub fn param_list(&self) -> Option<ParamList> {
+    self
+      .0
+      .first_child("Param")
+      .and_then(|param| param.children().into_iter().find_map(ParamList::cast))
+  }
+
+  pub fn body(&self) -> Option<StmtList> {
+    self
+      .0
+      .first_child("FnBody")
+      .and_then(|body| body.children().into_iter().find_map(StmtList::cast))
+  }
+}
+
+impl TypeRef {
+  /// 类型的源码文本（不含 trivia）
+  pub fn text(&self) -> String {
+    self
+      .0
+      .descendant_tokens()
+      .iter()
+      .filter(|token| !token.is_trivia())
+      .map(|token| token.text().to_string())
+      .collect()
+  }
+}
+
+impl ParamList {
+  /// 每个参数的类型和名字
+  pub fn params(&self) -> Vec<(TypeRef, SyntaxToken)> {
+    let mut params = vec![];
+    let mut current = Some(self.0.clone());
+    while let Some(list) = current {
+      current = None;
+      let mut ty = None;
+      for element in list.children_with_tokens() {
+        match element {
+          SyntaxElement::Node(node) if node.kind() == "ParamListTail" => current = Some(node),
+          SyntaxElement::Node(node) => ty = TypeRef::cast(node).or(ty),
+          SyntaxElement::Token(token) if token.kind() == TokenKind::Ident => {
+            if let Some(ty) = ty.take() {
+              params.push((ty, token));
+            }
+          }
+          SyntaxElement::Token(_) => {}
+        }
+      }
+    }
+    params
+  }
+}
+
+impl StmtList {
+  pub fn statements(&self) -> Vec<Stmt> {
+    flatten_list(&self.0, "StmtList")
+  }
+}
+
+impl Stmt {
+  /// 语句包裹的具体节点（跳过语句前的属性），如 `VarDef`、`Return`；`pass`、`break`、`continue` 语句返回 None
+  pub fn inner(&self) -> Option<SyntaxNode> {
+    self
+      .0
+      .children()
+      .into_iter()
+      .find(|child| child.kind() != "Attrs")
+  }
+
+  pub fn is_pass(&self) -> bool {
+    self.0.tokens().iter().any(|token| token.text() == "pass")
+  }
+}
+
+impl VarDecl {
+  pub fn ty(&self) -> Option<TypeRef> {
+    self.0.children().into_iter().find_map(TypeRef::cast)
+  }
+
+  pub fn name(&self) -> Option<SyntaxToken> {
+    ident_tokens(&self.0).into_iter().next()
+  }
+}
+
+impl VarDef {
+  pub fn ty(&self) -> Option<TypeRef> {
+    self.0.children().into_iter().find_map(TypeRef::cast)
+  }
+
+  pub fn name(&self) -> Option<SyntaxToken> {
+    ident_tokens(&self.0).into_iter().next()
+  }
+
+  pub fn value(&self) -> Option<Expr> {
+    self.0.children().into_iter().find_map(Expr::cast)
+  }
+}
+
+impl Assign {
+  pub fn name(&self) -> Option<SyntaxToken> {
+    ident_tokens(&self.0).into_iter().next()
+  }
+
+  pub fn value(&self) -> Option<Expr> {
+    self.0.children().into_iter().find_map(Expr::cast)
+  }
+}
+
+impl Return {
+  pub fn value(&self) -> Option<Expr> {
+    self.0.children().into_iter().find_map(Expr::cast)
+  }
+}
+
+impl BranchStmt {
+  pub fn condition(&self) -> Option<Expr> {
+    self.0.children().into_iter().find_map(Expr::cast)
+  }
+
+  pub fn then_branch(&self) -> Option<StmtList> {
+    self.0.children().into_iter().find_map(StmtList::cast)
+  }
+
+  pub fn else_branch(&self) -> Option<StmtList> {
+    self
+      .0
+      .children()
+      .into_iter()
+      .filter_map(StmtList::cast)
+      .nth(1)
+  }
+}
+
+impl LoopStmt {
+  pub fn condition(&self) -> Option<Expr> {
+    self.0.children().into_iter().find_map(Expr::cast)
+  }
+
+  pub fn body(&self) -> Option<StmtList> {
+    self.0.children().into_iter().find_map(StmtList::cast)
+  }
+}
+
+impl Expr {
+  /// 表达式中出现的所有函数调用
+  pub fn calls(&self) -> Vec<FnCall> {
+    self
+      .0
+      .descendants()
+      .into_iter()
+      .filter_map(FnCall::cast)
+      .collect()
+  }
+}
+
+impl FnCall {
+  pub fn callee(&self) -> Option<SyntaxToken> {
+    ident_tokens(&self.0).into_iter().next()
+  }
+
+  pub fn args(&self) -> Vec<Expr> {
+    self
+      .0
+      .children()
+      .into_iter()
+      .filter_map(Expr::cast)
+      .collect()
+  }
+}
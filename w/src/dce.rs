@@ -0,0 +1,311 @@
+//! 死代码消除
+//!
+//! - `eliminate_dead_code`：从有副作用的指令和终结指令出发标记用到的值，
+//!   删除结果没有被用到的纯指令（包括互相引用的死 phi）
+//! - `eliminate_dead_stores`：按栈槽做活跃分析，删除在读取之前就被覆盖或之后不再读取的 store
+//! - `remove_unreachable_functions`：删除从 main 出发调用不到的函数
+
+use crate::cfg::Cfg;
+use crate::ir::{BinOp, BlockId, Function, Imm, Inst, Module, Operand, VReg};
+use std::collections::{HashMap, HashSet};
+
+/// 指令是否除了写入结果之外没有别的效果，结果不用时可以删除。
+/// 函数调用和 store 有副作用；带溢出检查的运算和可能除以零的除法可能在运行时报错
+pub fn is_pure(inst: &Inst) -> bool {
+  match inst {
+    Inst::Call { .. } | Inst::Store { .. } => false,
+    Inst::Bin { trap: Some(_), .. } => false,
+    Inst::Bin {
+      op: BinOp::Div,
+      rhs,
+      ..
+    } => {
+      matches!(rhs, Operand::Imm(Imm::Int(n)) if *n != 0 && *n != -1)
+        || matches!(rhs, Operand::Imm(Imm::Float(_)))
+    }
+    _ => true,
+  }
+}
+
+pub fn eliminate_dead_code(function: &mut Function) {
+  let mut defs: HashMap<VReg, &Inst> = HashMap::new();
+  let mut work: Vec<&Operand> = vec![];
+  for block in &function.blocks {
+    for inst in &block.insts {
+      if let Some(dst) = inst.dst() {
+        defs.insert(dst, inst);
+      }
+      if !is_pure(inst) {
+        work.extend(inst.operands());
+      }
+    }
+    work.extend(block.term.operands());
+  }
+  let mut live: HashSet<VReg> = HashSet::new();
+  while let Some(operand) = work.pop() {
+    if let Operand::Reg(reg) = operand {
+      if live.insert(*reg) {
+        if let Some(inst) = defs.get(reg) {
+          work.extend(inst.operands());
+        }
+      }
+    }
+  }
+  for block in &mut function.blocks {
+    block
+      .insts
+      .retain(|inst| !is_pure(inst) || inst.dst().is_some_and(|dst| live.contains(&dst)));
+  }
+}
+
+/// 可能通过指针被读取的栈槽：第一个被 `addr` 取地址的栈槽及其后的所有栈槽
+fn escaped_slots(function: &Function) -> HashSet<String> {
+  let addressed: HashSet<&str> = function
+    .blocks
+    .iter()
+    .flat_map(|block| &block.insts)
+    .filter_map(|inst| match inst {
+      Inst::Addr { slot, .. } => Some(slot.as_str()),
+      _ => None,
+    })
+    .collect();
+  function
+    .slots()
+    .skip_while(|slot| !addressed.contains(slot.name.as_str()))
+    .map(|slot| slot.name.clone())
+    .collect()
+}
+
+/// 从块末尾往前更新活跃的栈槽；`remove` 为 true 时同时删除死 store
+fn transfer(
+  insts: &mut Vec<Inst>,
+  live: &mut HashSet<String>,
+  escaped: &HashSet<String>,
+  remove: bool,
+) {
+  let mut dead = vec![];
+  for (i, inst) in insts.iter().enumerate().rev() {
+    match inst {
+      Inst::Store { slot, .. } => dead.extend((!live.remove(slot)).then_some(i)),
+      Inst::Load { slot, .. } => {
+        live.insert(slot.clone());
+      }
+      // 被调函数和 elem 可能通过指针读取被取地址的栈槽
+      Inst::Call { .. } | Inst::Elem { .. } => live.extend(escaped.iter().cloned()),
+      _ => {}
+    }
+  }
+  if remove {
+    for i in dead {
+      insts.remove(i);
+    }
+  }
+}
+
+pub fn eliminate_dead_stores(function: &mut Function) {
+  let cfg = Cfg::new(function);
+  let escaped = escaped_slots(function);
+  let n = function.blocks.len();
+  // 每个块入口处活跃的栈槽，迭代到不动点
+  let mut live_in: Vec<HashSet<String>> = vec![HashSet::new(); n];
+  let live_out = |live_in: &[HashSet<String>], block: BlockId| {
+    let mut live = HashSet::new();
+    for succ in cfg.succs(block) {
+      live.extend(live_in[succ.0 as usize].iter().cloned());
+    }
+    live
+  };
+  let mut changed = true;
+  while changed {
+    changed = false;
+    for block in cfg.rpo.iter().rev() {
+      let mut live = live_out(&live_in, *block);
+      let mut insts = function.blocks[block.0 as usize].insts.clone();
+      transfer(&mut insts, &mut live, &escaped, false);
+      if live != live_in[block.0 as usize] {
+        live_in[block.0 as usize] = live;
+        changed = true;
+      }
+    }
+  }
+  for block in &mut function.blocks {
+    let mut live = live_out(&live_in, block.id);
+    transfer(&mut block.insts, &mut live, &escaped, true);
+  }
+}
+
+/// 删除从 main 出发调用不到的函数；没有 main 时什么也不做
+pub fn remove_unreachable_functions(module: &mut Module) {
+  if !module.functions.iter().any(|f| f.name == "main") {
+    return;
+  }
+  let index: HashMap<&str, &Function> = module
+    .functions
+    .iter()
+    .map(|f| (f.name.as_str(), f))
+    .collect();
+  let mut reachable: HashSet<String> = HashSet::new();
+  let mut work = vec!["main"];
+  while let Some(name) = work.pop() {
+    if !reachable.insert(name.to_string()) {
+      continue;
+    }
+    for callee in index[name].callees() {
+      if index.contains_key(callee) {
+        work.push(callee);
+      }
+    }
+  }
+  module.functions.retain(|f| reachable.contains(&f.name));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ir::parse_module;
+  use crate::ssa::{into_ssa, verify};
+
+  fn function(text: &str) -> Function {
+    parse_module(text).unwrap().functions.remove(0)
+  }
+
+  #[test]
+  fn test_remove_unused_pure_instructions() {
+    let mut f = function(
+      "fn f(a: int) -> int {
+  slot unused: int
+b0:
+  v0 = load int [a]
+  v1 = mul int v0, v0
+  v2 = add int v0, 1 trap 2
+  v3 = div int v0, 2
+  v4 = div int 1, v0
+  store [unused], v1
+  v5 = call int g(v0)
+  v6 = add int v0, v0
+  ret v6
+}
+",
+    );
+    into_ssa(&mut f);
+    eliminate_dead_code(&mut f);
+    assert_eq!(verify(&f), Ok(()));
+    // 溢出检查、可能除以零的除法和函数调用保留
+    assert_eq!(
+      f.to_string(),
+      "fn f(a: int) -> int {
+b0:
+  v7 = load int [a]
+  v2 = add int v7, 1 trap 2
+  v4 = div int 1, v7
+  v5 = call int g(v7)
+  v6 = add int v7, v7
+  ret v6
+}
+"
+    );
+  }
+
+  #[test]
+  fn test_remove_dead_phi_cycle() {
+    let mut f = function(
+      "fn f(n: int) -> void {
+  slot i: int
+  slot unused: int
+b0:
+  store [i], 0
+  store [unused], 0
+  jmp b1
+b1:
+  v0 = load int [i]
+  v1 = load int [n]
+  v2 = lt int v0, v1
+  br v2, b2, b3
+b2:
+  v3 = load int [unused]
+  v4 = add int v3, v0
+  store [unused], v4
+  v5 = add int v0, 1
+  store [i], v5
+  jmp b1
+b3:
+  ret
+}
+",
+    );
+    into_ssa(&mut f);
+    eliminate_dead_code(&mut f);
+    assert_eq!(verify(&f), Ok(()));
+    assert!(!f.to_string().contains("v4"));
+    assert_eq!(f.to_string().matches("phi").count(), 1);
+  }
+
+  #[test]
+  fn test_eliminate_dead_stores() {
+    let mut f = function(
+      "fn f() -> int {
+  slot x: int
+  slot va0.0: int
+  slot va0.1: int
+b0:
+  store [x], 1
+  store [x], 2
+  v0 = load int [x]
+  store [x], 3
+  store [va0.0], 4
+  store [va0.0], 5
+  store [va0.1], 6
+  v1 = addr [va0.0]
+  v2 = call int sum(v1, 2)
+  store [va0.1], 7
+  br true, b1, b2
+b1:
+  store [x], 8
+  jmp b2
+b2:
+  v3 = load int [x]
+  ret v3
+}
+",
+    );
+    eliminate_dead_stores(&mut f);
+    assert_eq!(
+      f.to_string(),
+      "fn f() -> int {
+  slot x: int
+  slot va0.0: int
+  slot va0.1: int
+b0:
+  store [x], 2
+  v0 = load int [x]
+  store [x], 3
+  store [va0.0], 5
+  store [va0.1], 6
+  v1 = addr [va0.0]
+  v2 = call int sum(v1, 2)
+  br true, b1, b2
+b1:
+  store [x], 8
+  jmp b2
+b2:
+  v3 = load int [x]
+  ret v3
+}
+"
+    );
+  }
+
+  #[test]
+  fn test_remove_unreachable_functions() {
+    let mut module = parse_module(
+      "fn main() -> int {\nb0:\n  v0 = call int a()\n  call void print_int(v0)\n  ret 0\n}\n
+fn a() -> int {\nb0:\n  v0 = call int b()\n  ret v0\n}\n
+fn b() -> int {\nb0:\n  v0 = call int b()\n  ret v0\n}\n
+fn unused() -> int {\nb0:\n  v0 = call int a()\n  ret v0\n}\n",
+    )
+    .unwrap();
+    remove_unreachable_functions(&mut module);
+    let names: Vec<&str> = module.functions.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(names, vec!["main", "a", "b"]);
+  }
+}
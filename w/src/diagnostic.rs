@@ -0,0 +1,125 @@
+//! 编译期诊断信息（错误、警告）及其渲染
+
+use crate::lexer::Span;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+  Warning,
+  Error,
+}
+
+impl fmt::Display for Level {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Level::Warning => write!(f, "warning"),
+      Level::Error => write!(f, "error"),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+  pub level: Level,
+  pub message: String,
+  pub span: Span,
+  pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+  pub fn error(message: String, span: Span) -> Self {
+    Diagnostic {
+      level: Level::Error,
+      message,
+      span,
+      notes: vec![],
+    }
+  }
+
+  pub fn warning(message: String, span: Span) -> Self {
+    Diagnostic {
+      level: Level::Warning,
+      message,
+      span,
+      notes: vec![],
+    }
+  }
+
+  pub fn with_note(mut self, note: String) -> Self {
+    self.notes.push(note);
+    self
+  }
+
+  pub fn is_error(&self) -> bool {
+    self.level == Level::Error
+  }
+
+  /// 渲染成带源码行和 `^^^` 标记的文本，例如：
+  ///
+  /// ```text
+  /// error: unknown type `banana`
+  ///  --> 1:1
+  ///   |
+  /// 1 | banana main()
+  ///   | ^^^^^^
+  /// ```
+  pub fn render(&self, source: &str) -> String {
+    let (line, col) = line_col(source, self.span.start);
+    let line_start = source[..self.span.start.min(source.len())]
+      .rfind('\n')
+      .map_or(0, |i| i + 1);
+    let line_text = source[line_start..].lines().next().unwrap_or("");
+    let gutter = " ".repeat(line.to_string().len());
+    let width = self
+      .span
+      .len()
+      .min(line_text.len().saturating_sub(col - 1))
+      .max(1);
+    let mut out = format!("{}: {}\n", self.level, self.message);
+    out.push_str(&format!("{}--> {}:{}\n", gutter, line, col));
+    out.push_str(&format!("{} |\n", gutter));
+    out.push_str(&format!("{} | {}\n", line, line_text));
+    out.push_str(&format!(
+      "{} | {}{}\n",
+      gutter,
+      " ".repeat(col - 1),
+      "^".repeat(width)
+    ));
+    for note in &self.notes {
+      out.push_str(&format!("{} = note: {}\n", gutter, note));
+    }
+    out
+  }
+}
+
+/// 字节偏移转换为 1 起始的行号和列号
+pub fn line_col(source: &str, offset: usize) -> (usize, usize) {
+  let offset = offset.min(source.len());
+  let before = &source[..offset];
+  let line = before.matches('\n').count() + 1;
+  let col = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+  (line, col)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_line_col() {
+    let source = "int main()\n  return 0\n";
+    assert_eq!(line_col(source, 0), (1, 1));
+    assert_eq!(line_col(source, 4), (1, 5));
+    assert_eq!(line_col(source, 13), (2, 3));
+  }
+
+  #[test]
+  fn test_render() {
+    let source = "banana main()\n  return 0\n";
+    let diagnostic = Diagnostic::error("unknown type `banana`".to_string(), Span::new(0, 6));
+    assert_eq!(
+      diagnostic.render(source),
+      "error: unknown type `banana`\n --> 1:1\n  |\n1 | banana main()\n  | ^^^^^^\n"
+    );
+  }
+}
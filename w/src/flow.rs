@@ -0,0 +1,510 @@
+//! 控制流分析
+//!
+//! 为每个函数体建立控制流图：基本块之间的边由顺序执行、`if/else`、`while`、
+//! `return`、`break`、`continue` 决定。图中有两个特殊的块：
+//! - `exit`：所有 `return` 跳转到这里
+//! - `end`：函数体执行到末尾后到达这里（隐式返回）
+//!
+//! 据此报告非 void 函数可能不返回值的错误（`main` 除外，执行到末尾时退出码为 0），
+//! 以及不可达语句的警告。
+//! 条件恰好是字面量 `true` 的 `while` 循环只能通过 `break` 退出。
+//!
+//! 每条语句还记录了它读取和写入的变量，`check_initialization` 在图上做前向数据流分析，
+//! 拒绝读取可能尚未赋值的变量（`int x` 这样不带初始值的声明）。
+
+use crate::ast::AST;
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::resolve::{is_variable, source_name};
+use crate::types::{is_auto, Type};
+use std::collections::HashSet;
+
+#[derive(Debug, Default, Clone)]
+pub struct Block {
+  pub succs: Vec<usize>,
+}
+
+/// 一条语句及它所在的基本块
+#[derive(Debug, Clone)]
+pub struct StmtInfo {
+  pub span: Span,
+  pub block: usize,
+  /// 顺序上位于它之前的语句（或包含它的 if / while 语句），函数体第一条语句为 None
+  pub prev: Option<usize>,
+  /// 不带初始值声明的变量
+  pub decls: Vec<String>,
+  /// 赋值的变量
+  pub defs: Vec<String>,
+  /// 读取的变量及位置，先于 defs 发生
+  pub uses: Vec<(String, Span)>,
+}
+
+#[derive(Debug)]
+pub struct ControlFlowGraph {
+  pub blocks: Vec<Block>,
+  pub stmts: Vec<StmtInfo>,
+  pub entry: usize,
+  pub end: usize,
+  pub exit: usize,
+}
+
+struct Builder {
+  blocks: Vec<Block>,
+  stmts: Vec<StmtInfo>,
+  current: usize,
+  exit: usize,
+  /// 外层循环：(continue 跳转到的条件块, break 跳转到的后继块)
+  loops: Vec<(usize, usize)>,
+  diagnostics: Vec<Diagnostic>,
+}
+
+impl ControlFlowGraph {
+  /// 由函数体的 StmtList 建立控制流图，同时报告循环外的 break / continue
+  pub fn build(body: &AST) -> (ControlFlowGraph, Vec<Diagnostic>) {
+    let mut builder = Builder {
+      blocks: vec![Block::default(), Block::default()],
+      stmts: vec![],
+      current: 0,
+      exit: 1,
+      loops: vec![],
+      diagnostics: vec![],
+    };
+    builder.stmt_list(body, None);
+    let end = builder.current;
+    let cfg = ControlFlowGraph {
+      blocks: builder.blocks,
+      stmts: builder.stmts,
+      entry: 0,
+      end,
+      exit: 1,
+    };
+    (cfg, builder.diagnostics)
+  }
+
+  /// 从入口出发能到达的块
+  pub fn reachable(&self) -> Vec<bool> {
+    let mut reachable = vec![false; self.blocks.len()];
+    let mut worklist = vec![self.entry];
+    while let Some(block) = worklist.pop() {
+      if reachable[block] {
+        continue;
+      }
+      reachable[block] = true;
+      worklist.extend(&self.blocks[block].succs);
+    }
+    reachable
+  }
+
+  /// 函数体能否执行到末尾
+  pub fn falls_through(&self) -> bool {
+    self.reachable()[self.end]
+  }
+
+  /// 不可达的语句，每段连续的不可达代码只取第一条
+  pub fn unreachable_stmts(&self) -> Vec<Span> {
+    let reachable = self.reachable();
+    let stmt_reachable = |i: usize| reachable[self.stmts[i].block];
+    (0..self.stmts.len())
+      .filter(|&i| !stmt_reachable(i) && self.stmts[i].prev.is_none_or(stmt_reachable))
+      .map(|i| self.stmts[i].span)
+      .collect()
+  }
+}
+
+impl Builder {
+  fn new_block(&mut self) -> usize {
+    self.blocks.push(Block::default());
+    self.blocks.len() - 1
+  }
+
+  fn edge(&mut self, from: usize, to: usize) {
+    self.blocks[from].succs.push(to);
+  }
+
+  /// 记录一条位于 block 块的语句，返回它的编号
+  fn record(&mut self, ast: &AST, block: usize, prev: Option<usize>) -> usize {
+    let mut info = StmtInfo {
+      span: ast.span,
+      block,
+      prev,
+      decls: vec![],
+      defs: vec![],
+      uses: vec![],
+    };
+    match ast.value.as_str() {
+      "VarDecl" => info.decls.push(ast.children[1].value.clone()),
+      "VarDef" => {
+        collect_uses(&ast.children[2], &mut info.uses);
+        info.defs.push(ast.children[1].value.clone());
+      }
+      "Assign" => {
+        collect_uses(&ast.children[1], &mut info.uses);
+        info.defs.push(ast.children[0].value.clone());
+      }
+      // if / while 只记录条件中的读取
+      "Return" | "Expr" | "BranchStmt" | "LoopStmt" => {
+        let exprs = if ast.value == "Return" || ast.value == "Expr" {
+          &ast.children[..]
+        } else {
+          &ast.children[..1]
+        };
+        for expr in exprs {
+          collect_uses(expr, &mut info.uses);
+        }
+      }
+      _ => {}
+    }
+    self.stmts.push(info);
+    self.stmts.len() - 1
+  }
+
+  /// 转移语句之后的代码位于一个没有前驱的新块中
+  fn jump(&mut self, target: usize) {
+    let current = self.current;
+    self.edge(current, target);
+    self.current = self.new_block();
+  }
+
+  fn stmt_list(&mut self, ast: &AST, mut prev: Option<usize>) {
+    let mut stmt_list = ast;
+    while stmt_list.children.len() == 2 {
+      prev = Some(self.stmt(&stmt_list.children[0].children[0], prev));
+      stmt_list = &stmt_list.children[1];
+    }
+  }
+
+  fn stmt(&mut self, ast: &AST, prev: Option<usize>) -> usize {
+    match ast.value.as_str() {
+      "Return" => {
+        let index = self.record(ast, self.current, prev);
+        self.jump(self.exit);
+        index
+      }
+      "break" | "continue" => {
+        let index = self.record(ast, self.current, prev);
+        match self.loops.last().copied() {
+          Some((header, after)) => self.jump(if ast.value == "break" { after } else { header }),
+          None => self.diagnostics.push(Diagnostic::error(
+            format!("`{}` outside of a loop", ast.value),
+            ast.span,
+          )),
+        }
+        index
+      }
+      "BranchStmt" => {
+        let index = self.record(ast, self.current, prev);
+        let (then_block, else_block, join) = (self.new_block(), self.new_block(), self.new_block());
+        self.edge(self.current, then_block);
+        self.edge(self.current, else_block);
+        for (body, block) in [
+          (&ast.children[1], then_block),
+          (&ast.children[2], else_block),
+        ] {
+          self.current = block;
+          self.stmt_list(body, Some(index));
+          self.edge(self.current, join);
+        }
+        self.current = join;
+        index
+      }
+      "LoopStmt" => {
+        let (header, body, after) = (self.new_block(), self.new_block(), self.new_block());
+        self.edge(self.current, header);
+        let index = self.record(ast, header, prev);
+        self.edge(header, body);
+        if ast.children[0].get_expression() != ["true"] {
+          self.edge(header, after);
+        }
+        self.loops.push((header, after));
+        self.current = body;
+        self.stmt_list(&ast.children[1], Some(index));
+        self.edge(self.current, header);
+        self.loops.pop();
+        self.current = after;
+        index
+      }
+      _ => self.record(ast, self.current, prev),
+    }
+  }
+}
+
+/// 表达式中读取的变量（函数名和关键字参数名除外）
+pub fn collect_uses(ast: &AST, uses: &mut Vec<(String, Span)>) {
+  match ast.value.as_str() {
+    "FnCall" => {
+      for arg in &ast.children[1..] {
+        collect_uses(arg, uses);
+      }
+    }
+    "NamedArg" => collect_uses(&ast.children[1], uses),
+    name if ast.children.is_empty() => {
+      if is_variable(source_name(name)) {
+        uses.push((name.to_string(), ast.span));
+      }
+    }
+    _ => {
+      for child in &ast.children {
+        collect_uses(child, uses);
+      }
+    }
+  }
+}
+
+impl ControlFlowGraph {
+  /// 读取时可能尚未赋值的变量：返回每个这样的变量第一次被读取的位置。
+  /// 变量名需要已经过名字解析，各声明的名字在函数内唯一
+  pub fn uninitialized_uses(&self) -> Vec<(String, Span)> {
+    let tracked: HashSet<&String> = self.stmts.iter().flat_map(|s| &s.decls).collect();
+    if tracked.is_empty() {
+      return vec![];
+    }
+    let mut block_stmts = vec![vec![]; self.blocks.len()];
+    for (i, stmt) in self.stmts.iter().enumerate() {
+      block_stmts[stmt.block].push(i);
+    }
+    let transfer = |assigned: &mut HashSet<String>, stmt: &StmtInfo| {
+      for name in &stmt.decls {
+        assigned.remove(name);
+      }
+      assigned.extend(stmt.defs.iter().cloned());
+    };
+
+    // 块入口处一定已赋值的变量；None 表示尚未到达（相当于全集）
+    let mut block_in: Vec<Option<HashSet<String>>> = vec![None; self.blocks.len()];
+    block_in[self.entry] = Some(HashSet::new());
+    let mut worklist = vec![self.entry];
+    while let Some(block) = worklist.pop() {
+      let mut assigned = block_in[block].clone().unwrap();
+      for &i in &block_stmts[block] {
+        transfer(&mut assigned, &self.stmts[i]);
+      }
+      for &succ in &self.blocks[block].succs {
+        let merged = match &block_in[succ] {
+          Some(old) => old.intersection(&assigned).cloned().collect(),
+          None => assigned.clone(),
+        };
+        if block_in[succ].as_ref() != Some(&merged) {
+          block_in[succ] = Some(merged);
+          worklist.push(succ);
+        }
+      }
+    }
+
+    let mut reported = HashSet::new();
+    let mut result = vec![];
+    for (i, stmt) in self.stmts.iter().enumerate() {
+      // 不可达的块不检查
+      let Some(mut assigned) = block_in[stmt.block].clone() else {
+        continue;
+      };
+      for &j in block_stmts[stmt.block].iter().take_while(|&&j| j < i) {
+        transfer(&mut assigned, &self.stmts[j]);
+      }
+      for (name, span) in &stmt.uses {
+        if tracked.contains(name) && !assigned.contains(name) && reported.insert(name.clone()) {
+          result.push((name.clone(), *span));
+        }
+      }
+    }
+    result
+  }
+}
+
+/// 确定赋值检查：拒绝读取可能尚未赋值的变量。需要在名字解析之后运行
+pub fn check_initialization(ast: &AST) -> Vec<Diagnostic> {
+  let mut diagnostics = vec![];
+  check_initialization_helper(ast, &mut diagnostics);
+  diagnostics
+}
+
+fn check_initialization_helper(ast: &AST, diagnostics: &mut Vec<Diagnostic>) {
+  match ast.value.as_str() {
+    "Fn" => {
+      let (cfg, _) = ControlFlowGraph::build(&ast.children[3].children[0]);
+      for (name, span) in cfg.uninitialized_uses() {
+        let name = source_name(&name);
+        diagnostics.push(
+          Diagnostic::error(
+            format!("variable `{}` is used before being assigned", name),
+            span,
+          )
+          .with_note(format!(
+            "`{}` is declared without an initial value and is not assigned on every path to this use",
+            name
+          )),
+        );
+      }
+    }
+    "Pg" | "FnList" => {
+      for child in &ast.children {
+        check_initialization_helper(child, diagnostics);
+      }
+    }
+    _ => {}
+  }
+}
+
+/// 函数体能否执行到末尾（需要补上隐式的返回）
+pub fn fn_falls_through(fn_ast: &AST) -> bool {
+  ControlFlowGraph::build(&fn_ast.children[3].children[0])
+    .0
+    .falls_through()
+}
+
+/// 返回非 void 的函数可能执行到末尾
+pub fn missing_return(name: &AST, ret: &Type) -> Diagnostic {
+  Diagnostic::error(
+    format!("function `{}` may not return a value", name.value),
+    name.span,
+  )
+  .with_note(format!(
+    "control can reach the end of `{}`, which returns `{}`",
+    name.value, ret
+  ))
+}
+
+/// 对程序中每个函数做控制流检查
+pub fn check_flow(ast: &AST) -> Vec<Diagnostic> {
+  let mut diagnostics = vec![];
+  check_flow_helper(ast, &mut diagnostics);
+  diagnostics
+}
+
+fn check_flow_helper(ast: &AST, diagnostics: &mut Vec<Diagnostic>) {
+  match ast.value.as_str() {
+    "Fn" => {
+      let (cfg, errors) = ControlFlowGraph::build(&ast.children[3].children[0]);
+      diagnostics.extend(errors);
+      for span in cfg.unreachable_stmts() {
+        diagnostics.push(Diagnostic::warning(
+          "unreachable statement".to_string(),
+          span,
+        ));
+      }
+      let ret = Type::from_ast(&ast.children[0]);
+      let name = &ast.children[1];
+      // 推导返回类型的函数在类型检查中推导出返回类型后再检查
+      if ret != Type::Void
+        && !is_auto(&ast.children[0])
+        && name.value != "main"
+        && cfg.falls_through()
+      {
+        diagnostics.push(missing_return(name, &ret));
+      }
+    }
+    "Pg" | "FnList" => {
+      for child in &ast.children {
+        check_flow_helper(child, diagnostics);
+      }
+    }
+    _ => {}
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::parser::Parser;
+
+  fn check(source: &str) -> Vec<String> {
+    let ast = Parser::new(Lexer::new(source)).parse();
+    check_flow(&ast)
+      .into_iter()
+      .map(|d| format!("{}: {}", d.level, &source[d.span.start..d.span.end]))
+      .collect()
+  }
+
+  #[test]
+  fn test_missing_return() {
+    assert_eq!(
+      check("int f(int a)\n  if a > 1\n    return 1\n  else\n    pass\n"),
+      vec!["error: f"]
+    );
+    assert!(check("int f(int a)\n  if a > 1\n    return 1\n  else\n    return 2\n").is_empty());
+    assert!(check("void f()\n  pass\n").is_empty());
+    assert!(check("int main()\n  print(1)\n").is_empty());
+  }
+
+  #[test]
+  fn test_unreachable_after_return() {
+    assert_eq!(
+      check("int f()\n  return 1\n  print(2)\n  print(3)\n"),
+      vec!["warning: print(2)"]
+    );
+    // 两个分支都返回时，if 之后的语句不可达
+    assert_eq!(
+      check("int f(int a)\n  if a > 1\n    return 1\n    a = 2\n  else\n    return 2\n  a = 3\n"),
+      vec!["warning: a = 2", "warning: a = 3"]
+    );
+  }
+
+  fn uninitialized(source: &str) -> Vec<String> {
+    let mut ast = Parser::new(Lexer::new(source)).parse();
+    crate::resolve::resolve(&mut ast);
+    check_initialization(&ast)
+      .into_iter()
+      .map(|d| format!("{}@{}", d.message, &source[d.span.start..]))
+      .map(|s| s.lines().next().unwrap().to_string())
+      .collect()
+  }
+
+  #[test]
+  fn test_definite_assignment() {
+    // 两个分支都赋值后可以读取
+    assert!(uninitialized(
+      "int f(int a)\n  int x\n  if a > 0\n    x = 1\n  else\n    x = 2\n  return x\n"
+    )
+    .is_empty());
+    assert_eq!(
+      uninitialized(
+        "int f(int a)\n  int x\n  if a > 0\n    x = 1\n  else\n    pass\n  return x + 1\n"
+      ),
+      vec!["variable `x` is used before being assigned@x + 1"]
+    );
+    // 循环体可能一次也不执行
+    assert_eq!(
+      uninitialized("int f(int a)\n  int x\n  while a > 0\n    x = a\n    a = a - 1\n  return x\n"),
+      vec!["variable `x` is used before being assigned@x"]
+    );
+    // 自引用的赋值先读后写
+    assert_eq!(
+      uninitialized("int f()\n  int x\n  x = x + 1\n  return x\n"),
+      vec!["variable `x` is used before being assigned@x + 1"]
+    );
+  }
+
+  #[test]
+  fn test_definite_assignment_with_loops() {
+    // while true 只能经 break 离开，break 之前已经赋值
+    assert!(
+      uninitialized("int f()\n  int x\n  while true\n    x = 1\n    break\n  return x\n")
+        .is_empty()
+    );
+    // 内层块中同名变量是另一个变量
+    assert_eq!(
+      uninitialized("int f(int a)\n  int x = 1\n  if a > 0\n    int x\n    return x\n  else\n    pass\n  return x\n"),
+      vec!["variable `x` is used before being assigned@x"]
+    );
+    // 名字中带数字的变量同样检查
+    assert_eq!(
+      uninitialized("int main()\n  int x1\n  print(x1)\n  return 0\n"),
+      vec!["variable `x1` is used before being assigned@x1)"]
+    );
+  }
+
+  #[test]
+  fn test_loops_break_continue() {
+    // 无限循环只能通过 return 离开，末尾不可达
+    assert!(check("int f()\n  while true\n    return 1\n").is_empty());
+    // break 之后可以到达函数末尾
+    assert_eq!(
+      check("int f()\n  while true\n    break\n    print(1)\n"),
+      vec!["warning: print(1)", "error: f"]
+    );
+    assert_eq!(
+      check("void f(int a)\n  while a > 0\n    a = a - 1\n    continue\n    pass\n  break\n"),
+      vec!["error: break", "warning: pass"]
+    );
+  }
+}
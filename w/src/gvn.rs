@@ -0,0 +1,222 @@
+//! 值编号：找出重复计算的纯表达式，后面的计算直接使用前面的结果
+//!
+//! - `local_value_numbering`：只在同一个基本块内查找
+//! - `global_value_numbering`：按支配树的先序遍历各块，支配当前块的块中算过的值都可以使用
+//!
+//! 在 SSA 形式上，操作数相同的表达式结果也相同，所以表达式以 (运算, 类型, 操作数) 为键，
+//! 加法、乘法和相等比较的两个操作数不分先后。
+//! load、elem 读取内存，call 可能有副作用（包括读取输入的 `scan()`），它们都不编号。
+//! 带溢出检查的运算和除法也可以复用：前面的同一个计算如果出错，程序已经结束了。
+
+use crate::cfg::{Cfg, Dominators};
+use crate::constprop::resolve;
+use crate::ir::{BinOp, BlockId, Function, Inst, IrType, Operand, VReg};
+use std::collections::HashMap;
+
+#[derive(PartialEq, Eq, Hash)]
+enum Expr {
+  /// 操作数以文本形式比较，浮点数常量因此也可以作为键
+  Bin(BinOp, IrType, String, String),
+  Addr(String),
+  Str(String),
+}
+
+fn expr(inst: &Inst) -> Option<Expr> {
+  match inst {
+    Inst::Bin {
+      op, ty, lhs, rhs, ..
+    } => {
+      let (mut lhs, mut rhs) = (lhs.to_string(), rhs.to_string());
+      if matches!(op, BinOp::Add | BinOp::Mul | BinOp::Eq | BinOp::Ne) && lhs > rhs {
+        std::mem::swap(&mut lhs, &mut rhs);
+      }
+      Some(Expr::Bin(*op, *ty, lhs, rhs))
+    }
+    Inst::Addr { slot, .. } => Some(Expr::Addr(slot.clone())),
+    Inst::Str { literal, .. } => Some(Expr::Str(literal.clone())),
+    _ => None,
+  }
+}
+
+pub fn local_value_numbering(function: &mut Function) {
+  number_values(function, false);
+}
+
+pub fn global_value_numbering(function: &mut Function) {
+  number_values(function, true);
+}
+
+fn number_values(function: &mut Function, global: bool) {
+  let cfg = Cfg::new(function);
+  let dominators = Dominators::new(&cfg);
+  let order: Vec<BlockId> = if global {
+    dominators.preorder()
+  } else {
+    function.blocks.iter().map(|block| block.id).collect()
+  };
+  let mut replace: HashMap<VReg, Operand> = HashMap::new();
+  // 表达式 -> 算出它的 (块, 虚拟寄存器)
+  let mut available: HashMap<Expr, Vec<(BlockId, VReg)>> = HashMap::new();
+  for id in order {
+    let block = &mut function.blocks[id.0 as usize];
+    block.insts.retain_mut(|inst| {
+      for operand in inst.operands_mut() {
+        resolve(&replace, operand);
+      }
+      let (Some(dst), Some(expr)) = (inst.dst(), expr(inst)) else {
+        return true;
+      };
+      let computed = available.entry(expr).or_default();
+      let earlier = computed
+        .iter()
+        .find(|(block, _)| *block == id || (global && dominators.dominates(*block, id)));
+      match earlier {
+        Some((_, reg)) => {
+          replace.insert(dst, Operand::Reg(*reg));
+          false
+        }
+        None => {
+          computed.push((id, dst));
+          true
+        }
+      }
+    });
+  }
+  // phi 的来源和终结指令可能用到之后才被替换的值
+  for block in &mut function.blocks {
+    for inst in &mut block.insts {
+      for operand in inst.operands_mut() {
+        resolve(&replace, operand);
+      }
+    }
+    for operand in block.term.operands_mut() {
+      resolve(&replace, operand);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ir::parse_module;
+  use crate::ssa::verify;
+
+  fn run(pass: fn(&mut Function), text: &str) -> String {
+    let mut function = parse_module(text).unwrap().functions.remove(0);
+    pass(&mut function);
+    assert_eq!(verify(&function), Ok(()));
+    function.to_string()
+  }
+
+  #[test]
+  fn test_reuse_in_block() {
+    let source = "fn f(a: int, b: int) -> int {
+b0:
+  v0 = load int [a]
+  v1 = load int [b]
+  v2 = mul int v0, v0
+  v3 = mul int v2, v0
+  v4 = mul int v0, v0
+  v5 = mul int v4, v0
+  v6 = add int v1, v0 trap 3
+  v7 = add int v0, v1 trap 4
+  v8 = sub int v1, v0
+  v9 = sub int v0, v1
+  v10 = add int v3, v5
+  v11 = add int v6, v7
+  v12 = add int v8, v9
+  v13 = add int v10, v11
+  v14 = add int v13, v12
+  ret v14
+}
+";
+    // a * a * a 只算一次，加法交换操作数后相同，减法不同
+    assert_eq!(
+      run(local_value_numbering, source),
+      "fn f(a: int, b: int) -> int {
+b0:
+  v0 = load int [a]
+  v1 = load int [b]
+  v2 = mul int v0, v0
+  v3 = mul int v2, v0
+  v6 = add int v1, v0 trap 3
+  v8 = sub int v1, v0
+  v9 = sub int v0, v1
+  v10 = add int v3, v3
+  v11 = add int v6, v6
+  v12 = add int v8, v9
+  v13 = add int v10, v11
+  v14 = add int v13, v12
+  ret v14
+}
+"
+    );
+  }
+
+  #[test]
+  fn test_calls_and_loads_are_not_reused() {
+    let source = "fn f(a: int) -> int {
+b0:
+  v0 = call int scan()
+  v1 = call int scan()
+  v2 = load int [a]
+  v3 = call int g(v2)
+  v4 = call int g(v2)
+  v5 = load int [a]
+  v6 = add int v0, v1
+  v7 = add int v3, v4
+  v8 = add int v6, v7
+  v9 = add int v8, v5
+  ret v9
+}
+";
+    assert_eq!(run(global_value_numbering, source), source);
+  }
+
+  #[test]
+  fn test_global_value_numbering() {
+    let source = "fn f(a: int) -> int {
+b0:
+  v0 = load int [a]
+  v1 = sub int v0, 2
+  v2 = lt int v0, 10
+  br v2, b1, b2
+b1:
+  v3 = sub int v0, 2
+  v4 = mul int v3, 3
+  jmp b3
+b2:
+  v5 = mul int v1, 3
+  jmp b3
+b3:
+  v6 = phi int [b1: v4], [b2: v5]
+  v7 = mul int v1, 3
+  v8 = add int v6, v7
+  ret v8
+}
+";
+    // b0 支配所有块，b1、b2 互不支配，它们算出的值在 b3 中不能用
+    let expected = "fn f(a: int) -> int {
+b0:
+  v0 = load int [a]
+  v1 = sub int v0, 2
+  v2 = lt int v0, 10
+  br v2, b1, b2
+b1:
+  v4 = mul int v1, 3
+  jmp b3
+b2:
+  v5 = mul int v1, 3
+  jmp b3
+b3:
+  v6 = phi int [b1: v4], [b2: v5]
+  v7 = mul int v1, 3
+  v8 = add int v6, v7
+  ret v8
+}
+";
+    assert_eq!(run(global_value_numbering, source), expected);
+    // 局部值编号不跨块
+    assert!(run(local_value_numbering, source).contains("v3 = sub int v0, 2"));
+  }
+}
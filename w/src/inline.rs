@@ -0,0 +1,510 @@
+//! 函数内联，在 SSA 形式上进行
+//!
+//! 按调用图自底向上处理：先处理被调函数，再把它们内联到调用方。是否内联由代价模型决定：
+//! 被调函数的规模（指令数加基本块数）不超过 `THRESHOLD` 加上内联的收益时内联，
+//! 收益包括省掉的调用序列、常量实参带来的化简机会，以及只有一处调用时可以删掉整个函数。
+//! 递归函数从不内联；`@inline` 跳过代价模型，`@noinline` 禁止内联。
+
+use crate::ir::{
+  Block, BlockId, Function, Inline, Inst, IrType, Module, Operand, Slot, Term, VReg,
+};
+use crate::ssa::zero;
+use std::collections::{HashMap, HashSet};
+
+/// 被调函数规模的基准上限
+const THRESHOLD: usize = 12;
+/// 调用方的规模超过这个值后不再向其中内联，避免代码膨胀
+const CALLER_LIMIT: usize = 1000;
+
+/// 函数的规模：指令数加基本块数，不计内联后会消失的参数读取
+fn size(function: &Function) -> usize {
+  let is_param = |slot: &str| function.params.iter().any(|param| param.name == slot);
+  function
+    .blocks
+    .iter()
+    .map(|block| {
+      let insts = block
+        .insts
+        .iter()
+        .filter(|inst| !matches!(inst, Inst::Load { slot, .. } if is_param(slot)))
+        .count();
+      insts + 1
+    })
+    .sum()
+}
+
+/// 能经过调用链回到自身的函数
+fn recursive_functions(module: &Module, index: &HashMap<String, usize>) -> HashSet<String> {
+  let mut recursive = HashSet::new();
+  for function in &module.functions {
+    let mut seen = HashSet::new();
+    let mut work: Vec<&str> = function.callees().collect();
+    while let Some(name) = work.pop() {
+      if name == function.name {
+        recursive.insert(function.name.clone());
+        break;
+      }
+      if let Some(&i) = index.get(name) {
+        if seen.insert(name) {
+          work.extend(module.functions[i].callees());
+        }
+      }
+    }
+  }
+  recursive
+}
+
+/// 调用图的后序：被调函数排在调用方之前
+fn bottom_up_order(module: &Module, index: &HashMap<String, usize>) -> Vec<usize> {
+  fn visit(
+    i: usize,
+    module: &Module,
+    index: &HashMap<String, usize>,
+    visited: &mut [bool],
+    order: &mut Vec<usize>,
+  ) {
+    if visited[i] {
+      return;
+    }
+    visited[i] = true;
+    for callee in module.functions[i].callees() {
+      if let Some(&j) = index.get(callee) {
+        visit(j, module, index, visited, order);
+      }
+    }
+    order.push(i);
+  }
+  let mut visited = vec![false; module.functions.len()];
+  let mut order = vec![];
+  for i in 0..module.functions.len() {
+    visit(i, module, index, &mut visited, &mut order);
+  }
+  order
+}
+
+/// 对一处调用的决定：内联时为 Ok，理由都记录下来
+fn decide(
+  caller: &Function,
+  callee: &Function,
+  args: &[Operand],
+  recursive: &HashSet<String>,
+  call_sites: usize,
+) -> Result<String, String> {
+  if recursive.contains(&callee.name) {
+    return Err("recursive".to_string());
+  }
+  match callee.inline {
+    Inline::Never => return Err("@noinline".to_string()),
+    Inline::Always => return Ok("@inline".to_string()),
+    Inline::Auto => {}
+  }
+  if callee.name == "main" {
+    return Err("main is never inlined".to_string());
+  }
+  if size(caller) > CALLER_LIMIT {
+    return Err(format!("`{}` is too large", caller.name));
+  }
+  let size = size(callee);
+  let constants = args
+    .iter()
+    .filter(|arg| matches!(arg, Operand::Imm(_)))
+    .count();
+  let mut limit = THRESHOLD + 2 + args.len() + 2 * constants;
+  if call_sites == 1 {
+    limit += size;
+  }
+  if size <= limit {
+    Ok(format!("size {} <= limit {}", size, limit))
+  } else {
+    Err(format!("size {} > limit {}", size, limit))
+  }
+}
+
+/// 把 `caller` 中 `at` 块第 `index` 条指令（一个调用）替换为 `callee` 的函数体。
+/// 调用之后的指令移到新的后续块中，callee 的返回值通过后续块开头的 phi 汇合。
+/// `site` 用于给 callee 中保留的栈槽取不重复的名字
+fn inline_call(caller: &mut Function, at: BlockId, index: usize, callee: &Function, site: usize) {
+  let Inst::Call { dst, ty, args, .. } = caller.blocks[at.0 as usize].insts[index].clone() else {
+    panic!("inline_call: not a call");
+  };
+  let reg_base = caller.new_vreg().0;
+  let block_base = caller.blocks.len() as u32;
+  let next = BlockId(block_base + callee.blocks.len() as u32);
+
+  let block = &mut caller.blocks[at.0 as usize];
+  let rest = block.insts.split_off(index + 1);
+  block.insts.pop();
+  let term = std::mem::replace(&mut block.term, Term::Jump(BlockId(block_base)));
+  for succ in term.successors() {
+    for inst in &mut caller.blocks[succ.0 as usize].insts {
+      if let Inst::Phi { incoming, .. } = inst {
+        for (from, _) in incoming {
+          if *from == at {
+            *from = next;
+          }
+        }
+      }
+    }
+  }
+
+  // 只被读取的参数直接替换为实参，其余的参数和局部变量成为调用方的栈槽
+  let mut replace: HashMap<VReg, Operand> = HashMap::new();
+  let mut slots: HashMap<&str, String> = HashMap::new();
+  let insts = || callee.blocks.iter().flat_map(|block| &block.insts);
+  for (param, arg) in callee.params.iter().zip(&args) {
+    let only_loaded = insts().all(|inst| match inst {
+      Inst::Store { slot, .. } | Inst::Addr { slot, .. } => *slot != param.name,
+      _ => true,
+    });
+    if only_loaded {
+      for inst in insts() {
+        if let Inst::Load { dst, slot, .. } = inst {
+          if *slot == param.name {
+            replace.insert(*dst, arg.clone());
+          }
+        }
+      }
+    } else {
+      let name = format!("{}.{}.{}", callee.name, site, param.name);
+      caller.blocks[at.0 as usize].insts.push(Inst::Store {
+        slot: name.clone(),
+        src: arg.clone(),
+      });
+      caller.locals.push(Slot {
+        name: name.clone(),
+        ty: param.ty,
+      });
+      slots.insert(&param.name, name);
+    }
+  }
+  for local in &callee.locals {
+    let name = format!("{}.{}.{}", callee.name, site, local.name);
+    caller.locals.push(Slot {
+      name: name.clone(),
+      ty: local.ty,
+    });
+    slots.insert(&local.name, name);
+  }
+
+  let reg = |reg: VReg| VReg(reg.0 + reg_base);
+  // 被调函数中的尾调用改回普通调用，结果寄存器排在被调函数的寄存器之后
+  let mut tail_reg = reg_base + callee.vreg_count();
+  let target = |block: BlockId| BlockId(block.0 + block_base);
+  let mut returns = vec![];
+  for block in &callee.blocks {
+    let mut insts = vec![];
+    for inst in &block.insts {
+      if inst.dst().is_some_and(|dst| replace.contains_key(&dst)) {
+        continue;
+      }
+      let mut inst = inst.clone();
+      for operand in inst.operands_mut() {
+        if let Operand::Reg(r) = operand {
+          *operand = replace.get(r).cloned().unwrap_or(Operand::Reg(reg(*r)));
+        }
+      }
+      match &mut inst {
+        Inst::Copy { dst, .. }
+        | Inst::Bin { dst, .. }
+        | Inst::Load { dst, .. }
+        | Inst::Addr { dst, .. }
+        | Inst::Elem { dst, .. }
+        | Inst::Str { dst, .. }
+        | Inst::Call { dst: Some(dst), .. } => *dst = reg(*dst),
+        Inst::Phi { dst, incoming, .. } => {
+          *dst = reg(*dst);
+          for (from, _) in incoming {
+            *from = target(*from);
+          }
+        }
+        Inst::Store { .. } | Inst::Call { dst: None, .. } => {}
+      }
+      match &mut inst {
+        Inst::Load { slot, .. } | Inst::Store { slot, .. } | Inst::Addr { slot, .. } => {
+          *slot = slots[slot.as_str()].clone();
+        }
+        _ => {}
+      }
+      insts.push(inst);
+    }
+    let mut term = block.term.clone();
+    for operand in term.operands_mut() {
+      if let Operand::Reg(r) = operand {
+        *operand = replace.get(r).cloned().unwrap_or(Operand::Reg(reg(*r)));
+      }
+    }
+    let term = match term {
+      Term::Ret(value) => {
+        returns.push((target(block.id), value.unwrap_or_else(|| zero(ty))));
+        Term::Jump(next)
+      }
+      Term::TailCall {
+        ty: call_ty,
+        callee,
+        args,
+      } => {
+        let call_dst = (call_ty != IrType::Void).then(|| {
+          tail_reg += 1;
+          VReg(tail_reg - 1)
+        });
+        insts.push(Inst::Call {
+          dst: call_dst,
+          ty: call_ty,
+          callee,
+          args,
+        });
+        let value = call_dst.map_or_else(|| zero(ty), Operand::Reg);
+        returns.push((target(block.id), value));
+        Term::Jump(next)
+      }
+      Term::Jump(to) => Term::Jump(target(to)),
+      Term::Branch { cond, then, els } => Term::Branch {
+        cond,
+        then: target(then),
+        els: target(els),
+      },
+    };
+    caller.blocks.push(Block {
+      id: target(block.id),
+      insts,
+      term,
+    });
+  }
+
+  let mut insts = vec![];
+  if let Some(dst) = dst {
+    insts.push(Inst::Phi {
+      dst,
+      ty,
+      incoming: returns,
+    });
+  }
+  insts.extend(rest);
+  caller.blocks.push(Block {
+    id: next,
+    insts,
+    term,
+  });
+}
+
+/// 内联整个模块中的调用，返回每处调用的决定
+pub fn inline_functions(module: &mut Module) -> Vec<String> {
+  let index: HashMap<String, usize> = module
+    .functions
+    .iter()
+    .enumerate()
+    .map(|(i, f)| (f.name.clone(), i))
+    .collect();
+  let recursive = recursive_functions(module, &index);
+  let mut call_sites: HashMap<String, usize> = HashMap::new();
+  for function in &module.functions {
+    for callee in function.callees() {
+      *call_sites.entry(callee.to_string()).or_default() += 1;
+    }
+  }
+
+  let mut remarks = vec![];
+  let mut site = 0;
+  for i in bottom_up_order(module, &index) {
+    // 内联进来的函数体已经处理过，不再检查其中的调用
+    let mut inlined: HashSet<BlockId> = HashSet::new();
+    let mut b = 0;
+    while b < module.functions[i].blocks.len() {
+      if inlined.contains(&BlockId(b as u32)) {
+        b += 1;
+        continue;
+      }
+      let mut found = None;
+      for (k, inst) in module.functions[i].blocks[b].insts.iter().enumerate() {
+        let Inst::Call { callee, args, .. } = inst else {
+          continue;
+        };
+        let Some(&j) = index.get(callee) else {
+          continue;
+        };
+        let caller = &module.functions[i];
+        let callee = &module.functions[j];
+        match decide(caller, callee, args, &recursive, call_sites[&callee.name]) {
+          Ok(reason) => {
+            remarks.push(format!(
+              "inline `{}` into `{}`: {}",
+              callee.name, caller.name, reason
+            ));
+            found = Some((k, j));
+            break;
+          }
+          Err(reason) => remarks.push(format!(
+            "do not inline `{}` into `{}`: {}",
+            callee.name, caller.name, reason
+          )),
+        }
+      }
+      let Some((k, j)) = found else {
+        b += 1;
+        continue;
+      };
+      let callee = module.functions[j].clone();
+      let caller = &mut module.functions[i];
+      let start = caller.blocks.len() as u32;
+      inline_call(caller, BlockId(b as u32), k, &callee, site);
+      site += 1;
+      inlined.extend((start..start + callee.blocks.len() as u32).map(BlockId));
+      // 调用之后的指令移到了新块中，本块已经处理完
+      b += 1;
+    }
+    module.functions[i].remove_unreachable_blocks();
+  }
+  remarks
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::constprop::propagate_constants;
+  use crate::ir::parse_module;
+  use crate::ssa::{into_ssa, verify};
+
+  fn inline(text: &str) -> (Module, Vec<String>) {
+    let mut module = parse_module(text).unwrap();
+    for function in &mut module.functions {
+      into_ssa(function);
+    }
+    let remarks = inline_functions(&mut module);
+    for function in &mut module.functions {
+      assert_eq!(verify(function), Ok(()), "{}", function);
+    }
+    (module, remarks)
+  }
+
+  #[test]
+  fn test_inline_small_function() {
+    let (mut module, remarks) = inline(
+      "fn main() -> int {
+b0:
+  v0 = call int scan()
+  v1 = call int add(v0, 2)
+  v2 = mul int v1, 3
+  ret v2
+}
+
+fn add(a: int, b: int) -> int {
+b0:
+  v0 = load int [a]
+  v1 = load int [b]
+  v2 = add int v0, v1
+  ret v2
+}
+",
+    );
+    assert_eq!(
+      remarks,
+      vec!["inline `add` into `main`: size 2 <= limit 20"]
+    );
+    let main = &mut module.functions[0];
+    propagate_constants(main);
+    assert_eq!(
+      main.to_string(),
+      "fn main() -> int {
+b0:
+  v0 = call int scan()
+  jmp b1
+b1:
+  v5 = add int v0, 2
+  jmp b2
+b2:
+  v2 = mul int v5, 3
+  ret v2
+}
+"
+    );
+  }
+
+  #[test]
+  fn test_inline_branches_and_slots() {
+    let (module, _) = inline(
+      "fn main() -> int {
+b0:
+  v0 = call int scan()
+  v1 = call int abs(v0)
+  jmp b1
+b1:
+  v2 = phi int [b0: v1]
+  call void print_int(v2)
+  ret 0
+}
+
+@inline fn abs(x: int) -> int {
+  slot va0.0: int
+b0:
+  v0 = load int [x]
+  store [va0.0], v0
+  v1 = addr [va0.0]
+  v2 = lt int v0, 0
+  br v2, b1, b2
+b1:
+  v3 = sub int 0, v0
+  ret v3
+b2:
+  ret v0
+}
+",
+    );
+    let main = module.functions[0].to_string();
+    // 被取地址的局部变量成为调用方的栈槽，参数直接替换为实参
+    assert!(main.contains("  slot abs.0.va0.0: int\n"));
+    assert!(main.contains("  store [abs.0.va0.0], v0\n  v4 = addr [abs.0.va0.0]\n"));
+    assert!(main.contains("b5:\n  v1 = phi int [b3: v6], [b4: v0]\n  jmp b1\n"));
+    // 原来从 b0 进入 b1 的边现在来自后续块
+    assert!(main.contains("  v2 = phi int [b5: v1]\n"));
+  }
+
+  #[test]
+  fn test_inline_decisions() {
+    let big = (0..20)
+      .map(|i| format!("  v{} = add int v0, {}\n", i + 1, i))
+      .collect::<String>();
+    let source = format!(
+      "fn main() -> int {{
+b0:
+  v0 = call int fib(10)
+  v1 = call int big(v0)
+  v2 = call int big(v1)
+  v3 = call int never(v2)
+  ret v3
+}}
+
+fn fib(n: int) -> int {{
+b0:
+  v0 = load int [n]
+  v1 = call int fib(v0)
+  ret v1
+}}
+
+fn big(a: int) -> int {{
+b0:
+  v0 = load int [a]
+{}  ret v20
+}}
+
+@noinline fn never(a: int) -> int {{
+b0:
+  v0 = load int [a]
+  ret v0
+}}
+",
+      big
+    );
+    let (module, remarks) = inline(&source);
+    assert_eq!(
+      remarks,
+      vec![
+        "do not inline `fib` into `fib`: recursive",
+        "do not inline `fib` into `main`: recursive",
+        "do not inline `big` into `main`: size 21 > limit 15",
+        "do not inline `big` into `main`: size 21 > limit 15",
+        "do not inline `never` into `main`: @noinline",
+      ]
+    );
+    assert_eq!(module.functions[0].blocks.len(), 1);
+  }
+}
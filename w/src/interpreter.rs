@@ -7,500 +7,601 @@
  * @Description: 这是默认设置,请设置`customMade`, 打开koroFileHeader查看配置 进行设置: https://github.com/OBKoro1/koro1FileHeader/wiki/%E9%85%8D%E7%BD%AE
  */
 
+//! x86-64 后端：把 IR 翻译成 AT&T 语法的汇编
+//!
+//! 调用约定与运行时的 print / scan 相同：%rbp 指向当前函数的栈帧，参数依次位于
+//! 0(%rbp)、8(%rbp)……；调用者把参数写到自己栈帧之后，再把 %rbp 移过去作为被调函数的栈帧，
+//! 返回值在 %rax 中。栈帧中先是参数和局部变量的栈槽，然后每个虚拟寄存器各占 8 字节，
+//! 最后保存用到的被调者保存的寄存器。
+//! 尾调用把实参写到当前栈帧的开头后直接跳转到被调函数，被调函数返回到当前函数的调用者。
+//! 虚拟寄存器由 `regalloc` 分到物理寄存器，溢出的留在栈帧中；
+//! 运算时把操作数读入 %rax / %rcx，结果写回虚拟寄存器的位置。
+
 use crate::ast::AST;
-use crate::aux::*;
-use std::collections::{HashMap, VecDeque};
-use std::time::SystemTime;
+use crate::ir::{BinOp, BlockId, Function, Imm, Inst, IrType, Module, Operand, Term, VReg};
+use crate::lower::{lower_program, LowerOptions, Overflow};
+use crate::regalloc::{allocate_registers, Allocation, Location};
+use crate::signature::collect_signatures;
+use std::collections::HashMap;
 
 pub struct Interpreter {
-  used_registers: Vec<String>,
-  // all_registers: Vec<String>,
-  operators: Vec<String>,
-  call_stack: Vec<i64>,
-  symbol_table: HashMap<String, HashMap<String, i64>>, // 函数名 -> 变量名 -> 偏移值
-  current_interpret_fn: String,
-  fn_table: HashMap<String, Vec<String>>, //函数名->参数表
+  /// 调试模式：生成额外的检查代码
+  pub debug: bool,
+  pub overflow: Overflow,
+  /// 分配物理寄存器；关闭时所有虚拟寄存器都放在栈帧中
+  pub allocate_registers: bool,
+  /// 源码，用于在运行时错误中报告行号；为空时不报告行号
+  pub source: String,
+  string_literals: Vec<String>, // 字符串常量，下标 n 对应标号 .LSTRn
+  overflow_traps: Vec<(String, String)>, // 溢出检查跳转的 (标号, 错误消息)
 }
 
-impl Interpreter {
-  pub fn new() -> Interpreter {
-    Interpreter {
-      used_registers: Vec::new(),
-      operators: vec![
-        "+".to_string(),
-        "-".to_string(),
-        "*".to_string(),
-        "/".to_string(),
-        "^".to_string(),
-        ">".to_string(),
-        "<".to_string(),
-        ">=".to_string(),
-        "<=".to_string(),
-        "==".to_string(),
-        "!=".to_string(),
-        "(".to_string(),
-        ")".to_string(),
-        "op(".to_string(),
-        "op)".to_string(),
-        ",".to_string(),
-      ],
-      call_stack: vec![0, 0],
-      symbol_table: HashMap::new(),
-      current_interpret_fn: String::new(),
-      fn_table: HashMap::new(),
-    }
+impl Default for Interpreter {
+  fn default() -> Self {
+    Self::new()
   }
+}
 
-  fn available_registers(&self) -> String {
-    let registers = vec![
-      "%r8", "%r9", "%r10", "%r11", "%r12", "%r13", "%r14", "%r15", "%rcx", "%rbx", "%rsi",
-      "%rdi",
-      // "rax", "rdx", 特殊用途，不要用于寄存器分配
-    ];
-    for reg in registers {
-      if !(self.used_registers.contains(&reg.to_string())) {
-        return reg.to_string();
-      }
-    }
-    panic!("No available registers");
-  }
-
-  fn op_priority(&self, op: &str) -> i8 {
-    match op {
-      ">" => 9,
-      "+" | "-" => 11,
-      "*" | "/" => 12,
-      "^" => 13,
-      "(" => 22,
-      ")" => 22,
-      "op(" => 2,
-      "op)" => 2,
-      "," => 3,
-      _ => 0,
+/// 一个函数的栈帧布局
+struct Frame {
+  /// 栈槽名 -> 偏移值
+  slots: HashMap<String, i64>,
+  /// 第一个虚拟寄存器的偏移值
+  vregs: i64,
+  /// 虚拟寄存器所在的位置
+  locations: Vec<Location>,
+  /// 被调者保存的寄存器及其保存位置
+  callee_saved: Vec<(&'static str, i64)>,
+  size: i64,
+}
+
+impl Frame {
+  fn new(function: &Function, allocation: Allocation) -> Frame {
+    let slots: HashMap<String, i64> = function
+      .slots()
+      .enumerate()
+      .map(|(i, slot)| (slot.name.clone(), i as i64 * 8))
+      .collect();
+    let vregs = slots.len() as i64 * 8;
+    let saves = vregs + function.vreg_count() as i64 * 8;
+    let callee_saved: Vec<(&'static str, i64)> = (allocation.callee_saved.into_iter())
+      .zip((saves..).step_by(8))
+      .collect();
+    Frame {
+      slots,
+      vregs,
+      locations: allocation.locations,
+      size: saves + callee_saved.len() as i64 * 8,
+      callee_saved,
     }
   }
 
-  fn get_op_code(&self, op: &str, operands: Vec<String>) -> &str {
-    if operands.is_empty() {}
-    match op {
-      "+" => "addq",
-      "-" => "subq",
-      "*" => "imulq",
-      "/" => "idivq",
-      "^" => "xorq",
-      ">" => "greater_than",
-      "<" => "less_than",
-      _ => "",
+  fn slot(&self, name: &str) -> String {
+    format!("{}(%rbp)", self.slots[name])
+  }
+
+  fn vreg(&self, reg: VReg) -> String {
+    match self.locations[reg.0 as usize] {
+      Location::Reg(name) => name.to_string(),
+      Location::Stack => self.stack(reg),
     }
   }
 
-  // 汇编代码生成器
-  // Pg
-  // ├─ Fn
-  // |  ├─ int
-  // |  ├─ main
-  // |  ├─ Param
-  // |  |  └─ ParamList
-  // |  |     └─ ε
-  // |  └─ FnBody
-  // |     └─ StmtList
-  // |        ├─ Stmt
-  // |        |  └─ Return
-  // |        |     └─ Expr
-  // |        |        └─ Term
-  // |        |           └─ Factor
-  // |        |              └─ 2
-  // |        └─ StmtList
-  // |           └─ ε
-  // └─ FnList
-  //    └─ ε
-  pub fn generate_asm(&mut self, ast: &mut AST) -> String {
-    let mut asm = String::new();
-    self.generate_asm_helper(ast, &mut asm);
-    asm
+  /// 虚拟寄存器在栈帧中的位置，分到寄存器的值在调用前后也保存在这里
+  fn stack(&self, reg: VReg) -> String {
+    format!("{}(%rbp)", self.vregs + reg.0 as i64 * 8)
+  }
+
+  /// 返回或尾调用之前恢复被调者保存的寄存器
+  fn restore_callee_saved(&self, asm: &mut String) {
+    for (reg, offset) in &self.callee_saved {
+      asm.push_str(&format!("  movq {}(%rbp), {}\n", offset, reg));
+    }
   }
+}
 
-  // 辅助函数，递归生成汇编代码
-  fn generate_asm_helper(&mut self, ast: &mut AST, asm: &mut String) {
-    match ast.value.as_str() {
-      "Pg" => self.generate_asm_pg(ast, asm),
-      "FnList" => self.generate_asm_fn_list(ast, asm),
-      "Fn" => self.generate_asm_fn(ast, asm),
-      "Param" => self.generate_asm_param(ast, asm),
-      "FnBody" => self.generate_asm_fn_body(ast, asm),
-      "StmtList" => self.generate_asm_stmt_list(ast, asm),
-      "Stmt" => self.generate_asm_stmt(ast, asm),
-      "VarDecl" => self.generate_asm_var_decl(ast, asm),
-      "VarDef" => self.generate_asm_var_def(ast, asm),
-      "Assign" => self.generate_asm_var_assign(ast, asm),
-      "Return" => self.generate_asm_ret(ast, asm),
-      "BranchStmt" => self.generate_asm_branch_stmt(ast, asm),
-      "LoopStmt" => self.generate_asm_loop_stmt(ast, asm),
-      "Expr" => self.generate_asm_expr(ast, asm),
-      "ε" => return,
-      _ => return,
+impl Interpreter {
+  pub fn new() -> Interpreter {
+    Interpreter {
+      debug: true,
+      overflow: Overflow::Trap,
+      allocate_registers: true,
+      source: String::new(),
+      string_literals: Vec::new(),
+      overflow_traps: Vec::new(),
     }
   }
 
-  fn generate_asm_pg(&mut self, ast: &mut AST, asm: &mut String) {
-    // asm.push_str("  .data\n");
-    // asm.push_str("stack_bottom:  .quad 0x0\n");
+  /// 把 AST 翻译成 IR
+  pub fn lower(&self, ast: &AST) -> Module {
+    let options = LowerOptions {
+      debug: self.debug,
+      overflow: self.overflow,
+      source: &self.source,
+    };
+    lower_program(ast, &collect_signatures(ast), &options)
+  }
+
+  pub fn generate_asm_module(&mut self, module: &Module) -> String {
+    let mut asm = String::new();
     asm.push_str("	.text\n");
     asm.push_str("	.globl	main\n");
-    for mut child in &mut ast.children {
-      self.generate_asm_helper(&mut child, asm);
+    for function in &module.functions {
+      self.generate_asm_fn(function, &mut asm);
     }
+    self.generate_asm_overflow_traps(&mut asm);
+    if !self.string_literals.is_empty() {
+      asm.push_str("\n	.section .rodata\n");
+      for (i, literal) in self.string_literals.iter().enumerate() {
+        asm.push_str(&format!(".LSTR{}:\n	.asciz {}\n", i, literal));
+      }
+    }
+    asm
   }
 
-  fn generate_asm_fn_list(&mut self, ast: &mut AST, asm: &mut String) {
-    for mut child in &mut ast.children {
-      self.generate_asm_helper(&mut child, asm);
+  /// 溢出检查的跳转目标：把错误消息交给运行时的 runtime_error 输出并结束进程
+  fn generate_asm_overflow_traps(&mut self, asm: &mut String) {
+    for (label, message) in std::mem::take(&mut self.overflow_traps) {
+      let message = format!("runtime error: {}", message);
+      self.string_literals.push(format!("\"{}\\n\"", message));
+      asm.push_str(&format!("{}:\n", label));
+      asm.push_str(&format!(
+        "  leaq .LSTR{}(%rip), %rsi\n",
+        self.string_literals.len() - 1
+      ));
+      asm.push_str(&format!("  movq ${}, %rdx\n", message.len() + 1));
+      asm.push_str("  jmp runtime_error\n");
     }
   }
 
-  fn generate_asm_fn(&mut self, ast: &mut AST, asm: &mut String) {
-    asm.push_str(&format!("\n\n{}:\n", ast.children[1].value));
-    if ast.children[1].value == "main" {
+  fn generate_asm_fn(&mut self, function: &Function, asm: &mut String) {
+    asm.push_str(&format!("\n\n{}:\n", function.name));
+    if function.name == "main" {
       asm.push_str(
-        "# 分配栈空间 1 页 4096 字节
+        "# 分配 8 MiB 栈空间
   movq $0, %rdi
-  movq $4096, %rsi
+  movq $8388608, %rsi
   movq $3, %rdx
   movq $34, %r10
-  movq $-1, %r8  
+  movq $-1, %r8
   movq $0, %r9
   movq $9, %rax
   syscall
   movq %rax, %rbp
-  \n",
+",
       );
     }
-    self
-      .symbol_table
-      .insert(ast.children[1].value.clone(), HashMap::new());
-    self.current_interpret_fn = ast.children[1].value.clone();
-    self.generate_asm_helper(&mut ast.children[2], asm); // interpret params
-    self.generate_asm_helper(&mut ast.children[3], asm); // interpret fn body
-  }
-
-  fn generate_asm_param(&mut self, ast: &mut AST, _asm: &mut String) {
-    let mut ast_param_list = &ast.children[0];
-    let entry = self
-      .fn_table
-      .entry(self.current_interpret_fn.clone())
-      .or_insert_with(Vec::new);
-
-    while ast_param_list.children.len() == 3 {
-      let param_name = &ast_param_list.children[1].value;
-      entry.push(param_name.clone());
-      let var_offset = self
-        .symbol_table
-        .get(&self.current_interpret_fn)
-        .unwrap()
-        .len() as i64
-        * 8;
-      self
-        .symbol_table
-        .get_mut(&self.current_interpret_fn)
-        .unwrap()
-        .insert(param_name.clone(), var_offset);
-      ast_param_list = &ast_param_list.children[2];
+    let mut allocation = if self.allocate_registers {
+      allocate_registers(function)
+    } else {
+      Allocation::on_stack(function)
+    };
+    let saves = std::mem::take(&mut allocation.saves);
+    let frame = Frame::new(function, allocation);
+    for (reg, offset) in &frame.callee_saved {
+      asm.push_str(&format!("  movq {}, {}(%rbp)\n", reg, offset));
     }
-  }
-
-  fn generate_asm_fn_body(&mut self, ast: &mut AST, asm: &mut String) {
-    self.generate_asm_helper(&mut ast.children[0], asm);
-  }
-
-  fn generate_asm_stmt_list(&mut self, ast: &mut AST, asm: &mut String) {
-    for mut child in &mut ast.children {
-      self.generate_asm_helper(&mut child, asm);
+    for (i, block) in function.blocks.iter().enumerate() {
+      asm.push_str(&format!("{}:\n", block_label(function, block.id)));
+      for (j, inst) in block.insts.iter().enumerate() {
+        // 跨越调用、分到调用者保存的寄存器的值在调用期间放在栈上
+        let saved = saves
+          .get(&(block.id, j))
+          .map_or(&[][..], |saved| &saved[..]);
+        for reg in saved {
+          asm.push_str(&format!(
+            "  movq {}, {}\n",
+            frame.vreg(*reg),
+            frame.stack(*reg)
+          ));
+        }
+        self.generate_asm_inst(inst, &frame, asm);
+        for reg in saved {
+          asm.push_str(&format!(
+            "  movq {}, {}\n",
+            frame.stack(*reg),
+            frame.vreg(*reg)
+          ));
+        }
+      }
+      let next = function.blocks.get(i + 1).map(|block| block.id);
+      self.generate_asm_term(function, &block.term, next, &frame, asm);
     }
   }
 
-  fn generate_asm_stmt(&mut self, ast: &mut AST, asm: &mut String) {
-    self.generate_asm_helper(&mut ast.children[0], asm);
-  }
-
-  fn generate_asm_var_decl(&mut self, ast: &mut AST, _asm: &mut String) {
-    let var_name = &ast.children[1].value;
-    let var_offset = self
-      .symbol_table
-      .get(&self.current_interpret_fn)
-      .unwrap()
-      .len() as i64
-      * 8;
-    self
-      .symbol_table
-      .get_mut(&self.current_interpret_fn)
-      .unwrap()
-      .insert(var_name.clone(), var_offset);
-  }
-
-  fn generate_asm_var_def(&mut self, ast: &mut AST, asm: &mut String) {
-    let var_name = &ast.children[1].value;
-    let var_offset = self
-      .symbol_table
-      .get(&self.current_interpret_fn)
-      .unwrap()
-      .len() as i64
-      * 8;
-    self
-      .symbol_table
-      .get_mut(&self.current_interpret_fn)
-      .unwrap()
-      .insert(var_name.clone(), var_offset);
-    self.generate_asm_helper(&mut ast.children[2], asm);
-    if ast.children[2].register.starts_with("%") {
-      asm.push_str(&format!(
-        "  movq {}, {}(%rbp)\n",
-        ast.children[2].register, var_offset
-      ));
-    } else {
-      let reg = self.available_registers();
-      asm.push_str(&format!("  movq {}, {}\n", ast.children[2].register, reg));
-      asm.push_str(&format!("  movq {}, {}(%rbp)\n", reg, var_offset));
-    }
-    self
-      .used_registers
-      .retain(|x| x != &ast.children[2].register);
-  }
-
-  fn generate_asm_var_assign(&mut self, ast: &mut AST, asm: &mut String) {
-    let var_name = &ast.children[0].value;
-    let var_offset = *self
-      .symbol_table
-      .get(&self.current_interpret_fn)
-      .unwrap()
-      .get(var_name)
-      .unwrap();
-    self.generate_asm_helper(&mut ast.children[1], asm);
-    if !ast.children[1].register.starts_with("%") {
-      let reg = self.available_registers();
-      asm.push_str(&format!("  movq {}, {}\n", ast.children[1].register, reg));
-      asm.push_str(&format!("  movq {}, {}(%rbp)\n", reg, var_offset));
-    } else {
-      asm.push_str(&format!(
-        "  movq {}, {}(%rbp)\n",
-        ast.children[1].register, var_offset
-      ));
-    }
-    self
-      .used_registers
-      .retain(|x| x != &ast.children[1].register);
-  }
-
-  fn generate_asm_ret(&mut self, ast: &mut AST, asm: &mut String) {
-    self.generate_asm_helper(&mut ast.children[0], asm);
-    asm.push_str(&format!("  movq {}, %rax\n", ast.children[0].register));
-    asm.push_str("	ret\n");
-  }
-
-  fn generate_asm_loop_stmt(&mut self, ast: &mut AST, asm: &mut String) {
-    let sys_time = SystemTime::now()
-      .duration_since(SystemTime::UNIX_EPOCH)
-      .unwrap()
-      .as_nanos()
-      .to_string();
-    let loop_label = self.current_interpret_fn.clone() + sys_time.as_str() + "looplabel";
-    let end_label = self.current_interpret_fn.clone() + sys_time.as_str() + "endlabel";
-    asm.push_str(&format!("{}:\n", loop_label));
-    self.generate_asm_helper(&mut ast.children[0], asm);
-    asm.push_str(&format!("  cmpq $0, {}\n", ast.children[0].register));
-    self
-      .used_registers
-      .retain(|x| x != &ast.children[0].register);
-    asm.push_str(&format!("  je {}\n", end_label));
-    self.generate_asm_helper(&mut ast.children[1], asm);
-    asm.push_str(&format!("  jmp {}\n", loop_label));
-    asm.push_str(&format!("{}:\n", end_label));
-  }
-
-  fn generate_asm_branch_stmt(&mut self, ast: &mut AST, asm: &mut String) {
-    self.generate_asm_helper(&mut ast.children[0], asm);
-    let reg = ast.children[0].register.clone();
-    let sys_time = SystemTime::now()
-      .duration_since(SystemTime::UNIX_EPOCH)
-      .unwrap()
-      .as_nanos()
-      .to_string();
-    let equal_label = self.current_interpret_fn.clone() + sys_time.as_str() + "equallabel";
-    let next_label = self.current_interpret_fn.clone() + sys_time.as_str() + "nextlabel";
-    asm.push_str(&format!("  cmpq $0, {}\n", reg));
-    self.used_registers.retain(|x| x != &reg);
-    asm.push_str(&format!("  je {}\n", equal_label));
-    self.generate_asm_helper(&mut ast.children[1], asm);
-    asm.push_str(&format!("  jmp {}\n", next_label));
-    asm.push_str(&format!("{}:\n", equal_label));
-    self.generate_asm_helper(&mut ast.children[2], asm);
-    asm.push_str(&format!("{}:\n", next_label));
-  }
-
-  fn generate_asm_fn_call(&mut self, callee: String, params: &Vec<String>, asm: &mut String) {
-    let cur_stack_top = self.call_stack.last().unwrap();
-    let inc = self
-      .symbol_table
-      .get(&self.current_interpret_fn)
-      .unwrap()
-      .len() as i64
-      * 8;
-    self.call_stack.push(cur_stack_top + inc);
-    asm.push_str(&format!("  addq ${}, %rbp\n", inc));
-    let mut param_offset = 0;
-    for param in params {
-      if param.starts_with("%") {
-        asm.push_str(&format!("  movq {}, {}(%rbp)\n", param, param_offset));
-        self.used_registers.retain(|x| x != param);
-      } else {
-        let reg = self.available_registers();
-        asm.push_str(&format!("	movq {}, {}\n", param, reg));
-        asm.push_str(&format!("	movq {}, {}(%rbp)\n", reg, param_offset));
+  /// 把操作数读入寄存器
+  fn load(&self, operand: &Operand, reg: &str, frame: &Frame, asm: &mut String) {
+    match operand {
+      Operand::Reg(vreg) => asm.push_str(&format!("  movq {}, {}\n", frame.vreg(*vreg), reg)),
+      Operand::Imm(imm) => {
+        let value = match imm {
+          Imm::Int(n) => *n,
+          // 浮点数以 IEEE 754 位模式保存在通用寄存器中
+          Imm::Float(x) => x.to_bits() as i64,
+          Imm::Bool(b) => *b as i64,
+        };
+        if i32::try_from(value).is_ok() {
+          asm.push_str(&format!("  movq ${}, {}\n", value, reg));
+        } else {
+          asm.push_str(&format!("  movabsq ${}, {}\n", value, reg));
+        }
       }
-      param_offset += 8;
-    }
-    let used_regs = self.used_registers.clone();
-    for reg in used_regs.clone() {
-      asm.push_str(&format!("  pushq {}\n", reg));
     }
-    asm.push_str(&format!("  call {}\n", callee));
-    for reg in used_regs.iter().rev() {
-      asm.push_str(&format!("  popq {}\n", reg));
-    }
-    asm.push_str(&format!("  subq ${}, %rbp\n", inc));
-    self.call_stack.pop();
-  }
-
-  fn generate_asm_expr(&mut self, ast: &mut AST, asm: &mut String) {
-    let expression = ast.get_expression();
-    println!("{:?}", expression);
-    let mut operators = VecDeque::<String>::new();
-    let mut registers = VecDeque::<String>::new();
-    for token in expression.iter() {
-      if self.operators.contains(token) {
-        // 运算符
-        if token.ends_with("(") {
-          operators.push_back(token.clone());
-          if token == "op(" {
-            // 函数调用运算符
-            registers.push_back("fn_params_start_label".to_string());
-          }
-          continue;
+  }
+
+  fn generate_asm_inst(&mut self, inst: &Inst, frame: &Frame, asm: &mut String) {
+    match inst {
+      Inst::Copy { dst, src, .. } => {
+        if let Some(value) = immediate(src) {
+          asm.push_str(&format!("  movq ${}, {}\n", value, frame.vreg(*dst)));
+          return;
         }
-        if token.ends_with(")") {
-          while !operators.back().unwrap().ends_with("(") {
-            self.step(asm, &mut operators, &mut registers);
-          }
-          let op = operators.pop_back().unwrap();
-          if op == "op(" {
-            // 函数调用
-            let callee = operators.pop_back().unwrap();
-            let mut params: Vec<String> = vec![];
-            while registers.back().unwrap() != "fn_params_start_label" {
-              let mut reg = registers.pop_back().unwrap();
-              if !reg.starts_with("%") {
-                let r = self.available_registers();
-                self.used_registers.push(r.clone());
-                asm.push_str(&format!("  movq {},{}\n", reg, r));
-                reg = r;
-              }
-              params.push(reg);
+        self.load(src, "%rax", frame, asm);
+      }
+      Inst::Bin {
+        op,
+        ty,
+        lhs,
+        rhs,
+        trap,
+        ..
+      } => {
+        self.load(lhs, "%rax", frame, asm);
+        if *ty == IrType::Float {
+          self.load(rhs, "%rcx", frame, asm);
+          self.generate_asm_float_op(*op, asm);
+        } else {
+          // 除法的除数必须在寄存器中，其他运算可以直接使用立即数
+          let rhs = match immediate(rhs) {
+            Some(value) if *op != BinOp::Div => format!("${}", value),
+            _ => {
+              self.load(rhs, "%rcx", frame, asm);
+              "%rcx".to_string()
             }
-            registers.pop_back();
-            params.reverse();
-            self.generate_asm_fn_call(callee, &params, asm);
-            let reg = self.available_registers();
-            asm.push_str(&format!("  movq %rax, {}\n", reg));
-            registers.push_back(reg.clone());
-            self.used_registers.push(reg);
-          } else { // op == "(", 仅表示优先级
-             // do nothing
-          }
-          continue;
+          };
+          self.generate_asm_int_op(*op, &rhs, *trap, asm);
         }
-        if operators.is_empty() {
-          operators.push_back(token.clone());
+      }
+      Inst::Load { slot, .. } => asm.push_str(&format!("  movq {}, %rax\n", frame.slot(slot))),
+      Inst::Store { slot, src } => {
+        if let Some(value) = immediate(src) {
+          asm.push_str(&format!("  movq ${}, {}\n", value, frame.slot(slot)));
         } else {
-          while !operators.is_empty()
-            && self.op_priority(operators.back().unwrap()) >= self.op_priority(token)
-            && !operators.back().unwrap().ends_with("(")
-          {
-            self.step(asm, &mut operators, &mut registers);
-          }
-          operators.push_back(token.clone());
+          self.load(src, "%rax", frame, asm);
+          asm.push_str(&format!("  movq %rax, {}\n", frame.slot(slot)));
         }
-      } else {
-        if is_identifier(token.as_str()) {
-          // 变量
-          if self
-            .symbol_table
-            .get(&self.current_interpret_fn)
-            .unwrap()
-            .contains_key(token)
-          {
-            let var_offset = *self
-              .symbol_table
-              .get(&self.current_interpret_fn)
-              .unwrap()
-              .get(token)
-              .unwrap();
-            registers.push_back(format!("{}(%rbp)", var_offset));
+      }
+      Inst::Addr { slot, .. } => asm.push_str(&format!("  leaq {}, %rax\n", frame.slot(slot))),
+      Inst::Elem { base, index, .. } => {
+        // 元素地址为 首地址 + 下标 * 8
+        self.load(base, "%rax", frame, asm);
+        self.load(index, "%rcx", frame, asm);
+        asm.push_str("  movq (%rax, %rcx, 8), %rax\n");
+      }
+      Inst::Str { literal, .. } => {
+        let label = match self.string_literals.iter().position(|s| s == literal) {
+          Some(index) => index,
+          None => {
+            self.string_literals.push(literal.clone());
+            self.string_literals.len() - 1
           }
-          // 函数名(看作运算符)
-          else {
-            operators.push_back(token.clone());
+        };
+        asm.push_str(&format!("  leaq .LSTR{}(%rip), %rax\n", label));
+      }
+      Inst::Call { callee, args, .. } => {
+        // 实参写到被调函数的栈帧中，再移动 %rbp
+        for (i, arg) in args.iter().enumerate() {
+          let offset = frame.size + i as i64 * 8;
+          if let Some(value) = immediate(arg) {
+            asm.push_str(&format!("  movq ${}, {}(%rbp)\n", value, offset));
+          } else {
+            self.load(arg, "%rax", frame, asm);
+            asm.push_str(&format!("  movq %rax, {}(%rbp)\n", offset));
           }
-        } else {
-          // 立即值
-          let reg = self.available_registers();
-          registers.push_back(reg.clone());
-          self.used_registers.push(reg.clone());
-          asm.push_str(&format!("	movq ${}, {}\n", token, reg));
         }
+        asm.push_str(&format!("  addq ${}, %rbp\n", frame.size));
+        asm.push_str(&format!("  call {}\n", callee));
+        asm.push_str(&format!("  subq ${}, %rbp\n", frame.size));
       }
+      Inst::Phi { .. } => panic!("phi should have been removed by out-of-SSA translation"),
     }
-    while !operators.is_empty() {
-      self.step(asm, &mut operators, &mut registers);
+    if let Some(dst) = inst.dst() {
+      asm.push_str(&format!("  movq %rax, {}\n", frame.vreg(dst)));
     }
-    ast.register = registers.pop_back().unwrap();
   }
 
-  /// 从运算符栈中弹出一个运算符，执行一步运算
-  fn step(
+  /// 整数运算：%rax = %rax op rhs，rhs 是 %rcx 或立即数；`trap` 时溢出跳转到运行时错误
+  fn generate_asm_int_op(&mut self, op: BinOp, rhs: &str, trap: Option<u32>, asm: &mut String) {
+    let verb = match op {
+      BinOp::Add => {
+        asm.push_str(&format!("  addq {}, %rax\n", rhs));
+        "add"
+      }
+      BinOp::Sub => {
+        asm.push_str(&format!("  subq {}, %rax\n", rhs));
+        "subtract"
+      }
+      BinOp::Mul => {
+        asm.push_str(&format!("  imulq {}, %rax\n", rhs));
+        "multiply"
+      }
+      BinOp::Div => {
+        asm.push_str(&format!("  cqto\n  idivq {}\n", rhs));
+        return;
+      }
+      _ => {
+        asm.push_str(&format!("  cmpq {}, %rax\n", rhs));
+        asm.push_str(&format!("  set{} %al\n", condition_code(op, false)));
+        asm.push_str("  movzbq %al, %rax\n");
+        return;
+      }
+    };
+    let Some(line) = trap else {
+      return;
+    };
+    let mut message = format!("attempt to {} with overflow", verb);
+    if line > 0 {
+      message.push_str(&format!(" at line {}", line));
+    }
+    let label = format!(".LOVF{}", self.overflow_traps.len());
+    asm.push_str(&format!("  jo {}\n", label));
+    self.overflow_traps.push((label, message));
+  }
+
+  /// 浮点运算：通过 %xmm0、%xmm1 进行，结果放回 %rax
+  fn generate_asm_float_op(&mut self, op: BinOp, asm: &mut String) {
+    asm.push_str("  movq %rax, %xmm0\n");
+    asm.push_str("  movq %rcx, %xmm1\n");
+    let sd = match op {
+      BinOp::Add => "addsd",
+      BinOp::Sub => "subsd",
+      BinOp::Mul => "mulsd",
+      BinOp::Div => "divsd",
+      _ => {
+        asm.push_str("  ucomisd %xmm1, %xmm0\n");
+        asm.push_str(&format!("  set{} %al\n", condition_code(op, true)));
+        asm.push_str("  movzbq %al, %rax\n");
+        return;
+      }
+    };
+    asm.push_str(&format!("  {} %xmm1, %xmm0\n", sd));
+    asm.push_str("  movq %xmm0, %rax\n");
+  }
+
+  fn generate_asm_term(
     &mut self,
+    function: &Function,
+    term: &Term,
+    next: Option<BlockId>,
+    frame: &Frame,
     asm: &mut String,
-    operators: &mut VecDeque<String>,
-    registers: &mut VecDeque<String>,
   ) {
-    let operator = operators.pop_back().unwrap();
-    let reg2 = registers.pop_back().unwrap();
-    let reg1 = registers.pop_back().unwrap();
-    let reg = self.available_registers();
-    let op_code = self.get_op_code(&operator, vec![reg1.clone(), reg2.clone()]);
-    if op_code == "idivq" {
-      asm.push_str(&format!("  movq {}, {}\n", reg2, reg));
-      asm.push_str(&format!(
-        "  movq {0}, %rax\n  xor %rdx, %rdx\n	cqto\n  idivq {1}\n  movq %rax, {1}\n",
-        reg1, reg
-      ));
-    } else if op_code == "greater_than" {
-      asm.push_str(&format!("  movq {}, {}\n", reg2, reg));
-      asm.push_str(&format!("  cmpq {}, {}\n", reg, reg1));
-      asm.push_str(&format!("  setg %al\n"));
-      asm.push_str(&format!("  movzbq %al, {}\n", reg));
-    } else if op_code == "less_than" {
-      asm.push_str(&format!("  movq {}, {}\n", reg2, reg));
-      asm.push_str(&format!("  cmpq {}, {}\n", reg, reg1));
-      asm.push_str(&format!("  setl %al\n"));
-      asm.push_str(&format!("  movzbq %al, {}\n", reg));
-    } else if op_code == "subq" {
-      asm.push_str(&format!("  movq {}, {}\n", reg1, reg));
-      asm.push_str(&format!("  subq {}, {}\n", reg2, reg));
-    } else {
-      asm.push_str(&format!("  movq {}, {}\n", reg2, reg));
-      asm.push_str(&format!("  {} {}, {}\n", op_code, reg1, reg));
+    match term {
+      Term::Jump(target) => {
+        if Some(*target) != next {
+          asm.push_str(&format!("  jmp {}\n", block_label(function, *target)));
+        }
+      }
+      Term::Branch { cond, then, els } => {
+        self.load(cond, "%rax", frame, asm);
+        asm.push_str("  cmpq $0, %rax\n");
+        asm.push_str(&format!("  je {}\n", block_label(function, *els)));
+        if Some(*then) != next {
+          asm.push_str(&format!("  jmp {}\n", block_label(function, *then)));
+        }
+      }
+      Term::Ret(value) => {
+        if let Some(value) = value {
+          self.load(value, "%rax", frame, asm);
+        }
+        frame.restore_callee_saved(asm);
+        asm.push_str("  ret\n");
+      }
+      Term::TailCall { callee, args, .. } => {
+        // 实参先写到栈帧之后，再依次搬到栈帧开头，避免覆盖还没有读取的虚拟寄存器；
+        // 被调函数沿用当前的 %rbp，返回到当前函数的调用者，所以跳转前要恢复被调者保存的寄存器
+        for (i, arg) in args.iter().enumerate() {
+          self.load(arg, "%rax", frame, asm);
+          asm.push_str(&format!(
+            "  movq %rax, {}(%rbp)\n",
+            frame.size + i as i64 * 8
+          ));
+        }
+        frame.restore_callee_saved(asm);
+        for i in 0..args.len() as i64 {
+          asm.push_str(&format!("  movq {}(%rbp), %rax\n", frame.size + i * 8));
+          asm.push_str(&format!("  movq %rax, {}(%rbp)\n", i * 8));
+        }
+        asm.push_str(&format!("  jmp {}\n", callee));
+      }
     }
-    self.used_registers.retain(|x| x != &reg1 && x != &reg2);
-    registers.push_back(reg.clone());
-    self.used_registers.push(reg);
+  }
+}
+
+/// 能直接作为 32 位立即数使用的操作数
+fn immediate(operand: &Operand) -> Option<i64> {
+  let value = match operand {
+    Operand::Reg(_) => return None,
+    Operand::Imm(Imm::Int(n)) => *n,
+    Operand::Imm(Imm::Float(x)) => x.to_bits() as i64,
+    Operand::Imm(Imm::Bool(b)) => *b as i64,
+  };
+  i32::try_from(value).is_ok().then_some(value)
+}
+
+fn block_label(function: &Function, block: BlockId) -> String {
+  format!(".L{}_{}", function.name, block.0)
+}
+
+/// 比较运算对应的 setcc 后缀；浮点数用 ucomisd 比较，条件码与无符号比较相同
+fn condition_code(op: BinOp, float: bool) -> &'static str {
+  match (op, float) {
+    (BinOp::Gt, false) => "g",
+    (BinOp::Lt, false) => "l",
+    (BinOp::Ge, false) => "ge",
+    (BinOp::Le, false) => "le",
+    (BinOp::Gt, true) => "a",
+    (BinOp::Lt, true) => "b",
+    (BinOp::Ge, true) => "ae",
+    (BinOp::Le, true) => "be",
+    (BinOp::Eq, _) => "e",
+    _ => "ne",
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ir::parse_module;
+  use crate::lexer::Lexer;
+  use crate::lower::POISON;
+  use crate::parser::Parser;
+  use crate::resolve::resolve;
+  use crate::typeck::check_program;
+
+  fn check(source: &str) -> AST {
+    let mut ast = Parser::new(Lexer::new(source)).parse();
+    resolve(&mut ast);
+    let signatures = collect_signatures(&ast);
+    check_program(&mut ast, &signatures, false);
+    ast
+  }
+
+  fn generate(interpreter: &mut Interpreter, ast: &AST) -> String {
+    let module = interpreter.lower(ast);
+    interpreter.generate_asm_module(&module)
+  }
+
+  fn compile(source: &str) -> String {
+    generate(&mut Interpreter::new(), &check(source))
+  }
+
+  #[test]
+  fn test_void_fn_gets_epilogue() {
+    let asm = compile("int main()\n  hello()\n  return 0\n\nvoid hello()\n  print(1)\n");
+    assert!(asm.trim_end().ends_with("ret"));
+  }
+
+  #[test]
+  fn test_main_falls_off_end_with_exit_code_zero() {
+    let asm = compile("void main()\n  print(1)\n");
+    assert!(asm.trim_end().ends_with("movq $0, %rax\n  ret"));
+  }
+
+  #[test]
+  fn test_bare_return() {
+    let asm = compile("void main()\n  if 1 > 2\n    return\n  else\n    pass\n  print(1)\n");
+    assert_eq!(asm.matches("movq $0, %rax\n  ret").count(), 2);
+  }
+
+  #[test]
+  fn test_no_epilogue_when_all_paths_return() {
+    let asm = compile("int f(int a)\n  if a > 1\n    return 1\n  else\n    return 2\n");
+    assert_eq!(asm.matches("ret").count(), 2);
+  }
+
+  #[test]
+  fn test_print_multiple_values() {
+    let asm = compile("void main()\n  int a = 1\n  print(a, \"hi\")\n");
+    assert!(asm.contains("call print_int"));
+    assert!(asm.contains("leaq .LSTR0(%rip)"));
+    assert!(asm.contains("call print_str"));
+    assert_eq!(asm.matches("call print_char").count(), 2);
+    assert!(asm.contains(".LSTR0:\n\t.asciz \"hi\""));
+  }
+
+  #[test]
+  fn test_variadic_call_passes_slice() {
+    let asm = compile(
+      "int main()\n  return count(4, 5)\n\nint count(int... xs)\n  return size(xs) + xs[0]\n",
+    );
+    // 两个变长实参存入调用方栈帧，传递首地址和长度
+    assert!(asm.contains("leaq 0(%rbp), %rax"));
+    assert!(asm.contains("movq $2, "));
+    // 函数内 xs 位于 0(%rbp)，xs.len 位于 8(%rbp)
+    assert!(asm.contains("movq 8(%rbp), %rax"));
+    assert!(asm.contains("movq (%rax, %rcx, 8), %rax"));
+  }
+
+  #[test]
+  fn test_float_ops_use_sse() {
+    let asm = compile("bool f(float a)\n  return a * 2.0 > 1.5\n");
+    assert!(asm.contains(&format!("movabsq ${}", 2.0f64.to_bits() as i64)));
+    assert!(asm.contains("mulsd %xmm1, %xmm0"));
+    assert!(asm.contains("ucomisd %xmm1, %xmm0\n  seta %al"));
+  }
+
+  #[test]
+  fn test_uninitialized_slot_is_poisoned_in_debug() {
+    let source = "int main()\n  int x\n  x = 1\n  return x\n";
+    assert!(compile(source).contains(&format!("movabsq ${}, %rax\n  movq %rax, 0(%rbp)", POISON)));
+    let mut interpreter = Interpreter::new();
+    interpreter.debug = false;
+    assert!(!generate(&mut interpreter, &check(source)).contains(&POISON.to_string()));
+  }
+
+  #[test]
+  fn test_overflow_checks() {
+    let source = "int f(int a)\n  int b = wrapping_add(a, 1)\n  return a * b\n";
+    let ast = check(source);
+    let mut interpreter = Interpreter::new();
+    interpreter.source = source.to_string();
+    let asm = generate(&mut interpreter, &ast);
+    // 只有乘法检查溢出，wrapping_add 不检查
+    assert_eq!(asm.matches("jo ").count(), 1);
+    assert!(asm.contains("imulq %rcx, %rax\n  jo .LOVF0"));
+    assert!(
+      asm.contains(".LOVF0:\n  leaq .LSTR0(%rip), %rsi\n  movq $59, %rdx\n  jmp runtime_error")
+    );
+    assert!(asm.contains("\"runtime error: attempt to multiply with overflow at line 3\\n\""));
+
+    let mut interpreter = Interpreter::new();
+    interpreter.overflow = Overflow::Wrap;
+    assert!(!generate(&mut interpreter, &ast).contains("jo "));
+  }
+
+  #[test]
+  fn test_generate_from_ir_text() {
+    let module = parse_module(
+      "fn f(a: int) -> int {\n  slot t: int\nb0:\n  v0 = load int [a]\n  v1 = call int g(v0, 2)\n  store [t], v1\n  br v1, b1, b2\nb1:\n  ret v1\nb2:\n  ret 0\n}\n",
+    )
+    .unwrap();
+    let mut interpreter = Interpreter::new();
+    interpreter.allocate_registers = false;
+    let asm = interpreter.generate_asm_module(&module);
+    // 栈帧：a、t 两个栈槽和 v0、v1 两个虚拟寄存器
+    assert!(asm.contains("  movq 16(%rbp), %rax\n  movq %rax, 32(%rbp)\n  movq $2, 40(%rbp)\n  addq $32, %rbp\n  call g\n  subq $32, %rbp\n  movq %rax, 24(%rbp)\n"));
+    assert!(asm.contains("  movq %rax, 8(%rbp)\n"));
+    assert!(asm.contains("  cmpq $0, %rax\n  je .Lf_2\n.Lf_1:\n"));
+  }
+
+  #[test]
+  fn test_immediate_operands() {
+    let module = parse_module(
+      "fn f(a: int) -> int {\nb0:\n  v0 = load int [a]\n  v1 = add int v0, 5\n  v2 = lt int v1, 100\n  v3 = div int v1, 3\n  v4 = copy int 7\n  ret v3\n}\n",
+    )
+    .unwrap();
+    let asm = Interpreter::new().generate_asm_module(&module);
+    assert!(asm.contains("  addq $5, %rax\n"));
+    assert!(asm.contains("  cmpq $100, %rax\n"));
+    // 除数必须在寄存器中
+    assert!(asm.contains("  movq $3, %rcx\n  cqto\n  idivq %rcx\n"));
+    assert!(asm.contains("  movq $7, %rdi\n"));
+  }
+
+  #[test]
+  fn test_registers_around_calls() {
+    let module = parse_module(
+      "fn f(a: int) -> int {\nb0:\n  v0 = load int [a]\n  v1 = add int v0, 1\n  v2 = call int g(v1)\n  v3 = add int v2, v0\n  tailcall int h(v3, v1)\n}\n",
+    )
+    .unwrap();
+    let asm = Interpreter::new().generate_asm_module(&module);
+    // v0、v1 跨越调用，分到被调者保存的寄存器，在入口保存，尾调用之前恢复
+    assert!(asm.contains("f:\n  movq %rbx, 40(%rbp)\n  movq %r12, 48(%rbp)\n"));
+    assert!(asm.contains("  movq 0(%rbp), %rax\n  movq %rax, %rbx\n"));
+    assert!(asm.contains("  movq %r12, %rax\n  movq %rax, 56(%rbp)\n  addq $56, %rbp\n"));
+    assert!(asm.contains(
+      "  movq 40(%rbp), %rbx\n  movq 48(%rbp), %r12\n  movq 56(%rbp), %rax\n  movq %rax, 0(%rbp)\n"
+    ));
   }
 }
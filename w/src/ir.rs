@@ -0,0 +1,1085 @@
+//! 三地址中间表示（IR）
+//!
+//! 每个函数由若干基本块组成，基本块是一串指令加一条终结指令（跳转、条件跳转、返回或尾调用）。
+//! 指令的结果写入新的虚拟寄存器 `v0`、`v1`……；参数和局部变量保存在栈槽中，
+//! 通过 `load` / `store` 读写。IR 有文本形式，`parse_module` 可以读回打印的结果：
+//!
+//! ```text
+//! fn max(a: int, b: int) -> int {
+//!   slot m: int
+//! b0:
+//!   v0 = load int [a]
+//!   v1 = load int [b]
+//!   v2 = gt int v0, v1
+//!   br v2, b1, b2
+//! b1:
+//!   ret v0
+//! b2:
+//!   ret v1
+//! }
+//! ```
+
+use crate::types::Type;
+use std::collections::HashSet;
+use std::fmt;
+
+/// IR 中值的类型，每个值都占 8 字节
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IrType {
+  Int,
+  Float,
+  Bool,
+  /// 地址：字符串、切片的首元素
+  Ptr,
+  Void,
+}
+
+impl IrType {
+  pub fn from_type(ty: &Type) -> IrType {
+    match ty {
+      Type::Float => IrType::Float,
+      Type::Bool => IrType::Bool,
+      Type::Str | Type::Slice(_) | Type::Array(..) => IrType::Ptr,
+      Type::Void => IrType::Void,
+      _ => IrType::Int,
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<IrType> {
+    match name {
+      "int" => Some(IrType::Int),
+      "float" => Some(IrType::Float),
+      "bool" => Some(IrType::Bool),
+      "ptr" => Some(IrType::Ptr),
+      "void" => Some(IrType::Void),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for IrType {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      IrType::Int => "int",
+      IrType::Float => "float",
+      IrType::Bool => "bool",
+      IrType::Ptr => "ptr",
+      IrType::Void => "void",
+    };
+    write!(f, "{}", name)
+  }
+}
+
+/// 虚拟寄存器
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VReg(pub u32);
+
+impl fmt::Display for VReg {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "v{}", self.0)
+  }
+}
+
+/// 基本块的编号，与它在 `Function::blocks` 中的下标相同
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId(pub u32);
+
+impl fmt::Display for BlockId {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "b{}", self.0)
+  }
+}
+
+/// 立即数
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Imm {
+  Int(i64),
+  Float(f64),
+  Bool(bool),
+}
+
+impl fmt::Display for Imm {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Imm::Int(n) => write!(f, "{}", n),
+      // {:?} 总是带小数点或指数，与整数区分
+      Imm::Float(x) => write!(f, "{:?}", x),
+      Imm::Bool(b) => write!(f, "{}", b),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+  Reg(VReg),
+  Imm(Imm),
+}
+
+impl fmt::Display for Operand {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Operand::Reg(reg) => write!(f, "{}", reg),
+      Operand::Imm(imm) => write!(f, "{}", imm),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinOp {
+  Add,
+  Sub,
+  Mul,
+  Div,
+  Lt,
+  Gt,
+  Le,
+  Ge,
+  Eq,
+  Ne,
+}
+
+const BIN_OPS: [(BinOp, &str, &str); 10] = [
+  (BinOp::Add, "add", "+"),
+  (BinOp::Sub, "sub", "-"),
+  (BinOp::Mul, "mul", "*"),
+  (BinOp::Div, "div", "/"),
+  (BinOp::Lt, "lt", "<"),
+  (BinOp::Gt, "gt", ">"),
+  (BinOp::Le, "le", "<="),
+  (BinOp::Ge, "ge", ">="),
+  (BinOp::Eq, "eq", "=="),
+  (BinOp::Ne, "ne", "!="),
+];
+
+impl BinOp {
+  /// 源码中的运算符，如 `+`
+  pub fn from_symbol(symbol: &str) -> Option<BinOp> {
+    BIN_OPS
+      .iter()
+      .find(|(_, _, s)| *s == symbol)
+      .map(|(op, _, _)| *op)
+  }
+
+  /// IR 文本中的名字，如 `add`
+  pub fn from_name(name: &str) -> Option<BinOp> {
+    BIN_OPS
+      .iter()
+      .find(|(_, n, _)| *n == name)
+      .map(|(op, _, _)| *op)
+  }
+
+  pub fn name(&self) -> &'static str {
+    BIN_OPS.iter().find(|(op, _, _)| op == self).unwrap().1
+  }
+
+  pub fn is_comparison(&self) -> bool {
+    !matches!(self, BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+  /// `v1 = copy int v0`，`v1 = copy int 5`
+  Copy { dst: VReg, ty: IrType, src: Operand },
+  /// `v2 = add int v0, v1`：`ty` 是操作数的类型，比较运算的结果是 bool。
+  /// `trap` 为 Some(行号) 时整数溢出跳转到运行时错误（`v2 = add int v0, v1 trap 3`）
+  Bin {
+    dst: VReg,
+    op: BinOp,
+    ty: IrType,
+    lhs: Operand,
+    rhs: Operand,
+    trap: Option<u32>,
+  },
+  /// `v0 = load int [x]`
+  Load { dst: VReg, ty: IrType, slot: String },
+  /// `store [x], v0`
+  Store { slot: String, src: Operand },
+  /// `v0 = addr [x]`：栈槽的地址。通过它可以访问 x 以及栈帧中 x 之后的所有栈槽
+  Addr { dst: VReg, slot: String },
+  /// `v2 = elem int v0, v1`：读取 v0 指向的数组中下标为 v1 的元素（每个元素 8 字节）
+  Elem {
+    dst: VReg,
+    ty: IrType,
+    base: Operand,
+    index: Operand,
+  },
+  /// `v0 = str "hi"`：字符串常量的地址，`literal` 带引号
+  Str { dst: VReg, literal: String },
+  /// `v2 = call int f(v0, v1)`，`call void print_int(v0)`
+  Call {
+    dst: Option<VReg>,
+    ty: IrType,
+    callee: String,
+    args: Vec<Operand>,
+  },
+  /// `v3 = phi int [b1: v0], [b2: 5]`：SSA 形式中按前驱块选择的值，只能出现在基本块开头
+  Phi {
+    dst: VReg,
+    ty: IrType,
+    incoming: Vec<(BlockId, Operand)>,
+  },
+}
+
+impl Inst {
+  /// 指令写入的虚拟寄存器
+  pub fn dst(&self) -> Option<VReg> {
+    match self {
+      Inst::Copy { dst, .. }
+      | Inst::Bin { dst, .. }
+      | Inst::Load { dst, .. }
+      | Inst::Addr { dst, .. }
+      | Inst::Elem { dst, .. }
+      | Inst::Str { dst, .. }
+      | Inst::Phi { dst, .. } => Some(*dst),
+      Inst::Call { dst, .. } => *dst,
+      Inst::Store { .. } => None,
+    }
+  }
+
+  /// 指令读取的操作数
+  pub fn operands(&self) -> Vec<&Operand> {
+    match self {
+      Inst::Copy { src, .. } | Inst::Store { src, .. } => vec![src],
+      Inst::Bin { lhs, rhs, .. } => vec![lhs, rhs],
+      Inst::Elem { base, index, .. } => vec![base, index],
+      Inst::Call { args, .. } => args.iter().collect(),
+      Inst::Phi { incoming, .. } => incoming.iter().map(|(_, value)| value).collect(),
+      Inst::Load { .. } | Inst::Addr { .. } | Inst::Str { .. } => vec![],
+    }
+  }
+
+  pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+    match self {
+      Inst::Copy { src, .. } | Inst::Store { src, .. } => vec![src],
+      Inst::Bin { lhs, rhs, .. } => vec![lhs, rhs],
+      Inst::Elem { base, index, .. } => vec![base, index],
+      Inst::Call { args, .. } => args.iter_mut().collect(),
+      Inst::Phi { incoming, .. } => incoming.iter_mut().map(|(_, value)| value).collect(),
+      Inst::Load { .. } | Inst::Addr { .. } | Inst::Str { .. } => vec![],
+    }
+  }
+
+  /// 指令读写的栈槽
+  pub fn slot(&self) -> Option<&str> {
+    match self {
+      Inst::Load { slot, .. } | Inst::Store { slot, .. } | Inst::Addr { slot, .. } => Some(slot),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for Inst {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Inst::Copy { dst, ty, src } => write!(f, "{} = copy {} {}", dst, ty, src),
+      Inst::Bin {
+        dst,
+        op,
+        ty,
+        lhs,
+        rhs,
+        trap,
+      } => {
+        write!(f, "{} = {} {} {}, {}", dst, op.name(), ty, lhs, rhs)?;
+        match trap {
+          Some(line) => write!(f, " trap {}", line),
+          None => Ok(()),
+        }
+      }
+      Inst::Load { dst, ty, slot } => write!(f, "{} = load {} [{}]", dst, ty, slot),
+      Inst::Store { slot, src } => write!(f, "store [{}], {}", slot, src),
+      Inst::Addr { dst, slot } => write!(f, "{} = addr [{}]", dst, slot),
+      Inst::Elem {
+        dst,
+        ty,
+        base,
+        index,
+      } => write!(f, "{} = elem {} {}, {}", dst, ty, base, index),
+      Inst::Str { dst, literal } => write!(f, "{} = str {}", dst, literal),
+      Inst::Call {
+        dst,
+        ty,
+        callee,
+        args,
+      } => {
+        if let Some(dst) = dst {
+          write!(f, "{} = ", dst)?;
+        }
+        let args: Vec<String> = args.iter().map(Operand::to_string).collect();
+        write!(f, "call {} {}({})", ty, callee, args.join(", "))
+      }
+      Inst::Phi { dst, ty, incoming } => {
+        let incoming: Vec<String> = incoming
+          .iter()
+          .map(|(block, value)| format!("[{}: {}]", block, value))
+          .collect();
+        write!(f, "{} = phi {} {}", dst, ty, incoming.join(", "))
+      }
+    }
+  }
+}
+
+/// 基本块的终结指令
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+  /// `jmp b1`
+  Jump(BlockId),
+  /// `br v0, b1, b2`：v0 为真时跳到 b1，否则跳到 b2
+  Branch {
+    cond: Operand,
+    then: BlockId,
+    els: BlockId,
+  },
+  /// `ret v0`，`ret`
+  Ret(Option<Operand>),
+  /// `tailcall int f(v0, v1)`：尾调用，被调函数复用当前栈帧并直接返回到当前函数的调用者
+  TailCall {
+    ty: IrType,
+    callee: String,
+    args: Vec<Operand>,
+  },
+}
+
+impl Term {
+  pub fn successors(&self) -> Vec<BlockId> {
+    match self {
+      Term::Jump(target) => vec![*target],
+      Term::Branch { then, els, .. } => vec![*then, *els],
+      Term::Ret(_) | Term::TailCall { .. } => vec![],
+    }
+  }
+
+  pub fn successors_mut(&mut self) -> Vec<&mut BlockId> {
+    match self {
+      Term::Jump(target) => vec![target],
+      Term::Branch { then, els, .. } => vec![then, els],
+      Term::Ret(_) | Term::TailCall { .. } => vec![],
+    }
+  }
+
+  pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+    match self {
+      Term::Branch { cond, .. } => vec![cond],
+      Term::Ret(Some(value)) => vec![value],
+      Term::TailCall { args, .. } => args.iter_mut().collect(),
+      Term::Jump(_) | Term::Ret(None) => vec![],
+    }
+  }
+
+  pub fn operands(&self) -> Vec<&Operand> {
+    match self {
+      Term::Branch { cond, .. } => vec![cond],
+      Term::Ret(Some(value)) => vec![value],
+      Term::TailCall { args, .. } => args.iter().collect(),
+      Term::Jump(_) | Term::Ret(None) => vec![],
+    }
+  }
+}
+
+impl fmt::Display for Term {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Term::Jump(target) => write!(f, "jmp {}", target),
+      Term::Branch { cond, then, els } => write!(f, "br {}, {}, {}", cond, then, els),
+      Term::Ret(Some(value)) => write!(f, "ret {}", value),
+      Term::Ret(None) => write!(f, "ret"),
+      Term::TailCall { ty, callee, args } => {
+        let args: Vec<String> = args.iter().map(Operand::to_string).collect();
+        write!(f, "tailcall {} {}({})", ty, callee, args.join(", "))
+      }
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+  pub id: BlockId,
+  pub insts: Vec<Inst>,
+  pub term: Term,
+}
+
+/// 栈槽：一个参数或局部变量
+#[derive(Debug, Clone, PartialEq)]
+pub struct Slot {
+  pub name: String,
+  pub ty: IrType,
+}
+
+/// 函数上的内联属性
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Inline {
+  /// 由内联的代价模型决定
+  #[default]
+  Auto,
+  /// `@inline`：只要不是递归函数就内联
+  Always,
+  /// `@noinline`：从不内联
+  Never,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+  pub name: String,
+  pub inline: Inline,
+  /// 参数按传参顺序排列，在栈帧中位于局部变量之前
+  pub params: Vec<Slot>,
+  pub locals: Vec<Slot>,
+  pub ret: IrType,
+  /// 第一个基本块是入口
+  pub blocks: Vec<Block>,
+}
+
+impl Function {
+  /// 参数和局部变量，按在栈帧中的顺序
+  pub fn slots(&self) -> impl Iterator<Item = &Slot> {
+    self.params.iter().chain(&self.locals)
+  }
+
+  /// 调用（包括尾调用）的函数名，每处调用一个
+  pub fn callees(&self) -> impl Iterator<Item = &str> {
+    self.blocks.iter().flat_map(|block| {
+      let calls = block.insts.iter().filter_map(|inst| match inst {
+        Inst::Call { callee, .. } => Some(callee.as_str()),
+        _ => None,
+      });
+      let tail = match &block.term {
+        Term::TailCall { callee, .. } => Some(callee.as_str()),
+        _ => None,
+      };
+      calls.chain(tail)
+    })
+  }
+
+  /// 虚拟寄存器的个数（最大编号加一）
+  pub fn vreg_count(&self) -> u32 {
+    self
+      .blocks
+      .iter()
+      .flat_map(|block| &block.insts)
+      .filter_map(Inst::dst)
+      .map(|reg| reg.0 + 1)
+      .max()
+      .unwrap_or(0)
+  }
+
+  /// 新的虚拟寄存器，编号大于已有的所有寄存器
+  pub fn new_vreg(&self) -> VReg {
+    VReg(self.vreg_count())
+  }
+
+  /// 删除从入口不可达的基本块，并重新编号；phi 中来自被删除的块的值一并删除
+  pub fn remove_unreachable_blocks(&mut self) {
+    let mut reachable = HashSet::new();
+    let mut stack = vec![BlockId(0)];
+    while let Some(id) = stack.pop() {
+      if reachable.insert(id) {
+        stack.extend(self.blocks[id.0 as usize].term.successors());
+      }
+    }
+    let mut renumber = vec![None; self.blocks.len()];
+    let mut next = 0;
+    for block in &self.blocks {
+      if reachable.contains(&block.id) {
+        renumber[block.id.0 as usize] = Some(BlockId(next));
+        next += 1;
+      }
+    }
+    self.blocks.retain(|block| reachable.contains(&block.id));
+    for block in &mut self.blocks {
+      block.id = renumber[block.id.0 as usize].unwrap();
+      for target in block.term.successors_mut() {
+        *target = renumber[target.0 as usize].unwrap();
+      }
+      for inst in &mut block.insts {
+        if let Inst::Phi { incoming, .. } = inst {
+          incoming.retain(|(from, _)| renumber[from.0 as usize].is_some());
+          for (from, _) in incoming {
+            *from = renumber[from.0 as usize].unwrap();
+          }
+        }
+      }
+    }
+  }
+}
+
+impl fmt::Display for Function {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let params: Vec<String> = self
+      .params
+      .iter()
+      .map(|slot| format!("{}: {}", slot.name, slot.ty))
+      .collect();
+    match self.inline {
+      Inline::Auto => {}
+      Inline::Always => write!(f, "@inline ")?,
+      Inline::Never => write!(f, "@noinline ")?,
+    }
+    writeln!(
+      f,
+      "fn {}({}) -> {} {{",
+      self.name,
+      params.join(", "),
+      self.ret
+    )?;
+    for slot in &self.locals {
+      writeln!(f, "  slot {}: {}", slot.name, slot.ty)?;
+    }
+    for block in &self.blocks {
+      writeln!(f, "{}:", block.id)?;
+      for inst in &block.insts {
+        writeln!(f, "  {}", inst)?;
+      }
+      writeln!(f, "  {}", block.term)?;
+    }
+    writeln!(f, "}}")
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Module {
+  pub functions: Vec<Function>,
+}
+
+impl fmt::Display for Module {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for (i, function) in self.functions.iter().enumerate() {
+      if i > 0 {
+        writeln!(f)?;
+      }
+      write!(f, "{}", function)?;
+    }
+    Ok(())
+  }
+}
+
+/// 解析 IR 的文本形式，错误信息带行号
+pub fn parse_module(text: &str) -> Result<Module, String> {
+  let mut module = Module::default();
+  let mut function: Option<Function> = None;
+  let mut block: Option<(BlockId, Vec<Inst>)> = None;
+  for (i, line) in text.lines().enumerate() {
+    let error = |message: String| format!("line {}: {}", i + 1, message);
+    let mut tokens = Tokens::new(line).map_err(error)?;
+    let Some(first) = tokens.peek().map(str::to_string) else {
+      continue;
+    };
+    let Some(current) = function.as_mut() else {
+      let mut new = Function {
+        name: String::new(),
+        inline: Inline::Auto,
+        params: vec![],
+        locals: vec![],
+        ret: IrType::Void,
+        blocks: vec![],
+      };
+      parse_header(&mut tokens, &mut new).map_err(error)?;
+      function = Some(new);
+      continue;
+    };
+    if first == "}" {
+      if block.is_some() {
+        return Err(error("the last block has no terminator".to_string()));
+      }
+      tokens.next();
+      tokens.end().map_err(error)?;
+      module.functions.push(function.take().unwrap());
+    } else if first == "slot" {
+      tokens.next();
+      current.locals.push(parse_slot(&mut tokens).map_err(error)?);
+      tokens.end().map_err(error)?;
+    } else if tokens.peek_at(1) == Some(":") {
+      if block.is_some() {
+        return Err(error(format!("block before `{}` has no terminator", first)));
+      }
+      let id = parse_block_id(&tokens.next_word().map_err(error)?).map_err(error)?;
+      if id.0 as usize != current.blocks.len() {
+        return Err(error(format!(
+          "expected block b{}, found {}",
+          current.blocks.len(),
+          id
+        )));
+      }
+      tokens.expect(":").map_err(error)?;
+      tokens.end().map_err(error)?;
+      block = Some((id, vec![]));
+    } else {
+      let Some((id, insts)) = block.as_mut() else {
+        return Err(error("instruction outside of a block".to_string()));
+      };
+      match parse_line(&mut tokens).map_err(error)? {
+        Line::Inst(inst) => insts.push(inst),
+        Line::Term(term) => {
+          current.blocks.push(Block {
+            id: *id,
+            insts: std::mem::take(insts),
+            term,
+          });
+          block = None;
+        }
+      }
+    }
+  }
+  if function.is_some() {
+    return Err("unexpected end of input: missing `}`".to_string());
+  }
+  Ok(module)
+}
+
+enum Line {
+  Inst(Inst),
+  Term(Term),
+}
+
+/// 一行 IR 文本中的记号
+struct Tokens {
+  tokens: Vec<String>,
+  pos: usize,
+}
+
+impl Tokens {
+  fn new(line: &str) -> Result<Tokens, String> {
+    let mut tokens = vec![];
+    let chars: Vec<char> = line.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+      let c = chars[i];
+      if c.is_whitespace() {
+        i += 1;
+      } else if c == '"' {
+        // 字符串常量保留引号和转义
+        let start = i;
+        i += 1;
+        while i < chars.len() && chars[i] != '"' {
+          i += if chars[i] == '\\' { 2 } else { 1 };
+        }
+        if i >= chars.len() {
+          return Err("unterminated string literal".to_string());
+        }
+        i += 1;
+        tokens.push(chars[start..i].iter().collect());
+      } else if c == '-' && chars.get(i + 1) == Some(&'>') {
+        tokens.push("->".to_string());
+        i += 2;
+      } else if "()[],:={}".contains(c) {
+        tokens.push(c.to_string());
+        i += 1;
+      } else {
+        let start = i;
+        while i < chars.len() && !chars[i].is_whitespace() && !"()[],:={}\"".contains(chars[i]) {
+          i += 1;
+        }
+        tokens.push(chars[start..i].iter().collect());
+      }
+    }
+    Ok(Tokens { tokens, pos: 0 })
+  }
+
+  fn peek(&self) -> Option<&str> {
+    self.peek_at(0)
+  }
+
+  fn peek_at(&self, offset: usize) -> Option<&str> {
+    self.tokens.get(self.pos + offset).map(String::as_str)
+  }
+
+  fn next(&mut self) -> Option<String> {
+    let token = self.tokens.get(self.pos).cloned();
+    self.pos += 1;
+    token
+  }
+
+  fn next_word(&mut self) -> Result<String, String> {
+    self
+      .next()
+      .ok_or_else(|| "unexpected end of line".to_string())
+  }
+
+  fn expect(&mut self, expected: &str) -> Result<(), String> {
+    match self.next() {
+      Some(token) if token == expected => Ok(()),
+      Some(token) => Err(format!("expected `{}`, found `{}`", expected, token)),
+      None => Err(format!("expected `{}`, found end of line", expected)),
+    }
+  }
+
+  fn eat(&mut self, token: &str) -> bool {
+    if self.peek() == Some(token) {
+      self.pos += 1;
+      true
+    } else {
+      false
+    }
+  }
+
+  fn end(&self) -> Result<(), String> {
+    match self.peek() {
+      Some(token) => Err(format!("unexpected `{}`", token)),
+      None => Ok(()),
+    }
+  }
+}
+
+fn parse_header(tokens: &mut Tokens, function: &mut Function) -> Result<(), String> {
+  if tokens.eat("@inline") {
+    function.inline = Inline::Always;
+  } else if tokens.eat("@noinline") {
+    function.inline = Inline::Never;
+  }
+  tokens.expect("fn")?;
+  function.name = tokens.next_word()?;
+  tokens.expect("(")?;
+  while !tokens.eat(")") {
+    if !function.params.is_empty() {
+      tokens.expect(",")?;
+    }
+    function.params.push(parse_slot(tokens)?);
+  }
+  tokens.expect("->")?;
+  function.ret = parse_type(&tokens.next_word()?)?;
+  tokens.expect("{")?;
+  tokens.end()
+}
+
+fn parse_slot(tokens: &mut Tokens) -> Result<Slot, String> {
+  let name = tokens.next_word()?;
+  tokens.expect(":")?;
+  let ty = parse_type(&tokens.next_word()?)?;
+  Ok(Slot { name, ty })
+}
+
+fn parse_type(word: &str) -> Result<IrType, String> {
+  IrType::from_name(word).ok_or_else(|| format!("unknown type `{}`", word))
+}
+
+fn parse_vreg(word: &str) -> Result<VReg, String> {
+  word
+    .strip_prefix('v')
+    .and_then(|n| n.parse().ok())
+    .map(VReg)
+    .ok_or_else(|| format!("expected a virtual register, found `{}`", word))
+}
+
+fn parse_block_id(word: &str) -> Result<BlockId, String> {
+  word
+    .strip_prefix('b')
+    .and_then(|n| n.parse().ok())
+    .map(BlockId)
+    .ok_or_else(|| format!("expected a block, found `{}`", word))
+}
+
+fn parse_operand(tokens: &mut Tokens) -> Result<Operand, String> {
+  let word = tokens.next_word()?;
+  if word.starts_with('v') {
+    return parse_vreg(&word).map(Operand::Reg);
+  }
+  let imm = match word.as_str() {
+    "true" | "false" => Imm::Bool(word == "true"),
+    _ if word.contains(['.', 'e']) || word.ends_with("inf") || word == "NaN" => Imm::Float(
+      word
+        .parse()
+        .map_err(|_| format!("invalid float `{}`", word))?,
+    ),
+    _ => Imm::Int(
+      word
+        .parse()
+        .map_err(|_| format!("invalid operand `{}`", word))?,
+    ),
+  };
+  Ok(Operand::Imm(imm))
+}
+
+fn parse_slot_ref(tokens: &mut Tokens) -> Result<String, String> {
+  tokens.expect("[")?;
+  let name = tokens.next_word()?;
+  tokens.expect("]")?;
+  Ok(name)
+}
+
+/// 调用的实参列表 `(v0, 1)`
+fn parse_args(tokens: &mut Tokens) -> Result<Vec<Operand>, String> {
+  tokens.expect("(")?;
+  let mut args = vec![];
+  while !tokens.eat(")") {
+    if !args.is_empty() {
+      tokens.expect(",")?;
+    }
+    args.push(parse_operand(tokens)?);
+  }
+  Ok(args)
+}
+
+fn parse_line(tokens: &mut Tokens) -> Result<Line, String> {
+  let dst = if tokens.peek_at(1) == Some("=") {
+    let dst = parse_vreg(&tokens.next_word()?)?;
+    tokens.expect("=")?;
+    Some(dst)
+  } else {
+    None
+  };
+  let opcode = tokens.next_word()?;
+  let needs_dst = || dst.ok_or_else(|| format!("`{}` needs a destination register", opcode));
+  let line = match opcode.as_str() {
+    "jmp" | "br" | "ret" | "tailcall" | "store" if dst.is_some() => {
+      return Err(format!("`{}` does not produce a value", opcode))
+    }
+    "jmp" => Line::Term(Term::Jump(parse_block_id(&tokens.next_word()?)?)),
+    "br" => {
+      let cond = parse_operand(tokens)?;
+      tokens.expect(",")?;
+      let then = parse_block_id(&tokens.next_word()?)?;
+      tokens.expect(",")?;
+      let els = parse_block_id(&tokens.next_word()?)?;
+      Line::Term(Term::Branch { cond, then, els })
+    }
+    "ret" if tokens.peek().is_none() => Line::Term(Term::Ret(None)),
+    "ret" => Line::Term(Term::Ret(Some(parse_operand(tokens)?))),
+    "tailcall" => Line::Term(Term::TailCall {
+      ty: parse_type(&tokens.next_word()?)?,
+      callee: tokens.next_word()?,
+      args: parse_args(tokens)?,
+    }),
+    "store" => {
+      let slot = parse_slot_ref(tokens)?;
+      tokens.expect(",")?;
+      let src = parse_operand(tokens)?;
+      Line::Inst(Inst::Store { slot, src })
+    }
+    "copy" => {
+      let ty = parse_type(&tokens.next_word()?)?;
+      let src = parse_operand(tokens)?;
+      Line::Inst(Inst::Copy {
+        dst: needs_dst()?,
+        ty,
+        src,
+      })
+    }
+    "load" => {
+      let ty = parse_type(&tokens.next_word()?)?;
+      let slot = parse_slot_ref(tokens)?;
+      Line::Inst(Inst::Load {
+        dst: needs_dst()?,
+        ty,
+        slot,
+      })
+    }
+    "addr" => Line::Inst(Inst::Addr {
+      dst: needs_dst()?,
+      slot: parse_slot_ref(tokens)?,
+    }),
+    "elem" => {
+      let ty = parse_type(&tokens.next_word()?)?;
+      let base = parse_operand(tokens)?;
+      tokens.expect(",")?;
+      let index = parse_operand(tokens)?;
+      Line::Inst(Inst::Elem {
+        dst: needs_dst()?,
+        ty,
+        base,
+        index,
+      })
+    }
+    "str" => {
+      let literal = tokens.next_word()?;
+      if !literal.starts_with('"') {
+        return Err(format!("expected a string literal, found `{}`", literal));
+      }
+      Line::Inst(Inst::Str {
+        dst: needs_dst()?,
+        literal,
+      })
+    }
+    "phi" => {
+      let ty = parse_type(&tokens.next_word()?)?;
+      let mut incoming = vec![];
+      while tokens.peek().is_some() {
+        if !incoming.is_empty() {
+          tokens.expect(",")?;
+        }
+        tokens.expect("[")?;
+        let block = parse_block_id(&tokens.next_word()?)?;
+        tokens.expect(":")?;
+        let value = parse_operand(tokens)?;
+        tokens.expect("]")?;
+        incoming.push((block, value));
+      }
+      Line::Inst(Inst::Phi {
+        dst: needs_dst()?,
+        ty,
+        incoming,
+      })
+    }
+    "call" => {
+      let ty = parse_type(&tokens.next_word()?)?;
+      let callee = tokens.next_word()?;
+      let args = parse_args(tokens)?;
+      if dst.is_none() != (ty == IrType::Void) {
+        return Err(format!(
+          "a call returning `{}` {} a destination register",
+          ty,
+          if ty == IrType::Void {
+            "cannot have"
+          } else {
+            "needs"
+          }
+        ));
+      }
+      Line::Inst(Inst::Call {
+        dst,
+        ty,
+        callee,
+        args,
+      })
+    }
+    _ => {
+      let op =
+        BinOp::from_name(&opcode).ok_or_else(|| format!("unknown instruction `{}`", opcode))?;
+      let ty = parse_type(&tokens.next_word()?)?;
+      let lhs = parse_operand(tokens)?;
+      tokens.expect(",")?;
+      let rhs = parse_operand(tokens)?;
+      let trap = if tokens.eat("trap") {
+        let line = tokens.next_word()?;
+        Some(
+          line
+            .parse()
+            .map_err(|_| format!("invalid line number `{}`", line))?,
+        )
+      } else {
+        None
+      };
+      Line::Inst(Inst::Bin {
+        dst: needs_dst()?,
+        op,
+        ty,
+        lhs,
+        rhs,
+        trap,
+      })
+    }
+  };
+  tokens.end()?;
+  Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SAMPLE: &str = "fn sum(xs: ptr, xs.len: int) -> int {
+  slot s: int
+  slot i: int
+b0:
+  store [s], 0
+  store [i], 0
+  jmp b1
+b1:
+  v0 = load int [i]
+  v1 = load int [xs.len]
+  v2 = lt int v0, v1
+  br v2, b2, b3
+b2:
+  v3 = load ptr [xs]
+  v4 = elem int v3, v0
+  v5 = load int [s]
+  v6 = add int v5, v4 trap 4
+  store [s], v6
+  v7 = add int v0, 1
+  store [i], v7
+  jmp b1
+b3:
+  v8 = load int [s]
+  ret v8
+}
+
+@noinline fn main() -> int {
+  slot va0.0: int
+  slot half: float
+b0:
+  v0 = str \"hi \\\"there\\\"\"
+  call void print_str(v0)
+  store [va0.0], 7
+  v1 = addr [va0.0]
+  v2 = call int sum(v1, 1)
+  v3 = copy float 0.5
+  store [half], v3
+  v4 = eq bool true, false
+  br v4, b1, b2
+b1:
+  ret 0
+b2:
+  tailcall int sum(v1, 1)
+}
+";
+
+  #[test]
+  fn test_print_and_parse_round_trip() {
+    let module = parse_module(SAMPLE).unwrap();
+    assert_eq!(module.functions.len(), 2);
+    assert_eq!(module.to_string(), SAMPLE);
+    let sum = &module.functions[0];
+    assert_eq!(module.functions[1].inline, Inline::Never);
+    let callees: Vec<&str> = module.functions[1].callees().collect();
+    assert_eq!(callees, vec!["print_str", "sum", "sum"]);
+    assert_eq!(sum.params[1].name, "xs.len");
+    assert_eq!(sum.vreg_count(), 9);
+    assert_eq!(
+      sum.blocks[1].term.successors(),
+      vec![BlockId(2), BlockId(3)]
+    );
+    assert_eq!(
+      sum.blocks[2].insts[3],
+      Inst::Bin {
+        dst: VReg(6),
+        op: BinOp::Add,
+        ty: IrType::Int,
+        lhs: Operand::Reg(VReg(5)),
+        rhs: Operand::Reg(VReg(4)),
+        trap: Some(4),
+      }
+    );
+  }
+
+  #[test]
+  fn test_parse_errors() {
+    let error = |text: &str| parse_module(text).unwrap_err();
+    assert_eq!(
+      error("fn f() -> int {\nb0:\n  v0 = frob int 1, 2\n  ret v0\n}\n"),
+      "line 3: unknown instruction `frob`"
+    );
+    assert_eq!(
+      error("fn f() -> int {\nb0:\n  v0 = add int 1, 2\n}\n"),
+      "line 4: the last block has no terminator"
+    );
+    assert_eq!(
+      error("fn f() -> void {\nb1:\n  ret\n}\n"),
+      "line 2: expected block b0, found b1"
+    );
+    assert_eq!(
+      error("fn f() -> void {\nb0:\n  call int g()\n  ret\n}\n"),
+      "line 3: a call returning `int` needs a destination register"
+    );
+    assert_eq!(
+      error("fn f() -> int {\nb0:\n  v0 = tailcall int g()\n}\n"),
+      "line 3: `tailcall` does not produce a value"
+    );
+    assert_eq!(
+      error("fn f() -> void {\nb0:\n  ret\n"),
+      "unexpected end of input: missing `}`"
+    );
+  }
+
+  #[test]
+  fn test_remove_unreachable_blocks() {
+    let mut module = parse_module(
+      "fn f() -> int {\nb0:\n  jmp b2\nb1:\n  jmp b3\nb2:\n  br true, b3, b3\nb3:\n  v0 = phi int [b1: 1], [b2: 2]\n  ret v0\n}\n",
+    )
+    .unwrap();
+    let f = &mut module.functions[0];
+    f.remove_unreachable_blocks();
+    assert_eq!(
+      f.to_string(),
+      "fn f() -> int {\nb0:\n  jmp b1\nb1:\n  br true, b2, b2\nb2:\n  v0 = phi int [b1: 2]\n  ret v0\n}\n"
+    );
+  }
+}
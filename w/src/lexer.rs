@@ -1,6 +1,68 @@
-use std::{char, str::Chars};
+use std::{char, fmt, str::Chars};
+
+/// 源码中的字节区间 [start, end)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+  pub start: usize,
+  pub end: usize,
+}
+
+impl Span {
+  pub fn new(start: usize, end: usize) -> Self {
+    Span { start, end }
+  }
+
+  pub fn len(&self) -> usize {
+    self.end - self.start
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.start == self.end
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TokenKind {
+  Number,
+  Str,
+  Ident,
+  Op,
+  Delim,
+  /// 由缩进增加合成的 `{`，源码中没有对应文本
+  Indent,
+  /// 由缩进减少合成的 `}`，源码中没有对应文本
+  Dedent,
+  Whitespace,
+  Comment,
+  /// 无法识别的字符
+  Error,
+  Eof,
+}
+
+/// 词法单元。`value` 是语法分析使用的值（字符串字面量已去掉引号），
+/// `span` 是它在源码中的原始区间，`trivia` 是它前面被跳过的空白和注释。
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+  pub kind: TokenKind,
+  pub value: String,
+  pub span: Span,
+  pub trivia: Span,
+}
+
+impl PartialEq<&str> for Token {
+  fn eq(&self, other: &&str) -> bool {
+    self.value == *other
+  }
+}
+
+impl fmt::Display for Token {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.value)
+  }
+}
 
 pub struct Lexer<'a> {
+  source: &'a str,
   input: Chars<'a>,
   current_char: Option<char>,
   current_indent: i8,
@@ -13,6 +75,7 @@ impl<'a> Lexer<'a> {
     let current_char = chars.next();
 
     Lexer {
+      source: input,
       input: chars,
       current_char,
       current_indent: 0,
@@ -20,6 +83,15 @@ impl<'a> Lexer<'a> {
     }
   }
 
+  pub fn source(&self) -> &'a str {
+    self.source
+  }
+
+  /// 当前字符在源码中的字节偏移
+  fn pos(&self) -> usize {
+    self.source.len() - self.input.as_str().len() - self.current_char.map_or(0, char::len_utf8)
+  }
+
   fn advance(&mut self) {
     self.current_char = self.input.next();
   }
@@ -36,21 +108,31 @@ impl<'a> Lexer<'a> {
     }
   }
 
+  fn skip_comment(&mut self) {
+    while let Some(ch) = self.current_char {
+      if ch == '\n' {
+        break;
+      }
+      self.advance();
+    }
+  }
+
   fn compute_indent(&mut self) {
     let mut indent;
-    if let Some(_) = self.current_char {
+    if self.current_char.is_some() {
       indent = self.current_indent * 2;
       while let Some(ch) = self.current_char {
         if ch == ' ' {
           indent += 1;
           self.advance();
+        } else if ch == '\n' {
+          self.advance();
+          indent = 0;
+        } else if ch == '#' {
+          // 注释行不影响缩进
+          self.skip_comment();
         } else {
-          if ch == '\n' {
-            self.advance();
-            indent = 0;
-          } else {
-            break;
-          };
+          break;
         }
       }
     } else {
@@ -81,15 +163,14 @@ impl<'a> Lexer<'a> {
   }
 
   fn read_keyword_or_identifier(&mut self) -> String {
-    let keyword_or_identifier = self.read_identifier();
-    keyword_or_identifier
+    self.read_identifier()
   }
 
   fn read_number(&mut self) -> String {
     let mut number = String::new();
 
     while let Some(ch) = self.current_char {
-      if ch.is_digit(10) {
+      if ch.is_ascii_digit() {
         number.push(ch);
         self.advance();
       } else {
@@ -102,7 +183,7 @@ impl<'a> Lexer<'a> {
       self.advance();
 
       while let Some(ch) = self.current_char {
-        if ch.is_digit(10) {
+        if ch.is_ascii_digit() {
           number.push(ch);
           self.advance();
         } else {
@@ -136,16 +217,16 @@ impl<'a> Lexer<'a> {
   /// # Examples
   ///
   /// ```
-  /// # use crate::lexer::Lexer;
-  /// let mut lexer = Lexer::new("+-*/%=&|".chars().collect());
+  /// # use w::lexer::Lexer;
+  /// let mut lexer = Lexer::new("+-*/%=&|");
   ///
-  /// assert_eq!(lexer.read_operator(), "+-*/%=&|".to_string());
+  /// assert_eq!(lexer.next_token(), "+-*/%=&|".to_string());
   /// ```
   fn read_operator(&mut self) -> String {
     let mut operator = String::new();
 
     while let Some(ch) = self.current_char {
-      if ch.is_ascii_punctuation() {
+      if "+-*/%=<>!&|^~.".contains(ch) {
         operator.push(ch);
         self.advance();
       } else {
@@ -163,36 +244,59 @@ impl<'a> Lexer<'a> {
   }
 
   pub fn next_token(&mut self) -> String {
+    self.read_token().value
+  }
+
+  /// 读取下一个词法单元，同时记录它的源码区间和前导 trivia
+  pub fn read_token(&mut self) -> Token {
+    let trivia_start = self.pos();
     self.skip_whitespace();
     self.compute_indent();
-    if self.indent_left > 0 {
-      self.indent_left -= 1;
-      return "{".to_string();
-    } else if self.indent_left < 0 {
-      self.indent_left += 1;
-      return "}".to_string();
+    let start = self.pos();
+    let trivia = Span::new(trivia_start, start);
+    if self.indent_left != 0 {
+      let (kind, value) = if self.indent_left > 0 {
+        self.indent_left -= 1;
+        (TokenKind::Indent, "{")
+      } else {
+        self.indent_left += 1;
+        (TokenKind::Dedent, "}")
+      };
+      return Token {
+        kind,
+        value: value.to_string(),
+        span: Span::new(start, start),
+        trivia,
+      };
     }
 
-    if let Some(ch) = self.current_char {
+    let (kind, value) = if let Some(ch) = self.current_char {
       match ch {
-        '0'..='9' => self.read_number(),
-        '"' => self.read_string_literal(),
-        '+' | '-' | '*' | '/' | '%' | '=' | '<' | '>' | '!' | '&' | '|' | '^' | '~' => {
-          self.read_operator()
+        '0'..='9' => (TokenKind::Number, self.read_number()),
+        '"' => (TokenKind::Str, self.read_string_literal()),
+        '+' | '-' | '*' | '/' | '%' | '=' | '<' | '>' | '!' | '&' | '|' | '^' | '~' | '.' => {
+          (TokenKind::Op, self.read_operator())
         }
-        '(' | ')' | '[' | ']' | ',' | ':' => self.read_delimiter(),
-        _ => {
+        '(' | ')' | '[' | ']' | ',' | ':' | ';' | '@' => (TokenKind::Delim, self.read_delimiter()),
+        c if c.is_alphanumeric() || c == '_' => {
           let identifier = self.read_keyword_or_identifier();
           match identifier.as_str() {
             "if" | "else" | "while" | "for" | "int" | "return" | "true" | "false" | "auto" => {
-              identifier
+              (TokenKind::Ident, identifier)
             }
-            _ => identifier,
+            _ => (TokenKind::Ident, identifier),
           }
         }
+        _ => (TokenKind::Error, self.read_delimiter()),
       }
     } else {
-      "".to_string()
+      (TokenKind::Eof, "".to_string())
+    };
+    Token {
+      kind,
+      value,
+      span: Span::new(start, self.pos()),
+      trivia,
     }
   }
 }
@@ -265,4 +369,38 @@ mod tests {
     assert_eq!(lexer.next_token(), ",");
     assert_eq!(lexer.next_token(), ":");
   }
+
+  #[test]
+  fn test_read_token_span_and_trivia() {
+    let source = "int main()\n  # comment\n  return 0";
+    let mut lexer = Lexer::new(source);
+    let mut tokens = vec![];
+    loop {
+      let token = lexer.read_token();
+      if token.kind == TokenKind::Eof {
+        break;
+      }
+      tokens.push(token);
+    }
+    let values: Vec<&str> = tokens.iter().map(|t| t.value.as_str()).collect();
+    assert_eq!(
+      values,
+      vec!["int", "main", "(", ")", "{", "return", "0", "}"]
+    );
+    assert_eq!(tokens[1].span, Span::new(4, 8));
+    assert_eq!(tokens[4].kind, TokenKind::Indent);
+    assert!(tokens[4].span.is_empty());
+    assert_eq!(
+      &source[tokens[4].trivia.start..tokens[4].trivia.end],
+      "\n  # comment\n  "
+    );
+    assert_eq!(tokens[7].kind, TokenKind::Dedent);
+  }
+
+  #[test]
+  fn test_operator_stops_at_delimiter() {
+    let mut lexer = Lexer::new("a*(b) int... xs");
+    let tokens: Vec<String> = (0..8).map(|_| lexer.next_token()).collect();
+    assert_eq!(tokens, vec!["a", "*", "(", "b", ")", "int", "...", "xs"]);
+  }
 }
@@ -0,0 +1,28 @@
+pub mod ast;
+pub mod aux;
+pub mod cfg;
+pub mod consteval;
+pub mod constprop;
+pub mod cst;
+pub mod dce;
+pub mod diagnostic;
+pub mod flow;
+pub mod gvn;
+pub mod inline;
+pub mod interpreter;
+pub mod ir;
+pub mod lexer;
+pub mod lint;
+pub mod loops;
+pub mod lower;
+pub mod main_run;
+pub mod parser;
+pub mod pass;
+pub mod peephole;
+pub mod regalloc;
+pub mod resolve;
+pub mod signature;
+pub mod ssa;
+pub mod tailcall;
+pub mod typeck;
+pub mod types;
@@ -0,0 +1,636 @@
+//! 可配置的 lint
+//!
+//! lint 在名字解析和类型检查之后运行，报告合法但很可能写错了的代码。每个 lint 有一个名字和
+//! 默认级别（allow / warn / deny）。级别可以被覆盖，优先级从低到高：
+//! 1. 默认级别
+//! 2. 命令行 `--allow NAME` / `--warn NAME` / `--deny NAME`
+//! 3. 函数上的属性 `@allow(NAME)` / `@warn(NAME)` / `@deny(NAME)`，只作用于该函数
+//!
+//! `--deny warnings` 把所有警告（包括其它检查产生的警告）都当作错误，用于 CI。
+
+use crate::ast::AST;
+use crate::aux::suggest_name;
+use crate::diagnostic::{Diagnostic, Level};
+use crate::flow::collect_uses;
+use crate::lexer::Span;
+use crate::resolve::source_name;
+use crate::signature::param_nodes;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LintLevel {
+  Allow,
+  Warn,
+  Deny,
+}
+
+impl LintLevel {
+  pub fn from_name(name: &str) -> Option<LintLevel> {
+    match name {
+      "allow" => Some(LintLevel::Allow),
+      "warn" => Some(LintLevel::Warn),
+      "deny" => Some(LintLevel::Deny),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for LintLevel {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      LintLevel::Allow => write!(f, "allow"),
+      LintLevel::Warn => write!(f, "warn"),
+      LintLevel::Deny => write!(f, "deny"),
+    }
+  }
+}
+
+pub struct Lint {
+  pub name: &'static str,
+  pub default: LintLevel,
+  pub description: &'static str,
+}
+
+pub const LINTS: [Lint; 7] = [
+  Lint {
+    name: "unused_variables",
+    default: LintLevel::Warn,
+    description: "局部变量声明后从未被读取",
+  },
+  Lint {
+    name: "unused_parameters",
+    default: LintLevel::Warn,
+    description: "函数参数从未被读取",
+  },
+  Lint {
+    name: "unused_functions",
+    default: LintLevel::Warn,
+    description: "函数不会从 main 被调用到",
+  },
+  Lint {
+    name: "shadowed_names",
+    default: LintLevel::Allow,
+    description: "内层块中的声明遮蔽了外层的同名变量",
+  },
+  Lint {
+    name: "constant_condition",
+    default: LintLevel::Warn,
+    description: "if / while 的条件不依赖任何变量（`while true` 除外）",
+  },
+  Lint {
+    name: "self_assignment",
+    default: LintLevel::Warn,
+    description: "把变量赋值给它自己",
+  },
+  Lint {
+    name: "empty_branch",
+    default: LintLevel::Warn,
+    description: "if 分支中只有 `pass`",
+  },
+];
+
+/// 函数上可以使用的属性，`const int f()` 等价于 `@const int f()`
+pub const ATTRIBUTES: [&str; 7] = [
+  "allow", "warn", "deny", "const", "inline", "noinline", "tailcall",
+];
+
+pub fn find_lint(name: &str) -> Option<&'static Lint> {
+  LINTS.iter().find(|lint| lint.name == name)
+}
+
+fn unknown_lint(name: &str) -> Diagnostic {
+  let diagnostic = Diagnostic::warning(format!("unknown lint `{}`", name), Span::default());
+  match suggest_name(name, LINTS.iter().map(|lint| lint.name)) {
+    Some(suggestion) => diagnostic.with_note(format!("did you mean `{}`?", suggestion)),
+    None => diagnostic,
+  }
+}
+
+/// 命令行指定的 lint 级别
+#[derive(Debug, Default, Clone)]
+pub struct LintConfig {
+  pub levels: HashMap<String, LintLevel>,
+  /// `--deny warnings`
+  pub deny_warnings: bool,
+}
+
+impl LintConfig {
+  /// 处理 `--allow NAME` 等选项，NAME 未知时返回错误信息
+  pub fn set(&mut self, level: LintLevel, name: &str) -> Result<(), String> {
+    if name == "warnings" && level == LintLevel::Deny {
+      self.deny_warnings = true;
+      return Ok(());
+    }
+    if find_lint(name).is_none() {
+      let diagnostic = unknown_lint(name);
+      let mut message = diagnostic.message;
+      for note in diagnostic.notes {
+        message.push_str(&format!(", {}", note));
+      }
+      return Err(message);
+    }
+    self.levels.insert(name.to_string(), level);
+    Ok(())
+  }
+}
+
+/// `--deny warnings`：把警告变成错误
+pub fn deny_warnings(diagnostics: Vec<Diagnostic>) -> Vec<Diagnostic> {
+  diagnostics
+    .into_iter()
+    .map(|diagnostic| {
+      if diagnostic.level != Level::Warning {
+        return diagnostic;
+      }
+      let mut diagnostic =
+        diagnostic.with_note("`--deny warnings` turns this warning into an error".to_string());
+      diagnostic.level = Level::Error;
+      diagnostic
+    })
+    .collect()
+}
+
+/// 对名字解析之后的程序运行所有 lint
+pub fn lint_program(ast: &AST, config: &LintConfig) -> Vec<Diagnostic> {
+  let mut fns = vec![];
+  collect_fns(ast, &mut fns);
+  let mut linter = Linter {
+    config,
+    attrs: &[],
+    diagnostics: vec![],
+  };
+  for f in &fns {
+    linter.check_attrs(f.fn_attrs());
+  }
+  // 常量和 static_assert 中调用的函数也算被使用
+  let mut roots = vec!["main"];
+  collect_item_callees(ast, &mut roots);
+  let reachable = reachable_fns(&fns, roots);
+  for f in &fns {
+    linter.attrs = f.fn_attrs();
+    let name = &f.children[1];
+    if !reachable.is_empty() && !reachable.contains(name.value.as_str()) {
+      linter.emit(
+        "unused_functions",
+        format!("function `{}` is never used", name.value),
+        name.span,
+        None,
+      );
+    }
+    linter.lint_fn(f);
+  }
+  let mut diagnostics = linter.diagnostics;
+  diagnostics.sort_by_key(|d| d.span.start);
+  diagnostics
+}
+
+fn collect_fns<'a>(ast: &'a AST, fns: &mut Vec<&'a AST>) {
+  match ast.value.as_str() {
+    "Fn" => fns.push(ast),
+    "Pg" | "FnList" => {
+      for child in &ast.children {
+        collect_fns(child, fns);
+      }
+    }
+    _ => {}
+  }
+}
+
+fn collect_item_callees<'a>(ast: &'a AST, callees: &mut Vec<&'a str>) {
+  match ast.value.as_str() {
+    "Const" | "StaticAssert" => collect_callees(ast, callees),
+    "Pg" | "FnList" => {
+      for child in &ast.children {
+        collect_item_callees(child, callees);
+      }
+    }
+    _ => {}
+  }
+}
+
+/// 从 main 等根出发能调用到的函数；没有 main 时为空
+fn reachable_fns<'a>(fns: &[&'a AST], roots: Vec<&'a str>) -> HashSet<&'a str> {
+  let calls: HashMap<&str, Vec<&str>> = fns
+    .iter()
+    .map(|f| {
+      let mut callees = vec![];
+      collect_callees(f, &mut callees);
+      (f.children[1].value.as_str(), callees)
+    })
+    .collect();
+  let mut reachable = HashSet::new();
+  if !calls.contains_key("main") {
+    return reachable;
+  }
+  let mut work = roots;
+  while let Some(name) = work.pop() {
+    if reachable.insert(name) {
+      work.extend(calls.get(name).into_iter().flatten());
+    }
+  }
+  reachable
+}
+
+fn collect_callees<'a>(ast: &'a AST, callees: &mut Vec<&'a str>) {
+  if ast.value == "FnCall" {
+    callees.push(&ast.children[0].value);
+  }
+  for child in &ast.children {
+    collect_callees(child, callees);
+  }
+}
+
+struct Linter<'a> {
+  config: &'a LintConfig,
+  /// 当前函数上的属性
+  attrs: &'a [AST],
+  diagnostics: Vec<Diagnostic>,
+}
+
+/// 一个函数内的声明及其使用情况
+#[derive(Default)]
+struct FnState {
+  /// 按源码中的名字记录每层作用域里的声明
+  scopes: Vec<HashSet<String>>,
+  /// (改名后的名字, 位置, 是否为参数)
+  decls: Vec<(String, Span, bool)>,
+  uses: HashSet<String>,
+}
+
+impl<'a> Linter<'a> {
+  /// 当前函数中 lint 的级别，以及说明级别来源的提示
+  fn level(&self, name: &str) -> (LintLevel, String) {
+    let lint = find_lint(name).unwrap();
+    let from_attr = self.attrs.iter().rev().find_map(|attr| {
+      let level = LintLevel::from_name(&attr.children[0].value)?;
+      attr.children[1..]
+        .iter()
+        .any(|arg| arg.value == name)
+        .then_some(level)
+    });
+    if let Some(level) = from_attr {
+      (
+        level,
+        format!("`{}` is set to `{}` by `@{}({})`", name, level, level, name),
+      )
+    } else if let Some(&level) = self.config.levels.get(name) {
+      (
+        level,
+        format!("`{}` is set to `{}` by `--{} {}`", name, level, level, name),
+      )
+    } else {
+      (
+        lint.default,
+        format!("`{}` is set to `{}` by default", name, lint.default),
+      )
+    }
+  }
+
+  fn emit(&mut self, lint: &str, message: String, span: Span, help: Option<String>) {
+    let (level, origin) = self.level(lint);
+    let diagnostic = match level {
+      LintLevel::Allow => return,
+      LintLevel::Warn => Diagnostic::warning(message, span),
+      LintLevel::Deny => Diagnostic::error(message, span),
+    };
+    let diagnostic = diagnostic.with_note(origin);
+    self.diagnostics.push(match help {
+      Some(help) => diagnostic.with_note(help),
+      None => diagnostic,
+    });
+  }
+
+  /// 检查属性名和其中的 lint 名
+  fn check_attrs(&mut self, attrs: &[AST]) {
+    for attr in attrs {
+      let name = &attr.children[0];
+      if !ATTRIBUTES.contains(&name.value.as_str()) {
+        let diagnostic =
+          Diagnostic::error(format!("unknown attribute `{}`", name.value), name.span);
+        self
+          .diagnostics
+          .push(match suggest_name(&name.value, ATTRIBUTES) {
+            Some(suggestion) => diagnostic.with_note(format!("did you mean `@{}`?", suggestion)),
+            None => diagnostic,
+          });
+        continue;
+      }
+      if LintLevel::from_name(&name.value).is_none() {
+        if attr.children.len() > 1 {
+          self.diagnostics.push(Diagnostic::error(
+            format!("`@{}` takes no arguments", name.value),
+            attr.span,
+          ));
+        }
+        continue;
+      }
+      if attr.children.len() == 1 {
+        self.diagnostics.push(Diagnostic::error(
+          format!(
+            "`@{}` expects lint names, e.g. `@{}(unused_variables)`",
+            name.value, name.value
+          ),
+          attr.span,
+        ));
+      }
+      for arg in &attr.children[1..] {
+        if find_lint(&arg.value).is_none() {
+          let mut diagnostic = unknown_lint(&arg.value);
+          diagnostic.span = arg.span;
+          self.diagnostics.push(diagnostic);
+        }
+      }
+    }
+    let has = |name: &str| attrs.iter().find(|attr| attr.children[0].value == name);
+    if let (Some(_), Some(noinline)) = (has("inline"), has("noinline")) {
+      self.diagnostics.push(Diagnostic::error(
+        "`@inline` and `@noinline` cannot be used together".to_string(),
+        noinline.span,
+      ));
+    }
+  }
+
+  fn lint_fn(&mut self, ast: &AST) {
+    let mut state = FnState {
+      scopes: vec![HashSet::new()],
+      ..Default::default()
+    };
+    for node in param_nodes(&ast.children[2]) {
+      let id = &node.children[1];
+      state.scopes[0].insert(id.value.clone());
+      state.decls.push((id.value.clone(), id.span, true));
+      let mut uses = vec![];
+      collect_uses(&node.children[2], &mut uses);
+      state.uses.extend(uses.into_iter().map(|(name, _)| name));
+    }
+    self.lint_stmt_list(&ast.children[3].children[0], &mut state);
+
+    for (unique, span, is_param) in &state.decls {
+      let name = source_name(unique);
+      if state.uses.contains(unique) || name.starts_with('_') {
+        continue;
+      }
+      let (lint, kind) = if *is_param {
+        ("unused_parameters", "parameter")
+      } else {
+        ("unused_variables", "variable")
+      };
+      self.emit(
+        lint,
+        format!("unused {} `{}`", kind, name),
+        *span,
+        Some(format!(
+          "if this is intentional, prefix it with an underscore: `_{}`",
+          name
+        )),
+      );
+    }
+  }
+
+  /// `if` / `while` 的语句块，其中的声明在块结束后不再可见
+  fn lint_block(&mut self, ast: &AST, state: &mut FnState) {
+    state.scopes.push(HashSet::new());
+    self.lint_stmt_list(ast, state);
+    state.scopes.pop();
+  }
+
+  fn lint_stmt_list(&mut self, ast: &AST, state: &mut FnState) {
+    let mut stmt_list = ast;
+    while stmt_list.children.len() == 2 {
+      self.lint_stmt(&stmt_list.children[0].children[0], state);
+      stmt_list = &stmt_list.children[1];
+    }
+  }
+
+  fn lint_stmt(&mut self, ast: &AST, state: &mut FnState) {
+    match ast.value.as_str() {
+      "VarDecl" => self.declare(&ast.children[1], state),
+      "VarDef" => {
+        self.read(&ast.children[2], state);
+        self.declare(&ast.children[1], state);
+      }
+      "Assign" => {
+        let target = &ast.children[0];
+        if ast.children[1].get_expression() == [target.value.as_str()] {
+          self.emit(
+            "self_assignment",
+            format!("`{}` is assigned to itself", source_name(&target.value)),
+            ast.span,
+            Some("this assignment has no effect".to_string()),
+          );
+        } else {
+          self.read(&ast.children[1], state);
+        }
+      }
+      "Return" | "Expr" => self.read(ast, state),
+      "BranchStmt" => {
+        self.check_condition(&ast.children[0], "if");
+        self.read(&ast.children[0], state);
+        let then = &ast.children[1];
+        if then.children.len() == 2
+          && then.children[0].children[0].value == "pass"
+          && then.children[1].children.len() != 2
+        {
+          self.emit(
+            "empty_branch",
+            "`if` branch only contains `pass`".to_string(),
+            then.children[0].span,
+            Some("invert the condition and move the `else` branch here".to_string()),
+          );
+        }
+        self.lint_block(then, state);
+        self.lint_block(&ast.children[2], state);
+      }
+      "LoopStmt" => {
+        if ast.children[0].get_expression() != ["true"] {
+          self.check_condition(&ast.children[0], "while");
+        }
+        self.read(&ast.children[0], state);
+        self.lint_block(&ast.children[1], state);
+      }
+      _ => {}
+    }
+  }
+
+  /// 不含变量和函数调用的条件每次求值的结果都相同
+  fn check_condition(&mut self, cond: &AST, keyword: &str) {
+    let mut uses = vec![];
+    collect_uses(cond, &mut uses);
+    let mut callees = vec![];
+    collect_callees(cond, &mut callees);
+    if uses.is_empty() && callees.is_empty() {
+      self.emit(
+        "constant_condition",
+        format!("the condition of this `{}` is constant", keyword),
+        cond.span,
+        Some("it does not depend on any variable, so it has the same value every time".to_string()),
+      );
+    }
+  }
+
+  fn read(&mut self, expr: &AST, state: &mut FnState) {
+    let mut uses = vec![];
+    collect_uses(expr, &mut uses);
+    state.uses.extend(uses.into_iter().map(|(name, _)| name));
+  }
+
+  fn declare(&mut self, id: &AST, state: &mut FnState) {
+    let name = source_name(&id.value);
+    let (current, outer) = state.scopes.split_last_mut().unwrap();
+    if outer.iter().any(|scope| scope.contains(name)) {
+      self.emit(
+        "shadowed_names",
+        format!("`{}` shadows a variable from an outer scope", name),
+        id.span,
+        None,
+      );
+    }
+    current.insert(name.to_string());
+    state.decls.push((id.value.clone(), id.span, false));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::parser::Parser;
+  use crate::resolve::resolve;
+
+  fn lint(source: &str, config: &LintConfig) -> Vec<Diagnostic> {
+    let mut ast = Parser::new(Lexer::new(source)).parse();
+    assert!(resolve(&mut ast).is_empty());
+    lint_program(&ast, config)
+  }
+
+  fn messages(source: &str) -> Vec<String> {
+    lint(source, &LintConfig::default())
+      .into_iter()
+      .map(|d| d.message)
+      .collect()
+  }
+
+  #[test]
+  fn test_unused_names() {
+    let source = "int main()\n  int a = 1\n  int _b = 2\n  return f(3)\n\nint f(int x, int y)\n  return x\n\nint g()\n  return 0\n";
+    let diagnostics = lint(source, &LintConfig::default());
+    let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+    assert_eq!(
+      messages,
+      vec![
+        "unused variable `a`",
+        "unused parameter `y`",
+        "function `g` is never used"
+      ]
+    );
+    assert_eq!(
+      diagnostics[0].notes,
+      vec![
+        "`unused_variables` is set to `warn` by default",
+        "if this is intentional, prefix it with an underscore: `_a`"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_recursion_does_not_count_as_use() {
+    assert_eq!(
+      messages("int main()\n  return 0\n\nint f(int n)\n  return f(n)\n"),
+      vec!["function `f` is never used"]
+    );
+  }
+
+  #[test]
+  fn test_statement_lints() {
+    let source = "int main()\n  int x = scan()\n  x = x\n  while 1 > 2\n    x = x + 1\n  while true\n    break\n  if x > 0\n    pass\n  else\n    x = 1\n  return x\n";
+    assert_eq!(
+      messages(source),
+      vec![
+        "`x` is assigned to itself",
+        "the condition of this `while` is constant",
+        "`if` branch only contains `pass`"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_names_with_digits() {
+    let source = "int main()\n  int i1 = 0\n  while i1 < 3\n    i1 = work(i1)\n  return 0\n\nint work(int p2)\n  return (p2 + 1)\n";
+    assert!(messages(source).is_empty());
+    assert_eq!(
+      messages("int main()\n  int k9 = 1\n  while 1 < 2\n    break\n  return 0\n"),
+      vec![
+        "unused variable `k9`",
+        "the condition of this `while` is constant"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_shadowing_is_allowed_by_default() {
+    let source =
+      "int main()\n  int x = 1\n  while x < 3\n    int x = 2\n    print(x)\n  return x\n";
+    assert!(messages(source).is_empty());
+    let mut config = LintConfig::default();
+    config.set(LintLevel::Deny, "shadowed_names").unwrap();
+    let diagnostics = lint(source, &config);
+    assert_eq!(diagnostics.len(), 1);
+    assert!(diagnostics[0].is_error());
+    assert_eq!(
+      diagnostics[0].notes,
+      vec!["`shadowed_names` is set to `deny` by `--deny shadowed_names`"]
+    );
+  }
+
+  #[test]
+  fn test_attributes_override_command_line() {
+    let source = "@allow(unused_variables)\n@deny(self_assignment)\nint main()\n  int a = 1\n  int b = 2\n  b = b\n  return b\n";
+    let mut config = LintConfig::default();
+    config.set(LintLevel::Deny, "unused_variables").unwrap();
+    let diagnostics = lint(source, &config);
+    assert_eq!(diagnostics.len(), 1);
+    assert!(diagnostics[0].is_error());
+    assert_eq!(
+      diagnostics[0].notes[0],
+      "`self_assignment` is set to `deny` by `@deny(self_assignment)`"
+    );
+  }
+
+  #[test]
+  fn test_unknown_lints_and_attributes() {
+    assert_eq!(
+      LintConfig::default().set(LintLevel::Warn, "unused_variable"),
+      Err("unknown lint `unused_variable`, did you mean `unused_variables`?".to_string())
+    );
+    let diagnostics = lint(
+      "@alow(unused_variables)\n@deny(self_asignment)\nint main()\n  return 0\n",
+      &LintConfig::default(),
+    );
+    assert_eq!(diagnostics[0].message, "unknown attribute `alow`");
+    assert_eq!(diagnostics[0].notes, vec!["did you mean `@allow`?"]);
+    assert_eq!(diagnostics[1].message, "unknown lint `self_asignment`");
+    let diagnostics = lint(
+      "@inline(always)\nint f()\n  return 0\n\n@inline\n@noinline\nint main()\n  return f()\n",
+      &LintConfig::default(),
+    );
+    assert_eq!(diagnostics[0].message, "`@inline` takes no arguments");
+    assert_eq!(
+      diagnostics[1].message,
+      "`@inline` and `@noinline` cannot be used together"
+    );
+  }
+
+  #[test]
+  fn test_deny_warnings() {
+    let mut config = LintConfig::default();
+    config.set(LintLevel::Deny, "warnings").unwrap();
+    assert!(config.deny_warnings);
+    let diagnostics = deny_warnings(lint("int main()\n  int a = 1\n  return 0\n", &config));
+    assert!(diagnostics[0].is_error());
+    assert_eq!(
+      diagnostics[0].notes.last().unwrap(),
+      "`--deny warnings` turns this warning into an error"
+    );
+  }
+}
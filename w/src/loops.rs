@@ -0,0 +1,1308 @@
+//! 循环优化，在 SSA 形式上进行
+//!
+//! 自然循环由回边（跳到支配自己的块的边）确定：回边的目标是循环头，循环体是循环头加上
+//! 不经过循环头就能到达回边起点的块。同一个循环头的多条回边合并为一个循环。
+//! 每个循环先补上前置块（preheader）：循环外进入循环头的唯一前驱，并且只跳到循环头。
+//!
+//! - `hoist_loop_invariants`：操作数都在循环外定义的纯运算移到前置块。可能在运行时报错的运算
+//!   （溢出检查、可能除以零）要保证移动后报错的时机不变：位于循环头、之前没有其他可能报错或有
+//!   副作用的指令时直接移动；位于循环体入口时，先在前置块中按初值判断一次循环头的条件，
+//!   循环至少执行一次才计算它们（`guard_loop`）
+//! - `reduce_strength`：归纳变量 `i`（每次迭代加减同一个循环不变量）乘以循环不变量 `k` 的乘法
+//!   改为一个新的归纳变量，每次迭代加上 `步长 * k`。带溢出检查的乘法只在由循环条件得出的
+//!   `i` 的范围证明它不会溢出时处理
+//! - `unroll_loops`：迭代次数是不超过 `MAX_TRIP_COUNT` 的常数的循环完全展开
+
+use crate::cfg::{Cfg, Dominators};
+use crate::constprop::fold_binary;
+use crate::dce::is_pure;
+use crate::ir::{BinOp, Block, BlockId, Function, Imm, Inst, IrType, Operand, Term, VReg};
+use std::collections::{HashMap, HashSet};
+
+/// 完全展开的循环最多迭代的次数
+const MAX_TRIP_COUNT: usize = 8;
+/// 展开后循环中指令总数的上限
+const MAX_UNROLLED_SIZE: usize = 64;
+
+pub struct Loop {
+  pub header: BlockId,
+  /// 回边的起点，按编号排序
+  pub latches: Vec<BlockId>,
+  /// 循环中的块（包括循环头），按编号排序
+  pub blocks: Vec<BlockId>,
+  pub preheader: Option<BlockId>,
+}
+
+impl Loop {
+  pub fn contains(&self, block: BlockId) -> bool {
+    self.blocks.binary_search(&block).is_ok()
+  }
+}
+
+/// 函数中的自然循环，内层循环在前
+pub fn find_loops(function: &Function) -> Vec<Loop> {
+  let cfg = Cfg::new(function);
+  let dom = Dominators::new(&cfg);
+  let mut loops: Vec<Loop> = vec![];
+  for &block in &cfg.rpo {
+    for &succ in cfg.succs(block) {
+      if !dom.dominates(succ, block) {
+        continue;
+      }
+      match loops.iter_mut().find(|l| l.header == succ) {
+        Some(l) => l.latches.push(block),
+        None => loops.push(Loop {
+          header: succ,
+          latches: vec![block],
+          blocks: vec![],
+          preheader: None,
+        }),
+      }
+    }
+  }
+  for l in &mut loops {
+    l.latches.sort();
+    let mut body = HashSet::from([l.header]);
+    let mut work = l.latches.clone();
+    while let Some(block) = work.pop() {
+      if body.insert(block) {
+        work.extend(
+          cfg
+            .preds(block)
+            .iter()
+            .filter(|b| dom.is_reachable(**b))
+            .copied(),
+        );
+      }
+    }
+    l.blocks = body.into_iter().collect();
+    l.blocks.sort();
+    let outside: Vec<BlockId> = cfg
+      .preds(l.header)
+      .iter()
+      .copied()
+      .filter(|b| !l.contains(*b))
+      .collect();
+    if let [pred] = outside[..] {
+      if cfg.succs(pred).len() == 1 {
+        l.preheader = Some(pred);
+      }
+    }
+  }
+  loops.sort_by_key(|l| l.blocks.len());
+  loops
+}
+
+/// 为循环新建前置块：循环外的前驱改为跳到前置块，循环头的 phi 中来自循环外的值合并到前置块
+fn insert_preheader(function: &mut Function, l: &Loop) {
+  let cfg = Cfg::new(function);
+  let outside: Vec<BlockId> = cfg
+    .preds(l.header)
+    .iter()
+    .copied()
+    .filter(|b| !l.contains(*b))
+    .collect();
+  let preheader = BlockId(function.blocks.len() as u32);
+  for &pred in &outside {
+    for target in function.blocks[pred.0 as usize].term.successors_mut() {
+      if *target == l.header {
+        *target = preheader;
+      }
+    }
+  }
+  let mut next = function.new_vreg().0;
+  let mut insts = vec![];
+  for inst in &mut function.blocks[l.header.0 as usize].insts {
+    let Inst::Phi { ty, incoming, .. } = inst else {
+      continue;
+    };
+    let (from_outside, mut from_loop): (Vec<_>, Vec<_>) = incoming
+      .drain(..)
+      .partition(|(from, _)| outside.contains(from));
+    let value = match &from_outside[..] {
+      [(_, value)] => value.clone(),
+      _ => {
+        insts.push(Inst::Phi {
+          dst: VReg(next),
+          ty: *ty,
+          incoming: from_outside,
+        });
+        next += 1;
+        Operand::Reg(VReg(next - 1))
+      }
+    };
+    from_loop.push((preheader, value));
+    from_loop.sort_by_key(|(from, _)| *from);
+    *incoming = from_loop;
+  }
+  function.blocks.push(Block {
+    id: preheader,
+    insts,
+    term: Term::Jump(l.header),
+  });
+}
+
+/// 保证每个循环都有前置块，返回函数中的循环
+pub fn ensure_preheaders(function: &mut Function) -> Vec<Loop> {
+  loop {
+    let loops = find_loops(function);
+    match loops.iter().find(|l| l.preheader.is_none()) {
+      Some(l) => insert_preheader(function, l),
+      None => return loops,
+    }
+  }
+}
+
+/// 在循环中定义的虚拟寄存器
+fn loop_defs(function: &Function, l: &Loop) -> HashSet<VReg> {
+  l.blocks
+    .iter()
+    .flat_map(|b| &function.blocks[b.0 as usize].insts)
+    .filter_map(Inst::dst)
+    .collect()
+}
+
+fn is_invariant(operand: &Operand, defs: &HashSet<VReg>) -> bool {
+  !matches!(operand, Operand::Reg(reg) if defs.contains(reg))
+}
+
+pub fn hoist_loop_invariants(function: &mut Function) {
+  // 给循环加上保护会新增块，之后重新查找循环；循环头的编号不变
+  let mut done = HashSet::new();
+  loop {
+    let loops = ensure_preheaders(function);
+    let Some(l) = loops.into_iter().find(|l| done.insert(l.header)) else {
+      return;
+    };
+    let mut defs = loop_defs(function, &l);
+    let mut hoisted = vec![];
+    let mut changed = true;
+    while changed {
+      changed = false;
+      for &id in &l.blocks {
+        // 循环头中到目前为止的指令都没有副作用、不会报错
+        let mut quiet = id == l.header;
+        let mut kept = vec![];
+        for inst in std::mem::take(&mut function.blocks[id.0 as usize].insts) {
+          let movable = match &inst {
+            Inst::Bin { .. } => is_pure(&inst) || quiet,
+            Inst::Copy { .. } | Inst::Addr { .. } | Inst::Str { .. } => true,
+            _ => false,
+          };
+          if movable && inst.operands().iter().all(|op| is_invariant(op, &defs)) {
+            defs.remove(&inst.dst().unwrap());
+            hoisted.push(inst);
+            changed = true;
+          } else {
+            quiet &= is_pure(&inst);
+            kept.push(inst);
+          }
+        }
+        function.blocks[id.0 as usize].insts = kept;
+      }
+    }
+    let preheader = l.preheader.unwrap();
+    function.blocks[preheader.0 as usize]
+      .insts
+      .append(&mut hoisted);
+    guard_loop(function, &l, &mut defs);
+  }
+}
+
+/// 循环体入口中可能报错的不变运算：循环头不报错、只从循环头离开循环，循环体的入口块只从循环头进入，
+/// 运算之前的指令都不报错、没有副作用。这样的运算在每次进入循环体时都会执行
+fn guarded_invariants(function: &Function, l: &Loop, defs: &mut HashSet<VReg>) -> Vec<usize> {
+  let cfg = Cfg::new(function);
+  let header = &function.blocks[l.header.0 as usize];
+  let Term::Branch { then, els, .. } = header.term else {
+    return vec![];
+  };
+  let (body, exit) = match (l.contains(then), l.contains(els)) {
+    (true, false) => (then, els),
+    (false, true) => (els, then),
+    _ => return vec![],
+  };
+  let exits = l
+    .blocks
+    .iter()
+    .any(|b| *b != l.header && cfg.succs(*b).iter().any(|s| !l.contains(*s)));
+  if exits
+    || body == l.header
+    || cfg.preds(body) != [l.header]
+    || cfg.preds(exit) != [l.header]
+    || !header.insts.iter().all(is_pure)
+  {
+    return vec![];
+  }
+  // 循环头中除 phi 之外的值在循环之后使用时，保护条件不成立的一侧无法给出它们的值
+  let header_values: HashSet<VReg> = header
+    .insts
+    .iter()
+    .filter(|inst| !matches!(inst, Inst::Phi { .. }))
+    .filter_map(Inst::dst)
+    .collect();
+  let used_after = function
+    .blocks
+    .iter()
+    .filter(|block| !l.contains(block.id))
+    .flat_map(|block| {
+      block
+        .insts
+        .iter()
+        .flat_map(Inst::operands)
+        .chain(block.term.operands())
+    })
+    .any(|op| matches!(op, Operand::Reg(reg) if header_values.contains(reg)));
+  if used_after {
+    return vec![];
+  }
+  let mut found = vec![];
+  for (i, inst) in function.blocks[body.0 as usize].insts.iter().enumerate() {
+    if matches!(inst, Inst::Bin { .. }) && inst.operands().iter().all(|op| is_invariant(op, defs)) {
+      defs.remove(&inst.dst().unwrap());
+      found.push(i);
+    } else if !is_pure(inst) {
+      break;
+    }
+  }
+  found
+}
+
+/// 把循环体入口中可能报错的不变运算移出循环。循环可能一次也不执行，所以在前置块中先按初值
+/// 计算一遍循环头的条件作为保护：条件成立时经过新的前置块（移出的运算放在这里）进入循环，
+/// 否则直接跳到出口。出口处用 phi 合并两条路径上循环头 phi 的值
+fn guard_loop(function: &mut Function, l: &Loop, defs: &mut HashSet<VReg>) {
+  let found = guarded_invariants(function, l, defs);
+  if found.is_empty() {
+    return;
+  }
+  let preheader = l.preheader.unwrap();
+  let header = function.blocks[l.header.0 as usize].clone();
+  let Term::Branch { cond, then, els } = header.term else {
+    unreachable!()
+  };
+  let body = if l.contains(then) { then } else { els };
+  let exit = if l.contains(then) { els } else { then };
+  let mut insts = function.blocks[body.0 as usize].insts.clone();
+  let mut hoisted = vec![];
+  for i in found.into_iter().rev() {
+    hoisted.push(insts.remove(i));
+  }
+  hoisted.reverse();
+  function.blocks[body.0 as usize].insts = insts;
+
+  // 循环头的指令在前置块中按初值复制一份
+  let mut next = function.new_vreg().0;
+  let mut values: HashMap<VReg, Operand> = HashMap::new();
+  let mut phis = vec![];
+  let mut guard = vec![];
+  for inst in &header.insts {
+    if let Inst::Phi { dst, ty, incoming } = inst {
+      let init = incoming
+        .iter()
+        .find(|(from, _)| *from == preheader)
+        .unwrap();
+      values.insert(*dst, init.1.clone());
+      phis.push((*dst, *ty));
+      continue;
+    }
+    let mut inst = inst.clone();
+    for operand in inst.operands_mut() {
+      if let Operand::Reg(reg) = operand {
+        if let Some(value) = values.get(reg) {
+          *operand = value.clone();
+        }
+      }
+    }
+    let dst = inst.dst().unwrap();
+    rename_dst(&mut inst, &HashMap::from([(dst, VReg(next))]));
+    values.insert(dst, Operand::Reg(VReg(next)));
+    next += 1;
+    guard.push(inst);
+  }
+  let cond = match cond {
+    Operand::Reg(reg) => values.get(&reg).cloned().unwrap_or(Operand::Reg(reg)),
+    imm => imm,
+  };
+
+  let entry = BlockId(function.blocks.len() as u32);
+  let block = &mut function.blocks[preheader.0 as usize];
+  block.insts.append(&mut guard);
+  block.term = Term::Branch {
+    cond,
+    then: if then == body { entry } else { exit },
+    els: if els == body { entry } else { exit },
+  };
+  function.blocks.push(Block {
+    id: entry,
+    insts: hoisted,
+    term: Term::Jump(l.header),
+  });
+  for inst in &mut function.blocks[l.header.0 as usize].insts {
+    if let Inst::Phi { incoming, .. } = inst {
+      for (from, _) in incoming.iter_mut() {
+        if *from == preheader {
+          *from = entry;
+        }
+      }
+      incoming.sort_by_key(|(from, _)| *from);
+    }
+  }
+
+  // 循环之后对循环头 phi 的使用改为出口处合并后的值
+  let used: HashSet<VReg> = function
+    .blocks
+    .iter()
+    .filter(|block| !l.contains(block.id) && block.id != preheader)
+    .flat_map(|block| {
+      let phis = block
+        .insts
+        .iter()
+        .filter(move |inst| block.id != exit || !matches!(inst, Inst::Phi { .. }));
+      phis.flat_map(Inst::operands).chain(block.term.operands())
+    })
+    .filter_map(|op| match op {
+      Operand::Reg(reg) => Some(*reg),
+      _ => None,
+    })
+    .collect();
+  let mut merged: HashMap<VReg, Operand> = HashMap::new();
+  let mut exit_phis = vec![];
+  for (phi, ty) in phis.into_iter().filter(|(phi, _)| used.contains(phi)) {
+    let dst = VReg(next);
+    next += 1;
+    let mut incoming = vec![
+      (l.header, Operand::Reg(phi)),
+      (preheader, values[&phi].clone()),
+    ];
+    incoming.sort_by_key(|(from, _)| *from);
+    exit_phis.push(Inst::Phi { dst, ty, incoming });
+    merged.insert(phi, Operand::Reg(dst));
+  }
+  for block in &mut function.blocks {
+    if l.contains(block.id) || block.id == preheader || block.id == entry {
+      continue;
+    }
+    let is_exit = block.id == exit;
+    for inst in &mut block.insts {
+      if let (true, Inst::Phi { incoming, .. }) = (is_exit, &mut *inst) {
+        // 出口原有的 phi 只有来自循环头的值，从前置块来时取对应的初值
+        let from_header = incoming.iter().find(|(from, _)| *from == l.header).unwrap();
+        let value = match &from_header.1 {
+          Operand::Reg(reg) => values.get(reg).cloned().unwrap_or(Operand::Reg(*reg)),
+          imm => imm.clone(),
+        };
+        incoming.push((preheader, value));
+        incoming.sort_by_key(|(from, _)| *from);
+        continue;
+      }
+      for operand in inst.operands_mut() {
+        if let Operand::Reg(reg) = operand {
+          if let Some(value) = merged.get(reg) {
+            *operand = value.clone();
+          }
+        }
+      }
+    }
+    for operand in block.term.operands_mut() {
+      if let Operand::Reg(reg) = operand {
+        if let Some(value) = merged.get(reg) {
+          *operand = value.clone();
+        }
+      }
+    }
+  }
+  let insts = &mut function.blocks[exit.0 as usize].insts;
+  let at = insts
+    .iter()
+    .position(|inst| !matches!(inst, Inst::Phi { .. }))
+    .unwrap_or(insts.len());
+  insts.splice(at..at, exit_phis);
+}
+
+/// 前置块中的乘法 `lhs * rhs`，两个都是常量或其中一个是 0、1 时直接得出结果
+fn emit_mul(insts: &mut Vec<Inst>, next: &mut u32, lhs: Operand, rhs: Operand) -> Operand {
+  match (&lhs, &rhs) {
+    (Operand::Imm(a), Operand::Imm(b)) => {
+      return Operand::Imm(fold_binary(BinOp::Mul, *a, *b, false).unwrap());
+    }
+    (zero @ Operand::Imm(Imm::Int(0)), _) | (_, zero @ Operand::Imm(Imm::Int(0))) => {
+      return zero.clone();
+    }
+    (Operand::Imm(Imm::Int(1)), other) | (other, Operand::Imm(Imm::Int(1))) => {
+      return other.clone();
+    }
+    _ => {}
+  }
+  let dst = VReg(*next);
+  *next += 1;
+  insts.push(Inst::Bin {
+    dst,
+    op: BinOp::Mul,
+    ty: IrType::Int,
+    lhs,
+    rhs,
+    trap: None,
+  });
+  Operand::Reg(dst)
+}
+
+/// 基本归纳变量：循环头中的 phi `i = phi [前置块: 初值], [回边: i ± 步长]`，步长是循环不变量。
+/// 返回 (i, 初值, 加或减, 步长)
+fn induction_variables(function: &Function, l: &Loop) -> Vec<(VReg, Operand, BinOp, Operand)> {
+  let ([latch], Some(preheader)) = (&l.latches[..], l.preheader) else {
+    return vec![];
+  };
+  let defs = loop_defs(function, l);
+  let insts: HashMap<VReg, &Inst> = l
+    .blocks
+    .iter()
+    .flat_map(|b| &function.blocks[b.0 as usize].insts)
+    .filter_map(|inst| inst.dst().map(|dst| (dst, inst)))
+    .collect();
+  let mut ivs = vec![];
+  for inst in &function.blocks[l.header.0 as usize].insts {
+    let Inst::Phi {
+      dst,
+      ty: IrType::Int,
+      incoming,
+    } = inst
+    else {
+      continue;
+    };
+    let value = |block| {
+      incoming
+        .iter()
+        .find(|(from, _)| *from == block)
+        .map(|(_, v)| v)
+    };
+    let (Some(init), Some(Operand::Reg(next))) = (value(preheader), value(*latch)) else {
+      continue;
+    };
+    let Some(Inst::Bin { op, lhs, rhs, .. }) = insts.get(next) else {
+      continue;
+    };
+    let i = Operand::Reg(*dst);
+    let step = match op {
+      BinOp::Add if *lhs == i => rhs,
+      BinOp::Add if *rhs == i => lhs,
+      BinOp::Sub if *lhs == i => rhs,
+      _ => continue,
+    };
+    if is_invariant(step, &defs) {
+      ivs.push((*dst, init.clone(), *op, step.clone()));
+    }
+  }
+  ivs
+}
+
+/// 常量初值、常量步长、由循环头与常量的比较限定的归纳变量在循环中取到的范围，
+/// 包括最后一次更新得到的、使循环结束的值
+fn iv_range(
+  function: &Function,
+  l: &Loop,
+  iv: &(VReg, Operand, BinOp, Operand),
+) -> Option<(i64, i64)> {
+  let (i, Operand::Imm(Imm::Int(init)), op, Operand::Imm(Imm::Int(step))) = iv else {
+    return None;
+  };
+  let delta = match op {
+    BinOp::Add => *step,
+    _ => step.checked_neg()?,
+  };
+  let header = &function.blocks[l.header.0 as usize];
+  let Term::Branch {
+    cond: Operand::Reg(cond),
+    then,
+    ..
+  } = header.term
+  else {
+    return None;
+  };
+  let Some(Inst::Bin { op, lhs, rhs, .. }) = header.insts.iter().find(|x| x.dst() == Some(cond))
+  else {
+    return None;
+  };
+  // 统一成 `i op n`，条件成立时进入循环体
+  let i = Operand::Reg(*i);
+  let (op, n) = match (lhs, rhs) {
+    (lhs, Operand::Imm(Imm::Int(n))) if *lhs == i => (*op, *n),
+    (Operand::Imm(Imm::Int(n)), rhs) if *rhs == i => match op {
+      BinOp::Lt => (BinOp::Gt, *n),
+      BinOp::Le => (BinOp::Ge, *n),
+      BinOp::Gt => (BinOp::Lt, *n),
+      BinOp::Ge => (BinOp::Le, *n),
+      _ => return None,
+    },
+    _ => return None,
+  };
+  let op = match (op, l.contains(then)) {
+    (op, true) => op,
+    (BinOp::Lt, false) => BinOp::Ge,
+    (BinOp::Le, false) => BinOp::Gt,
+    (BinOp::Gt, false) => BinOp::Le,
+    (BinOp::Ge, false) => BinOp::Lt,
+    _ => return None,
+  };
+  match op {
+    BinOp::Lt | BinOp::Le if delta > 0 => {
+      let last = if op == BinOp::Lt {
+        n.checked_sub(1)?
+      } else {
+        n
+      };
+      Some((*init, (*init).max(last.checked_add(delta)?)))
+    }
+    BinOp::Gt | BinOp::Ge if delta < 0 => {
+      let last = if op == BinOp::Gt {
+        n.checked_add(1)?
+      } else {
+        n
+      };
+      Some(((*init).min(last.checked_add(delta)?), *init))
+    }
+    _ => None,
+  }
+}
+
+/// 带溢出检查的 `i * k` 在整个循环中都不会溢出：归纳变量的范围两端和 `步长 * k` 都能算出
+fn mul_cannot_overflow(
+  function: &Function,
+  l: &Loop,
+  iv: &(VReg, Operand, BinOp, Operand),
+  k: &Operand,
+) -> bool {
+  let (Some((lo, hi)), Operand::Imm(Imm::Int(k)), Operand::Imm(Imm::Int(step))) =
+    (iv_range(function, l, iv), k, &iv.3)
+  else {
+    return false;
+  };
+  lo.checked_mul(*k).is_some() && hi.checked_mul(*k).is_some() && step.checked_mul(*k).is_some()
+}
+
+pub fn reduce_strength(function: &mut Function) {
+  for l in ensure_preheaders(function) {
+    let ivs = induction_variables(function, &l);
+    if ivs.is_empty() {
+      continue;
+    }
+    let defs = loop_defs(function, &l);
+    let preheader = l.preheader.unwrap();
+    let latch = l.latches[0];
+    let mut next = function.new_vreg().0;
+    let mut replace: HashMap<VReg, Operand> = HashMap::new();
+    let mut pre_insts = vec![];
+    let mut phis = vec![];
+    let mut latch_insts = vec![];
+    for &id in &l.blocks {
+      for inst in &function.blocks[id.0 as usize].insts {
+        let Inst::Bin {
+          dst,
+          op: BinOp::Mul,
+          ty: IrType::Int,
+          lhs,
+          rhs,
+          trap,
+        } = inst
+        else {
+          continue;
+        };
+        let found = ivs.iter().find_map(|iv| {
+          let i = Operand::Reg(iv.0);
+          if *lhs == i && is_invariant(rhs, &defs) {
+            Some((iv, rhs))
+          } else if *rhs == i && is_invariant(lhs, &defs) {
+            Some((iv, lhs))
+          } else {
+            None
+          }
+        });
+        let Some((iv, k)) = found else {
+          continue;
+        };
+        if trap.is_some() && !mul_cannot_overflow(function, &l, iv, k) {
+          continue;
+        }
+        let (_, init, op, step) = iv;
+        // j = i * k 改为 j = phi [前置块: 初值 * k], [回边: j ± 步长 * k]
+        let start = emit_mul(&mut pre_insts, &mut next, init.clone(), k.clone());
+        let delta = emit_mul(&mut pre_insts, &mut next, step.clone(), k.clone());
+        let j = VReg(next);
+        let j_next = VReg(next + 1);
+        next += 2;
+        phis.push(Inst::Phi {
+          dst: j,
+          ty: IrType::Int,
+          incoming: {
+            let mut incoming = vec![(preheader, start), (latch, Operand::Reg(j_next))];
+            incoming.sort_by_key(|(from, _)| *from);
+            incoming
+          },
+        });
+        latch_insts.push(Inst::Bin {
+          dst: j_next,
+          op: *op,
+          ty: IrType::Int,
+          lhs: Operand::Reg(j),
+          rhs: delta,
+          trap: None,
+        });
+        replace.insert(*dst, Operand::Reg(j));
+      }
+    }
+    if replace.is_empty() {
+      continue;
+    }
+    for block in &mut function.blocks {
+      block
+        .insts
+        .retain(|inst| inst.dst().is_none_or(|dst| !replace.contains_key(&dst)));
+      let operands = block
+        .insts
+        .iter_mut()
+        .flat_map(Inst::operands_mut)
+        .chain(block.term.operands_mut());
+      for operand in operands {
+        if let Operand::Reg(reg) = operand {
+          if let Some(value) = replace.get(reg) {
+            *operand = value.clone();
+          }
+        }
+      }
+    }
+    function.blocks[preheader.0 as usize]
+      .insts
+      .append(&mut pre_insts);
+    let header = &mut function.blocks[l.header.0 as usize].insts;
+    header.splice(0..0, phis);
+    function.blocks[latch.0 as usize]
+      .insts
+      .append(&mut latch_insts);
+  }
+}
+
+/// 可以完全展开的循环：唯一的出口是循环头的条件跳转，条件是归纳变量与常量的比较，
+/// 归纳变量的初值和步长都是常量。返回 (循环体的入口, 出口, 迭代次数)
+fn trip_count(function: &Function, l: &Loop) -> Option<(BlockId, BlockId, usize)> {
+  let header = &function.blocks[l.header.0 as usize];
+  let Term::Branch {
+    cond: Operand::Reg(cond),
+    then,
+    els,
+  } = &header.term
+  else {
+    return None;
+  };
+  let (body, exit) = match (l.contains(*then), l.contains(*els)) {
+    (true, false) => (*then, *els),
+    (false, true) => (*els, *then),
+    _ => return None,
+  };
+  let exits = l.blocks.iter().any(|b| {
+    *b != l.header
+      && function.blocks[b.0 as usize]
+        .term
+        .successors()
+        .iter()
+        .any(|s| !l.contains(*s))
+  });
+  if exits {
+    return None;
+  }
+  let Some(Inst::Bin { op, lhs, rhs, .. }) = header.insts.iter().find(|i| i.dst() == Some(*cond))
+  else {
+    return None;
+  };
+  if !op.is_comparison() {
+    return None;
+  }
+  induction_variables(function, l)
+    .into_iter()
+    .find_map(|(i, init, step_op, step)| {
+      let (Operand::Imm(mut value), Operand::Imm(step)) = (init, step) else {
+        return None;
+      };
+      let i = Operand::Reg(i);
+      let holds = |value: Imm| match (lhs, rhs) {
+        (lhs, Operand::Imm(n)) if *lhs == i => fold_binary(*op, value, *n, false),
+        (Operand::Imm(n), rhs) if *rhs == i => fold_binary(*op, *n, value, false),
+        _ => None,
+      };
+      let mut count = 0;
+      while holds(value)? == Imm::Bool(body == *then) {
+        count += 1;
+        if count > MAX_TRIP_COUNT {
+          return None;
+        }
+        value = fold_binary(step_op, value, step, true)?;
+      }
+      Some((body, exit, count))
+    })
+}
+
+/// 完全展开循环：循环复制 `count` 份，依次相连，第 k 份执行第 k 次迭代；
+/// 最后一份只保留循环头，直接跳到出口。原来的循环作为第 0 份
+fn unroll(function: &mut Function, l: &Loop, body: BlockId, exit: BlockId, count: usize) {
+  let latch = l.latches[0];
+  let defs: Vec<VReg> = {
+    let mut defs: Vec<VReg> = loop_defs(function, l).into_iter().collect();
+    defs.sort();
+    defs
+  };
+  let mut next_reg = function.new_vreg().0;
+  let mut next_block = function.blocks.len() as u32;
+  let mut reg_maps: Vec<HashMap<VReg, VReg>> = vec![defs.iter().map(|r| (*r, *r)).collect()];
+  let mut block_maps: Vec<HashMap<BlockId, BlockId>> =
+    vec![l.blocks.iter().map(|b| (*b, *b)).collect()];
+  for _ in 1..=count {
+    reg_maps.push(
+      defs
+        .iter()
+        .map(|r| {
+          next_reg += 1;
+          (*r, VReg(next_reg - 1))
+        })
+        .collect(),
+    );
+    block_maps.push(
+      l.blocks
+        .iter()
+        .map(|b| {
+          next_block += 1;
+          (*b, BlockId(next_block - 1))
+        })
+        .collect(),
+    );
+  }
+
+  let mut copies = vec![];
+  for k in 1..=count {
+    let (regs, blocks) = (&reg_maps[k], &block_maps[k]);
+    let ids: &[BlockId] = if k == count { &[l.header] } else { &l.blocks };
+    for &id in ids {
+      let mut block = function.blocks[id.0 as usize].clone();
+      block.id = blocks[&id];
+      for inst in &mut block.insts {
+        if let Inst::Phi { incoming, .. } = inst {
+          if id == l.header {
+            // 循环头的 phi 取上一份回边送来的值
+            let value = incoming
+              .iter()
+              .find(|(from, _)| *from == latch)
+              .unwrap()
+              .1
+              .clone();
+            let value = match value {
+              Operand::Reg(reg) => Operand::Reg(*reg_maps[k - 1].get(&reg).unwrap_or(&reg)),
+              imm => imm,
+            };
+            *incoming = vec![(block_maps[k - 1][&latch], value)];
+            continue;
+          }
+          for (from, _) in incoming.iter_mut() {
+            *from = blocks.get(from).copied().unwrap_or(*from);
+          }
+        }
+      }
+      for inst in &mut block.insts {
+        let skip_phi = id == l.header && matches!(inst, Inst::Phi { .. });
+        if !skip_phi {
+          for operand in inst.operands_mut() {
+            if let Operand::Reg(reg) = operand {
+              *reg = regs.get(reg).copied().unwrap_or(*reg);
+            }
+          }
+        }
+        rename_dst(inst, regs);
+      }
+      for operand in block.term.operands_mut() {
+        if let Operand::Reg(reg) = operand {
+          *reg = regs.get(reg).copied().unwrap_or(*reg);
+        }
+      }
+      if id == l.header {
+        block.term = Term::Jump(if k == count { exit } else { blocks[&body] });
+      } else {
+        for target in block.term.successors_mut() {
+          *target = match blocks.get(target) {
+            _ if *target == l.header => block_maps[k + 1][&l.header],
+            Some(copy) => *copy,
+            None => *target,
+          };
+        }
+      }
+      copies.push(block);
+    }
+  }
+
+  // 第 0 份：循环头只从前置块进入，回边接到第 1 份
+  let header = &mut function.blocks[l.header.0 as usize];
+  for inst in &mut header.insts {
+    if let Inst::Phi { incoming, .. } = inst {
+      incoming.retain(|(from, _)| !l.contains(*from));
+    }
+  }
+  header.term = Term::Jump(if count == 0 { exit } else { body });
+  if count > 0 {
+    for target in function.blocks[latch.0 as usize].term.successors_mut() {
+      if *target == l.header {
+        *target = block_maps[1][&l.header];
+      }
+    }
+  }
+
+  // 循环之后只能看到循环头中的值，改用最后一份中的
+  let last_regs = &reg_maps[count];
+  let last_header = block_maps[count][&l.header];
+  for block in &mut function.blocks {
+    if l.contains(block.id) {
+      continue;
+    }
+    for inst in &mut block.insts {
+      if let Inst::Phi { incoming, .. } = inst {
+        for (from, _) in incoming.iter_mut() {
+          if *from == l.header {
+            *from = last_header;
+          }
+        }
+      }
+      for operand in inst.operands_mut() {
+        if let Operand::Reg(reg) = operand {
+          *reg = last_regs.get(reg).copied().unwrap_or(*reg);
+        }
+      }
+    }
+    for operand in block.term.operands_mut() {
+      if let Operand::Reg(reg) = operand {
+        *reg = last_regs.get(reg).copied().unwrap_or(*reg);
+      }
+    }
+  }
+  function.blocks.extend(copies);
+  function.remove_unreachable_blocks();
+}
+
+fn rename_dst(inst: &mut Inst, regs: &HashMap<VReg, VReg>) {
+  match inst {
+    Inst::Copy { dst, .. }
+    | Inst::Bin { dst, .. }
+    | Inst::Load { dst, .. }
+    | Inst::Addr { dst, .. }
+    | Inst::Elem { dst, .. }
+    | Inst::Str { dst, .. }
+    | Inst::Phi { dst, .. }
+    | Inst::Call { dst: Some(dst), .. } => *dst = regs[dst],
+    Inst::Store { .. } | Inst::Call { dst: None, .. } => {}
+  }
+}
+
+pub fn unroll_loops(function: &mut Function) {
+  loop {
+    let loops = ensure_preheaders(function);
+    let size = |l: &Loop| -> usize {
+      l.blocks
+        .iter()
+        .map(|b| function.blocks[b.0 as usize].insts.len() + 1)
+        .sum()
+    };
+    let candidate = loops.iter().find_map(|l| {
+      let (body, exit, count) = trip_count(function, l)?;
+      (size(l) * (count + 1) <= MAX_UNROLLED_SIZE).then_some((l, body, exit, count))
+    });
+    let Some((l, body, exit, count)) = candidate else {
+      return;
+    };
+    unroll(function, l, body, exit, count);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ir::parse_module;
+  use crate::ssa::{into_ssa, verify};
+
+  fn function(text: &str) -> Function {
+    let mut function = parse_module(text).unwrap().functions.remove(0);
+    into_ssa(&mut function);
+    function
+  }
+
+  fn run(pass: fn(&mut Function), text: &str) -> String {
+    let mut function = function(text);
+    pass(&mut function);
+    assert_eq!(verify(&function), Ok(()));
+    function.to_string()
+  }
+
+  // while i < n { j = 0; while j < 3 { j = j + 1 }; i = i + 1 }，外层循环头有两个循环外的前驱
+  const NESTED: &str = "fn f(n: int, c: bool) -> void {
+  slot i: int
+  slot j: int
+b0:
+  v9 = load bool [c]
+  br v9, b1, b2
+b1:
+  store [i], 1
+  jmp b3
+b2:
+  store [i], 2
+  jmp b3
+b3:
+  v0 = load int [i]
+  v1 = load int [n]
+  v2 = lt int v0, v1
+  br v2, b4, b8
+b4:
+  store [j], 0
+  jmp b5
+b5:
+  v3 = load int [j]
+  v4 = lt int v3, 3
+  br v4, b6, b7
+b6:
+  v5 = add int v3, 1
+  store [j], v5
+  jmp b5
+b7:
+  v6 = add int v0, 1
+  store [i], v6
+  jmp b3
+b8:
+  ret
+}
+";
+
+  #[test]
+  fn test_find_loops_and_preheaders() {
+    let mut f = function(NESTED);
+    let loops = find_loops(&f);
+    assert_eq!(loops.len(), 2);
+    assert_eq!(loops[0].header, BlockId(5));
+    assert_eq!(loops[0].blocks, [5, 6].map(BlockId));
+    assert_eq!(loops[0].preheader, Some(BlockId(4)));
+    assert_eq!(loops[1].header, BlockId(3));
+    assert_eq!(loops[1].latches, vec![BlockId(7)]);
+    assert_eq!(loops[1].blocks, [3, 4, 5, 6, 7].map(BlockId));
+    assert_eq!(loops[1].preheader, None);
+    let loops = ensure_preheaders(&mut f);
+    assert_eq!(verify(&f), Ok(()));
+    assert_eq!(loops[1].preheader, Some(BlockId(9)));
+    // 两个前驱送来的初值在前置块中合并
+    let text = f.to_string();
+    assert!(text.contains("b1:\n  jmp b9\nb2:\n  jmp b9\n"));
+    assert!(text.contains("b9:\n  v15 = phi int [b1: 1], [b2: 2]\n"));
+    assert!(text.contains("v10 = phi int [b7: v6], [b9: v15]"));
+  }
+
+  #[test]
+  fn test_hoist_loop_invariants() {
+    // 循环头中的溢出检查和除法可以移出；循环体中在调用之后才执行的、可能报错的运算留在原处
+    assert_eq!(
+      run(
+        hoist_loop_invariants,
+        "fn f(a: int, n: int) -> int {
+  slot i: int
+  slot s: int
+b0:
+  jmp b1
+b1:
+  v0 = load int [i]
+  v1 = load int [a]
+  v2 = sub int v1, 2 trap 3
+  v3 = lt int v0, v2
+  v4 = div int 100, v1
+  br v3, b2, b3
+b2:
+  v5 = mul int v1, v1
+  v6 = add int v5, 1
+  call void print_int(v0)
+  v7 = add int v1, 1 trap 5
+  v8 = load int [s]
+  v9 = add int v8, v6
+  v10 = add int v9, v7
+  v11 = add int v10, v4
+  store [s], v11
+  v12 = add int v0, 1
+  store [i], v12
+  jmp b1
+b3:
+  v13 = load int [s]
+  ret v13
+}
+"
+      ),
+      "fn f(a: int, n: int) -> int {
+b0:
+  v16 = load int [a]
+  v17 = load int [n]
+  v2 = sub int v16, 2 trap 3
+  v4 = div int 100, v16
+  v5 = mul int v16, v16
+  v6 = add int v5, 1
+  jmp b1
+b1:
+  v14 = phi int [b0: 0], [b2: v12]
+  v15 = phi int [b0: 0], [b2: v11]
+  v3 = lt int v14, v2
+  br v3, b2, b3
+b2:
+  call void print_int(v14)
+  v7 = add int v16, 1 trap 5
+  v9 = add int v15, v6
+  v10 = add int v9, v7
+  v11 = add int v10, v4
+  v12 = add int v14, 1
+  jmp b1
+b3:
+  ret v15
+}
+"
+    );
+  }
+
+  #[test]
+  fn test_hoist_guarded_invariants() {
+    // 默认的溢出检查模式下 `accumulate` 翻译出的 IR：循环体中每次都重新计算 `a - 2`
+    let mut f = function(
+      "fn accumulate(a: int) -> int {
+  slot res: int
+  slot cur: int
+b0:
+  v0 = copy int 0
+  store [res], v0
+  v1 = copy int 1
+  store [cur], v1
+  jmp b1
+b1:
+  v2 = load int [cur]
+  v3 = load int [a]
+  v4 = lt int v2, v3
+  br v4, b2, b3
+b2:
+  v5 = load int [cur]
+  v6 = load int [a]
+  v7 = copy int 2
+  v8 = sub int v6, v7 trap 6
+  v9 = gt int v5, v8
+  br v9, b4, b5
+b3:
+  v19 = load int [res]
+  ret v19
+b4:
+  v10 = load int [res]
+  v11 = copy int 1
+  v12 = add int v10, v11 trap 7
+  store [res], v12
+  jmp b6
+b5:
+  jmp b6
+b6:
+  v13 = load int [res]
+  v14 = load int [cur]
+  v15 = add int v13, v14 trap 10
+  store [res], v15
+  v16 = load int [cur]
+  v17 = copy int 1
+  v18 = add int v16, v17 trap 11
+  store [cur], v18
+  jmp b1
+}
+",
+    );
+    crate::constprop::propagate_constants(&mut f);
+    hoist_loop_invariants(&mut f);
+    assert_eq!(verify(&f), Ok(()));
+    // 按初值判断循环至少执行一次之后才计算 `a - 2`；循环一次也不执行时直接跳到出口
+    assert_eq!(
+      f.to_string(),
+      "fn accumulate(a: int) -> int {
+b0:
+  v23 = load int [a]
+  v24 = lt int 1, v23
+  br v24, b7, b3
+b1:
+  v20 = phi int [b6: v15], [b7: 0]
+  v22 = phi int [b6: v18], [b7: 1]
+  v4 = lt int v22, v23
+  br v4, b2, b3
+b2:
+  v9 = gt int v22, v8
+  br v9, b4, b5
+b3:
+  v25 = phi int [b0: 0], [b1: v20]
+  ret v25
+b4:
+  v12 = add int v20, 1 trap 7
+  jmp b6
+b5:
+  jmp b6
+b6:
+  v21 = phi int [b4: v12], [b5: v20]
+  v15 = add int v21, v22 trap 10
+  v18 = add int v22, 1 trap 11
+  jmp b1
+b7:
+  v8 = sub int v23, 2 trap 6
+  jmp b1
+}
+"
+    );
+  }
+
+  #[test]
+  fn test_reduce_strength() {
+    // 带溢出检查的乘法不处理
+    assert_eq!(
+      run(
+        reduce_strength,
+        "fn f(n: int, k: int) -> int {
+  slot i: int
+  slot s: int
+b0:
+  store [i], 3
+  jmp b1
+b1:
+  v0 = load int [i]
+  v1 = load int [n]
+  v2 = lt int v0, v1
+  br v2, b2, b3
+b2:
+  v3 = mul int v0, 4
+  v4 = load int [k]
+  v5 = mul int v4, v0
+  v6 = mul int v0, 5 trap 7
+  v7 = load int [s]
+  v8 = add int v7, v3
+  v9 = add int v8, v5
+  v10 = add int v9, v6
+  store [s], v10
+  v11 = sub int v0, 2
+  store [i], v11
+  jmp b1
+b3:
+  v12 = load int [s]
+  ret v12
+}
+"
+      ),
+      "fn f(n: int, k: int) -> int {
+b0:
+  v15 = load int [n]
+  v16 = load int [k]
+  v19 = mul int 3, v16
+  v20 = mul int 2, v16
+  jmp b1
+b1:
+  v17 = phi int [b0: 12], [b2: v18]
+  v21 = phi int [b0: v19], [b2: v22]
+  v13 = phi int [b0: 3], [b2: v11]
+  v14 = phi int [b0: 0], [b2: v10]
+  v2 = lt int v13, v15
+  br v2, b2, b3
+b2:
+  v6 = mul int v13, 5 trap 7
+  v8 = add int v14, v17
+  v9 = add int v8, v21
+  v10 = add int v9, v6
+  v11 = sub int v13, 2
+  v18 = sub int v17, 8
+  v22 = sub int v21, v20
+  jmp b1
+b3:
+  ret v14
+}
+"
+    );
+  }
+
+  #[test]
+  fn test_reduce_strength_with_overflow_checks() {
+    // i 从 0 开始每次加 1，循环头保证 i < 100，i * 4 不会溢出
+    let text = "fn f() -> int {
+  slot i: int
+  slot s: int
+b0:
+  jmp b1
+b1:
+  v0 = load int [i]
+  v1 = lt int v0, 100
+  br v1, b2, b3
+b2:
+  v2 = mul int v0, 4 trap 5
+  v3 = load int [s]
+  v4 = add int v3, v2 trap 5
+  store [s], v4
+  v5 = add int v0, 1 trap 6
+  store [i], v5
+  jmp b1
+b3:
+  v6 = load int [s]
+  ret v6
+}
+";
+    assert_eq!(
+      run(reduce_strength, text),
+      "fn f() -> int {
+b0:
+  jmp b1
+b1:
+  v9 = phi int [b0: 0], [b2: v10]
+  v7 = phi int [b0: 0], [b2: v5]
+  v8 = phi int [b0: 0], [b2: v4]
+  v1 = lt int v7, 100
+  br v1, b2, b3
+b2:
+  v4 = add int v8, v9 trap 5
+  v5 = add int v7, 1 trap 6
+  v10 = add int v9, 4
+  jmp b1
+b3:
+  ret v8
+}
+"
+    );
+    // 范围的上端乘以 4 会溢出时保留原来的乘法
+    let large = text.replace("lt int v0, 100", "lt int v0, 4611686018427387904");
+    assert!(run(reduce_strength, &large).contains("mul int v7, 4 trap 5"));
+  }
+
+  const SMALL: &str = "fn f() -> int {
+  slot i: int
+  slot s: int
+b0:
+  jmp b1
+b1:
+  v0 = load int [i]
+  v1 = lt int v0, 3
+  br v1, b2, b3
+b2:
+  v2 = load int [s]
+  v3 = add int v2, v0
+  store [s], v3
+  v4 = add int v0, 1
+  store [i], v4
+  jmp b1
+b3:
+  v5 = load int [s]
+  ret v5
+}
+";
+
+  #[test]
+  fn test_unroll_loops() {
+    let unrolled = run(unroll_loops, SMALL);
+    assert!(!unrolled.contains("br "));
+    assert_eq!(unrolled.matches("v1 = lt").count(), 1);
+    assert!(unrolled.contains("b3:\n  ret v22\n"));
+    assert!(unrolled.contains("b8:\n  v21 = phi int [b7: v15]\n  v22 = phi int [b7: v14]\n"));
+    // 展开后再做常量传播，结果是常量
+    let mut f = function(SMALL);
+    unroll_loops(&mut f);
+    crate::constprop::propagate_constants(&mut f);
+    assert!(f.to_string().contains("ret 3\n"));
+    // 迭代次数超过上限时不展开
+    let many = SMALL.replace("lt int v0, 3", "lt int v0, 9");
+    assert!(run(unroll_loops, &many).contains("br v1, b2, b3"));
+  }
+}
@@ -0,0 +1,754 @@
+//! 把 AST 翻译成三地址 IR
+//!
+//! 参数和局部变量各占一个栈槽，每次读写都是一条 `load` / `store`；表达式的每个中间结果
+//! 写入一个新的虚拟寄存器，常量也先用 `copy` 装入虚拟寄存器。`if` / `while` 翻译成基本块
+//! 和显式的跳转，`break` / `continue` 跳到循环的出口和条件所在的块。
+//!
+//! 默认参数、关键字参数在这里展开成按位置传递的实参；变长实参依次存入调用方的栈槽，
+//! 以 (首地址, 个数) 两个实参传递。内建的 `print` 按实参类型展开成对运行时
+//! `print_int` / `print_float` / `print_bool` / `print_str` 的调用。
+//!
+//! 带 `@tailcall` 属性的语句中的调用，以及 `@tailcall` 函数对自身的调用，直接翻译成 `tailcall`，
+//! 在任何优化级别下都不占用新的栈帧。
+
+use crate::ast::{collect_chain, fold_chain, AST};
+use crate::diagnostic::line_col;
+use crate::ir::{
+  BinOp, Block, BlockId, Function, Imm, Inline, Inst, IrType, Module, Operand, Slot, Term, VReg,
+};
+use crate::signature::{lower_call_args, param_nodes, FnSignature, WRAPPING_FUNCTIONS};
+use crate::tailcall::stmt_call;
+use crate::types::Type;
+use std::collections::HashMap;
+
+/// 调试模式下填入未初始化变量的毒值，便于在调试器中辨认
+pub const POISON: i64 = 0xdeadbeefdeadbeef_u64 as i64;
+
+/// 整数加、减、乘溢出时的行为
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+  /// 回绕，得到补码运算的结果
+  Wrap,
+  /// 跳转到运行时错误，报告溢出的运算和源码行号
+  Trap,
+}
+
+impl Overflow {
+  pub fn from_name(name: &str) -> Option<Overflow> {
+    match name {
+      "wrap" => Some(Overflow::Wrap),
+      "trap" => Some(Overflow::Trap),
+      _ => None,
+    }
+  }
+}
+
+/// 翻译选项
+pub struct LowerOptions<'a> {
+  /// 调试模式：未初始化的变量填入毒值
+  pub debug: bool,
+  pub overflow: Overflow,
+  /// 源码，用于在运行时错误中报告行号；为空时行号为 0
+  pub source: &'a str,
+}
+
+/// 翻译整个程序中的函数，常量和类型定义不生成代码
+pub fn lower_program(
+  ast: &AST,
+  signatures: &HashMap<String, FnSignature>,
+  options: &LowerOptions,
+) -> Module {
+  let mut module = Module::default();
+  lower_items(ast, signatures, options, &mut module);
+  module
+}
+
+fn lower_items(
+  ast: &AST,
+  signatures: &HashMap<String, FnSignature>,
+  options: &LowerOptions,
+  module: &mut Module,
+) {
+  match ast.value.as_str() {
+    "Fn" => module
+      .functions
+      .push(Lowerer::new(signatures, options).lower_fn(ast)),
+    "Pg" | "FnList" => {
+      for child in &ast.children {
+        lower_items(child, signatures, options, module);
+      }
+    }
+    _ => {}
+  }
+}
+
+/// 翻译一个函数
+struct Lowerer<'a> {
+  signatures: &'a HashMap<String, FnSignature>,
+  options: &'a LowerOptions<'a>,
+  name: String,
+  params: Vec<Slot>,
+  locals: Vec<Slot>,
+  /// 变长实参的栈槽，放在所有局部变量之后
+  vararg_slots: Vec<Slot>,
+  slot_types: HashMap<String, IrType>,
+  /// 基本块的指令和终结指令，翻译结束时没有终结指令的块补上返回
+  blocks: Vec<(Vec<Inst>, Option<Term>)>,
+  current: usize,
+  next_vreg: u32,
+  /// 外层循环的 (条件块, 出口块)
+  loops: Vec<(BlockId, BlockId)>,
+  vararg_count: usize,
+  /// 当前语句所在的行
+  line: u32,
+  /// 函数带有 `@tailcall` 属性，对自身的调用都是尾调用
+  tail_recursive: bool,
+}
+
+impl<'a> Lowerer<'a> {
+  fn new(signatures: &'a HashMap<String, FnSignature>, options: &'a LowerOptions<'a>) -> Self {
+    Lowerer {
+      signatures,
+      options,
+      name: String::new(),
+      params: vec![],
+      locals: vec![],
+      vararg_slots: vec![],
+      slot_types: HashMap::new(),
+      blocks: vec![(vec![], None)],
+      current: 0,
+      next_vreg: 0,
+      loops: vec![],
+      vararg_count: 0,
+      line: 0,
+      tail_recursive: false,
+    }
+  }
+
+  fn lower_fn(mut self, ast: &AST) -> Function {
+    self.name = ast.children[1].value.clone();
+    self.tail_recursive = ast.has_attr("tailcall");
+    let sig = &self.signatures[&self.name];
+    for (node, param) in param_nodes(&ast.children[2]).into_iter().zip(&sig.params) {
+      let name = node.children[1].value.clone();
+      if param.variadic {
+        // 变长参数是一个切片：首元素地址和长度 `xs.len` 各占一个栈槽
+        self.params.push(Slot {
+          name: name.clone(),
+          ty: IrType::Ptr,
+        });
+        self.params.push(Slot {
+          name: format!("{}.len", name),
+          ty: IrType::Int,
+        });
+      } else {
+        self.params.push(Slot {
+          name,
+          ty: IrType::from_type(&param.ty),
+        });
+      }
+    }
+    for slot in &self.params {
+      self.slot_types.insert(slot.name.clone(), slot.ty);
+    }
+    // main 的返回值是进程的退出码
+    let ret = match IrType::from_type(&Type::from_ast(&ast.children[0])) {
+      IrType::Void if self.name == "main" => IrType::Int,
+      ty => ty,
+    };
+    self.lower_stmt_list(&ast.children[3].children[0]);
+
+    let mut blocks = vec![];
+    for (i, (insts, term)) in self.blocks.into_iter().enumerate() {
+      // 执行到函数末尾：main 返回 0，其它函数（只会是 void 函数）直接返回
+      let term = term.unwrap_or(match self.name.as_str() {
+        "main" => Term::Ret(Some(Operand::Imm(Imm::Int(0)))),
+        _ => Term::Ret(None),
+      });
+      blocks.push(Block {
+        id: BlockId(i as u32),
+        insts,
+        term,
+      });
+    }
+    self.locals.append(&mut self.vararg_slots);
+    let inline = if ast.has_attr("inline") {
+      Inline::Always
+    } else if ast.has_attr("noinline") {
+      Inline::Never
+    } else {
+      Inline::Auto
+    };
+    let mut function = Function {
+      name: self.name,
+      inline,
+      params: self.params,
+      locals: self.locals,
+      ret,
+      blocks,
+    };
+    function.remove_unreachable_blocks();
+    function
+  }
+
+  fn vreg(&mut self) -> VReg {
+    self.next_vreg += 1;
+    VReg(self.next_vreg - 1)
+  }
+
+  fn new_block(&mut self) -> BlockId {
+    self.blocks.push((vec![], None));
+    BlockId(self.blocks.len() as u32 - 1)
+  }
+
+  fn switch_to(&mut self, block: BlockId) {
+    self.current = block.0 as usize;
+  }
+
+  /// 在当前块末尾加一条指令；当前块已经结束时（如 return 之后的语句）放进一个新的不可达块
+  fn emit(&mut self, inst: Inst) {
+    if self.blocks[self.current].1.is_some() {
+      let block = self.new_block();
+      self.switch_to(block);
+    }
+    self.blocks[self.current].0.push(inst);
+  }
+
+  /// 结束当前块；已经结束的块保持原来的终结指令
+  fn terminate(&mut self, term: Term) {
+    let block = &mut self.blocks[self.current];
+    if block.1.is_none() {
+      block.1 = Some(term);
+    }
+  }
+
+  fn declare(&mut self, name: &str, ty: IrType) {
+    if self.slot_types.insert(name.to_string(), ty).is_none() {
+      self.locals.push(Slot {
+        name: name.to_string(),
+        ty,
+      });
+    }
+  }
+
+  fn lower_stmt_list(&mut self, ast: &AST) {
+    let mut stmt_list = ast;
+    while stmt_list.children.len() == 2 {
+      let stmt = &stmt_list.children[0];
+      if !self.options.source.is_empty() {
+        self.line = line_col(self.options.source, stmt.children[0].span.start).0 as u32;
+      }
+      match stmt_call(&stmt.children[0]) {
+        Some(call) if stmt.has_attr("tailcall") || self.is_self_tail_call(call) => {
+          self.lower_tail_call(call)
+        }
+        _ => self.lower_stmt(&stmt.children[0]),
+      }
+      stmt_list = &stmt_list.children[1];
+    }
+  }
+
+  fn is_self_tail_call(&self, call: &AST) -> bool {
+    self.tail_recursive && call.children[0].value == self.name
+  }
+
+  /// 翻译调用之后把它从块末尾取下，换成结束当前块的尾调用
+  fn lower_tail_call(&mut self, call: &AST) {
+    self.lower_call(call);
+    let Some(Inst::Call {
+      ty, callee, args, ..
+    }) = self.blocks[self.current].0.pop()
+    else {
+      unreachable!()
+    };
+    self.terminate(Term::TailCall { ty, callee, args });
+  }
+
+  fn lower_stmt(&mut self, ast: &AST) {
+    match ast.value.as_str() {
+      "VarDecl" => {
+        let name = &ast.children[1].value;
+        self.declare(name, IrType::from_type(&Type::from_ast(&ast.children[0])));
+        if self.options.debug {
+          // 确定赋值检查保证不会读到这个值；一旦读到说明编译器有错
+          self.emit(Inst::Store {
+            slot: name.clone(),
+            src: Operand::Imm(Imm::Int(POISON)),
+          });
+        }
+      }
+      "VarDef" => {
+        let (value, _) = self.lower_value(&ast.children[2]);
+        let name = &ast.children[1].value;
+        self.declare(name, IrType::from_type(&Type::from_ast(&ast.children[0])));
+        self.emit(Inst::Store {
+          slot: name.clone(),
+          src: value,
+        });
+      }
+      "Assign" => {
+        let (value, _) = self.lower_value(&ast.children[1]);
+        self.emit(Inst::Store {
+          slot: ast.children[0].value.clone(),
+          src: value,
+        });
+      }
+      "Return" => {
+        let value = match ast.children.first() {
+          Some(expr) => Some(self.lower_value(expr).0),
+          None if self.name == "main" => Some(Operand::Imm(Imm::Int(0))),
+          None => None,
+        };
+        self.terminate(Term::Ret(value));
+      }
+      "BranchStmt" => {
+        let (cond, _) = self.lower_value(&ast.children[0]);
+        let then = self.new_block();
+        let els = self.new_block();
+        let join = self.new_block();
+        self.terminate(Term::Branch { cond, then, els });
+        self.switch_to(then);
+        self.lower_stmt_list(&ast.children[1]);
+        self.terminate(Term::Jump(join));
+        self.switch_to(els);
+        self.lower_stmt_list(&ast.children[2]);
+        self.terminate(Term::Jump(join));
+        self.switch_to(join);
+      }
+      "LoopStmt" => {
+        let header = self.new_block();
+        let body = self.new_block();
+        let exit = self.new_block();
+        self.terminate(Term::Jump(header));
+        self.switch_to(header);
+        let (cond, _) = self.lower_value(&ast.children[0]);
+        self.terminate(Term::Branch {
+          cond,
+          then: body,
+          els: exit,
+        });
+        self.loops.push((header, exit));
+        self.switch_to(body);
+        self.lower_stmt_list(&ast.children[1]);
+        self.terminate(Term::Jump(header));
+        self.loops.pop();
+        self.switch_to(exit);
+      }
+      "break" => {
+        let exit = self.loops.last().unwrap().1;
+        self.terminate(Term::Jump(exit));
+      }
+      "continue" => {
+        let header = self.loops.last().unwrap().0;
+        self.terminate(Term::Jump(header));
+      }
+      "Expr" => {
+        self.lower_value(ast);
+      }
+      _ => {}
+    }
+  }
+
+  /// 翻译表达式，返回结果和它的类型；void 函数调用的结果是 `0`
+  fn lower_value(&mut self, ast: &AST) -> (Operand, IrType) {
+    match ast.value.as_str() {
+      "Expr" | "Term" => self.lower_expr(ast),
+      "Factor" if ast.children.len() == 3 => self.lower_value(&ast.children[1]),
+      "Factor" => self.lower_value(&ast.children[0]),
+      "FnCall" => self
+        .lower_call(ast)
+        .unwrap_or((Operand::Imm(Imm::Int(0)), IrType::Void)),
+      "Index" => {
+        let (base, _) = self.lower_value(&ast.children[0]);
+        let (index, _) = self.lower_value(&ast.children[1]);
+        let ty = ast.ty.as_ref().map_or(IrType::Int, IrType::from_type);
+        let dst = self.vreg();
+        self.emit(Inst::Elem {
+          dst,
+          ty,
+          base,
+          index,
+        });
+        (Operand::Reg(dst), ty)
+      }
+      _ => self.lower_leaf(ast),
+    }
+  }
+
+  /// 运算符链：按优先级组合各操作数，操作数从左到右求值
+  fn lower_expr(&mut self, ast: &AST) -> (Operand, IrType) {
+    let mut operands = vec![];
+    let mut ops = vec![];
+    collect_chain(ast, &mut operands, &mut ops);
+    let mut values = vec![];
+    for operand in operands {
+      values.push(self.lower_value(operand));
+    }
+    fold_chain(ops, values, |op, lhs, rhs| self.apply(op, lhs, rhs))
+  }
+
+  fn apply(
+    &mut self,
+    op: &AST,
+    (lhs, lhs_ty): (Operand, IrType),
+    (rhs, _): (Operand, IrType),
+  ) -> (Operand, IrType) {
+    let bin_op = BinOp::from_symbol(&op.value)
+      .unwrap_or_else(|| panic!("unsupported operator `{}`", op.value));
+    // 运算符节点上记录的是操作数的类型
+    let ty = op.ty.as_ref().map_or(lhs_ty, IrType::from_type);
+    let trap = (self.options.overflow == Overflow::Trap
+      && ty == IrType::Int
+      && matches!(bin_op, BinOp::Add | BinOp::Sub | BinOp::Mul))
+    .then_some(self.line);
+    self.binary(bin_op, ty, lhs, rhs, trap)
+  }
+
+  fn binary(
+    &mut self,
+    op: BinOp,
+    ty: IrType,
+    lhs: Operand,
+    rhs: Operand,
+    trap: Option<u32>,
+  ) -> (Operand, IrType) {
+    let dst = self.vreg();
+    self.emit(Inst::Bin {
+      dst,
+      op,
+      ty,
+      lhs,
+      rhs,
+      trap,
+    });
+    let result = if op.is_comparison() { IrType::Bool } else { ty };
+    (Operand::Reg(dst), result)
+  }
+
+  fn lower_leaf(&mut self, ast: &AST) -> (Operand, IrType) {
+    let token = ast.value.as_str();
+    if token.starts_with('"') {
+      let dst = self.vreg();
+      self.emit(Inst::Str {
+        dst,
+        literal: token.to_string(),
+      });
+      return (Operand::Reg(dst), IrType::Ptr);
+    }
+    let imm = if token == "true" || token == "false" {
+      Imm::Bool(token == "true")
+    } else if token.starts_with(|c: char| c.is_ascii_digit())
+      || (token.starts_with('-') && token.len() > 1)
+    {
+      // 常量折叠产生的字面量可能是负数
+      if ast.ty == Some(Type::Float) || token.contains(['.', 'e']) {
+        Imm::Float(token.parse().unwrap())
+      } else {
+        Imm::Int(
+          token
+            .parse()
+            .unwrap_or_else(|_| panic!("integer literal `{}` is too large", token)),
+        )
+      }
+    } else {
+      let ty = *self.slot_types.get(token).unwrap_or_else(|| {
+        // 名字解析阶段已经报告了未定义的变量
+        panic!("undefined variable `{}` in function `{}`", token, self.name)
+      });
+      let dst = self.vreg();
+      self.emit(Inst::Load {
+        dst,
+        ty,
+        slot: token.to_string(),
+      });
+      return (Operand::Reg(dst), ty);
+    };
+    let ty = match imm {
+      Imm::Int(_) => IrType::Int,
+      Imm::Float(_) => IrType::Float,
+      Imm::Bool(_) => IrType::Bool,
+    };
+    let dst = self.vreg();
+    self.emit(Inst::Copy {
+      dst,
+      ty,
+      src: Operand::Imm(imm),
+    });
+    (Operand::Reg(dst), ty)
+  }
+
+  /// 翻译函数调用，void 函数返回 None
+  fn lower_call(&mut self, call: &AST) -> Option<(Operand, IrType)> {
+    let name = call.children[0].value.clone();
+    let Some(sig) = self.signatures.get(&name) else {
+      return self.lower_builtin(&name, call);
+    };
+    let mut call = call.clone();
+    lower_call_args(&mut call, sig);
+    let ty = IrType::from_type(&sig.ret);
+    let mut args = vec![];
+    for arg in &call.children[1..] {
+      if arg.value == "VarArgs" {
+        let (base, len) = self.lower_varargs(arg);
+        args.push(base);
+        args.push(len);
+      } else {
+        args.push(self.lower_value(arg).0);
+      }
+    }
+    self.call(ty, &name, args)
+  }
+
+  fn call(&mut self, ty: IrType, callee: &str, args: Vec<Operand>) -> Option<(Operand, IrType)> {
+    let dst = (ty != IrType::Void).then(|| self.vreg());
+    self.emit(Inst::Call {
+      dst,
+      ty,
+      callee: callee.to_string(),
+      args,
+    });
+    dst.map(|dst| (Operand::Reg(dst), ty))
+  }
+
+  /// 变长实参依次存入调用方栈帧中一段连续的栈槽，返回 (首地址, 个数)
+  fn lower_varargs(&mut self, ast: &AST) -> (Operand, Operand) {
+    let id = self.vararg_count;
+    self.vararg_count += 1;
+    for (i, arg) in ast.children.iter().enumerate() {
+      let (value, ty) = self.lower_value(arg);
+      let slot = format!("va{}.{}", id, i);
+      self.vararg_slots.push(Slot {
+        name: slot.clone(),
+        ty,
+      });
+      self.emit(Inst::Store { slot, src: value });
+    }
+    let base = self.vreg();
+    if ast.children.is_empty() {
+      self.emit(Inst::Copy {
+        dst: base,
+        ty: IrType::Ptr,
+        src: Operand::Imm(Imm::Int(0)),
+      });
+    } else {
+      self.emit(Inst::Addr {
+        dst: base,
+        slot: format!("va{}.0", id),
+      });
+    }
+    (
+      Operand::Reg(base),
+      Operand::Imm(Imm::Int(ast.children.len() as i64)),
+    )
+  }
+
+  fn lower_builtin(&mut self, name: &str, call: &AST) -> Option<(Operand, IrType)> {
+    let args = &call.children[1..];
+    match name {
+      "print" => {
+        self.lower_print(args);
+        None
+      }
+      "scan" => self.call(IrType::Int, "scan", vec![]),
+      // 切片的长度保存在 `xs.len` 中
+      "size" => {
+        let slice = args[0].get_expression();
+        assert!(slice.len() == 1, "`size` expects a variable");
+        let dst = self.vreg();
+        self.emit(Inst::Load {
+          dst,
+          ty: IrType::Int,
+          slot: format!("{}.len", slice[0]),
+        });
+        Some((Operand::Reg(dst), IrType::Int))
+      }
+      _ => {
+        let (_, op) = WRAPPING_FUNCTIONS
+          .iter()
+          .find(|(f, _)| *f == name)
+          .unwrap_or_else(|| panic!("undefined function `{}`", name));
+        // 回绕运算不检查溢出
+        let (lhs, _) = self.lower_value(&args[0]);
+        let (rhs, _) = self.lower_value(&args[1]);
+        let op = BinOp::from_symbol(op).unwrap();
+        Some(self.binary(op, IrType::Int, lhs, rhs, None))
+      }
+    }
+  }
+
+  /// 内建 print：依次打印各实参，以空格分隔，最后换行
+  fn lower_print(&mut self, args: &[AST]) {
+    // 先求出全部参数，再逐个输出，参数中的调用或陷阱不会留下半行输出
+    let values: Vec<(Operand, IrType)> = args.iter().map(|arg| self.lower_value(arg)).collect();
+    for (i, (value, ty)) in values.into_iter().enumerate() {
+      if i > 0 {
+        self.call(IrType::Void, "print_char", vec![Operand::Imm(Imm::Int(32))]);
+      }
+      let callee = match ty {
+        IrType::Ptr => "print_str",
+        IrType::Float => "print_float",
+        IrType::Bool => "print_bool",
+        _ => "print_int",
+      };
+      self.call(IrType::Void, callee, vec![value]);
+    }
+    self.call(IrType::Void, "print_char", vec![Operand::Imm(Imm::Int(10))]);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::parser::Parser;
+  use crate::resolve::resolve;
+  use crate::signature::collect_signatures;
+  use crate::typeck::check_program;
+
+  fn lower(source: &str, debug: bool) -> Module {
+    let mut ast = Parser::new(Lexer::new(source)).parse();
+    resolve(&mut ast);
+    let signatures = collect_signatures(&ast);
+    check_program(&mut ast, &signatures, false);
+    let options = LowerOptions {
+      debug,
+      overflow: Overflow::Trap,
+      source,
+    };
+    lower_program(&ast, &collect_signatures(&ast), &options)
+  }
+
+  #[test]
+  fn test_lower_loop_and_branch() {
+    let source = "int main()\n  int i = 0\n  while i < 3\n    if i == 1\n      break\n    else\n      i = i + 1\n  return i\n";
+    assert_eq!(
+      lower(source, false).to_string(),
+      "fn main() -> int {
+  slot i: int
+b0:
+  v0 = copy int 0
+  store [i], v0
+  jmp b1
+b1:
+  v1 = load int [i]
+  v2 = copy int 3
+  v3 = lt int v1, v2
+  br v3, b2, b3
+b2:
+  v4 = load int [i]
+  v5 = copy int 1
+  v6 = eq int v4, v5
+  br v6, b4, b5
+b3:
+  v10 = load int [i]
+  ret v10
+b4:
+  jmp b3
+b5:
+  v7 = load int [i]
+  v8 = copy int 1
+  v9 = add int v7, v8 trap 7
+  store [i], v9
+  jmp b6
+b6:
+  jmp b1
+}
+"
+    );
+  }
+
+  #[test]
+  fn test_lower_calls() {
+    let source = "void main()\n  int x\n  print(f(1, c = 2.5), \"s\")\n\nint f(int a, float c = 1.0, int... rest)\n  return size(rest) + a\n";
+    let module = lower(source, true);
+    assert_eq!(
+      module.functions[0].to_string(),
+      "fn main() -> int {
+  slot x: int
+b0:
+  store [x], -2401053088876216593
+  v0 = copy int 1
+  v1 = copy float 2.5
+  v2 = copy ptr 0
+  v3 = call int f(v0, v1, v2, 0)
+  v4 = str \"s\"
+  call void print_int(v3)
+  call void print_char(32)
+  call void print_str(v4)
+  call void print_char(10)
+  ret 0
+}
+"
+    );
+    let f = &module.functions[1];
+    let params: Vec<String> = f
+      .params
+      .iter()
+      .map(|p| format!("{}: {}", p.name, p.ty))
+      .collect();
+    assert_eq!(
+      params,
+      vec!["a: int", "c: float", "rest: ptr", "rest.len: int"]
+    );
+  }
+
+  #[test]
+  fn test_print_evaluates_arguments_first() {
+    let source = "void main()\n  print(1, k(2))\n\nint k(int n)\n  print(n)\n  return n\n";
+    assert_eq!(
+      lower(source, false).functions[0].to_string(),
+      "fn main() -> int {
+b0:
+  v0 = copy int 1
+  v1 = copy int 2
+  v2 = call int k(v1)
+  call void print_int(v0)
+  call void print_char(32)
+  call void print_int(v2)
+  call void print_char(10)
+  ret 0
+}
+"
+    );
+  }
+
+  #[test]
+  fn test_wrapping_builtins_do_not_trap() {
+    let module = lower("int f(int a)\n  return wrapping_mul(a, a) - 1\n", false);
+    let text = module.to_string();
+    assert!(text.contains("v2 = mul int v0, v1\n"));
+    assert!(text.contains("v4 = sub int v2, v3 trap 2\n"));
+  }
+
+  #[test]
+  fn test_marked_tail_calls() {
+    let source = "@tailcall\nint f(int n)\n  if n == 0\n    @tailcall\n    return g(n)\n  else\n    return f(n - 1)\n\nint g(int n)\n  h(n)\n  return n\n\nvoid h(int n)\n  @tailcall\n  h(n)\n";
+    let module = lower(source, false);
+    assert_eq!(
+      module.functions[0].to_string(),
+      "fn f(n: int) -> int {
+b0:
+  v0 = load int [n]
+  v1 = copy int 0
+  v2 = eq int v0, v1
+  br v2, b1, b2
+b1:
+  v3 = load int [n]
+  tailcall int g(v3)
+b2:
+  v5 = load int [n]
+  v6 = copy int 1
+  v7 = sub int v5, v6 trap 7
+  tailcall int f(v7)
+}
+"
+    );
+    // 没有标记的调用留给优化
+    assert!(module.functions[1]
+      .to_string()
+      .contains("  call void h(v0)\n"));
+    assert!(module.functions[2]
+      .to_string()
+      .contains("  tailcall void h(v0)\n"));
+  }
+}
@@ -6,16 +6,14 @@
  * @FilePath: /W/w/src/main.rs
  * @Description: 这是默认设置,请设置`customMade`, 打开koroFileHeader查看配置 进行设置: https://github.com/OBKoro1/koro1FileHeader/wiki/%E9%85%8D%E7%BD%AE
  */
-pub mod ast;
-pub mod aux;
-pub mod interpreter;
-pub mod lexer;
-pub mod main_run;
-pub mod parser;
-use main_run::*;
+use w::main_run::*;
 
 fn main() {
+  let options = get_options();
   let (input, output_filename) = get_input();
-  let ast = src2ast(input);
-  ast2exe(ast, output_filename);
+  let ast = src2ast(input.clone(), &options);
+  if options.lint_only {
+    return;
+  }
+  ast2exe(ast, &input, output_filename, &options);
 }
@@ -1,13 +1,163 @@
 use crate::ast::AST;
+use crate::consteval::{eval_constants, fold_constants};
+use crate::diagnostic::Diagnostic;
+use crate::flow::{check_flow, check_initialization};
 use crate::interpreter::Interpreter;
 use crate::lexer::Lexer;
+use crate::lint::{deny_warnings, lint_program, LintConfig, LintLevel};
+use crate::lower::Overflow;
 use crate::parser::Parser;
+use crate::pass::{leave_ssa, optimize, OptLevel, Pipeline};
+use crate::peephole::optimize_asm;
+use crate::resolve::resolve;
+use crate::signature::{check_calls, collect_signatures};
+use crate::tailcall::check_tail_calls;
+use crate::typeck::check_program;
+use crate::types::{check_returns, check_types};
 use std::env;
 use std::fs;
 use std::process::Command;
 
+/// 编译选项，由以 `--` 开头的命令行参数指定
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+  /// `--release`：不生成调试用的检查代码（如未初始化变量的毒值），整数溢出默认回绕
+  pub release: bool,
+  /// `w lint <filename>`：只做检查，不生成代码
+  pub lint_only: bool,
+  /// `--allow NAME` / `--warn NAME` / `--deny NAME`（也可以写成 `--deny=NAME`）
+  pub lints: LintConfig,
+  /// `--infer-return-types`：允许 `auto` 作为函数的返回类型
+  pub infer_return_types: bool,
+  /// `--overflow=trap` / `--overflow=wrap`：整数溢出时的行为
+  pub overflow: Option<Overflow>,
+  /// `--dump-ir`：打印 SSA 形式的 IR
+  pub dump_ir: bool,
+  /// `--dump-inlining`：打印每处调用是否内联及其原因
+  pub dump_inlining: bool,
+  /// `--unroll-loops`：完全展开迭代次数是小常数的循环
+  pub unroll_loops: bool,
+  /// `--dump-peephole`：打印每个函数中各条窥孔优化规则生效的次数
+  pub dump_peephole: bool,
+  /// `-O0` / `-O1` / `-O2` / `-Os`，默认 `-O2`
+  pub opt_level: OptLevel,
+  /// `-C passes=a,b,...`：只运行列出的步骤，忽略优化级别
+  pub passes: Option<String>,
+}
+
+impl Options {
+  /// 未指定 `--overflow` 时，调试构建检查溢出，`--release` 构建回绕
+  pub fn overflow(&self) -> Overflow {
+    self.overflow.unwrap_or(if self.release {
+      Overflow::Wrap
+    } else {
+      Overflow::Trap
+    })
+  }
+
+  pub fn pipeline(&self) -> Pipeline {
+    match &self.passes {
+      Some(names) => Pipeline::from_names(names).unwrap(),
+      None => Pipeline::for_level(self.opt_level, self.unroll_loops),
+    }
+  }
+}
+
+/// 解析命令行参数（不含程序名），返回选项和其余的位置参数
+pub fn parse_args(args: &[String]) -> Result<(Options, Vec<String>), String> {
+  let mut options = Options::default();
+  let mut positional = vec![];
+  let mut args = args.iter();
+  while let Some(arg) = args.next() {
+    if let Some(level) = arg.strip_prefix("-O") {
+      options.opt_level = OptLevel::from_name(level).ok_or(format!(
+        "invalid optimization level `{}`; expected `-O0`, `-O1`, `-O2` or `-Os`",
+        arg
+      ))?;
+      continue;
+    }
+    if let Some(value) = arg.strip_prefix("-C") {
+      let value = match value {
+        "" => args
+          .next()
+          .cloned()
+          .ok_or("option `-C` expects `passes=<pass,...>`".to_string())?,
+        _ => value.to_string(),
+      };
+      let names = value
+        .strip_prefix("passes=")
+        .ok_or(format!("unknown codegen option `{}`", value))?;
+      Pipeline::from_names(names)?;
+      options.passes = Some(names.to_string());
+      continue;
+    }
+    let Some(option) = arg.strip_prefix("--") else {
+      if positional.is_empty() && !options.lint_only && arg == "lint" {
+        options.lint_only = true;
+      } else {
+        positional.push(arg.clone());
+      }
+      continue;
+    };
+    let (option, value) = match option.split_once('=') {
+      Some((option, value)) => (option, Some(value.to_string())),
+      None => (option, None),
+    };
+    if option == "release" && value.is_none() {
+      options.release = true;
+    } else if option == "infer-return-types" && value.is_none() {
+      options.infer_return_types = true;
+    } else if option == "dump-ir" && value.is_none() {
+      options.dump_ir = true;
+    } else if option == "dump-inlining" && value.is_none() {
+      options.dump_inlining = true;
+    } else if option == "unroll-loops" && value.is_none() {
+      options.unroll_loops = true;
+    } else if option == "dump-peephole" && value.is_none() {
+      options.dump_peephole = true;
+    } else if option == "overflow" {
+      let name = value.or_else(|| args.next().cloned()).unwrap_or_default();
+      options.overflow = Some(
+        Overflow::from_name(&name)
+          .ok_or("option `--overflow` expects `trap` or `wrap`".to_string())?,
+      );
+    } else if let Some(level) = LintLevel::from_name(option) {
+      let name = match value {
+        Some(name) => name,
+        None => args
+          .next()
+          .cloned()
+          .ok_or(format!("option `--{}` expects a lint name", option))?,
+      };
+      options.lints.set(level, &name)?;
+    } else {
+      return Err(format!("unknown option `{}`", arg));
+    }
+  }
+  Ok((options, positional))
+}
+
+fn parse_env_args() -> (Options, Vec<String>) {
+  let args: Vec<String> = env::args().skip(1).collect();
+  parse_args(&args).unwrap_or_else(|message| {
+    eprintln!("error: {}", message);
+    std::process::exit(1);
+  })
+}
+
+pub fn get_options() -> Options {
+  parse_env_args().0
+}
+
+/// 去掉选项和子命令之后的命令行参数（第一个是程序名）
+fn positional_args() -> Vec<String> {
+  let mut args = vec![env::args().next().unwrap_or_default()];
+  args.extend(parse_env_args().1);
+  args
+}
+
 pub fn get_input() -> (String, String) {
-  let args: Vec<String> = env::args().collect();
+  let args = positional_args();
   if args.len() == 2 {
     // args[1]指定了w语言编译器测试用的源文件（args[1] is of form `name`.w）
     let input_filename = &args[1];
@@ -17,10 +167,10 @@ pub fn get_input() -> (String, String) {
       output_filename.truncate(index); // Remove the extension
     }
     output_filename.push_str(".s"); // Add the new extension
-    return (
+    (
       fs::read_to_string(input_filename).expect("Failed to read file"),
       output_filename,
-    );
+    )
   } else if args.len() == 1 {
     // 没有指定源文件，使用tmp目录下的return_2.w作为源文件
     let current_dir = env::current_dir().expect("Failed to get current directory");
@@ -28,31 +178,109 @@ pub fn get_input() -> (String, String) {
       .to_str()
       .expect("Failed to convert current directory to string")
       .to_owned(); // Convert to owned String
-    print!("Current directory: {}\n", current_dir_str);
+    println!("Current directory: {}", current_dir_str);
     let filename = current_dir_str.clone() + "/../tmp/return_2.w";
     let output_filename = current_dir_str.clone() + "/../tmp/return_2.s";
     let input = fs::read_to_string(filename).expect("Failed to read file");
-    return (input, output_filename);
+    (input, output_filename)
   } else {
-    eprintln!("Usage: {} <filename>", args[0]);
+    eprintln!(
+      "Usage: {} [lint] [--release] [--overflow=trap|wrap] [--infer-return-types] [--dump-ir] [--dump-inlining] [-O0|-O1|-O2|-Os] [-C passes=<pass,...>] [--unroll-loops] [--dump-peephole] [--allow|--warn|--deny <lint>] <filename>",
+      args[0]
+    );
     std::process::exit(1);
   }
 }
 
-pub fn src2ast(input: String) -> AST {
+pub fn src2ast(input: String, options: &Options) -> AST {
   let lexer = Lexer::new(&input);
   let mut parser = Parser::new(lexer);
-  let ast = parser.parse();
+  let mut ast = parser.parse();
+  // 数组长度在收集函数签名之前求值
+  let (mut consts, diagnostics) = eval_constants(&mut ast);
+  report_diagnostics(&input, diagnostics, options);
+  let signatures = collect_signatures(&ast);
+  let mut diagnostics = check_types(&ast);
+  diagnostics.extend(check_returns(&ast));
+  diagnostics.extend(check_flow(&ast));
+  diagnostics.extend(check_calls(&ast, &signatures));
+  diagnostics.extend(check_tail_calls(&ast, &signatures));
+  diagnostics.extend(resolve(&mut ast));
+  diagnostics.extend(check_initialization(&ast));
+  report_diagnostics(&input, diagnostics, options);
+  // 类型检查依赖名字解析的结果，并且假定类型标注都是合法的
+  let diagnostics = check_program(&mut ast, &signatures, options.infer_return_types);
+  report_diagnostics(&input, diagnostics, options);
+  report_diagnostics(&input, lint_program(&ast, &options.lints), options);
+  report_diagnostics(&input, fold_constants(&mut ast, &mut consts), options);
+  // 类型检查之后打印，节点后标出推导出的类型
   println!("\n\n\n");
   let mut path = vec![];
   ast.print(0, &mut path);
   ast
 }
 
-pub fn ast2exe(mut ast: AST, asm_filename: String) {
+/// 打印诊断信息，有错误时终止编译
+pub fn report_diagnostics(source: &str, diagnostics: Vec<Diagnostic>, options: &Options) {
+  let diagnostics = if options.lints.deny_warnings {
+    deny_warnings(diagnostics)
+  } else {
+    diagnostics
+  };
+  for diagnostic in &diagnostics {
+    eprintln!("{}", diagnostic.render(source));
+  }
+  let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+  if errors > 0 {
+    eprintln!("error: aborting due to {} previous error(s)", errors);
+    std::process::exit(1);
+  }
+}
+
+pub fn ast2exe(ast: AST, source: &str, asm_filename: String, options: &Options) {
   // 解析抽象语法树，生成汇编代码
   let mut interpreter = Interpreter::new();
-  let asm = interpreter.generate_asm(&mut ast);
+  interpreter.debug = !options.release;
+  interpreter.overflow = options.overflow();
+  interpreter.source = source.to_string();
+  let pipeline = options.pipeline();
+  interpreter.allocate_registers = pipeline.regalloc;
+  let mut module = interpreter.lower(&ast);
+  // 不做 IR 优化时也不转为 SSA 形式，局部变量保留在各自的栈槽中
+  let remarks = if pipeline.passes.is_empty() {
+    vec![]
+  } else {
+    optimize(&mut module, &pipeline.passes)
+  };
+  if options.dump_inlining {
+    for remark in &remarks {
+      println!("{}", remark);
+    }
+  }
+  if options.dump_ir {
+    println!("{}", module);
+  }
+  leave_ssa(&mut module);
+  let mut asm = interpreter.generate_asm_module(&module);
+  if pipeline.peephole {
+    let remarks;
+    (asm, remarks) = optimize_asm(&asm);
+    if options.dump_peephole {
+      for remark in &remarks {
+        println!("{}", remark);
+      }
+    }
+  }
+  // 优化级别和运行的步骤记录在目标文件的 .comment 节中
+  let level = match options.passes {
+    Some(_) => "-C passes".to_string(),
+    None => options.opt_level.name().to_string(),
+  };
+  asm.push_str(&format!(
+    "\n\t.ident\t\"w {}: {}\"\n",
+    level,
+    pipeline.describe()
+  ));
 
   fs::write(&asm_filename, asm).expect("Failed to write to file");
   println!("Assembly code written to file: {}", asm_filename);
@@ -61,15 +289,15 @@ pub fn ast2exe(mut ast: AST, asm_filename: String) {
   let exe_file = asm_filename.clone().replace(".s", "");
   let mut cmd = Command::new("gcc");
 
-  let args: Vec<String> = env::args().collect();
-  if args.len() == 1 {
-    cmd.current_dir("/home/wm/0WM/W/tmp");
+  if positional_args().len() == 1 {
+    cmd.current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/../tmp"));
   }
   cmd
-    .arg("/home/wm/0WM/W/w/asm/print.o") // build in function
-    .arg("/home/wm/0WM/W/w/asm/scan.o") // build in function
+    .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/asm/print.o")) // build in function
+    .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/asm/scan.o")) // build in function
+    .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/asm/runtime.o")) // 运行时错误
     .arg(asm_filename)
-    .arg("-O0")
+    .arg(gcc_opt_level(options))
     .arg("-g")
     .arg("-o")
     .arg(exe_file);
@@ -86,3 +314,95 @@ pub fn ast2exe(mut ast: AST, asm_filename: String) {
     eprintln!("stderr: {}", stderr);
   }
 }
+
+/// 传给 gcc 的优化级别；gcc 只汇编和链接，这里只是保持一致
+fn gcc_opt_level(options: &Options) -> &'static str {
+  match options.passes {
+    Some(_) => "-O0",
+    None => options.opt_level.name(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+  }
+
+  #[test]
+  fn test_parse_args() {
+    let (options, positional) = parse_args(&args(&[
+      "lint",
+      "--deny",
+      "warnings",
+      "--allow=unused_functions",
+      "a.w",
+    ]))
+    .unwrap();
+    assert!(options.lint_only && options.lints.deny_warnings);
+    assert_eq!(
+      options.lints.levels.get("unused_functions"),
+      Some(&LintLevel::Allow)
+    );
+    assert_eq!(positional, vec!["a.w"]);
+
+    let (options, positional) = parse_args(&args(&[
+      "--release",
+      "--infer-return-types",
+      "--dump-ir",
+      "--dump-inlining",
+      "--unroll-loops",
+      "--dump-peephole",
+      "lint.w",
+    ]))
+    .unwrap();
+    assert!(options.release && options.infer_return_types && options.dump_ir && !options.lint_only);
+    assert!(options.dump_inlining && options.unroll_loops && options.dump_peephole);
+    assert_eq!(positional, vec!["lint.w"]);
+
+    assert_eq!(
+      parse_args(&args(&["--fast"])).unwrap_err(),
+      "unknown option `--fast`"
+    );
+    let (options, _) = parse_args(&args(&["--release", "a.w"])).unwrap();
+    assert_eq!(options.overflow(), Overflow::Wrap);
+    let (options, _) = parse_args(&args(&["--release", "--overflow", "trap", "a.w"])).unwrap();
+    assert_eq!(options.overflow(), Overflow::Trap);
+    assert_eq!(
+      parse_args(&args(&["--overflow=saturate"])).unwrap_err(),
+      "option `--overflow` expects `trap` or `wrap`"
+    );
+    assert_eq!(
+      parse_args(&args(&["--warn"])).unwrap_err(),
+      "option `--warn` expects a lint name"
+    );
+  }
+
+  #[test]
+  fn test_optimization_options() {
+    let (options, positional) = parse_args(&args(&["a.w"])).unwrap();
+    assert_eq!(options.opt_level, OptLevel::O2);
+    assert_eq!(positional, vec!["a.w"]);
+    let (options, _) = parse_args(&args(&["-O1", "-Os", "a.w"])).unwrap();
+    assert_eq!(options.opt_level, OptLevel::Os);
+    assert_eq!(gcc_opt_level(&options), "-Os");
+    assert_eq!(
+      parse_args(&args(&["-O3"])).unwrap_err(),
+      "invalid optimization level `-O3`; expected `-O0`, `-O1`, `-O2` or `-Os`"
+    );
+
+    let (options, _) = parse_args(&args(&["-C", "passes=constprop,regalloc", "a.w"])).unwrap();
+    assert_eq!(options.pipeline().describe(), "constprop,regalloc");
+    let (options, _) = parse_args(&args(&["-O0", "-Cpasses=", "a.w"])).unwrap();
+    assert_eq!(options.pipeline().describe(), "");
+    assert_eq!(
+      parse_args(&args(&["-C", "opt-level=2"])).unwrap_err(),
+      "unknown codegen option `opt-level=2`"
+    );
+    assert!(parse_args(&args(&["-C", "passes=fold"]))
+      .unwrap_err()
+      .starts_with("unknown pass `fold`"));
+  }
+}
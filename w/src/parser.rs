@@ -1,27 +1,41 @@
 use crate::ast::AST;
-use crate::lexer::Lexer;
+use crate::cst::{split_trivia, Checkpoint, GreenNodeBuilder, SyntaxNode};
+use crate::lexer::{Lexer, Span, Token, TokenKind};
+use crate::types::PRIMITIVE_TYPES;
 use core::panic;
 use std::collections::{HashSet, VecDeque};
 use std::vec;
 pub struct Parser<'a> {
   lexer: Lexer<'a>,
-  current_tokens: VecDeque<String>,
+  current_tokens: VecDeque<Token>,
   keywords: HashSet<String>,
+  /// 无损模式下同时构建 CST
+  cst_builder: Option<GreenNodeBuilder>,
+  /// 最后一个消耗的（非合成）token 的结束位置，用于计算节点区间
+  last_end: usize,
 }
 
 impl<'a> Parser<'a> {
   pub fn new(mut lexer: Lexer<'a>) -> Self {
     let mut current_tokens = VecDeque::new();
-    current_tokens.push_back(lexer.next_token());
+    current_tokens.push_back(lexer.read_token());
     let mut keywords = HashSet::new();
     keywords.insert("if".to_string());
     keywords.insert("else".to_string());
     keywords.insert("return".to_string());
     keywords.insert("pass".to_string());
+    keywords.insert("while".to_string());
+    keywords.insert("type".to_string());
+    keywords.insert("break".to_string());
+    keywords.insert("continue".to_string());
+    keywords.insert("const".to_string());
+    keywords.insert("static_assert".to_string());
     Parser {
       lexer,
-      current_tokens: current_tokens,
+      current_tokens,
       keywords,
+      cst_builder: None,
+      last_end: 0,
     }
   }
 
@@ -29,70 +43,340 @@ impl<'a> Parser<'a> {
     if self.current_tokens.len() <= 1 {
       self.prefetch_token();
     }
-    self.current_tokens.pop_front();
+    let token = self.current_tokens.pop_front().unwrap();
+    if !matches!(token.kind, TokenKind::Indent | TokenKind::Dedent) {
+      self.last_end = token.span.end;
+    }
+    self.push_cst_token(&token);
+  }
+
+  fn expect_token(&mut self, expected: &str, context: &str) {
+    if self.current_tokens[0] == expected {
+      self.consume_token();
+    } else {
+      panic!(
+        "{} error, expected `{}` but got `{}`",
+        context, expected, self.current_tokens[0]
+      );
+    }
+  }
+
+  fn peek(&mut self, n: usize) -> &Token {
+    while self.current_tokens.len() <= n {
+      self.prefetch_token();
+    }
+    &self.current_tokens[n]
+  }
+
+  /// 第 n 个 token 与前一个 token 在同一行
+  fn on_same_line(&mut self, n: usize) -> bool {
+    let trivia = self.peek(n).trivia;
+    !self.lexer.source()[trivia.start..trivia.end].contains('\n')
+  }
+
+  fn start_pos(&self) -> usize {
+    self.current_tokens[0].span.start
+  }
+
+  fn span_from(&self, start: usize) -> Span {
+    Span::new(start, self.last_end.max(start))
   }
 
   fn prefetch_token(&mut self) {
-    let token = self.lexer.next_token();
+    let token = self.lexer.read_token();
     self.current_tokens.push_back(token);
   }
 
+  fn start_node(&mut self, kind: &str) {
+    if let Some(builder) = &mut self.cst_builder {
+      builder.start_node(kind);
+    }
+  }
+
+  fn checkpoint(&self) -> Option<Checkpoint> {
+    self.cst_builder.as_ref().map(GreenNodeBuilder::checkpoint)
+  }
+
+  fn start_node_at(&mut self, checkpoint: Option<Checkpoint>, kind: &str) {
+    if let (Some(builder), Some(checkpoint)) = (&mut self.cst_builder, checkpoint) {
+      builder.start_node_at(checkpoint, kind);
+    }
+  }
+
+  fn finish_node(&mut self) {
+    if let Some(builder) = &mut self.cst_builder {
+      builder.finish_node();
+    }
+  }
+
+  /// 把 token 及其前导 trivia 加入 CST
+  fn push_cst_token(&mut self, token: &Token) {
+    if let Some(builder) = &mut self.cst_builder {
+      let source = self.lexer.source();
+      for (kind, text) in split_trivia(&source[token.trivia.start..token.trivia.end]) {
+        builder.token(kind, text);
+      }
+      if token.kind != TokenKind::Eof {
+        builder.token(token.kind, &source[token.span.start..token.span.end]);
+      }
+    }
+  }
+
   /// 自顶向下递归下降语法分析； 表格驱动语法分析
   pub fn parse(&mut self) -> AST {
+    self.parse_pg()
+  }
+
+  /// 无损模式：在生成 AST 的同时构建 CST，`cst.text()` 与源码逐字节相同。
+  /// 编辑工具和编译器共用同一个语法分析器。
+  pub fn parse_with_cst(&mut self) -> (AST, SyntaxNode) {
+    self.cst_builder = Some(GreenNodeBuilder::new());
     let ast = self.parse_pg();
-    ast
+    let builder = self.cst_builder.take().unwrap();
+    (ast, SyntaxNode::new_root(builder.finish()))
   }
 
   fn parse_pg(&mut self) -> AST {
-    println!("pg->Fn FnList");
-    let f = self.parse_fn();
+    println!("pg->Item FnList");
+    let start = self.start_pos();
+    self.start_node("Pg");
+    let f = self.parse_item();
     let fn_list = self.parse_fn_list();
-    AST::new("Pg".to_string(), vec![f, fn_list])
+    // 文件末尾的空白和注释挂在 EOF token 上
+    let eof = self.current_tokens[0].clone();
+    self.push_cst_token(&eof);
+    self.finish_node();
+    AST::new("Pg".to_string(), vec![f, fn_list]).with_span(self.span_from(start))
+  }
+
+  fn parse_item(&mut self) -> AST {
+    if self.current_tokens[0] == "type" {
+      println!("Item->TypeDef");
+      self.parse_type_def()
+    } else if self.current_tokens[0] == "static_assert" {
+      println!("Item->StaticAssert");
+      self.parse_static_assert()
+    } else {
+      self.parse_fn_or_const()
+    }
   }
 
-  fn parse_fn(&mut self) -> AST {
-    println!("Fn->Type Identifier Param FnBody FnList");
+  fn parse_type_def(&mut self) -> AST {
+    println!("TypeDef->type Identifier = Type");
+    let start = self.start_pos();
+    self.start_node("TypeDef");
+    self.consume_token(); // type token
+    let id = self.parse_identifier();
+    self.expect_token("=", "parse_type_def");
+    let ty = self.parse_type();
+    self.finish_node();
+    AST::new("TypeDef".to_string(), vec![id, ty]).with_span(self.span_from(start))
+  }
+
+  /// StaticAssert -> static_assert ( Expr ) | static_assert ( Expr , String )
+  fn parse_static_assert(&mut self) -> AST {
+    println!("StaticAssert->static_assert(Expr)");
+    let start = self.start_pos();
+    self.start_node("StaticAssert");
+    self.consume_token(); // static_assert token
+    self.expect_token("(", "parse_static_assert");
+    let mut children = vec![self.parse_expr()];
+    if self.current_tokens[0] == "," {
+      self.consume_token();
+      if self.current_tokens[0].kind != TokenKind::Str {
+        panic!(
+          "parse_static_assert error, expected a string message but got {}",
+          self.current_tokens[0]
+        );
+      }
+      children.push(self.parse_basic());
+    }
+    self.expect_token(")", "parse_static_assert");
+    self.finish_node();
+    AST::new("StaticAssert".to_string(), children).with_span(self.span_from(start))
+  }
+
+  /// Fn -> Attrs Type Identifier Param FnBody
+  ///     | Attrs const Type Identifier Param FnBody
+  /// Const -> const Type Identifier = Expr
+  ///
+  /// `const` 函数记为一个 `@const` 属性
+  fn parse_fn_or_const(&mut self) -> AST {
+    let start = self.start_pos();
+    let checkpoint = self.checkpoint();
+    let mut attrs = self.parse_attrs();
+    let is_const = self.current_tokens[0] == "const";
+    if is_const {
+      let span = self.current_tokens[0].span;
+      self.consume_token();
+      let name = AST::new("const".to_string(), vec![]).with_span(span);
+      attrs
+        .children
+        .push(AST::new("Attr".to_string(), vec![name]).with_span(span));
+    }
     let ty = self.parse_type();
     let id = self.parse_identifier();
+    if is_const && self.current_tokens[0] == "=" {
+      println!("Item->Const");
+      println!("Const->const Type Identifier = Expr");
+      if attrs.children.len() > 1 {
+        panic!("parse_fn_or_const error, attributes are only allowed on functions");
+      }
+      self.start_node_at(checkpoint, "Const");
+      self.consume_token(); // = token
+      let expr = self.parse_expr();
+      self.finish_node();
+      return AST::new("Const".to_string(), vec![ty, id, expr]).with_span(self.span_from(start));
+    }
+    println!("Item->Fn");
+    println!("Fn->Attrs Type Identifier Param FnBody FnList");
+    self.start_node_at(checkpoint, "Fn");
     let pa = self.parse_param();
     let fb = self.parse_fn_body();
-    AST::new("Fn".to_string(), vec![ty, id, pa, fb])
+    self.finish_node();
+    // 属性放在最后，函数的前四个子节点保持不变
+    AST::new("Fn".to_string(), vec![ty, id, pa, fb, attrs]).with_span(self.span_from(start))
+  }
+
+  /// Attrs -> Attr Attrs | ε
+  /// Attr -> @ Identifier | @ Identifier ( Identifier {, Identifier} )
+  fn parse_attrs(&mut self) -> AST {
+    let start = self.start_pos();
+    self.start_node("Attrs");
+    let mut attrs = vec![];
+    while self.current_tokens[0] == "@" {
+      println!("Attrs->Attr Attrs");
+      let attr_start = self.start_pos();
+      self.start_node("Attr");
+      self.consume_token(); // @ token
+      let mut children = vec![self.parse_identifier()];
+      if self.current_tokens[0] == "(" {
+        self.consume_token();
+        children.push(self.parse_identifier());
+        while self.current_tokens[0] == "," {
+          self.consume_token();
+          children.push(self.parse_identifier());
+        }
+        self.expect_token(")", "parse_attrs");
+      }
+      self.finish_node();
+      attrs.push(AST::new("Attr".to_string(), children).with_span(self.span_from(attr_start)));
+    }
+    println!("Attrs->ε");
+    self.finish_node();
+    AST::new("Attrs".to_string(), attrs).with_span(self.span_from(start))
   }
 
   fn parse_fn_list(&mut self) -> AST {
-    if self.current_tokens[0] == "".to_string() {
+    let start = self.start_pos();
+    self.start_node("FnList");
+    let children = if self.current_tokens[0] == "" {
       println!("FnList->ε");
-      return AST::new(
-        "FnList".to_string(),
-        vec![AST::new("ε".to_string(), vec![])],
-      );
-    }
-    println!("FnList->Fn FnList");
-    let f = self.parse_fn();
-    let fl = self.parse_fn_list();
-    AST::new("FnList".to_string(), vec![f, fl])
+      vec![AST::new("ε".to_string(), vec![])]
+    } else {
+      println!("FnList->Item FnList");
+      let f = self.parse_item();
+      let fl = self.parse_fn_list();
+      vec![f, fl]
+    };
+    self.finish_node();
+    AST::new("FnList".to_string(), children).with_span(self.span_from(start))
   }
 
+  /// Type -> Identifier
+  ///       | [ Type ; Integer ]
+  ///       | [ Type ; Expr ]             (长度在编译期求值)
+  ///       | [ Type ]
+  ///       | fn ( TypeList ) -> Type
   fn parse_type(&mut self) -> AST {
-    if self.current_tokens[0] != "".to_string() {
-      println!("Type->{}", self.current_tokens[0]);
-      let token = self.current_tokens[0].clone();
+    let start = self.start_pos();
+    self.start_node("Type");
+    let inner = if self.current_tokens[0] == "[" {
+      let checkpoint = self.checkpoint();
       self.consume_token();
-      return AST::new(token, vec![]);
+      let elem = self.parse_type();
+      if self.current_tokens[0] == ";" {
+        println!("Type->[Type; Integer]");
+        self.start_node_at(checkpoint, "ArrayType");
+        self.consume_token();
+        // 整数字面量之外的长度是常量表达式，由常量求值替换成字面量
+        let len = if self.current_tokens[0].kind == TokenKind::Number && *self.peek(1) == "]" {
+          self.parse_basic()
+        } else {
+          self.parse_expr()
+        };
+        self.expect_token("]", "parse_type");
+        self.finish_node();
+        AST::new("ArrayType".to_string(), vec![elem, len])
+      } else {
+        println!("Type->[Type]");
+        self.start_node_at(checkpoint, "SliceType");
+        self.expect_token("]", "parse_type");
+        self.finish_node();
+        AST::new("SliceType".to_string(), vec![elem])
+      }
+    } else if self.current_tokens[0] == "fn" {
+      println!("Type->fn(TypeList) -> Type");
+      self.start_node("FnType");
+      self.consume_token(); // fn token
+      self.expect_token("(", "parse_type");
+      let list_start = self.start_pos();
+      self.start_node("TypeList");
+      let mut params = vec![];
+      while self.current_tokens[0] != ")" {
+        params.push(self.parse_type());
+        if self.current_tokens[0] == "," {
+          self.consume_token();
+        }
+      }
+      self.finish_node();
+      let params = AST::new("TypeList".to_string(), params).with_span(self.span_from(list_start));
+      self.expect_token(")", "parse_type");
+      self.expect_token("->", "parse_type");
+      let ret = self.parse_type();
+      self.finish_node();
+      AST::new("FnType".to_string(), vec![params, ret])
+    } else if self.current_tokens[0].kind == TokenKind::Ident {
+      println!("Type->{}", self.current_tokens[0]);
+      self.parse_identifier()
     } else {
       panic!(
         "parse_type error, expected Type, but got {}",
         self.current_tokens[0]
       );
+    };
+    self.finish_node();
+    let span = self.span_from(start);
+    let inner = if inner.children.is_empty() {
+      inner
+    } else {
+      inner.with_span(span)
+    };
+    AST::new("Type".to_string(), vec![inner]).with_span(span)
+  }
+
+  /// 当前位置是否以类型开头（用于区分变量声明/定义和其他语句）
+  fn at_type_start(&mut self) -> bool {
+    let first = self.peek(0).clone();
+    if first == "[" || first == "fn" || PRIMITIVE_TYPES.contains(&first.value.as_str()) {
+      return true;
     }
+    // 用户定义的类型名：`Meters d`，两个标识符位于同一行
+    let second = self.peek(1).clone();
+    first.kind == TokenKind::Ident
+      && second.kind == TokenKind::Ident
+      && !self.keywords.contains(&first.value)
+      && !self.keywords.contains(&second.value)
+      && self.on_same_line(1)
   }
 
   fn parse_identifier(&mut self) -> AST {
-    if self.current_tokens[0] != "".to_string() {
+    if self.current_tokens[0] != "" {
       println!("Identifier->{}", self.current_tokens[0]);
-      let token = self.current_tokens[0].clone();
+      let token = self.current_tokens[0].value.clone();
+      let span = self.current_tokens[0].span;
       self.consume_token();
-      return AST::new(token, vec![]);
+      AST::new(token, vec![]).with_span(span)
     } else {
       panic!(
         "parse_identifier error, expected Identifier, but got {}",
@@ -103,6 +387,8 @@ impl<'a> Parser<'a> {
 
   fn parse_param(&mut self) -> AST {
     println!("Param->(ParamList)");
+    let start = self.start_pos();
+    self.start_node("Param");
     let pl: AST;
     if self.current_tokens[0] == "(" {
       self.consume_token();
@@ -121,52 +407,93 @@ impl<'a> Parser<'a> {
         self.current_tokens[0]
       );
     }
-    return AST::new("Param".to_string(), vec![pl]);
+    self.finish_node();
+    AST::new("Param".to_string(), vec![pl]).with_span(self.span_from(start))
   }
 
   fn parse_param_list(&mut self) -> AST {
-    if self.current_tokens[0] == ")".to_string() {
+    let start = self.start_pos();
+    self.start_node("ParamList");
+    let children = if self.current_tokens[0] == ")" {
       println!("ParamList->ε");
-      return AST::new(
-        "ParamList".to_string(),
-        vec![AST::new("ε".to_string(), vec![])],
-      );
-    }
-    println!("ParamList->Type Identifier ParamListTail");
-    let ty = self.parse_type();
-    let id = self.parse_identifier();
-    let plt = self.parse_param_list_tail();
-    return AST::new("ParamList".to_string(), vec![ty, id, plt]);
+      vec![AST::new("ε".to_string(), vec![])]
+    } else {
+      println!("ParamList->Type Identifier Default ParamListTail");
+      let ty = self.parse_param_type();
+      let id = self.parse_identifier();
+      let de = self.parse_param_default();
+      let plt = self.parse_param_list_tail();
+      vec![ty, id, de, plt]
+    };
+    self.finish_node();
+    AST::new("ParamList".to_string(), children).with_span(self.span_from(start))
   }
 
   fn parse_param_list_tail(&mut self) -> AST {
-    if self.current_tokens[0] == ")".to_string() {
+    let start = self.start_pos();
+    self.start_node("ParamListTail");
+    let children = if self.current_tokens[0] == ")" {
       println!("ParamListTail->ε");
-      return AST::new(
-        "ParamListTail".to_string(),
-        vec![AST::new("ε".to_string(), vec![])],
-      );
+      vec![AST::new("ε".to_string(), vec![])]
+    } else {
+      println!("ParamListTail->, Type Identifier Default ParamListTail");
+      if self.current_tokens[0] == "," {
+        self.consume_token();
+        let ty = self.parse_param_type();
+        let id = self.parse_identifier();
+        let de = self.parse_param_default();
+        let plt = self.parse_param_list_tail();
+        vec![ty, id, de, plt]
+      } else {
+        panic!(
+          "parse_param_list_tail error, expected `,` but got `{}`",
+          self.current_tokens[0]
+        );
+      }
+    };
+    self.finish_node();
+    AST::new("ParamListTail".to_string(), children).with_span(self.span_from(start))
+  }
+
+  /// 参数类型，`int... xs` 表示变长参数：Type[Variadic[Type[int]]]
+  fn parse_param_type(&mut self) -> AST {
+    let start = self.start_pos();
+    let checkpoint = self.checkpoint();
+    let ty = self.parse_type();
+    if self.current_tokens[0] != "..." {
+      return ty;
     }
-    println!("ParamListTail->, Type Identifier ParamListTail");
-    let ty: AST;
-    let id: AST;
-    let plt: AST;
-    if self.current_tokens[0] == "," {
+    println!("Type->Type...");
+    self.start_node_at(checkpoint, "Type");
+    self.start_node_at(checkpoint, "Variadic");
+    self.consume_token();
+    self.finish_node();
+    self.finish_node();
+    let span = self.span_from(start);
+    let variadic = AST::new("Variadic".to_string(), vec![ty]).with_span(span);
+    AST::new("Type".to_string(), vec![variadic]).with_span(span)
+  }
+
+  /// 参数默认值：Default -> = Expr | ε
+  fn parse_param_default(&mut self) -> AST {
+    let start = self.start_pos();
+    self.start_node("Default");
+    let children = if self.current_tokens[0] == "=" {
+      println!("Default->= Expr");
       self.consume_token();
-      ty = self.parse_type();
-      id = self.parse_identifier();
-      plt = self.parse_param_list_tail();
+      vec![self.parse_expr()]
     } else {
-      panic!(
-        "parse_param_list_tail error, expected `,` but got `{}`",
-        self.current_tokens[0]
-      );
-    }
-    return AST::new("ParamListTail".to_string(), vec![ty, id, plt]);
+      println!("Default->ε");
+      vec![AST::new("ε".to_string(), vec![])]
+    };
+    self.finish_node();
+    AST::new("Default".to_string(), children).with_span(self.span_from(start))
   }
 
   fn parse_fn_body(&mut self) -> AST {
     println!("FnBody->{{StmtList}}");
+    let start = self.start_pos();
+    self.start_node("FnBody");
     let sl: AST;
     if self.current_tokens[0] == "{" {
       self.consume_token();
@@ -185,36 +512,43 @@ impl<'a> Parser<'a> {
         self.current_tokens[0]
       );
     }
-    AST::new("FnBody".to_string(), vec![sl])
+    self.finish_node();
+    AST::new("FnBody".to_string(), vec![sl]).with_span(self.span_from(start))
   }
 
   fn parse_stmt_list(&mut self) -> AST {
-    if self.current_tokens[0] != "}" {
+    let start = self.start_pos();
+    self.start_node("StmtList");
+    let children = if self.current_tokens[0] != "}" {
       println!("StmtList->Stmt StmtList");
       let s = self.parse_stmt();
       let sl = self.parse_stmt_list();
-      return AST::new("StmtList".to_string(), vec![s, sl]);
+      vec![s, sl]
     } else {
       println!("StmtList->ε");
-      return AST::new(
-        "StmtList".to_string(),
-        vec![AST::new("ε".to_string(), vec![])],
-      );
-    }
+      vec![AST::new("ε".to_string(), vec![])]
+    };
+    self.finish_node();
+    AST::new("StmtList".to_string(), children).with_span(self.span_from(start))
   }
 
   fn parse_loop_stmt(&mut self) -> AST {
     println!("LoopStmt->while Expr {{ StmtList }}");
+    let start = self.start_pos();
+    self.start_node("LoopStmt");
     self.consume_token(); // while token
     let ex = self.parse_expr();
     self.consume_token(); // { token
     let sl = self.parse_stmt_list();
     self.consume_token(); // } token
-    return AST::new("LoopStmt".to_string(), vec![ex, sl]);
+    self.finish_node();
+    AST::new("LoopStmt".to_string(), vec![ex, sl]).with_span(self.span_from(start))
   }
 
   fn parse_branch_stmt(&mut self) -> AST {
     println!("BranchStmt->if Expr {{ StmtList }} else {{ StmtList }}");
+    let start = self.start_pos();
+    self.start_node("BranchStmt");
     self.consume_token(); // if token
     let ex = self.parse_expr();
     self.consume_token(); // { token
@@ -224,109 +558,155 @@ impl<'a> Parser<'a> {
     self.consume_token(); // { token
     let sl2 = self.parse_stmt_list();
     self.consume_token(); // } token
-    return AST::new("BranchStmt".to_string(), vec![ex, sl1, sl2]);
+    self.finish_node();
+    AST::new("BranchStmt".to_string(), vec![ex, sl1, sl2]).with_span(self.span_from(start))
   }
 
+  /// Stmt -> Attrs StmtInner
+  ///
+  /// 语句的属性（如 `@tailcall`）放在语句之后作为第二个子节点，没有属性的语句只有一个子节点
   fn parse_stmt(&mut self) -> AST {
+    let start = self.start_pos();
+    self.start_node("Stmt");
+    let attrs = (self.current_tokens[0] == "@").then(|| self.parse_attrs());
+    let stmt = self.parse_stmt_inner();
+    self.finish_node();
+    let children = std::iter::once(stmt).chain(attrs).collect();
+    AST::new("Stmt".to_string(), children).with_span(self.span_from(start))
+  }
+
+  fn parse_stmt_inner(&mut self) -> AST {
     if self.current_tokens[0] == "while" {
       println!("Stmt->LoopStmt");
-      let ls = self.parse_loop_stmt();
-      return AST::new("Stmt".to_string(), vec![ls]);
+      return self.parse_loop_stmt();
     }
     if self.current_tokens[0] == "if" {
       println!("Stmt->BranchStmt");
-      let bs = self.parse_branch_stmt();
-      return AST::new("Stmt".to_string(), vec![bs]);
+      return self.parse_branch_stmt();
     }
     if self.current_tokens[0] == "return" {
       println!("Stmt->Return");
-      let rtn = self.parse_return();
-      return AST::new("Stmt".to_string(), vec![rtn]);
+      return self.parse_return();
     }
-    while self.current_tokens.len() < 3 {
-      self.prefetch_token();
-    }
-    if self.current_tokens[2] == "=".to_string() {
-      println!("Stmt->VarDef");
-      let vd = self.parse_var_def();
-      return AST::new("Stmt".to_string(), vec![vd]);
-    } else if self.current_tokens[1] == "=".to_string() {
-      println!("Stmt->Assign");
-      let a = self.parse_assign();
-      return AST::new("Stmt".to_string(), vec![a]);
-    } else if self.current_tokens[0] == "pass".to_string() {
-      println!("Stmt->pass");
+    if self.current_tokens[0] == "pass"
+      || self.current_tokens[0] == "break"
+      || self.current_tokens[0] == "continue"
+    {
+      println!("Stmt->{}", self.current_tokens[0]);
+      let keyword = self.current_tokens[0].value.clone();
+      let span = self.current_tokens[0].span;
       self.consume_token();
-      return AST::new(
-        "Stmt".to_string(),
-        vec![AST::new("pass".to_string(), vec![])],
-      );
-    } else if self.keywords.contains(self.current_tokens[0].as_str()) {
-      println!("Stmt->VarDecl");
-      let vd = self.parse_var_decl();
-      return AST::new("Stmt".to_string(), vec![vd]);
+      AST::new(keyword, vec![]).with_span(span)
+    } else if self.at_type_start() {
+      println!("Stmt->VarDecl|VarDef");
+      self.parse_var_decl_or_def()
+    } else if *self.peek(1) == "=" {
+      println!("Stmt->Assign");
+      self.parse_assign()
     } else {
       println!("Stmt->Expr");
-      let e = self.parse_expr();
-      return AST::new("Stmt".to_string(), vec![e]);
+      self.parse_expr()
     }
   }
 
   fn parse_fn_call(&mut self) -> AST {
     println!("FnCall->Identifier(ExprList)");
-    let fn_name = self.current_tokens[0].clone();
-    self.consume_token(); // 跳过函数名
+    let start = self.start_pos();
+    self.start_node("FnCall");
+    let fn_name = self.parse_identifier(); // 函数名
     self.consume_token(); // 跳过左括号
     let mut expr_list = vec![];
-    expr_list.push(AST::new(fn_name, vec![]));
-    while self.current_tokens[0] != ")".to_string() {
-      let expr = self.parse_expr();
+    expr_list.push(fn_name);
+    while self.current_tokens[0] != ")" {
+      let expr = if self.peek(0).kind == TokenKind::Ident && *self.peek(1) == "=" {
+        self.parse_named_arg()
+      } else {
+        self.parse_expr()
+      };
       expr_list.push(expr);
       if self.current_tokens[0] == "," {
         self.consume_token();
       }
     }
     self.consume_token();
-    return AST::new("FnCall".to_string(), expr_list);
+    self.finish_node();
+    AST::new("FnCall".to_string(), expr_list).with_span(self.span_from(start))
   }
 
-  fn parse_return(&mut self) -> AST {
-    println!("Return->Expr");
-    self.consume_token();
+  /// 下标访问：Index -> Identifier [ Expr ]
+  fn parse_index(&mut self) -> AST {
+    println!("Index->Identifier[Expr]");
+    let start = self.start_pos();
+    self.start_node("Index");
+    let id = self.parse_identifier();
+    self.expect_token("[", "parse_index");
     let ex = self.parse_expr();
-    return AST::new("Return".to_string(), vec![ex]);
+    self.expect_token("]", "parse_index");
+    self.finish_node();
+    AST::new("Index".to_string(), vec![id, ex]).with_span(self.span_from(start))
   }
 
-  fn parse_var_decl(&mut self) -> AST {
-    println!("VarDecl->Type Identifier");
-    let ty = self.parse_type();
+  /// 关键字参数：NamedArg -> Identifier = Expr
+  fn parse_named_arg(&mut self) -> AST {
+    println!("NamedArg->Identifier = Expr");
+    let start = self.start_pos();
+    self.start_node("NamedArg");
     let id = self.parse_identifier();
-    return AST::new("VarDecl".to_string(), vec![ty, id]);
+    self.expect_token("=", "parse_named_arg");
+    let ex = self.parse_expr();
+    self.finish_node();
+    AST::new("NamedArg".to_string(), vec![id, ex]).with_span(self.span_from(start))
+  }
+
+  fn parse_return(&mut self) -> AST {
+    let start = self.start_pos();
+    self.start_node("Return");
+    self.consume_token();
+    // `return` 之后同一行没有表达式时是不带返回值的 return
+    let bare =
+      self.current_tokens[0] == "}" || self.current_tokens[0] == "" || !self.on_same_line(0);
+    let children = if bare {
+      println!("Return->ε");
+      vec![]
+    } else {
+      println!("Return->Expr");
+      vec![self.parse_expr()]
+    };
+    self.finish_node();
+    AST::new("Return".to_string(), children).with_span(self.span_from(start))
   }
 
-  fn parse_var_def(&mut self) -> AST {
-    println!("VarDef->Type Identifier = Expr");
+  /// 类型写完之后才知道是 VarDecl 还是 VarDef，CST 节点通过 checkpoint 补建
+  fn parse_var_decl_or_def(&mut self) -> AST {
+    let start = self.start_pos();
+    let checkpoint = self.checkpoint();
     let ty = self.parse_type();
     let id = self.parse_identifier();
     if self.current_tokens[0] == "=" {
+      println!("VarDef->Type Identifier = Expr");
+      self.start_node_at(checkpoint, "VarDef");
       self.consume_token();
       let ex = self.parse_expr();
-      return AST::new("VarDef".to_string(), vec![ty, id, ex]);
+      self.finish_node();
+      AST::new("VarDef".to_string(), vec![ty, id, ex]).with_span(self.span_from(start))
     } else {
-      panic!(
-        "parse_var_def error, expected = but got {}",
-        self.current_tokens[0]
-      );
+      println!("VarDecl->Type Identifier");
+      self.start_node_at(checkpoint, "VarDecl");
+      self.finish_node();
+      AST::new("VarDecl".to_string(), vec![ty, id]).with_span(self.span_from(start))
     }
   }
 
   fn parse_assign(&mut self) -> AST {
     println!("Assign->Identifier = Expr");
+    let start = self.start_pos();
+    self.start_node("Assign");
     let id = self.parse_identifier();
     if self.current_tokens[0] == "=" {
       self.consume_token();
       let ex = self.parse_expr();
-      return AST::new("Assign".to_string(), vec![id, ex]);
+      self.finish_node();
+      AST::new("Assign".to_string(), vec![id, ex]).with_span(self.span_from(start))
     } else {
       panic!(
         "parse_assign error, expected = but got {}",
@@ -337,9 +717,12 @@ impl<'a> Parser<'a> {
 
   fn parse_expr(&mut self) -> AST {
     // TODO 应该改为根据优先级解析，而不是仅仅三层解析
+    let start = self.start_pos();
+    self.start_node("Expr");
     let left = self.parse_term();
-    let op = self.current_tokens[0].clone();
-    if op == "+"
+    let op = self.current_tokens[0].value.clone();
+    let op_span = self.current_tokens[0].span;
+    let children = if op == "+"
       || op == "-"
       || op == "=="
       || op == "!="
@@ -351,32 +734,41 @@ impl<'a> Parser<'a> {
       self.consume_token();
       let right = self.parse_expr();
       println!("Expr->Term {} Expr", op);
-      return AST::new("Expr".to_string(), vec![left, AST::new(op, vec![]), right]);
+      vec![left, AST::new(op, vec![]).with_span(op_span), right]
     } else {
       println!("Expr->Term");
-      return AST::new("Expr".to_string(), vec![left]);
-    }
+      vec![left]
+    };
+    self.finish_node();
+    AST::new("Expr".to_string(), children).with_span(self.span_from(start))
   }
 
   fn parse_term(&mut self) -> AST {
+    let start = self.start_pos();
+    self.start_node("Term");
     let left = self.parse_factor();
-    let op = self.current_tokens[0].clone();
-    if op == "*" || op == "/" {
+    let op = self.current_tokens[0].value.clone();
+    let op_span = self.current_tokens[0].span;
+    let children = if op == "*" || op == "/" {
       self.consume_token();
       let right = self.parse_term();
       println!("Term->Factor {} Term", op);
-      return AST::new("Term".to_string(), vec![left, AST::new(op, vec![]), right]);
+      vec![left, AST::new(op, vec![]).with_span(op_span), right]
     } else {
       println!("Term->Factor");
-      return AST::new("Term".to_string(), vec![left]);
-    }
+      vec![left]
+    };
+    self.finish_node();
+    AST::new("Term".to_string(), children).with_span(self.span_from(start))
   }
 
   fn parse_factor(&mut self) -> AST {
     while self.current_tokens.len() < 2 {
       self.prefetch_token();
     }
-    if self.current_tokens[0] == "(" {
+    let start = self.start_pos();
+    self.start_node("Factor");
+    let children = if self.current_tokens[0] == "(" {
       println!("Factor->(Expr)");
       self.consume_token();
       let ex = self.parse_expr();
@@ -388,31 +780,37 @@ impl<'a> Parser<'a> {
           self.current_tokens[0]
         );
       }
-      return AST::new(
-        "Factor".to_string(),
-        vec![
-          AST::new("(".to_string(), vec![]),
-          ex,
-          AST::new(")".to_string(), vec![]),
-        ],
-      );
+      vec![
+        AST::new("(".to_string(), vec![]),
+        ex,
+        AST::new(")".to_string(), vec![]),
+      ]
     } else if self.current_tokens[1] == "(" {
       println!("Factor->FnCall");
-      let fc = self.parse_fn_call();
-      return AST::new("Factor".to_string(), vec![fc]);
+      vec![self.parse_fn_call()]
+    } else if self.current_tokens[1] == "[" {
+      println!("Factor->Index");
+      vec![self.parse_index()]
     } else {
       println!("Factor->Basic");
-      let bs = self.parse_basic();
-      return AST::new("Factor".to_string(), vec![bs]);
-    }
+      vec![self.parse_basic()]
+    };
+    self.finish_node();
+    AST::new("Factor".to_string(), children).with_span(self.span_from(start))
   }
 
   fn parse_basic(&mut self) -> AST {
-    if self.current_tokens[0] != "".to_string() {
+    if self.current_tokens[0].kind != TokenKind::Eof {
       println!("Basic->{}", self.current_tokens[0]);
-      let token = self.current_tokens[0].clone();
+      let span = self.current_tokens[0].span;
+      // 字符串字面量保留引号，以便与标识符区分
+      let token = if self.current_tokens[0].kind == TokenKind::Str {
+        self.lexer.source()[span.start..span.end].to_string()
+      } else {
+        self.current_tokens[0].value.clone()
+      };
       self.consume_token();
-      return AST::new("".to_string() + token.as_str(), vec![]);
+      AST::new(token, vec![]).with_span(span)
     } else {
       panic!(
         "parse_basic error, expected Basic, but got {}",
@@ -421,3 +819,120 @@ impl<'a> Parser<'a> {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::cst::{AstNode, SourceFile};
+  use crate::signature::param_nodes;
+
+  const SOURCE: &str = "int main()
+  # 读入并累加
+  int b = scan()   # trailing comment
+  print(b + add(1,  2))
+  return 0
+
+int add(int a, int b)
+  if a > b
+    return a - b
+  else
+    return a + b
+";
+
+  #[test]
+  fn test_cst_round_trip() {
+    let mut parser = Parser::new(Lexer::new(SOURCE));
+    let (_, cst) = parser.parse_with_cst();
+    assert_eq!(cst.text(), SOURCE);
+    assert_eq!(cst.text_range().end, SOURCE.len());
+  }
+
+  #[test]
+  fn test_cst_round_trip_without_trailing_newline() {
+    let source = "int main()\n  return 0";
+    let (_, cst) = Parser::new(Lexer::new(source)).parse_with_cst();
+    assert_eq!(cst.text(), source);
+  }
+
+  #[test]
+  fn test_cst_matches_ast() {
+    let (ast, cst) = Parser::new(Lexer::new(SOURCE)).parse_with_cst();
+    let plain = Parser::new(Lexer::new(SOURCE)).parse();
+    assert_eq!(ast.get_expression(), plain.get_expression());
+    assert_eq!(cst.kind(), ast.value);
+  }
+
+  #[test]
+  fn test_fn_attributes() {
+    let source = "@allow(unused_variables, empty_branch)\n@inline\nint main()\n  return 0\n";
+    let (ast, cst) = Parser::new(Lexer::new(source)).parse_with_cst();
+    assert_eq!(cst.text(), source);
+    let f = &ast.children[0];
+    assert_eq!(f.children[1].value, "main");
+    let attrs: Vec<Vec<String>> = f.fn_attrs().iter().map(AST::get_expression).collect();
+    assert_eq!(
+      attrs,
+      vec![
+        vec!["allow", "unused_variables", "empty_branch"],
+        vec!["inline"]
+      ]
+    );
+    let functions = SourceFile::cast(cst).unwrap().functions();
+    assert_eq!(functions[0].name().unwrap().text(), "main");
+  }
+
+  #[test]
+  fn test_stmt_attributes() {
+    let source = "int f(int n)\n  @tailcall\n  return f(n)\n";
+    let (ast, cst) = Parser::new(Lexer::new(source)).parse_with_cst();
+    assert_eq!(cst.text(), source);
+    let stmt = &ast.children[0].children[3].children[0].children[0];
+    assert_eq!(stmt.children[0].value, "Return");
+    assert!(stmt.has_attr("tailcall"));
+    let functions = SourceFile::cast(cst).unwrap().functions();
+    let statements = functions[0].body().unwrap().statements();
+    assert_eq!(statements[0].inner().unwrap().kind(), "Return");
+  }
+
+  #[test]
+  fn test_const_items() {
+    let source = "const int N = 2 * 3\nstatic_assert(N > 1, \"too small\")\nconst int f([int; N + 1] xs)\n  return 0\n";
+    let (ast, cst) = Parser::new(Lexer::new(source)).parse_with_cst();
+    assert_eq!(cst.text(), source);
+    let items: Vec<&str> = ast.children.iter().map(|c| c.value.as_str()).collect();
+    assert_eq!(items, vec!["Const", "FnList"]);
+    let def = &ast.children[0];
+    assert_eq!(def.children[1].value, "N");
+    assert_eq!(def.children[2].get_expression(), vec!["2", "*", "3"]);
+    let assert = &ast.children[1].children[0];
+    assert_eq!(assert.value, "StaticAssert");
+    assert_eq!(assert.children[1].value, "\"too small\"");
+    let f = &ast.children[1].children[1].children[0];
+    assert!(f.has_attr("const"));
+    // 不是字面量的数组长度保留为表达式，由常量求值替换
+    let param = param_nodes(&f.children[2])[0];
+    assert_eq!(param.children[0].children[0].children[1].value, "Expr");
+  }
+
+  #[test]
+  fn test_typed_view() {
+    let (_, cst) = Parser::new(Lexer::new(SOURCE)).parse_with_cst();
+    let file = SourceFile::cast(cst).unwrap();
+    let functions = file.functions();
+    assert_eq!(functions.len(), 2);
+    assert_eq!(functions[0].name().unwrap().text(), "main");
+    assert_eq!(functions[1].ret_type().unwrap().text(), "int");
+
+    let params = functions[1].param_list().unwrap().params();
+    let names: Vec<&str> = params.iter().map(|(_, name)| name.text()).collect();
+    assert_eq!(names, vec!["a", "b"]);
+
+    let stmts = functions[0].body().unwrap().statements();
+    assert_eq!(stmts.len(), 3);
+    let def = crate::cst::VarDef::cast(stmts[0].inner().unwrap()).unwrap();
+    assert_eq!(def.name().unwrap().text(), "b");
+    assert_eq!(def.value().unwrap().syntax().text().trim(), "scan()");
+    let name = def.name().unwrap();
+    assert_eq!(&SOURCE[name.text_range().start..name.text_range().end], "b");
+  }
+}
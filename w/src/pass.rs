@@ -0,0 +1,387 @@
+//! 优化流程：把每个函数转为 SSA 形式，依次运行各个优化，最后消除 phi 交给后端。
+//! 每个优化之后都用 `ssa::verify` 检查，出错说明优化有 bug，直接 panic。
+//!
+//! 运行哪些优化由优化级别 `-O0` / `-O1` / `-O2` / `-Os` 决定，
+//! 也可以用 `-C passes=...` 按名字逐个指定，其中还可以包括后端的寄存器分配和窥孔优化。
+
+use crate::constprop::propagate_constants;
+use crate::dce::{eliminate_dead_code, eliminate_dead_stores, remove_unreachable_functions};
+use crate::gvn::{global_value_numbering, local_value_numbering};
+use crate::inline::inline_functions;
+use crate::ir::{Function, Module};
+use crate::loops::{hoist_loop_invariants, reduce_strength, unroll_loops};
+use crate::ssa::{into_ssa, out_of_ssa, verify};
+use crate::tailcall::eliminate_tail_calls;
+
+#[derive(Clone, Copy)]
+pub enum Run {
+  /// 作用于单个函数
+  Function(fn(&mut Function)),
+  /// 作用于整个模块，返回向用户报告的决策（如内联的决定）
+  Module(fn(&mut Module) -> Vec<String>),
+}
+
+#[derive(Clone, Copy)]
+pub struct Pass {
+  pub name: &'static str,
+  pub run: Run,
+}
+
+/// `-O2` 运行的优化，按顺序。内联之后再做一遍化简，然后把尾调用改为跳转，
+/// 再做循环优化（尾递归改成的循环也包括在内）
+pub const DEFAULT_PASSES: &[Pass] = &[
+  Pass {
+    name: "constprop",
+    run: Run::Function(propagate_constants),
+  },
+  Pass {
+    name: "gvn",
+    run: Run::Function(global_value_numbering),
+  },
+  Pass {
+    name: "dse",
+    run: Run::Function(eliminate_dead_stores),
+  },
+  Pass {
+    name: "dce",
+    run: Run::Function(eliminate_dead_code),
+  },
+  Pass {
+    name: "inline",
+    run: Run::Module(inline_functions),
+  },
+  Pass {
+    name: "constprop",
+    run: Run::Function(propagate_constants),
+  },
+  Pass {
+    name: "gvn",
+    run: Run::Function(global_value_numbering),
+  },
+  Pass {
+    name: "dse",
+    run: Run::Function(eliminate_dead_stores),
+  },
+  Pass {
+    name: "dce",
+    run: Run::Function(eliminate_dead_code),
+  },
+  Pass {
+    name: "tailcall",
+    run: Run::Function(eliminate_tail_calls),
+  },
+  Pass {
+    name: "licm",
+    run: Run::Function(hoist_loop_invariants),
+  },
+  Pass {
+    name: "strength",
+    run: Run::Function(reduce_strength),
+  },
+  Pass {
+    name: "dce",
+    run: Run::Function(eliminate_dead_code),
+  },
+];
+
+/// `--unroll-loops` 时接在默认优化之后：展开循环，再化简展开后的代码
+pub const UNROLL_PASSES: &[Pass] = &[
+  Pass {
+    name: "unroll",
+    run: Run::Function(unroll_loops),
+  },
+  Pass {
+    name: "constprop",
+    run: Run::Function(propagate_constants),
+  },
+  Pass {
+    name: "dce",
+    run: Run::Function(eliminate_dead_code),
+  },
+];
+
+/// `-O1`：只做不增大代码的局部化简，值编号只在基本块内进行
+pub const BASIC_PASSES: &[Pass] = &[
+  Pass {
+    name: "constprop",
+    run: Run::Function(propagate_constants),
+  },
+  Pass {
+    name: "lvn",
+    run: Run::Function(local_value_numbering),
+  },
+  Pass {
+    name: "dse",
+    run: Run::Function(eliminate_dead_stores),
+  },
+  Pass {
+    name: "dce",
+    run: Run::Function(eliminate_dead_code),
+  },
+];
+
+/// `-Os`：不内联、不做会增加指令的强度削减
+pub const SIZE_PASSES: &[Pass] = &[
+  Pass {
+    name: "constprop",
+    run: Run::Function(propagate_constants),
+  },
+  Pass {
+    name: "gvn",
+    run: Run::Function(global_value_numbering),
+  },
+  Pass {
+    name: "dse",
+    run: Run::Function(eliminate_dead_stores),
+  },
+  Pass {
+    name: "dce",
+    run: Run::Function(eliminate_dead_code),
+  },
+  Pass {
+    name: "tailcall",
+    run: Run::Function(eliminate_tail_calls),
+  },
+  Pass {
+    name: "licm",
+    run: Run::Function(hoist_loop_invariants),
+  },
+  Pass {
+    name: "dce",
+    run: Run::Function(eliminate_dead_code),
+  },
+];
+
+/// 各个优化级别用到的所有 IR 优化
+fn all_passes() -> impl Iterator<Item = &'static Pass> {
+  DEFAULT_PASSES
+    .iter()
+    .chain(UNROLL_PASSES)
+    .chain(BASIC_PASSES)
+    .chain(SIZE_PASSES)
+}
+
+/// 按名字查找 IR 上的优化
+pub fn find_pass(name: &str) -> Option<Pass> {
+  all_passes().find(|pass| pass.name == name).copied()
+}
+
+/// 后端的步骤，也可以写在 `-C passes=` 中
+pub const BACKEND_PASSES: [&str; 2] = ["regalloc", "peephole"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OptLevel {
+  /// 不做优化，虚拟寄存器都放在栈帧中，便于调试
+  O0,
+  O1,
+  #[default]
+  O2,
+  /// 优化代码大小
+  Os,
+}
+
+impl OptLevel {
+  /// `-O` 之后的部分
+  pub fn from_name(name: &str) -> Option<OptLevel> {
+    match name {
+      "0" => Some(OptLevel::O0),
+      "1" => Some(OptLevel::O1),
+      "2" => Some(OptLevel::O2),
+      "s" => Some(OptLevel::Os),
+      _ => None,
+    }
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      OptLevel::O0 => "-O0",
+      OptLevel::O1 => "-O1",
+      OptLevel::O2 => "-O2",
+      OptLevel::Os => "-Os",
+    }
+  }
+}
+
+/// 整个编译流程要运行的步骤
+pub struct Pipeline {
+  pub passes: Vec<Pass>,
+  pub regalloc: bool,
+  pub peephole: bool,
+}
+
+impl Pipeline {
+  /// 优化级别对应的步骤；`unroll` 时在 `-O1`、`-O2` 的最后展开循环
+  pub fn for_level(level: OptLevel, unroll: bool) -> Pipeline {
+    let mut passes = match level {
+      OptLevel::O0 => vec![],
+      OptLevel::O1 => BASIC_PASSES.to_vec(),
+      OptLevel::O2 => DEFAULT_PASSES.to_vec(),
+      OptLevel::Os => SIZE_PASSES.to_vec(),
+    };
+    if unroll && matches!(level, OptLevel::O1 | OptLevel::O2) {
+      passes.extend_from_slice(UNROLL_PASSES);
+    }
+    Pipeline {
+      passes,
+      regalloc: level != OptLevel::O0,
+      peephole: level != OptLevel::O0,
+    }
+  }
+
+  /// `-C passes=` 的值：逗号分隔的步骤名，按给出的顺序运行
+  pub fn from_names(names: &str) -> Result<Pipeline, String> {
+    let mut pipeline = Pipeline {
+      passes: vec![],
+      regalloc: false,
+      peephole: false,
+    };
+    for name in names
+      .split(',')
+      .map(str::trim)
+      .filter(|name| !name.is_empty())
+    {
+      match name {
+        "regalloc" => pipeline.regalloc = true,
+        "peephole" => pipeline.peephole = true,
+        _ => pipeline.passes.push(find_pass(name).ok_or_else(|| {
+          let mut known: Vec<&str> = vec![];
+          for pass in all_passes() {
+            if !known.contains(&pass.name) {
+              known.push(pass.name);
+            }
+          }
+          known.extend(BACKEND_PASSES);
+          format!(
+            "unknown pass `{}`; known passes: {}",
+            name,
+            known.join(", ")
+          )
+        })?),
+      }
+    }
+    Ok(pipeline)
+  }
+
+  /// 记录到汇编输出中的步骤列表
+  pub fn describe(&self) -> String {
+    let mut names: Vec<&str> = self.passes.iter().map(|pass| pass.name).collect();
+    if self.regalloc {
+      names.push("regalloc");
+    }
+    if self.peephole {
+      names.push("peephole");
+    }
+    names.join(",")
+  }
+}
+
+fn check(function: &Function, pass: &str) {
+  if let Err(message) = verify(function) {
+    panic!(
+      "invalid SSA in function `{}` after pass `{}`: {}\n{}",
+      function.name, pass, message, function
+    );
+  }
+}
+
+/// 转为 SSA 形式并运行 `passes`，结果仍是 SSA 形式。main 调用不到的函数不再生成。
+/// 返回各个模块级优化的决策
+pub fn optimize(module: &mut Module, passes: &[Pass]) -> Vec<String> {
+  remove_unreachable_functions(module);
+  for function in &mut module.functions {
+    into_ssa(function);
+    check(function, "ssa");
+  }
+  let mut remarks = vec![];
+  for pass in passes {
+    match pass.run {
+      Run::Function(run) => {
+        for function in &mut module.functions {
+          run(function);
+          check(function, pass.name);
+        }
+      }
+      Run::Module(run) => {
+        remarks.extend(run(module));
+        for function in &module.functions {
+          check(function, pass.name);
+        }
+      }
+    }
+  }
+  remove_unreachable_functions(module);
+  remarks
+}
+
+/// 消除 phi，之后可以生成汇编
+pub fn leave_ssa(module: &mut Module) {
+  for function in &mut module.functions {
+    out_of_ssa(function);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ir::parse_module;
+
+  const SOURCE: &str = "fn f(a: int) -> int {\n  slot x: int\nb0:\n  v0 = load int [a]\n  store [x], v0\n  v1 = load int [x]\n  ret v1\n}\n";
+
+  #[test]
+  fn test_optimize_and_leave_ssa() {
+    let mut module = parse_module(SOURCE).unwrap();
+    optimize(&mut module, &[]);
+    assert_eq!(
+      module.to_string(),
+      "fn f(a: int) -> int {\nb0:\n  v2 = load int [a]\n  ret v2\n}\n"
+    );
+    leave_ssa(&mut module);
+    assert_eq!(
+      module.to_string(),
+      "fn f(a: int) -> int {\nb0:\n  v2 = load int [a]\n  ret v2\n}\n"
+    );
+  }
+
+  #[test]
+  fn test_pipelines() {
+    assert_eq!(Pipeline::for_level(OptLevel::O0, true).describe(), "");
+    assert_eq!(
+      Pipeline::for_level(OptLevel::O1, false).describe(),
+      "constprop,lvn,dse,dce,regalloc,peephole"
+    );
+    assert_eq!(
+      Pipeline::for_level(OptLevel::O2, true).describe(),
+      "constprop,gvn,dse,dce,inline,constprop,gvn,dse,dce,tailcall,licm,strength,dce,unroll,constprop,dce,regalloc,peephole"
+    );
+    assert_eq!(
+      Pipeline::for_level(OptLevel::Os, true).describe(),
+      "constprop,gvn,dse,dce,tailcall,licm,dce,regalloc,peephole"
+    );
+    let pipeline = Pipeline::from_names("peephole, inline,dce").unwrap();
+    assert_eq!(pipeline.describe(), "inline,dce,peephole");
+    assert!(!pipeline.regalloc);
+    assert_eq!(
+      Pipeline::from_names("dce,cse").err().unwrap(),
+      "unknown pass `cse`; known passes: constprop, gvn, dse, dce, inline, tailcall, licm, strength, unroll, lvn, regalloc, peephole"
+    );
+  }
+
+  #[test]
+  #[should_panic(
+    expected = "invalid SSA in function `f` after pass `broken`: v2 is defined more than once"
+  )]
+  fn test_verify_after_each_pass() {
+    fn broken(function: &mut Function) {
+      let inst = function.blocks[0].insts[0].clone();
+      function.blocks[0].insts.push(inst);
+    }
+    let mut module = parse_module(SOURCE).unwrap();
+    optimize(
+      &mut module,
+      &[Pass {
+        name: "broken",
+        run: Run::Function(broken),
+      }],
+    );
+  }
+}
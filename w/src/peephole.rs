@@ -0,0 +1,603 @@
+//! 窥孔优化：把后端生成的汇编解析成指令列表，用规则表中的模式反复改写相邻的几条指令
+//!
+//! 后端约定 %rax、%rcx 是临时寄存器，只在一条 IR 指令的代码内部使用：
+//! 在标号、无条件跳转和 call 处它们都不活跃，ret 读取 %rax 作为返回值。
+//! 判断临时寄存器是否还会被读取时依赖这个约定，其他寄存器一律当作活跃。
+
+use std::fmt;
+
+/// 一行汇编
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line {
+  Label(String),
+  /// 指令：助记符和逗号分隔的操作数
+  Inst {
+    op: String,
+    args: Vec<String>,
+  },
+  /// 伪指令、注释和空行，原样保留
+  Other(String),
+}
+
+impl fmt::Display for Line {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Line::Label(name) => write!(f, "{}:", name),
+      Line::Inst { op, args } if args.is_empty() => write!(f, "  {}", op),
+      Line::Inst { op, args } => write!(f, "  {} {}", op, args.join(", ")),
+      Line::Other(text) => write!(f, "{}", text),
+    }
+  }
+}
+
+fn inst(op: &str, args: &[&str]) -> Line {
+  Line::Inst {
+    op: op.to_string(),
+    args: args.iter().map(|arg| arg.to_string()).collect(),
+  }
+}
+
+/// 按括号外的逗号切分操作数，如 `(%rax, %rcx, 8), %rax`
+fn split_args(text: &str) -> Vec<String> {
+  let mut args = vec![];
+  let mut depth = 0;
+  let mut start = 0;
+  for (i, c) in text.char_indices() {
+    match c {
+      '(' => depth += 1,
+      ')' => depth -= 1,
+      ',' if depth == 0 => {
+        args.push(text[start..i].trim().to_string());
+        start = i + 1;
+      }
+      _ => {}
+    }
+  }
+  args.push(text[start..].trim().to_string());
+  args
+}
+
+pub fn parse_asm(asm: &str) -> Vec<Line> {
+  asm
+    .lines()
+    .map(|line| {
+      let text = line.trim();
+      if let Some(name) = text.strip_suffix(':') {
+        if !name.contains(char::is_whitespace) {
+          return Line::Label(name.to_string());
+        }
+      }
+      if text.is_empty() || text.starts_with('.') || text.starts_with('#') {
+        return Line::Other(line.to_string());
+      }
+      match text.split_once(char::is_whitespace) {
+        Some((op, args)) => Line::Inst {
+          op: op.to_string(),
+          args: split_args(args.trim()),
+        },
+        None => Line::Inst {
+          op: text.to_string(),
+          args: vec![],
+        },
+      }
+    })
+    .collect()
+}
+
+pub fn render_asm(lines: &[Line]) -> String {
+  lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+/// 一条改写规则：`apply` 看到窗口中的 `width` 行和窗口之后的所有行，
+/// 能改写时返回替换窗口的新指令
+pub struct Rule {
+  pub name: &'static str,
+  pub width: usize,
+  pub apply: fn(&[Line], &[Line]) -> Option<Vec<Line>>,
+}
+
+/// 规则表，窗口内同时有多条规则适用时用靠前的规则
+pub const RULES: &[Rule] = &[
+  Rule {
+    name: "self-move",
+    width: 1,
+    apply: self_move,
+  },
+  Rule {
+    name: "store-forward",
+    width: 2,
+    apply: store_forward,
+  },
+  Rule {
+    name: "load-store",
+    width: 2,
+    apply: load_store,
+  },
+  Rule {
+    name: "dead-move",
+    width: 2,
+    apply: dead_move,
+  },
+  Rule {
+    name: "move-chain",
+    width: 2,
+    apply: move_chain,
+  },
+  Rule {
+    name: "op-in-place",
+    width: 3,
+    apply: op_in_place,
+  },
+  Rule {
+    name: "fold-load",
+    width: 2,
+    apply: fold_load,
+  },
+  Rule {
+    name: "fold-divisor",
+    width: 3,
+    apply: fold_divisor,
+  },
+  Rule {
+    name: "setcc-branch",
+    width: 4,
+    apply: setcc_branch,
+  },
+  Rule {
+    name: "setcc-store-branch",
+    width: 5,
+    apply: setcc_store_branch,
+  },
+  Rule {
+    name: "branch-over-jump",
+    width: 3,
+    apply: branch_over_jump,
+  },
+  Rule {
+    name: "jump-to-next",
+    width: 2,
+    apply: jump_to_next,
+  },
+];
+
+/// 64 位通用寄存器的全部别名
+fn aliases(reg: &str) -> &[&'static str] {
+  match reg {
+    "%rax" => &["%rax", "%eax", "%ax", "%al"],
+    "%rcx" => &["%rcx", "%ecx", "%cx", "%cl"],
+    "%rdx" => &["%rdx", "%edx", "%dx", "%dl"],
+    _ => &[],
+  }
+}
+
+fn mentions(arg: &str, reg: &str) -> bool {
+  arg.contains(reg) || aliases(reg).iter().any(|alias| arg.contains(alias))
+}
+
+fn is_reg(arg: &str) -> bool {
+  arg.starts_with('%') && !arg.starts_with("%xmm")
+}
+
+fn is_mem(arg: &str) -> bool {
+  arg.ends_with(')')
+}
+
+fn is_scratch(reg: &str) -> bool {
+  reg == "%rax" || reg == "%rcx"
+}
+
+/// 只写入最后一个操作数、不读取它的指令
+fn is_move(op: &str) -> bool {
+  matches!(op, "movq" | "movabsq" | "leaq" | "movzbq")
+}
+
+/// 读取并改写第二个操作数的整数运算
+fn is_arith(op: &str) -> bool {
+  matches!(op, "addq" | "subq" | "imulq" | "andq" | "orq" | "xorq")
+}
+
+fn reads(op: &str, args: &[String], reg: &str) -> bool {
+  match op {
+    "cqto" => reg == "%rax",
+    "idivq" => reg == "%rax" || reg == "%rdx" || mentions(&args[0], reg),
+    "ret" => reg == "%rax",
+    "syscall" => true,
+    _ if is_move(op) => {
+      let (dst, srcs) = args.split_last().unwrap();
+      srcs.iter().any(|arg| mentions(arg, reg)) || (is_mem(dst) && mentions(dst, reg))
+    }
+    _ => args.iter().any(|arg| mentions(arg, reg)),
+  }
+}
+
+fn writes(op: &str, args: &[String], reg: &str) -> bool {
+  match op {
+    "cqto" => reg == "%rdx",
+    "call" => is_scratch(reg),
+    _ if is_move(op) => args.last().is_some_and(|dst| dst == reg),
+    _ => false,
+  }
+}
+
+/// `reg` 在 `lines` 开始处的值之后是否不再被读取
+fn is_dead(reg: &str, lines: &[Line]) -> bool {
+  for line in lines {
+    match line {
+      Line::Label(_) => return is_scratch(reg),
+      Line::Other(_) => {}
+      Line::Inst { op, args } => {
+        if reads(op, args, reg) {
+          return false;
+        }
+        if writes(op, args, reg) {
+          return true;
+        }
+        if op == "jmp" {
+          return is_scratch(reg);
+        }
+      }
+    }
+  }
+  is_scratch(reg)
+}
+
+/// 窗口中的指令都是 `Inst` 时取出 (助记符, 操作数)
+fn insts(window: &[Line]) -> Option<Vec<(&str, &[String])>> {
+  window
+    .iter()
+    .map(|line| match line {
+      Line::Inst { op, args } => Some((op.as_str(), args.as_slice())),
+      _ => None,
+    })
+    .collect()
+}
+
+/// `movq %r, %r`
+fn self_move(window: &[Line], _: &[Line]) -> Option<Vec<Line>> {
+  match insts(window)?[..] {
+    [("movq", [src, dst])] if is_reg(src) && src == dst => Some(vec![]),
+    _ => None,
+  }
+}
+
+/// `movq %r, M; movq M, %s` → `movq %r, M; movq %r, %s`
+fn store_forward(window: &[Line], _: &[Line]) -> Option<Vec<Line>> {
+  match insts(window)?[..] {
+    [("movq", [src, mem]), ("movq", [load, dst])]
+      if is_reg(src) && is_mem(mem) && mem == load && is_reg(dst) =>
+    {
+      Some(vec![window[0].clone(), inst("movq", &[src, dst])])
+    }
+    _ => None,
+  }
+}
+
+/// `movq M, %r; movq %r, M` → `movq M, %r`
+fn load_store(window: &[Line], _: &[Line]) -> Option<Vec<Line>> {
+  match insts(window)?[..] {
+    [("movq", [mem, reg]), ("movq", [src, store])]
+      if is_mem(mem) && is_reg(reg) && reg == src && mem == store =>
+    {
+      Some(vec![window[0].clone()])
+    }
+    _ => None,
+  }
+}
+
+/// `movq X, %r` 之后紧接着覆盖 %r，前一条没有用
+fn dead_move(window: &[Line], _: &[Line]) -> Option<Vec<Line>> {
+  let [(first, [_, reg]), (op, args)] = insts(window)?[..] else {
+    return None;
+  };
+  if is_move(first)
+    && first != "leaq"
+    && is_reg(reg)
+    && !reads(op, args, reg)
+    && writes(op, args, reg)
+  {
+    Some(vec![window[1].clone()])
+  } else {
+    None
+  }
+}
+
+/// `movq X, %r; movq %r, M` → `movq X, M`，X 是立即数或寄存器且 %r 之后不再读取
+fn move_chain(window: &[Line], after: &[Line]) -> Option<Vec<Line>> {
+  match insts(window)?[..] {
+    [("movq", [src, reg]), ("movq", [value, mem])]
+      if (is_reg(src) || src.starts_with('$'))
+        && is_reg(reg)
+        && reg == value
+        && is_mem(mem)
+        && !mentions(mem, reg)
+        && is_dead(reg, after) =>
+    {
+      Some(vec![inst("movq", &[src, mem])])
+    }
+    _ => None,
+  }
+}
+
+/// `movq %a, %b; op X, %b; movq %b, %a` → `op X, %a`，%b 之后不再读取
+fn op_in_place(window: &[Line], after: &[Line]) -> Option<Vec<Line>> {
+  match insts(window)?[..] {
+    [("movq", [a, b]), (op, [x, dst]), ("movq", [src, back])]
+      if is_reg(a)
+        && is_reg(b)
+        && a != b
+        && is_arith(op)
+        && dst == b
+        && src == b
+        && back == a
+        && !mentions(x, b)
+        && is_dead(b, after) =>
+    {
+      Some(vec![inst(op, &[x, a])])
+    }
+    _ => None,
+  }
+}
+
+/// `movq M, %rcx; op %rcx, %r` → `op M, %r`，%rcx 之后不再读取
+fn fold_load(window: &[Line], after: &[Line]) -> Option<Vec<Line>> {
+  match insts(window)?[..] {
+    [("movq", [mem, reg]), (op, [src, dst])]
+      if is_mem(mem)
+        && is_reg(reg)
+        && src == reg
+        && dst != reg
+        && is_reg(dst)
+        && (is_arith(op) || op == "cmpq")
+        && !mentions(mem, dst)
+        && is_dead(reg, after) =>
+    {
+      Some(vec![inst(op, &[mem, dst])])
+    }
+    _ => None,
+  }
+}
+
+/// `movq M, %rcx; cqto; idivq %rcx` → `cqto; idivq M`
+fn fold_divisor(window: &[Line], after: &[Line]) -> Option<Vec<Line>> {
+  match insts(window)?[..] {
+    [("movq", [mem, reg]), ("cqto", []), ("idivq", [divisor])]
+      if is_mem(mem)
+        && reg == divisor
+        && !mentions(mem, "%rdx")
+        && !mentions(reg, "%rax")
+        && !mentions(reg, "%rdx")
+        && is_dead(reg, after) =>
+    {
+      Some(vec![window[1].clone(), inst("idivq", &[mem])])
+    }
+    _ => None,
+  }
+}
+
+/// 条件码取反
+fn negate(cc: &str) -> Option<&'static str> {
+  Some(match cc {
+    "e" => "ne",
+    "ne" => "e",
+    "l" => "ge",
+    "ge" => "l",
+    "g" => "le",
+    "le" => "g",
+    "b" => "ae",
+    "ae" => "b",
+    "a" => "be",
+    "be" => "a",
+    _ => return None,
+  })
+}
+
+/// setcc 得到的 0/1 再与 0 比较后跳转，改为直接按原来的条件码跳转。
+/// mov 和 setcc 不改变标志位，`je` 在条件不成立时跳转
+fn branch_on_setcc(set: &str, jump: &str, label: &str) -> Option<Line> {
+  let cc = set.strip_prefix("set")?;
+  let cc = match jump {
+    "je" => negate(cc)?,
+    "jne" => negate(negate(cc)?)?,
+    _ => return None,
+  };
+  Some(inst(&format!("j{}", cc), &[label]))
+}
+
+/// `setCC %al; movzbq %al, %rax; cmpq $0, %rax; je L` → `jNCC L`，%rax 之后不再读取
+fn setcc_branch(window: &[Line], after: &[Line]) -> Option<Vec<Line>> {
+  match insts(window)?[..] {
+    [(set, [al]), ("movzbq", [al2, rax]), ("cmpq", [zero, rax2]), (jump, [label])]
+      if al == "%al"
+        && al2 == "%al"
+        && rax == "%rax"
+        && zero == "$0"
+        && rax2 == "%rax"
+        && is_dead("%rax", after) =>
+    {
+      Some(vec![branch_on_setcc(set, jump, label)?])
+    }
+    _ => None,
+  }
+}
+
+/// 同上，但比较结果还要写回内存：`setCC %al; movzbq %al, %rax; movq %rax, M; cmpq $0, %rax; je L`
+fn setcc_store_branch(window: &[Line], _: &[Line]) -> Option<Vec<Line>> {
+  match insts(window)?[..] {
+    [(set, [al]), ("movzbq", [al2, rax]), ("movq", [src, mem]), ("cmpq", [zero, rax2]), (jump, [label])]
+      if al == "%al"
+        && al2 == "%al"
+        && rax == "%rax"
+        && src == "%rax"
+        && is_mem(mem)
+        && zero == "$0"
+        && rax2 == "%rax" =>
+    {
+      let mut lines = window[..3].to_vec();
+      lines.push(branch_on_setcc(set, jump, label)?);
+      Some(lines)
+    }
+    _ => None,
+  }
+}
+
+/// `jCC L1; jmp L2; L1:` → `jNCC L2; L1:`
+fn branch_over_jump(window: &[Line], _: &[Line]) -> Option<Vec<Line>> {
+  let [Line::Inst { op, args }, Line::Inst {
+    op: jmp,
+    args: target,
+  }, Line::Label(next)] = window
+  else {
+    return None;
+  };
+  let cc = negate(op.strip_prefix('j')?)?;
+  if jmp != "jmp" || args.len() != 1 || args[0] != *next {
+    return None;
+  }
+  Some(vec![
+    inst(&format!("j{}", cc), &[&target[0]]),
+    window[2].clone(),
+  ])
+}
+
+/// 跳转到紧接着的标号
+fn jump_to_next(window: &[Line], _: &[Line]) -> Option<Vec<Line>> {
+  let [Line::Inst { op, args }, Line::Label(next)] = window else {
+    return None;
+  };
+  let is_jump = op == "jmp" || negate(op.strip_prefix('j')?).is_some();
+  if is_jump && args.len() == 1 && args[0] == *next {
+    Some(vec![window[1].clone()])
+  } else {
+    None
+  }
+}
+
+/// 反复应用规则表直到没有规则适用。返回每个函数中各条规则生效的次数，
+/// 按函数出现的顺序和规则表的顺序排列
+pub fn optimize_lines(lines: &mut Vec<Line>) -> Vec<(String, &'static str, usize)> {
+  let widest = RULES.iter().map(|rule| rule.width).max().unwrap_or(1);
+  let mut fired: Vec<(String, &'static str, usize)> = vec![];
+  let mut function = String::new();
+  let mut i = 0;
+  while i < lines.len() {
+    if let Line::Label(name) = &lines[i] {
+      if !name.starts_with('.') {
+        function = name.clone();
+      }
+    }
+    let rewrite = RULES.iter().find_map(|rule| {
+      let window = lines.get(i..i + rule.width)?;
+      (rule.apply)(window, &lines[i + rule.width..]).map(|new| (rule, new))
+    });
+    let Some((rule, new)) = rewrite else {
+      i += 1;
+      continue;
+    };
+    lines.splice(i..i + rule.width, new);
+    match fired
+      .iter_mut()
+      .find(|(name, rule_name, _)| *name == function && *rule_name == rule.name)
+    {
+      Some((_, _, count)) => *count += 1,
+      None => fired.push((function.clone(), rule.name, 1)),
+    }
+    // 改写之后前面的指令可能组成新的模式，但不能退回到当前函数之前
+    let start = lines[..i]
+      .iter()
+      .rposition(|line| matches!(line, Line::Label(name) if *name == function))
+      .unwrap_or(0);
+    i = i.saturating_sub(widest - 1).max(start);
+  }
+  let order = |rule: &str| RULES.iter().position(|r| r.name == rule);
+  let functions: Vec<String> = fired.iter().map(|(f, _, _)| f.clone()).collect();
+  fired.sort_by_key(|(f, rule, _)| (functions.iter().position(|g| g == f), order(rule)));
+  fired
+}
+
+/// 对汇编文本做窥孔优化，返回优化后的汇编和生效规则的说明
+pub fn optimize_asm(asm: &str) -> (String, Vec<String>) {
+  let mut lines = parse_asm(asm);
+  let fired = optimize_lines(&mut lines);
+  let remarks = fired
+    .into_iter()
+    .map(|(function, rule, count)| {
+      format!("peephole `{}` in `{}`: {} time(s)", rule, function, count)
+    })
+    .collect();
+  (render_asm(&lines), remarks)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn run(asm: &str) -> String {
+    optimize_asm(asm).0
+  }
+
+  #[test]
+  fn test_parse_and_render() {
+    let asm = "\t.text\n\t.globl\tmain\n\n\nmain:\n# 注释\n  movq (%rax, %rcx, 8), %rax\n  ret\n.LSTR0:\n\t.asciz \"a, b\"\n";
+    let lines = parse_asm(asm);
+    assert_eq!(lines[4], Line::Label("main".to_string()));
+    assert_eq!(lines[6], inst("movq", &["(%rax, %rcx, 8)", "%rax"]));
+    assert_eq!(lines[7], inst("ret", &[]));
+    assert_eq!(render_asm(&lines), asm);
+  }
+
+  #[test]
+  fn test_store_forward_and_self_move() {
+    let (asm, remarks) = optimize_asm(
+      "f:\n  movq 0(%rbp), %rax\n  addq $1, %rax\n  movq %rax, 8(%rbp)\n  movq 8(%rbp), %rax\n  movq %rax, 16(%rbp)\n  movq 8(%rbp), %rcx\n  ret\n",
+    );
+    assert_eq!(
+      asm,
+      "f:\n  movq 0(%rbp), %rax\n  addq $1, %rax\n  movq %rax, 8(%rbp)\n  movq %rax, 16(%rbp)\n  movq 8(%rbp), %rcx\n  ret\n"
+    );
+    assert_eq!(
+      remarks,
+      vec![
+        "peephole `self-move` in `f`: 1 time(s)",
+        "peephole `store-forward` in `f`: 1 time(s)",
+      ]
+    );
+    // 读到另一个寄存器时改为寄存器之间的传送
+    assert_eq!(
+      run("f:\n  movq %rax, 8(%rbp)\n  movq 8(%rbp), %rcx\n  imulq %rcx, %rax\n  ret\n"),
+      "f:\n  movq %rax, 8(%rbp)\n  movq %rax, %rcx\n  imulq %rcx, %rax\n  ret\n"
+    );
+  }
+
+  #[test]
+  fn test_moves_through_dead_registers() {
+    // %r9 之后被覆盖，运算可以直接在 %r8 上进行
+    assert_eq!(
+      run("f:\n  movq %r8, %r9\n  addq $3, %r9\n  movq %r9, %r8\n  movq $1, %r9\n  movq %r9, 8(%rbp)\n  ret\n"),
+      "f:\n  addq $3, %r8\n  movq $1, %r9\n  movq %r9, 8(%rbp)\n  ret\n"
+    );
+    // 临时寄存器在标号处不活跃
+    assert_eq!(
+      run("f:\n  movq $5, %rax\n  movq %rax, 8(%rbp)\n.L1:\n  movq 0(%rbp), %rax\n  movq 16(%rbp), %rcx\n  subq %rcx, %rax\n  movq 24(%rbp), %rcx\n  cqto\n  idivq %rcx\n  ret\n"),
+      "f:\n  movq $5, 8(%rbp)\n.L1:\n  movq 0(%rbp), %rax\n  subq 16(%rbp), %rax\n  cqto\n  idivq 24(%rbp)\n  ret\n"
+    );
+    // ret 读取 %rax，%rcx 之后还要用
+    let asm =
+      "f:\n  movq 0(%rbp), %rcx\n  addq %rcx, %rax\n  movq %rcx, 8(%rbp)\n  movq $5, %rax\n  ret\n";
+    assert_eq!(run(asm), asm);
+  }
+
+  #[test]
+  fn test_branches() {
+    assert_eq!(
+      run("f:\n  cmpq $3, %rax\n  setl %al\n  movzbq %al, %rax\n  cmpq $0, %rax\n  je .L2\n.L1:\n  ret\n"),
+      "f:\n  cmpq $3, %rax\n  jge .L2\n.L1:\n  ret\n"
+    );
+    assert_eq!(
+      run("f:\n  ucomisd %xmm1, %xmm0\n  seta %al\n  movzbq %al, %rax\n  movq %rax, 8(%rbp)\n  movq 8(%rbp), %rax\n  cmpq $0, %rax\n  je .L1\n  jmp .L2\n.L1:\n  jmp .L3\n.L3:\n  ret\n"),
+      "f:\n  ucomisd %xmm1, %xmm0\n  seta %al\n  movzbq %al, %rax\n  movq %rax, 8(%rbp)\n  ja .L2\n.L1:\n.L3:\n  ret\n"
+    );
+    // 不认识的条件码不改写
+    let asm = "f:\n  setp %al\n  movzbq %al, %rax\n  cmpq $0, %rax\n  je .L2\n  ret\n";
+    assert_eq!(run(asm), asm);
+  }
+}
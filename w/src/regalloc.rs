@@ -0,0 +1,325 @@
+//! 寄存器分配：在消除 phi 之后的 IR 上做线性扫描（Poletto & Sarkar）
+//!
+//! 按块的排列顺序给指令和终结指令编号，由活跃分析得到每个虚拟寄存器的活跃区间
+//! （取覆盖所有活跃位置的最小区间），按起点依次分配。
+//! 区间只用来判断两个值能否共用寄存器；哪些值跨越某次调用由活跃分析在调用处直接求出，
+//! 因为按排列顺序得到的区间可能把排在调用之前、实际在调用之后才执行的使用也包括进来。
+//!
+//! - %rax、%rcx 是后端的临时寄存器，%rdx 用于除法，%rbp、%rsp 保存栈帧，都不参与分配
+//! - 跨越调用的区间优先使用被调者保存的寄存器，函数用到它们时在入口保存、返回前恢复
+//! - 调用者保存的寄存器若分给了跨越调用的区间，就在这次调用前后把值存到栈上再读回，
+//!   相当于在调用处把活跃区间切开
+//! - 没有空闲寄存器时，溢出结束得最晚的区间，它留在栈帧中自己的位置上
+
+use crate::cfg::Cfg;
+use crate::ir::{BlockId, Function, Inst, Operand, VReg};
+use std::collections::{HashMap, HashSet};
+
+/// 被调者保存的寄存器
+pub const CALLEE_SAVED: [&str; 5] = ["%rbx", "%r12", "%r13", "%r14", "%r15"];
+/// 调用者保存的寄存器，运行时的 print / scan 也可能改写它们
+pub const CALLER_SAVED: [&str; 6] = ["%rsi", "%rdi", "%r8", "%r9", "%r10", "%r11"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Location {
+  Reg(&'static str),
+  /// 栈帧中这个虚拟寄存器自己的位置
+  Stack,
+}
+
+pub struct Allocation {
+  /// 下标是虚拟寄存器的编号
+  pub locations: Vec<Location>,
+  /// 用到的被调者保存的寄存器，按 `CALLEE_SAVED` 的顺序
+  pub callee_saved: Vec<&'static str>,
+  /// 每处调用 (块, 指令下标) 前后需要存到栈上的虚拟寄存器
+  pub saves: HashMap<(BlockId, usize), Vec<VReg>>,
+}
+
+impl Allocation {
+  /// 不分配寄存器，所有虚拟寄存器都在栈上
+  pub fn on_stack(function: &Function) -> Allocation {
+    Allocation {
+      locations: vec![Location::Stack; function.vreg_count() as usize],
+      callee_saved: vec![],
+      saves: HashMap::new(),
+    }
+  }
+}
+
+/// 调用的 (块, 指令下标) 和调用之后仍然活跃的值（不含调用的结果），按编号排序
+type CallSite = ((BlockId, usize), Vec<VReg>);
+
+struct Interval {
+  vreg: VReg,
+  start: usize,
+  end: usize,
+  /// 在某次调用之后仍然活跃
+  across_call: bool,
+}
+
+fn uses(operands: Vec<&Operand>) -> impl Iterator<Item = VReg> + '_ {
+  operands.into_iter().filter_map(|operand| match operand {
+    Operand::Reg(reg) => Some(*reg),
+    Operand::Imm(_) => None,
+  })
+}
+
+/// 每个块入口处活跃的虚拟寄存器
+fn live_in(function: &Function, cfg: &Cfg) -> Vec<HashSet<VReg>> {
+  let mut live_in = vec![HashSet::new(); function.blocks.len()];
+  let mut changed = true;
+  while changed {
+    changed = false;
+    for block in cfg.rpo.iter().rev() {
+      let block = &function.blocks[block.0 as usize];
+      let mut live: HashSet<VReg> = HashSet::new();
+      for succ in cfg.succs(block.id) {
+        live.extend(live_in[succ.0 as usize].iter().copied());
+      }
+      live.extend(uses(block.term.operands()));
+      for inst in block.insts.iter().rev() {
+        if let Some(dst) = inst.dst() {
+          live.remove(&dst);
+        }
+        live.extend(uses(inst.operands()));
+      }
+      if live != live_in[block.id.0 as usize] {
+        live_in[block.id.0 as usize] = live;
+        changed = true;
+      }
+    }
+  }
+  live_in
+}
+
+/// 每处调用之后活跃的值：从块出口处活跃的值开始逆序扫描
+fn calls(function: &Function, cfg: &Cfg, live_in: &[HashSet<VReg>]) -> Vec<CallSite> {
+  let mut calls = vec![];
+  for block in &function.blocks {
+    let mut live: HashSet<VReg> = HashSet::new();
+    for succ in cfg.succs(block.id) {
+      live.extend(live_in[succ.0 as usize].iter().copied());
+    }
+    live.extend(uses(block.term.operands()));
+    for (i, inst) in block.insts.iter().enumerate().rev() {
+      if let Some(dst) = inst.dst() {
+        live.remove(&dst);
+      }
+      if matches!(inst, Inst::Call { .. }) {
+        let mut across: Vec<VReg> = live.iter().copied().collect();
+        across.sort();
+        calls.push(((block.id, i), across));
+      }
+      live.extend(uses(inst.operands()));
+    }
+  }
+  calls
+}
+
+/// 活跃区间和调用处活跃的值
+fn intervals(function: &Function) -> (Vec<Interval>, Vec<CallSite>) {
+  let cfg = Cfg::new(function);
+  let live_in = live_in(function, &cfg);
+  let calls = calls(function, &cfg, &live_in);
+  let across: HashSet<VReg> = calls.iter().flat_map(|(_, live)| live).copied().collect();
+  let mut ranges: HashMap<VReg, (usize, usize)> = HashMap::new();
+  let mut cover = |reg: VReg, pos: usize| {
+    let range = ranges.entry(reg).or_insert((pos, pos));
+    range.0 = range.0.min(pos);
+    range.1 = range.1.max(pos);
+  };
+  let mut pos = 0;
+  for block in &function.blocks {
+    for reg in &live_in[block.id.0 as usize] {
+      cover(*reg, pos);
+    }
+    for inst in &block.insts {
+      for reg in uses(inst.operands()).chain(inst.dst()) {
+        cover(reg, pos);
+      }
+      pos += 1;
+    }
+    for reg in uses(block.term.operands()) {
+      cover(reg, pos);
+    }
+    for succ in cfg.succs(block.id) {
+      for reg in &live_in[succ.0 as usize] {
+        cover(*reg, pos);
+      }
+    }
+    pos += 1;
+  }
+  let mut intervals: Vec<Interval> = ranges
+    .into_iter()
+    .map(|(vreg, (start, end))| Interval {
+      vreg,
+      start,
+      end,
+      across_call: across.contains(&vreg),
+    })
+    .collect();
+  intervals.sort_by_key(|interval| (interval.start, interval.vreg.0));
+  (intervals, calls)
+}
+
+/// 从空闲寄存器中挑一个：跨越调用时先找被调者保存的，否则先找调用者保存的
+fn pick(free: &[&'static str], across_call: bool) -> Option<&'static str> {
+  let (first, second) = if across_call {
+    (&CALLEE_SAVED[..], &CALLER_SAVED[..])
+  } else {
+    (&CALLER_SAVED[..], &CALLEE_SAVED[..])
+  };
+  first
+    .iter()
+    .chain(second)
+    .find(|reg| free.contains(reg))
+    .copied()
+}
+
+pub fn allocate_registers(function: &Function) -> Allocation {
+  let (intervals, calls) = intervals(function);
+  let mut locations = vec![Location::Stack; function.vreg_count() as usize];
+  let mut free: Vec<&'static str> = CALLEE_SAVED.iter().chain(&CALLER_SAVED).copied().collect();
+  // 正在占用寄存器的区间：(区间下标, 寄存器)
+  let mut active: Vec<(usize, &'static str)> = vec![];
+  for (i, interval) in intervals.iter().enumerate() {
+    // 区间在这条指令读取操作数之后结束，结果可以写入同一个寄存器
+    active.retain(|(j, reg)| {
+      let expired = intervals[*j].end <= interval.start;
+      if expired {
+        free.push(reg);
+      }
+      !expired
+    });
+    if let Some(reg) = pick(&free, interval.across_call) {
+      free.retain(|r| *r != reg);
+      locations[interval.vreg.0 as usize] = Location::Reg(reg);
+      active.push((i, reg));
+      continue;
+    }
+    // 溢出结束得最晚的区间
+    let (k, &(j, reg)) = active
+      .iter()
+      .enumerate()
+      .max_by_key(|(_, (j, _))| (intervals[*j].end, intervals[*j].vreg.0))
+      .unwrap();
+    if intervals[j].end > interval.end {
+      locations[intervals[j].vreg.0 as usize] = Location::Stack;
+      locations[interval.vreg.0 as usize] = Location::Reg(reg);
+      active[k] = (i, reg);
+    }
+  }
+  let callee_saved = CALLEE_SAVED
+    .iter()
+    .filter(|reg| locations.contains(&Location::Reg(reg)))
+    .copied()
+    .collect();
+  let mut saves = HashMap::new();
+  for (site, live) in calls {
+    let saved: Vec<VReg> = live
+      .into_iter()
+      .filter(|reg| {
+        matches!(locations[reg.0 as usize], Location::Reg(reg) if CALLER_SAVED.contains(&reg))
+      })
+      .collect();
+    if !saved.is_empty() {
+      saves.insert(site, saved);
+    }
+  }
+  Allocation {
+    locations,
+    callee_saved,
+    saves,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ir::parse_module;
+
+  fn allocate(source: &str) -> (Function, Allocation) {
+    let function = parse_module(source).unwrap().functions.remove(0);
+    let allocation = allocate_registers(&function);
+    (function, allocation)
+  }
+
+  #[test]
+  fn test_short_intervals_share_registers() {
+    let (_, allocation) = allocate(
+      "fn f(a: int) -> int {\nb0:\n  v0 = load int [a]\n  v1 = add int v0, 1\n  v2 = add int v1, 2\n  br v2, b1, b2\nb1:\n  ret v1\nb2:\n  ret v2\n}\n",
+    );
+    // v0 在 v1 的定义处结束，v1 沿用它的寄存器；v1、v2 一直活跃到分支之后
+    assert_eq!(
+      allocation.locations,
+      vec![
+        Location::Reg("%rsi"),
+        Location::Reg("%rsi"),
+        Location::Reg("%rdi"),
+      ]
+    );
+    assert!(allocation.callee_saved.is_empty() && allocation.saves.is_empty());
+  }
+
+  #[test]
+  fn test_values_across_calls() {
+    let mut source = "fn f(a: int) -> int {\nb0:\n".to_string();
+    for i in 0..7 {
+      source.push_str(&format!("  v{} = load int [a]\n", i));
+    }
+    source.push_str("  v7 = call int g()\n");
+    for i in 0..7 {
+      source.push_str(&format!("  v{} = add int v{}, v{}\n", i + 8, i + 7, i));
+    }
+    source.push_str("  ret v14\n}\n");
+    let (_, allocation) = allocate(&source);
+    // 前 5 个用被调者保存的寄存器，其余两个在调用前后存到栈上
+    assert_eq!(allocation.callee_saved, CALLEE_SAVED.to_vec());
+    assert_eq!(allocation.locations[4], Location::Reg("%r15"));
+    assert_eq!(allocation.locations[5], Location::Reg("%rsi"));
+    assert_eq!(allocation.saves[&(BlockId(0), 7)], vec![VReg(5), VReg(6)]);
+    assert_eq!(allocation.locations[7], Location::Reg("%r8"));
+  }
+
+  #[test]
+  fn test_call_result_used_in_earlier_block() {
+    // 调用在 b2 中，使用结果的 b1 排在它前面：按排列顺序 v6 的区间包含这次调用，
+    // 但它是调用的结果，不能在调用之后用保存的旧值覆盖
+    let mut source = "fn f(a: int) -> int {\nb0:\n".to_string();
+    for i in 0..6 {
+      source.push_str(&format!("  v{} = load int [a]\n", i));
+    }
+    source.push_str("  jmp b2\nb1:\n  v7 = add int v6, v0\n");
+    for i in 1..6 {
+      source.push_str(&format!("  v{} = add int v{}, v{}\n", i + 7, i + 6, i));
+    }
+    source.push_str("  ret v12\nb2:\n  v6 = call int g()\n  jmp b1\n}\n");
+    let (_, allocation) = allocate(&source);
+    assert_eq!(allocation.locations[5], Location::Reg("%rsi"));
+    assert_eq!(allocation.locations[6], Location::Reg("%rdi"));
+    assert_eq!(allocation.saves[&(BlockId(2), 0)], vec![VReg(5)]);
+  }
+
+  #[test]
+  fn test_spill_when_out_of_registers() {
+    let n = CALLEE_SAVED.len() + CALLER_SAVED.len() + 2;
+    let mut source = "fn f(a: int) -> int {\nb0:\n".to_string();
+    for i in 0..n {
+      source.push_str(&format!("  v{} = load int [a]\n", i));
+    }
+    // 按定义的顺序使用，后定义的区间结束得晚
+    source.push_str(&format!("  v{} = copy int 0\n", n));
+    for i in 0..n {
+      source.push_str(&format!("  v{} = add int v{}, v{}\n", n + i + 1, n + i, i));
+    }
+    source.push_str(&format!("  ret v{}\n}}\n", 2 * n));
+    let (_, allocation) = allocate(&source);
+    let spilled: Vec<usize> = (0..allocation.locations.len())
+      .filter(|i| allocation.locations[*i] == Location::Stack)
+      .collect();
+    // 寄存器用完后新的区间结束得最晚，直接溢出；
+    // 之后的 copy 区间很短，换下结束得最晚的 v10
+    assert_eq!(spilled, vec![n - 3, n - 2, n - 1]);
+  }
+}
@@ -0,0 +1,391 @@
+//! 名字解析
+//!
+//! 为每个函数建立词法作用域：函数参数和函数体最外层语句在同一个作用域中，
+//! `if` / `else` / `while` 的语句块各自开启一个新的作用域。每个变量的使用都绑定到
+//! 可见的声明上，未声明或已离开作用域的名字报错。
+//!
+//! 内层块可以遮蔽外层的同名变量。被遮蔽的声明及其使用会被改名为 `x@1` 这样的唯一名字，
+//! 因此代码生成器仍可以为每个函数使用一张扁平的符号表。
+//!
+//! 全局常量 `const int N = ...` 位于所有函数作用域之外，函数中与它同名的变量会被改名，
+//! 因此名字仍为 `N` 的使用一定指向全局常量。
+
+use crate::ast::AST;
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::signature::param_nodes;
+use std::collections::HashMap;
+
+/// 一个声明：改名后的唯一名字及声明位置
+struct Binding {
+  unique: String,
+  span: Span,
+}
+
+#[derive(Default)]
+struct Resolver {
+  /// 全局常量
+  globals: HashMap<String, Binding>,
+  scopes: Vec<HashMap<String, Binding>>,
+  /// 已经结束的块中声明过的名字，用于给出更准确的提示
+  ended: HashMap<String, Span>,
+  /// 当前函数中每个名字被声明的次数
+  counts: HashMap<String, usize>,
+  diagnostics: Vec<Diagnostic>,
+}
+
+/// 解析整个程序中的名字，必要时改名被遮蔽的变量，返回诊断信息
+pub fn resolve(ast: &mut AST) -> Vec<Diagnostic> {
+  let mut resolver = Resolver::default();
+  resolver.collect_globals(ast);
+  resolver.resolve_item(ast);
+  resolver.diagnostics
+}
+
+/// 程序中的全局常量（`Const` 节点）
+pub fn collect_consts(ast: &AST) -> Vec<&AST> {
+  let mut consts = vec![];
+  collect_consts_helper(ast, &mut consts);
+  consts
+}
+
+fn collect_consts_helper<'a>(ast: &'a AST, consts: &mut Vec<&'a AST>) {
+  match ast.value.as_str() {
+    "Const" => consts.push(ast),
+    "Pg" | "FnList" => {
+      for child in &ast.children {
+        collect_consts_helper(child, consts);
+      }
+    }
+    _ => {}
+  }
+}
+
+impl Resolver {
+  fn collect_globals(&mut self, ast: &AST) {
+    for def in collect_consts(ast) {
+      let id = &def.children[1];
+      if self.globals.contains_key(&id.value) {
+        self.diagnostics.push(Diagnostic::error(
+          format!("constant `{}` is defined more than once", id.value),
+          id.span,
+        ));
+        continue;
+      }
+      self.globals.insert(
+        id.value.clone(),
+        Binding {
+          unique: id.value.clone(),
+          span: id.span,
+        },
+      );
+    }
+  }
+
+  fn resolve_item(&mut self, ast: &mut AST) {
+    match ast.value.as_str() {
+      "Fn" => self.resolve_fn(ast),
+      // 常量的初始值只能引用其它常量
+      "Const" => {
+        self.scopes = vec![HashMap::new()];
+        self.resolve_expr(&mut ast.children[2]);
+        self.scopes.clear();
+      }
+      "Pg" | "FnList" => {
+        for child in &mut ast.children {
+          self.resolve_item(child);
+        }
+      }
+      _ => {}
+    }
+  }
+
+  fn resolve_fn(&mut self, ast: &mut AST) {
+    self.scopes = vec![HashMap::new()];
+    self.ended.clear();
+    self.counts.clear();
+    // 与全局常量同名的局部变量需要改名
+    for name in self.globals.keys() {
+      self.counts.insert(name.clone(), 1);
+    }
+    let params: Vec<(String, Span)> = param_nodes(&ast.children[2])
+      .into_iter()
+      .map(|node| (node.children[1].value.clone(), node.children[1].span))
+      .collect();
+    let mut uniques = vec![];
+    for (name, span) in params {
+      uniques.push(self.declare(&name, span));
+    }
+    let mut list = &mut ast.children[2].children[0];
+    for unique in uniques {
+      list.children[1].value = unique;
+      list = &mut list.children[3];
+    }
+    // 函数体最外层与参数共用一个作用域
+    self.resolve_stmt_list(&mut ast.children[3].children[0]);
+    self.scopes.clear();
+  }
+
+  /// 语句块：开启新的作用域
+  fn resolve_block(&mut self, ast: &mut AST) {
+    self.scopes.push(HashMap::new());
+    self.resolve_stmt_list(ast);
+    for (name, binding) in self.scopes.pop().unwrap() {
+      self.ended.insert(name, binding.span);
+    }
+  }
+
+  fn resolve_stmt_list(&mut self, ast: &mut AST) {
+    let mut stmt_list = ast;
+    while stmt_list.children.len() == 2 {
+      let (stmt, rest) = stmt_list.children.split_at_mut(1);
+      self.resolve_stmt(&mut stmt[0].children[0]);
+      stmt_list = &mut rest[0];
+    }
+  }
+
+  fn resolve_stmt(&mut self, ast: &mut AST) {
+    match ast.value.as_str() {
+      "VarDecl" => {
+        let unique = self.declare(&ast.children[1].value, ast.children[1].span);
+        ast.children[1].value = unique;
+      }
+      "VarDef" => {
+        // 初始化表达式中的名字先于新声明解析：`int x = x` 中右侧的 x 是外层的 x
+        self.resolve_expr(&mut ast.children[2]);
+        let unique = self.declare(&ast.children[1].value, ast.children[1].span);
+        ast.children[1].value = unique;
+      }
+      "Assign" => {
+        self.resolve_name(&mut ast.children[0]);
+        let target = &ast.children[0];
+        if self.globals.contains_key(&target.value) {
+          self.diagnostics.push(Diagnostic::error(
+            format!("cannot assign to constant `{}`", target.value),
+            target.span,
+          ));
+        }
+        self.resolve_expr(&mut ast.children[1]);
+      }
+      "Return" => {
+        for child in &mut ast.children {
+          self.resolve_expr(child);
+        }
+      }
+      "BranchStmt" => {
+        self.resolve_expr(&mut ast.children[0]);
+        self.resolve_block(&mut ast.children[1]);
+        self.resolve_block(&mut ast.children[2]);
+      }
+      "LoopStmt" => {
+        self.resolve_expr(&mut ast.children[0]);
+        self.resolve_block(&mut ast.children[1]);
+      }
+      "Expr" => self.resolve_expr(ast),
+      _ => {}
+    }
+  }
+
+  fn resolve_expr(&mut self, ast: &mut AST) {
+    match ast.value.as_str() {
+      // 函数名和关键字参数名不是变量
+      "FnCall" => {
+        for arg in &mut ast.children[1..] {
+          self.resolve_expr(arg);
+        }
+      }
+      "NamedArg" => self.resolve_expr(&mut ast.children[1]),
+      _ if ast.children.is_empty() => {
+        if is_variable(&ast.value) {
+          self.resolve_name(ast);
+        }
+      }
+      _ => {
+        for child in &mut ast.children {
+          self.resolve_expr(child);
+        }
+      }
+    }
+  }
+
+  /// 在当前作用域中声明变量，返回它的唯一名字
+  fn declare(&mut self, name: &str, span: Span) -> String {
+    if let Some(previous) = self.scopes.last().unwrap().get(name) {
+      self.diagnostics.push(
+        Diagnostic::error(
+          format!("`{}` is already declared in this scope", name),
+          span,
+        )
+        .with_note("a name can only be shadowed in an inner block".to_string()),
+      );
+      return previous.unique.clone();
+    }
+    let count = self.counts.entry(name.to_string()).or_insert(0);
+    let unique = if *count == 0 {
+      name.to_string()
+    } else {
+      format!("{}@{}", name, count)
+    };
+    *count += 1;
+    self.scopes.last_mut().unwrap().insert(
+      name.to_string(),
+      Binding {
+        unique: unique.clone(),
+        span,
+      },
+    );
+    unique
+  }
+
+  /// 把一次变量使用绑定到最近的可见声明
+  fn resolve_name(&mut self, ast: &mut AST) {
+    let binding = self
+      .scopes
+      .iter()
+      .rev()
+      .find_map(|scope| scope.get(&ast.value))
+      .or_else(|| self.globals.get(&ast.value));
+    match binding {
+      Some(binding) => ast.value = binding.unique.clone(),
+      None => {
+        let mut diagnostic = Diagnostic::error(
+          format!("cannot find variable `{}` in this scope", ast.value),
+          ast.span,
+        );
+        if self.ended.contains_key(&ast.value) {
+          diagnostic = diagnostic.with_note(format!(
+            "`{}` was declared in a block that has already ended",
+            ast.value
+          ));
+        }
+        self.diagnostics.push(diagnostic);
+      }
+    }
+  }
+}
+
+/// 改名后的变量在源码中的名字：`x@1` -> `x`
+pub fn source_name(unique: &str) -> &str {
+  unique.split('@').next().unwrap()
+}
+
+/// 表达式中的记号是否为变量名（而不是字面量或 true / false），
+/// 与词法分析器的规则一致：以字母或 `_` 开头，后面是字母、数字或 `_`
+pub fn is_variable(token: &str) -> bool {
+  let mut chars = token.chars();
+  match chars.next() {
+    Some(c) if c.is_alphabetic() || c == '_' => {}
+    _ => return false,
+  }
+  chars.all(|c| c.is_alphanumeric() || c == '_') && token != "true" && token != "false"
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::parser::Parser;
+
+  fn messages(source: &str) -> Vec<String> {
+    let mut ast = Parser::new(Lexer::new(source)).parse();
+    resolve(&mut ast).into_iter().map(|d| d.message).collect()
+  }
+
+  #[test]
+  fn test_undefined_variable() {
+    let source = "int main()\n  int a = 1\n  return a + b\n";
+    let mut ast = Parser::new(Lexer::new(source)).parse();
+    let diagnostics = resolve(&mut ast);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+      diagnostics[0].message,
+      "cannot find variable `b` in this scope"
+    );
+    assert_eq!(
+      &source[diagnostics[0].span.start..diagnostics[0].span.end],
+      "b"
+    );
+  }
+
+  #[test]
+  fn test_block_scope_ends() {
+    let source = "int main()\n  if 1 > 0\n    int x = 1\n  else\n    pass\n  return x\n";
+    let mut ast = Parser::new(Lexer::new(source)).parse();
+    let diagnostics = resolve(&mut ast);
+    assert_eq!(
+      diagnostics[0].notes,
+      vec!["`x` was declared in a block that has already ended"]
+    );
+  }
+
+  #[test]
+  fn test_shadowing_renames_inner_declaration() {
+    let source =
+      "int main()\n  int x = 1\n  while x < 3\n    int x = x + 10\n    print(x)\n  return x\n";
+    let mut ast = Parser::new(Lexer::new(source)).parse();
+    assert!(resolve(&mut ast).is_empty());
+    let expression = ast.get_expression();
+    let names: Vec<&str> = expression
+      .iter()
+      .map(String::as_str)
+      .filter(|t| t.starts_with('x'))
+      .collect();
+    // 外层 x，循环条件，内层声明（右侧用外层 x），print 内层 x，返回外层 x
+    assert_eq!(names, vec!["x", "x", "x@1", "x", "x@1", "x"]);
+  }
+
+  #[test]
+  fn test_names_with_digits() {
+    let source =
+      "int main()\n  int a1 = 1\n  while a1 < 3\n    int a1 = 2\n    print(a1)\n  return a1\n";
+    let mut ast = Parser::new(Lexer::new(source)).parse();
+    assert!(resolve(&mut ast).is_empty());
+    let names: Vec<String> = ast
+      .get_expression()
+      .into_iter()
+      .filter(|t| t.starts_with("a1"))
+      .collect();
+    assert_eq!(names, vec!["a1", "a1", "a1@1", "a1@1", "a1"]);
+    assert_eq!(
+      messages(
+        "int main()\n  if 1 > 0\n    int z1 = 5\n  else\n    pass\n  print(z1, q_2)\n  return 0\n"
+      ),
+      vec![
+        "cannot find variable `z1` in this scope",
+        "cannot find variable `q_2` in this scope"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_redeclaration_in_same_scope() {
+    assert_eq!(
+      messages("int main(int a)\n  int a = 1\n  int b\n  int b\n  return a\n"),
+      vec![
+        "`a` is already declared in this scope",
+        "`b` is already declared in this scope"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_global_constants() {
+    let source = "const int N = 3\nconst int N = 4\nint main(int x)\n  N = 1\n  if x > N\n    int N = x\n    return N\n  else\n    pass\n  return N\n";
+    let mut ast = Parser::new(Lexer::new(source)).parse();
+    let messages: Vec<String> = resolve(&mut ast).into_iter().map(|d| d.message).collect();
+    assert_eq!(
+      messages,
+      vec![
+        "constant `N` is defined more than once",
+        "cannot assign to constant `N`"
+      ]
+    );
+    let names: Vec<String> = ast
+      .get_expression()
+      .into_iter()
+      .filter(|t| t.starts_with('N'))
+      .collect();
+    // 与常量同名的局部变量被改名
+    assert_eq!(names, vec!["N", "N", "N", "N", "N@1", "N@1", "N"]);
+  }
+}
@@ -0,0 +1,548 @@
+//! 函数签名与调用实参绑定
+//!
+//! 参数可以带默认值（`int add(int a, int b = 1)`），调用时可以用关键字参数（`add(b=2, a=1)`）。
+//! `bind_args` 把一次调用的实参对应到形参上；`lower_call_args` 在生成代码前把调用改写成
+//! 纯位置参数的形式，缺省的参数直接在调用处填入默认值表达式，因此调用约定不变。
+//!
+//! 最后一个参数可以是变长参数（`int sum(int... xs)`），多出来的位置实参被收集到
+//! `VarArgs` 节点中，由代码生成器在调用方栈上排成连续的切片，函数内以 `[int]` 使用。
+
+use crate::ast::AST;
+use crate::aux::suggest_name;
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::resolve::{is_variable, source_name};
+use crate::types::Type;
+use std::collections::HashMap;
+
+/// 内建函数及其参数个数（None 表示任意个）
+pub const BUILTIN_FUNCTIONS: [(&str, Option<usize>); 6] = [
+  ("print", None),
+  ("scan", Some(0)),
+  ("size", Some(1)),
+  ("wrapping_add", Some(2)),
+  ("wrapping_sub", Some(2)),
+  ("wrapping_mul", Some(2)),
+];
+
+/// 溢出时回绕的整数运算内建函数及对应的运算符
+pub const WRAPPING_FUNCTIONS: [(&str, &str); 3] = [
+  ("wrapping_add", "+"),
+  ("wrapping_sub", "-"),
+  ("wrapping_mul", "*"),
+];
+
+#[derive(Clone)]
+pub struct Param {
+  pub name: String,
+  pub ty: Type,
+  /// 默认值表达式
+  pub default: Option<AST>,
+  /// 是否为变长参数 `T... name`
+  pub variadic: bool,
+  pub span: Span,
+}
+
+#[derive(Clone)]
+pub struct FnSignature {
+  pub name: String,
+  pub params: Vec<Param>,
+  pub ret: Type,
+  pub span: Span,
+}
+
+/// 实参来源：调用中的第 i 个实参，第 i 个形参的默认值，或收集到变长参数中的若干实参
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArgSource {
+  Given(usize),
+  Default(usize),
+  Rest(Vec<usize>),
+}
+
+/// 依次返回 `Param` 下的 ParamList / ParamListTail 节点，每个节点为 [Type, Identifier, Default, Tail]
+pub fn param_nodes(param: &AST) -> Vec<&AST> {
+  let mut nodes = vec![];
+  let mut list = &param.children[0];
+  while list.children.len() == 4 {
+    nodes.push(list);
+    list = &list.children[3];
+  }
+  nodes
+}
+
+impl FnSignature {
+  pub fn from_ast(fn_ast: &AST) -> Self {
+    let params = param_nodes(&fn_ast.children[2])
+      .into_iter()
+      .map(|node| {
+        let default = &node.children[2].children[0];
+        Param {
+          // 名字解析可能给参数改名，关键字参数按源码中的名字匹配
+          name: source_name(&node.children[1].value).to_string(),
+          ty: Type::from_ast(&node.children[0]),
+          default: (default.value != "ε").then(|| default.clone()),
+          variadic: node.children[0].children[0].value == "Variadic",
+          span: node.children[1].span,
+        }
+      })
+      .collect();
+    FnSignature {
+      name: fn_ast.children[1].value.clone(),
+      params,
+      ret: Type::from_ast(&fn_ast.children[0]),
+      span: fn_ast.children[1].span,
+    }
+  }
+
+  pub fn is_variadic(&self) -> bool {
+    self.params.last().is_some_and(|p| p.variadic)
+  }
+
+  /// 不含变长参数的形参个数
+  pub fn fixed_params(&self) -> usize {
+    self.params.len() - self.is_variadic() as usize
+  }
+}
+
+/// 收集程序中所有函数的签名
+pub fn collect_signatures(ast: &AST) -> HashMap<String, FnSignature> {
+  let mut signatures = HashMap::new();
+  collect_signatures_helper(ast, &mut signatures);
+  signatures
+}
+
+fn collect_signatures_helper(ast: &AST, signatures: &mut HashMap<String, FnSignature>) {
+  match ast.value.as_str() {
+    "Fn" => {
+      let signature = FnSignature::from_ast(ast);
+      signatures.insert(signature.name.clone(), signature);
+    }
+    "Pg" | "FnList" => {
+      for child in &ast.children {
+        collect_signatures_helper(child, signatures);
+      }
+    }
+    _ => {}
+  }
+}
+
+/// 把调用 `call`（FnCall 节点）的实参绑定到 `sig` 的形参上，按形参顺序返回每个实参的来源
+pub fn bind_args(sig: &FnSignature, call: &AST) -> Result<Vec<ArgSource>, Diagnostic> {
+  let args = &call.children[1..];
+  let fixed = sig.fixed_params();
+  let mut bound: Vec<Option<ArgSource>> = vec![None; fixed];
+  let mut rest = vec![];
+  let mut seen_named = false;
+  for (i, arg) in args.iter().enumerate() {
+    let index = if arg.value == "NamedArg" {
+      seen_named = true;
+      let name = &arg.children[0].value;
+      match sig.params.iter().position(|p| &p.name == name) {
+        Some(index) if index == fixed => {
+          return Err(Diagnostic::error(
+            format!("variadic parameter `{}` cannot be passed by name", name),
+            arg.children[0].span,
+          ))
+        }
+        Some(index) => index,
+        None => {
+          return Err(Diagnostic::error(
+            format!("function `{}` has no parameter named `{}`", sig.name, name),
+            arg.children[0].span,
+          ))
+        }
+      }
+    } else {
+      if seen_named {
+        return Err(Diagnostic::error(
+          "positional argument follows keyword argument".to_string(),
+          arg.span,
+        ));
+      }
+      if i >= fixed && sig.is_variadic() {
+        rest.push(i);
+        continue;
+      }
+      if i >= fixed {
+        return Err(Diagnostic::error(
+          format!(
+            "function `{}` takes {} argument(s) but {} were given",
+            sig.name,
+            sig.params.len(),
+            args.len()
+          ),
+          arg.span,
+        ));
+      }
+      i
+    };
+    if bound[index].is_some() {
+      return Err(Diagnostic::error(
+        format!(
+          "argument `{}` of `{}` specified more than once",
+          sig.params[index].name, sig.name
+        ),
+        arg.span,
+      ));
+    }
+    bound[index] = Some(ArgSource::Given(i));
+  }
+
+  let mut sources = vec![];
+  for (index, source) in bound.into_iter().enumerate() {
+    match source {
+      Some(source) => sources.push(source),
+      None if sig.params[index].default.is_some() => sources.push(ArgSource::Default(index)),
+      None => {
+        return Err(
+          Diagnostic::error(
+            format!(
+              "missing argument `{}` in call to `{}`",
+              sig.params[index].name, sig.name
+            ),
+            call.span,
+          )
+          .with_note(format!("`{}` has no default value", sig.params[index].name)),
+        )
+      }
+    }
+  }
+  if sig.is_variadic() {
+    sources.push(ArgSource::Rest(rest));
+  }
+  Ok(sources)
+}
+
+/// 把 FnCall 改写成纯位置参数：关键字参数按形参排好，缺省的参数填入默认值，
+/// 变长参数对应的实参放进最后一个 `VarArgs` 节点
+pub fn lower_call_args(call: &mut AST, sig: &FnSignature) {
+  let sources = bind_args(sig, call).unwrap_or_else(|d| panic!("{}", d.message));
+  let mut args: Vec<Option<AST>> = call.children.drain(1..).map(Some).collect();
+  for source in sources {
+    let arg = match source {
+      ArgSource::Given(i) => {
+        let arg = args[i].take().unwrap();
+        if arg.value == "NamedArg" {
+          arg.children.into_iter().nth(1).unwrap()
+        } else {
+          arg
+        }
+      }
+      ArgSource::Default(i) => sig.params[i].default.clone().unwrap(),
+      ArgSource::Rest(indices) => AST::new(
+        "VarArgs".to_string(),
+        indices.iter().map(|&i| args[i].take().unwrap()).collect(),
+      )
+      .with_span(call.span),
+    };
+    call.children.push(arg);
+  }
+}
+
+/// 检查函数定义、参数默认值和所有调用的实参。
+/// 签名在检查之前已经全部收集，调用可以出现在被调函数的定义之前
+pub fn check_calls(ast: &AST, signatures: &HashMap<String, FnSignature>) -> Vec<Diagnostic> {
+  let mut diagnostics = vec![];
+  check_fn_definitions(ast, &mut HashMap::new(), &mut diagnostics);
+  let mut names: Vec<&String> = signatures.keys().collect();
+  names.sort();
+  for name in names {
+    check_defaults(&signatures[name], &mut diagnostics);
+  }
+  check_calls_helper(ast, signatures, &mut diagnostics);
+  diagnostics
+}
+
+/// 同名函数只能定义一次，也不能与内建函数重名
+fn check_fn_definitions<'a>(
+  ast: &'a AST,
+  defined: &mut HashMap<&'a str, Span>,
+  diagnostics: &mut Vec<Diagnostic>,
+) {
+  match ast.value.as_str() {
+    "Fn" => {
+      let name = &ast.children[1];
+      // print 等内建函数允许被用户定义的同名函数覆盖，这里只检查重复定义
+      if defined.insert(&name.value, name.span).is_some() {
+        diagnostics.push(Diagnostic::error(
+          format!("function `{}` is defined more than once", name.value),
+          name.span,
+        ));
+      }
+    }
+    "Pg" | "FnList" => {
+      for child in &ast.children {
+        check_fn_definitions(child, defined, diagnostics);
+      }
+    }
+    _ => {}
+  }
+}
+
+fn check_defaults(sig: &FnSignature, diagnostics: &mut Vec<Diagnostic>) {
+  let mut seen_default = false;
+  for (index, param) in sig.params.iter().enumerate() {
+    if param.variadic {
+      if index + 1 != sig.params.len() {
+        diagnostics.push(Diagnostic::error(
+          format!(
+            "variadic parameter `{}` must be the last parameter",
+            param.name
+          ),
+          param.span,
+        ));
+      }
+      if param.default.is_some() {
+        diagnostics.push(Diagnostic::error(
+          format!(
+            "variadic parameter `{}` cannot have a default value",
+            param.name
+          ),
+          param.span,
+        ));
+      }
+      continue;
+    }
+    match &param.default {
+      Some(default) => {
+        seen_default = true;
+        // 默认值在调用处求值，不能引用被调函数的局部变量
+        for name in default_free_names(default) {
+          diagnostics.push(Diagnostic::error(
+            format!(
+              "default value of parameter `{}` cannot refer to variable `{}`",
+              param.name, name
+            ),
+            default.span,
+          ));
+        }
+      }
+      None if seen_default => diagnostics.push(Diagnostic::error(
+        format!(
+          "parameter `{}` without a default value follows a parameter with one",
+          param.name
+        ),
+        param.span,
+      )),
+      None => {}
+    }
+  }
+}
+
+fn default_free_names(ast: &AST) -> Vec<String> {
+  if ast.children.is_empty() {
+    let name = &ast.value;
+    if is_variable(name) {
+      return vec![name.clone()];
+    }
+    return vec![];
+  }
+  let args = if ast.value == "FnCall" {
+    &ast.children[1..]
+  } else {
+    &ast.children[..]
+  };
+  args.iter().flat_map(default_free_names).collect()
+}
+
+fn check_calls_helper(
+  ast: &AST,
+  signatures: &HashMap<String, FnSignature>,
+  diagnostics: &mut Vec<Diagnostic>,
+) {
+  if ast.value == "FnCall" {
+    let name = &ast.children[0];
+    if let Some(sig) = signatures.get(&name.value) {
+      if let Err(diagnostic) = bind_args(sig, ast) {
+        diagnostics.push(diagnostic);
+      }
+    } else if let Some((_, arity)) = BUILTIN_FUNCTIONS.iter().find(|(b, _)| *b == name.value) {
+      let given = ast.children.len() - 1;
+      if arity.is_some_and(|arity| arity != given) {
+        diagnostics.push(Diagnostic::error(
+          format!(
+            "function `{}` takes {} argument(s) but {} were given",
+            name.value,
+            arity.unwrap(),
+            given
+          ),
+          ast.span,
+        ));
+      }
+    } else {
+      let mut diagnostic =
+        Diagnostic::error(format!("cannot find function `{}`", name.value), name.span);
+      let candidates = signatures
+        .keys()
+        .map(String::as_str)
+        .chain(BUILTIN_FUNCTIONS.iter().map(|(b, _)| *b));
+      if let Some(suggestion) = suggest_name(&name.value, candidates) {
+        diagnostic = diagnostic.with_note(format!("did you mean `{}`?", suggestion));
+      }
+      diagnostics.push(diagnostic);
+    }
+  }
+  for child in &ast.children {
+    check_calls_helper(child, signatures, diagnostics);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::parser::Parser;
+
+  fn parse(source: &str) -> AST {
+    Parser::new(Lexer::new(source)).parse()
+  }
+
+  fn messages(source: &str) -> Vec<String> {
+    let ast = parse(source);
+    check_calls(&ast, &collect_signatures(&ast))
+      .into_iter()
+      .map(|d| d.message)
+      .collect()
+  }
+
+  fn find_call(ast: &mut AST) -> Option<&mut AST> {
+    if ast.value == "FnCall" {
+      return Some(ast);
+    }
+    ast.children.iter_mut().find_map(find_call)
+  }
+
+  const ADD: &str = "\n\nint add(int a, int b = 10, int c = 100)\n  return a + b + c\n";
+
+  #[test]
+  fn test_signature_defaults() {
+    let ast = parse(ADD.trim_start());
+    let sig = &collect_signatures(&ast)["add"];
+    let names: Vec<&str> = sig.params.iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(names, vec!["a", "b", "c"]);
+    assert!(sig.params[0].default.is_none());
+    assert_eq!(
+      sig.params[1].default.as_ref().unwrap().get_expression(),
+      vec!["10"]
+    );
+  }
+
+  #[test]
+  fn test_lower_named_and_default_args() {
+    let mut ast = parse(&format!("int main()\n  return add(c=3, a=1){}", ADD));
+    let signatures = collect_signatures(&ast);
+    let call = find_call(&mut ast).unwrap();
+    lower_call_args(call, &signatures["add"]);
+    assert_eq!(
+      call.get_expression(),
+      vec!["add", "op(", "1", "10", "3", "op)"]
+    );
+  }
+
+  #[test]
+  fn test_valid_calls() {
+    assert!(messages(&format!(
+      "int main()\n  return add(1) + add(1, 2) + add(1, c=5){}",
+      ADD
+    ))
+    .is_empty());
+  }
+
+  #[test]
+  fn test_missing_and_duplicate_args() {
+    assert_eq!(
+      messages(&format!(
+        "int main()\n  add(b=1)\n  add(1, a=2)\n  add(1, d=2)\n  add(1, 2, 3, 4)\n  add(b=1, 2)\n  return 0{}",
+        ADD
+      )),
+      vec![
+        "missing argument `a` in call to `add`",
+        "argument `a` of `add` specified more than once",
+        "function `add` has no parameter named `d`",
+        "function `add` takes 3 argument(s) but 4 were given",
+        "positional argument follows keyword argument",
+      ]
+    );
+  }
+
+  const SUM: &str = "\n\nint sum(int base, int... xs)\n  return base + size(xs)\n";
+
+  #[test]
+  fn test_bind_variadic_args() {
+    let mut ast = parse(&format!("int main()\n  return sum(1, 2, 3, 4){}", SUM));
+    let signatures = collect_signatures(&ast);
+    assert!(signatures["sum"].is_variadic());
+    let call = find_call(&mut ast).unwrap();
+    assert_eq!(
+      bind_args(&signatures["sum"], call).unwrap(),
+      vec![ArgSource::Given(0), ArgSource::Rest(vec![1, 2, 3])]
+    );
+    lower_call_args(call, &signatures["sum"]);
+    assert_eq!(call.children[2].value, "VarArgs");
+    assert_eq!(call.children[2].children.len(), 3);
+  }
+
+  #[test]
+  fn test_bad_variadic() {
+    assert_eq!(
+      messages(&format!(
+        "int f(int... xs, int a)\n  return a\n\nint main()\n  return sum(xs=1){}",
+        SUM
+      )),
+      vec![
+        "variadic parameter `xs` must be the last parameter",
+        "variadic parameter `xs` cannot be passed by name",
+      ]
+    );
+  }
+
+  #[test]
+  fn test_unknown_function_suggestion() {
+    let source = "int main()\n  print(fibonaci(5))\n  return scan(1) + nothing_like_it()\n\nint fibonacci(int a)\n  return a\n";
+    let ast = parse(source);
+    let diagnostics = check_calls(&ast, &collect_signatures(&ast));
+    let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+    assert_eq!(
+      messages,
+      vec![
+        "cannot find function `fibonaci`",
+        "function `scan` takes 0 argument(s) but 1 were given",
+        "cannot find function `nothing_like_it`",
+      ]
+    );
+    assert_eq!(diagnostics[0].notes, vec!["did you mean `fibonacci`?"]);
+    assert!(diagnostics[2].notes.is_empty());
+    assert_eq!(
+      &source[diagnostics[0].span.start..diagnostics[0].span.end],
+      "fibonaci"
+    );
+  }
+
+  #[test]
+  fn test_forward_call_arity_and_duplicates() {
+    assert_eq!(
+      messages(
+        "int main()\n  return f(1, 2)\n\nint f(int a)\n  return a\n\nint f(int b)\n  return b\n"
+      ),
+      vec![
+        "function `f` is defined more than once",
+        "function `f` takes 1 argument(s) but 2 were given",
+      ]
+    );
+  }
+
+  #[test]
+  fn test_bad_defaults() {
+    assert_eq!(
+      messages("int f(int a = 1, int b, int c = b)\n  return a\n"),
+      vec![
+        "parameter `b` without a default value follows a parameter with one",
+        "default value of parameter `c` cannot refer to variable `b`",
+      ]
+    );
+    assert_eq!(
+      messages("int f(int a1, int b2 = a1 + 1)\n  return b2\n"),
+      vec!["default value of parameter `b2` cannot refer to variable `a1`"]
+    );
+  }
+}
@@ -0,0 +1,558 @@
+//! SSA 形式的构造、消除与检查
+//!
+//! `into_ssa` 把地址没有被取走的栈槽提升为虚拟寄存器：在写入该栈槽的块的迭代支配边界上
+//! 插入 phi，再沿支配树重命名，删除对这些栈槽的 load / store。
+//! `out_of_ssa` 把每个 phi 拆成前驱块末尾写入临时寄存器的 copy，和块开头从临时寄存器
+//! 读出的 copy，这样同一块中的多个 phi 互不干扰，也不需要拆分关键边。
+//! `verify` 检查 SSA 的性质，每个优化之后都会运行。
+
+use crate::cfg::{Cfg, Dominators};
+use crate::ir::{Block, BlockId, Function, Imm, Inst, IrType, Operand, Slot, Term, VReg};
+use std::collections::{HashMap, HashSet};
+
+/// 类型的零值，用作没有赋值的路径上变量的值（类型检查保证这样的值不会被读取）
+pub fn zero(ty: IrType) -> Operand {
+  Operand::Imm(match ty {
+    IrType::Float => Imm::Float(0.0),
+    IrType::Bool => Imm::Bool(false),
+    _ => Imm::Int(0),
+  })
+}
+
+/// 可以提升为虚拟寄存器的栈槽：`addr [x]` 的结果可以访问 x 及其后的所有栈槽，
+/// 所以只有位于第一个被取地址的栈槽之前的才可以提升
+fn promotable_slots(function: &Function) -> Vec<Slot> {
+  let escaped: HashSet<&str> = function
+    .blocks
+    .iter()
+    .flat_map(|block| &block.insts)
+    .filter_map(|inst| match inst {
+      Inst::Addr { slot, .. } => Some(slot.as_str()),
+      _ => None,
+    })
+    .collect();
+  function
+    .slots()
+    .take_while(|slot| !escaped.contains(slot.name.as_str()))
+    .cloned()
+    .collect()
+}
+
+/// 保证入口块没有前驱：否则把入口的内容移到新块，入口只跳转过去
+fn ensure_entry_has_no_preds(function: &mut Function) {
+  let entry = BlockId(0);
+  if !function
+    .blocks
+    .iter()
+    .any(|block| block.term.successors().contains(&entry))
+  {
+    return;
+  }
+  let moved = BlockId(function.blocks.len() as u32);
+  for block in &mut function.blocks {
+    for target in block.term.successors_mut() {
+      if *target == entry {
+        *target = moved;
+      }
+    }
+  }
+  let old = std::mem::replace(
+    &mut function.blocks[0],
+    Block {
+      id: entry,
+      insts: vec![],
+      term: Term::Jump(moved),
+    },
+  );
+  function.blocks.push(Block { id: moved, ..old });
+}
+
+/// 放置在块开头的 phi：为哪个栈槽、结果寄存器、来自各前驱的值
+#[derive(Clone)]
+struct PendingPhi {
+  slot: usize,
+  dst: VReg,
+  incoming: Vec<(BlockId, Operand)>,
+}
+
+struct Renamer<'a> {
+  cfg: &'a Cfg,
+  dom: &'a Dominators,
+  slots: HashMap<String, usize>,
+  /// 每个被提升的栈槽当前的值
+  values: Vec<Vec<Operand>>,
+  /// 被删除的 load 的结果寄存器，替换为读到的值
+  replace: HashMap<VReg, Operand>,
+  phis: Vec<Vec<PendingPhi>>,
+}
+
+impl Renamer<'_> {
+  fn resolve(&self, operand: &mut Operand) {
+    if let Operand::Reg(reg) = operand {
+      if let Some(value) = self.replace.get(reg) {
+        *operand = value.clone();
+      }
+    }
+  }
+
+  fn rename(&mut self, function: &mut Function, block: BlockId) {
+    let mut pushed = vec![];
+    for phi in &self.phis[block.0 as usize] {
+      self.values[phi.slot].push(Operand::Reg(phi.dst));
+      pushed.push(phi.slot);
+    }
+    let insts = std::mem::take(&mut function.blocks[block.0 as usize].insts);
+    let mut kept = vec![];
+    for mut inst in insts {
+      for operand in inst.operands_mut() {
+        self.resolve(operand);
+      }
+      match &inst {
+        Inst::Load { dst, slot, .. } if self.slots.contains_key(slot) => {
+          let value = self.values[self.slots[slot]].last().unwrap().clone();
+          self.replace.insert(*dst, value);
+        }
+        Inst::Store { slot, src } if self.slots.contains_key(slot) => {
+          let slot = self.slots[slot];
+          self.values[slot].push(src.clone());
+          pushed.push(slot);
+        }
+        _ => kept.push(inst),
+      }
+    }
+    let current = &mut function.blocks[block.0 as usize];
+    current.insts = kept;
+    for operand in current.term.operands_mut() {
+      self.resolve(operand);
+    }
+    for &succ in self.cfg.succs(block) {
+      for phi in &mut self.phis[succ.0 as usize] {
+        let value = self.values[phi.slot].last().unwrap().clone();
+        phi.incoming.push((block, value));
+      }
+    }
+    for &child in self.dom.children(block) {
+      self.rename(function, child);
+    }
+    for slot in pushed {
+      self.values[slot].pop();
+    }
+  }
+}
+
+/// 把函数转为 SSA 形式
+pub fn into_ssa(function: &mut Function) {
+  function.remove_unreachable_blocks();
+  ensure_entry_has_no_preds(function);
+  let cfg = Cfg::new(function);
+  let dom = Dominators::new(&cfg);
+  let promoted = promotable_slots(function);
+  let mut next = function.new_vreg().0;
+  let mut fresh = || {
+    next += 1;
+    VReg(next - 1)
+  };
+
+  // 在写入栈槽的块的迭代支配边界上放置 phi
+  let mut phis = vec![vec![]; function.blocks.len()];
+  for (i, slot) in promoted.iter().enumerate() {
+    let mut work: Vec<BlockId> = function
+      .blocks
+      .iter()
+      .filter(|block| {
+        block
+          .insts
+          .iter()
+          .any(|inst| matches!(inst, Inst::Store { slot: s, .. } if *s == slot.name))
+      })
+      .map(|block| block.id)
+      .collect();
+    let mut placed = HashSet::new();
+    while let Some(block) = work.pop() {
+      for &frontier in dom.frontier(block) {
+        if placed.insert(frontier) {
+          phis[frontier.0 as usize].push(PendingPhi {
+            slot: i,
+            dst: fresh(),
+            incoming: vec![],
+          });
+          work.push(frontier);
+        }
+      }
+    }
+  }
+
+  // 参数的初始值在入口读出，局部变量的初始值是零值
+  let mut entry_loads = vec![];
+  let mut values = vec![];
+  for slot in &promoted {
+    if function.params.contains(slot) {
+      let dst = fresh();
+      entry_loads.push(Inst::Load {
+        dst,
+        ty: slot.ty,
+        slot: slot.name.clone(),
+      });
+      values.push(vec![Operand::Reg(dst)]);
+    } else {
+      values.push(vec![zero(slot.ty)]);
+    }
+  }
+
+  let mut renamer = Renamer {
+    cfg: &cfg,
+    dom: &dom,
+    slots: promoted
+      .iter()
+      .enumerate()
+      .map(|(i, slot)| (slot.name.clone(), i))
+      .collect(),
+    values,
+    replace: HashMap::new(),
+    phis,
+  };
+  renamer.rename(function, BlockId(0));
+
+  for (block, phis) in function.blocks.iter_mut().zip(renamer.phis) {
+    let mut insts: Vec<Inst> = phis
+      .into_iter()
+      .map(|mut phi| {
+        phi.incoming.sort_by_key(|(from, _)| *from);
+        Inst::Phi {
+          dst: phi.dst,
+          ty: promoted[phi.slot].ty,
+          incoming: phi.incoming,
+        }
+      })
+      .collect();
+    insts.append(&mut block.insts);
+    block.insts = insts;
+  }
+  function.blocks[0].insts.splice(0..0, entry_loads);
+  function.locals.retain(|slot| !promoted.contains(slot));
+}
+
+/// 消除 phi，结果不再是 SSA 形式（同一个虚拟寄存器可能被多处写入）
+pub fn out_of_ssa(function: &mut Function) {
+  let mut next = function.new_vreg().0;
+  let mut copies: Vec<Vec<Inst>> = vec![vec![]; function.blocks.len()];
+  for block in &mut function.blocks {
+    for inst in &mut block.insts {
+      let Inst::Phi { dst, ty, incoming } = inst else {
+        break;
+      };
+      let temp = VReg(next);
+      next += 1;
+      for (from, value) in incoming.drain(..) {
+        copies[from.0 as usize].push(Inst::Copy {
+          dst: temp,
+          ty: *ty,
+          src: value,
+        });
+      }
+      *inst = Inst::Copy {
+        dst: *dst,
+        ty: *ty,
+        src: Operand::Reg(temp),
+      };
+    }
+  }
+  for (block, mut copies) in function.blocks.iter_mut().zip(copies) {
+    block.insts.append(&mut copies);
+  }
+}
+
+/// 检查 SSA 的性质：每个虚拟寄存器只定义一次，定义支配所有使用，
+/// phi 只在块的开头，且为每个前驱恰好提供一个值
+pub fn verify(function: &Function) -> Result<(), String> {
+  let n = function.blocks.len();
+  for (i, block) in function.blocks.iter().enumerate() {
+    if block.id.0 as usize != i {
+      return Err(format!("block {} is at position {}", block.id, i));
+    }
+    if let Some(target) = block
+      .term
+      .successors()
+      .into_iter()
+      .find(|t| t.0 as usize >= n)
+    {
+      return Err(format!("{}: jump to missing block {}", block.id, target));
+    }
+  }
+  let cfg = Cfg::new(function);
+  let dom = Dominators::new(&cfg);
+  let slots: HashSet<&str> = function.slots().map(|slot| slot.name.as_str()).collect();
+
+  // 定义的位置：(块, 块中的下标)
+  let mut defs: HashMap<VReg, (BlockId, usize)> = HashMap::new();
+  for block in &function.blocks {
+    let mut phis_done = false;
+    for (i, inst) in block.insts.iter().enumerate() {
+      if let Some(dst) = inst.dst() {
+        if defs.insert(dst, (block.id, i)).is_some() {
+          return Err(format!("{} is defined more than once", dst));
+        }
+      }
+      if let Some(slot) = inst.slot() {
+        if !slots.contains(slot) {
+          return Err(format!("{}: unknown slot `{}`", block.id, slot));
+        }
+      }
+      match inst {
+        Inst::Phi { dst, incoming, .. } => {
+          if phis_done {
+            return Err(format!(
+              "{}: phi {} after a non-phi instruction",
+              block.id, dst
+            ));
+          }
+          let preds = cfg.preds(block.id);
+          let mut seen = HashSet::new();
+          for (from, _) in incoming {
+            if !preds.contains(from) {
+              return Err(format!(
+                "{}: phi {} has a value for {}, which is not a predecessor",
+                block.id, dst, from
+              ));
+            }
+            if !seen.insert(*from) {
+              return Err(format!(
+                "{}: phi {} has more than one value for {}",
+                block.id, dst, from
+              ));
+            }
+          }
+          if let Some(pred) = preds.iter().find(|pred| !seen.contains(pred)) {
+            return Err(format!(
+              "{}: phi {} has no value for predecessor {}",
+              block.id, dst, pred
+            ));
+          }
+        }
+        _ => phis_done = true,
+      }
+    }
+  }
+
+  // 使用处必须被定义处支配；phi 的值在对应前驱的末尾使用
+  let check = |reg: VReg, block: BlockId, position: usize| -> Result<(), String> {
+    let Some(&(def_block, def_position)) = defs.get(&reg) else {
+      return Err(format!("{}: {} is used but never defined", block, reg));
+    };
+    let dominated = if def_block == block {
+      def_position < position
+    } else {
+      dom.dominates(def_block, block)
+    };
+    if dominated {
+      Ok(())
+    } else {
+      Err(format!(
+        "{}: use of {} is not dominated by its definition in {}",
+        block, reg, def_block
+      ))
+    }
+  };
+  for block in &function.blocks {
+    if !dom.is_reachable(block.id) {
+      continue;
+    }
+    for (i, inst) in block.insts.iter().enumerate() {
+      if let Inst::Phi { incoming, .. } = inst {
+        for (from, value) in incoming {
+          if let Operand::Reg(reg) = value {
+            check(*reg, *from, usize::MAX)?;
+          }
+        }
+        continue;
+      }
+      for operand in inst.operands() {
+        if let Operand::Reg(reg) = operand {
+          check(*reg, block.id, i)?;
+        }
+      }
+    }
+    for operand in block.term.operands() {
+      if let Operand::Reg(reg) = operand {
+        check(*reg, block.id, usize::MAX)?;
+      }
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ir::parse_module;
+
+  fn function(text: &str) -> Function {
+    parse_module(text).unwrap().functions.remove(0)
+  }
+
+  const COUNT: &str = "fn count(n: int) -> int {
+  slot i: int
+  slot s: int
+b0:
+  store [i], 0
+  store [s], 0
+  jmp b1
+b1:
+  v0 = load int [i]
+  v1 = load int [n]
+  v2 = lt int v0, v1
+  br v2, b2, b3
+b2:
+  v3 = load int [s]
+  v4 = load int [i]
+  v5 = add int v3, v4
+  store [s], v5
+  v6 = add int v4, 1
+  store [i], v6
+  jmp b1
+b3:
+  v7 = load int [s]
+  ret v7
+}
+";
+
+  #[test]
+  fn test_into_ssa_inserts_phis() {
+    let mut f = function(COUNT);
+    into_ssa(&mut f);
+    assert_eq!(
+      f.to_string(),
+      "fn count(n: int) -> int {
+b0:
+  v10 = load int [n]
+  jmp b1
+b1:
+  v8 = phi int [b0: 0], [b2: v6]
+  v9 = phi int [b0: 0], [b2: v5]
+  v2 = lt int v8, v10
+  br v2, b2, b3
+b2:
+  v5 = add int v9, v8
+  v6 = add int v8, 1
+  jmp b1
+b3:
+  ret v9
+}
+"
+    );
+    assert_eq!(verify(&f), Ok(()));
+  }
+
+  #[test]
+  fn test_into_ssa_keeps_escaping_slots() {
+    let mut f = function(
+      "fn f() -> int {
+  slot b: int
+  slot a.0: int
+  slot a.1: int
+b0:
+  store [a.0], 1
+  store [a.1], 2
+  store [b], 3
+  v0 = addr [a.0]
+  v1 = call int g(v0)
+  v2 = load int [a.1]
+  v3 = load int [b]
+  v4 = add int v2, v3
+  ret v4
+}
+",
+    );
+    into_ssa(&mut f);
+    // a.1 位于 a.0 之后，也可以通过 v0 访问
+    assert_eq!(
+      f.to_string(),
+      "fn f() -> int {
+  slot a.0: int
+  slot a.1: int
+b0:
+  store [a.0], 1
+  store [a.1], 2
+  v0 = addr [a.0]
+  v1 = call int g(v0)
+  v2 = load int [a.1]
+  v4 = add int v2, 3
+  ret v4
+}
+"
+    );
+  }
+
+  #[test]
+  fn test_entry_with_predecessors() {
+    let mut f = function(
+      "fn f() -> void {\n  slot x: int\nb0:\n  store [x], 1\n  br true, b0, b1\nb1:\n  ret\n}\n",
+    );
+    into_ssa(&mut f);
+    assert_eq!(
+      f.to_string(),
+      "fn f() -> void {\nb0:\n  jmp b2\nb1:\n  ret\nb2:\n  v0 = phi int [b0: 0], [b2: 1]\n  br true, b2, b1\n}\n"
+    );
+    assert_eq!(verify(&f), Ok(()));
+  }
+
+  #[test]
+  fn test_out_of_ssa() {
+    let mut f = function(COUNT);
+    into_ssa(&mut f);
+    out_of_ssa(&mut f);
+    assert_eq!(
+      f.to_string(),
+      "fn count(n: int) -> int {
+b0:
+  v10 = load int [n]
+  v11 = copy int 0
+  v12 = copy int 0
+  jmp b1
+b1:
+  v8 = copy int v11
+  v9 = copy int v12
+  v2 = lt int v8, v10
+  br v2, b2, b3
+b2:
+  v5 = add int v9, v8
+  v6 = add int v8, 1
+  v11 = copy int v6
+  v12 = copy int v5
+  jmp b1
+b3:
+  ret v9
+}
+"
+    );
+  }
+
+  #[test]
+  fn test_verify_errors() {
+    let error = |text: &str| verify(&function(text)).unwrap_err();
+    assert_eq!(
+      error("fn f() -> int {\nb0:\n  v0 = copy int 1\n  v0 = copy int 2\n  ret v0\n}\n"),
+      "v0 is defined more than once"
+    );
+    assert_eq!(
+      error("fn f() -> int {\nb0:\n  v0 = add int v1, 1\n  v1 = copy int 2\n  ret v0\n}\n"),
+      "b0: use of v1 is not dominated by its definition in b0"
+    );
+    assert_eq!(
+      error("fn f() -> int {\nb0:\n  br true, b1, b2\nb1:\n  v0 = copy int 1\n  jmp b2\nb2:\n  ret v0\n}\n"),
+      "b2: use of v0 is not dominated by its definition in b1"
+    );
+    assert_eq!(
+      error("fn f() -> int {\nb0:\n  br true, b1, b2\nb1:\n  jmp b2\nb2:\n  v0 = phi int [b1: 1]\n  ret v0\n}\n"),
+      "b2: phi v0 has no value for predecessor b0"
+    );
+    assert_eq!(
+      error("fn f() -> int {\nb0:\n  jmp b1\nb1:\n  v0 = copy int 1\n  v1 = phi int [b0: 1]\n  ret v1\n}\n"),
+      "b1: phi v1 after a non-phi instruction"
+    );
+    assert_eq!(
+      error("fn f() -> int {\nb0:\n  v0 = load int [x]\n  ret v0\n}\n"),
+      "b0: unknown slot `x`"
+    );
+  }
+}
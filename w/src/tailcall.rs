@@ -0,0 +1,585 @@
+//! 尾调用优化，在 SSA 形式上进行
+//!
+//! 调用的结果不经任何运算就被返回时，这个调用是尾调用：
+//! - 对自身的尾调用改为跳回函数开头的循环。入口块中读取参数的指令之后分出循环头，
+//!   参数的值改由循环头的 phi 给出，各处尾调用把实参作为 phi 的值跳过来
+//! - 对其他函数的尾调用改为 `tailcall`，被调函数复用当前的栈帧
+//!
+//! 实参中有 `addr` 取得的地址（变长实参）时，它指向当前栈帧中的栈槽，不能复用栈帧。
+//!
+//! 带 `@tailcall` 属性的函数中，对自身的每处调用都必须是尾调用；带 `@tailcall` 属性的语句
+//! 中的调用（被调函数可以是其他函数）也必须是尾调用，否则是编译错误（`check_tail_calls`，在 AST 上检查）。
+//! 这些调用在翻译成 IR 时就直接成为 `tailcall`，不依赖优化级别；这里的优化只处理其余的尾调用。
+
+use crate::ast::AST;
+use crate::diagnostic::Diagnostic;
+use crate::ir::{Block, BlockId, Function, Inst, Operand, Term, VReg};
+use crate::signature::{bind_args, ArgSource, FnSignature};
+use std::collections::{HashMap, HashSet};
+
+/// 块末尾的调用是否是尾调用：经过只含 phi 的块，最终返回的就是调用的结果（void 函数不返回值）
+fn is_tail_call(function: &Function, block: &Block) -> bool {
+  let Some(Inst::Call { dst, .. }) = block.insts.last() else {
+    return false;
+  };
+  let mut value = dst.map(Operand::Reg);
+  let mut from = block.id;
+  let mut term = &block.term;
+  // 只含 phi 的块可能构成环，最多走过每个块一次
+  for _ in 0..function.blocks.len() {
+    match term {
+      Term::Ret(None) => return true,
+      Term::Ret(Some(ret)) => return value.as_ref() == Some(ret),
+      Term::Jump(to) => {
+        let next = &function.blocks[to.0 as usize];
+        for inst in &next.insts {
+          let Inst::Phi { dst, incoming, .. } = inst else {
+            return false;
+          };
+          if value.is_some()
+            && incoming
+              .iter()
+              .any(|(block, v)| *block == from && Some(v) == value.as_ref())
+          {
+            value = Some(Operand::Reg(*dst));
+          }
+        }
+        from = next.id;
+        term = &next.term;
+      }
+      _ => return false,
+    }
+  }
+  false
+}
+
+/// 把入口块中读取参数之后的部分移到新的循环头，返回循环头和各参数读出的寄存器
+fn split_entry(function: &mut Function) -> (BlockId, Vec<Option<VReg>>) {
+  let header = BlockId(function.blocks.len() as u32);
+  let entry = &mut function.blocks[0];
+  let mut loads = vec![None; function.params.len()];
+  let mut split = 0;
+  for inst in &entry.insts {
+    let Inst::Load { dst, slot, .. } = inst else {
+      break;
+    };
+    let Some(i) = function.params.iter().position(|param| param.name == *slot) else {
+      break;
+    };
+    loads[i] = Some(*dst);
+    split += 1;
+  }
+  let insts = entry.insts.split_off(split);
+  let term = std::mem::replace(&mut entry.term, Term::Jump(header));
+  for succ in term.successors() {
+    for inst in &mut function.blocks[succ.0 as usize].insts {
+      if let Inst::Phi { incoming, .. } = inst {
+        for (from, _) in incoming {
+          if *from == BlockId(0) {
+            *from = header;
+          }
+        }
+      }
+    }
+  }
+  function.blocks.push(Block {
+    id: header,
+    insts,
+    term,
+  });
+  (header, loads)
+}
+
+pub fn eliminate_tail_calls(function: &mut Function) {
+  let addrs: HashSet<VReg> = function
+    .blocks
+    .iter()
+    .flat_map(|block| &block.insts)
+    .filter_map(|inst| match inst {
+      Inst::Addr { dst, .. } => Some(*dst),
+      _ => None,
+    })
+    .collect();
+  let mut tails: Vec<BlockId> = vec![];
+  let mut recursive = false;
+  for block in &function.blocks {
+    let Some(Inst::Call { callee, args, .. }) = block.insts.last() else {
+      continue;
+    };
+    let escapes = args
+      .iter()
+      .any(|arg| matches!(arg, Operand::Reg(reg) if addrs.contains(reg)));
+    if !escapes && is_tail_call(function, block) {
+      tails.push(block.id);
+      recursive |= *callee == function.name;
+    }
+  }
+  if tails.is_empty() {
+    return;
+  }
+
+  // 参数的当前值由循环头的 phi 给出，替换入口之后对参数的所有使用
+  let mut header = None;
+  let mut phis: Vec<(VReg, VReg)> = vec![];
+  if recursive {
+    let (block, loads) = split_entry(function);
+    for tail in &mut tails {
+      if *tail == BlockId(0) {
+        *tail = block;
+      }
+    }
+    let mut replace = HashMap::new();
+    for (next, load) in (function.new_vreg().0..).zip(loads.iter().flatten()) {
+      phis.push((*load, VReg(next)));
+      replace.insert(*load, Operand::Reg(VReg(next)));
+    }
+    for block in function.blocks.iter_mut().skip(1) {
+      let operands = block
+        .insts
+        .iter_mut()
+        .flat_map(Inst::operands_mut)
+        .chain(block.term.operands_mut());
+      for operand in operands {
+        if let Operand::Reg(reg) = operand {
+          if let Some(value) = replace.get(reg) {
+            *operand = value.clone();
+          }
+        }
+      }
+    }
+    header = Some((block, loads));
+  }
+
+  let mut incoming: Vec<(BlockId, Vec<Operand>)> = vec![];
+  let mut removed: Vec<(BlockId, BlockId)> = vec![];
+  for &id in &tails {
+    let block = &mut function.blocks[id.0 as usize];
+    let Some(Inst::Call {
+      ty, callee, args, ..
+    }) = block.insts.pop()
+    else {
+      unreachable!()
+    };
+    removed.extend(block.term.successors().into_iter().map(|to| (id, to)));
+    block.term = match &header {
+      Some((header, _)) if callee == function.name => {
+        incoming.push((id, args));
+        Term::Jump(*header)
+      }
+      _ => Term::TailCall { ty, callee, args },
+    };
+  }
+  for (from, to) in removed {
+    for inst in &mut function.blocks[to.0 as usize].insts {
+      if let Inst::Phi { incoming, .. } = inst {
+        incoming.retain(|(block, _)| *block != from);
+      }
+    }
+  }
+
+  if let Some((header, loads)) = header {
+    let mut insts: Vec<Inst> = vec![];
+    let params = loads.iter().zip(&function.params).enumerate();
+    for (i, (load, param)) in params {
+      let Some(load) = load else {
+        continue;
+      };
+      let dst = phis.iter().find(|(from, _)| from == load).unwrap().1;
+      let mut values = vec![(BlockId(0), Operand::Reg(*load))];
+      values.extend(incoming.iter().map(|(from, args)| (*from, args[i].clone())));
+      values.sort_by_key(|(from, _)| *from);
+      insts.push(Inst::Phi {
+        dst,
+        ty: param.ty,
+        incoming: values,
+      });
+    }
+    let block = &mut function.blocks[header.0 as usize];
+    insts.append(&mut block.insts);
+    block.insts = insts;
+  }
+  function.remove_unreachable_blocks();
+}
+
+/// 表达式恰好是一个函数调用时返回调用节点
+fn as_call(expr: &AST) -> Option<&AST> {
+  match expr.value.as_str() {
+    "FnCall" => Some(expr),
+    "Expr" | "Term" | "Factor" if expr.children.len() == 1 => as_call(&expr.children[0]),
+    "Factor" if expr.children.len() == 3 => as_call(&expr.children[1]),
+    _ => None,
+  }
+}
+
+/// `return f(...)` 或表达式语句 `f(...)` 中的调用
+pub fn stmt_call(stmt: &AST) -> Option<&AST> {
+  match stmt.value.as_str() {
+    "Return" => stmt.children.first().and_then(as_call),
+    "Expr" => as_call(stmt),
+    _ => None,
+  }
+}
+
+/// 收集语句列表中位于尾部的调用；`tail` 表示列表执行完后函数随即返回
+fn collect_tail_calls<'a>(stmt_list: &'a AST, tail: bool, is_void: bool, calls: &mut Vec<&'a AST>) {
+  let mut stmt_list = stmt_list;
+  while stmt_list.children.len() == 2 {
+    let stmt = &stmt_list.children[0].children[0];
+    stmt_list = &stmt_list.children[1];
+    // 之后是列表末尾，或者紧跟着一个不带值的 return
+    let next = stmt_list.children.first().and_then(|s| s.children.first());
+    let last = match next {
+      None => tail,
+      Some(next) => next.value == "Return" && next.children.is_empty(),
+    };
+    match stmt.value.as_str() {
+      "Return" => calls.extend(stmt_call(stmt)),
+      "Expr" if last && is_void => calls.extend(stmt_call(stmt)),
+      "BranchStmt" => {
+        collect_tail_calls(&stmt.children[1], last, is_void, calls);
+        collect_tail_calls(&stmt.children[2], last, is_void, calls);
+      }
+      "LoopStmt" => collect_tail_calls(&stmt.children[1], false, is_void, calls),
+      _ => {}
+    }
+  }
+}
+
+/// 带属性的语句
+fn collect_attributed_stmts<'a>(stmt_list: &'a AST, stmts: &mut Vec<&'a AST>) {
+  let mut stmt_list = stmt_list;
+  while stmt_list.children.len() == 2 {
+    let stmt = &stmt_list.children[0];
+    if !stmt.stmt_attrs().is_empty() {
+      stmts.push(stmt);
+    }
+    match stmt.children[0].value.as_str() {
+      "BranchStmt" => {
+        collect_attributed_stmts(&stmt.children[0].children[1], stmts);
+        collect_attributed_stmts(&stmt.children[0].children[2], stmts);
+      }
+      "LoopStmt" => collect_attributed_stmts(&stmt.children[0].children[1], stmts),
+      _ => {}
+    }
+    stmt_list = &stmt_list.children[1];
+  }
+}
+
+/// 对名为 `name` 的函数的所有调用
+fn collect_calls<'a>(ast: &'a AST, name: &str, calls: &mut Vec<&'a AST>) {
+  if ast.value == "FnCall" && ast.children[0].value == name {
+    calls.push(ast);
+  }
+  for child in &ast.children {
+    collect_calls(child, name, calls);
+  }
+}
+
+/// 检查要求成为尾调用的调用：
+/// - `@tailcall` 函数中对自身的调用
+/// - 带 `@tailcall` 属性的语句中的调用，被调函数可以是任意用户函数
+///
+/// 它们必须处在尾部，被调函数不能是内置函数，也不能传递变长实参。
+/// 语句上只能使用 `@tailcall` 属性
+pub fn check_tail_calls(ast: &AST, signatures: &HashMap<String, FnSignature>) -> Vec<Diagnostic> {
+  let mut diagnostics = vec![];
+  check_tail_calls_helper(ast, signatures, &mut diagnostics);
+  diagnostics
+}
+
+fn check_tail_calls_helper(
+  ast: &AST,
+  signatures: &HashMap<String, FnSignature>,
+  diagnostics: &mut Vec<Diagnostic>,
+) {
+  match ast.value.as_str() {
+    "Fn" => {
+      let name = &ast.children[1].value;
+      let body = &ast.children[3].children[0];
+      let is_void = ast.children[0].children[0].value == "void";
+      let mut tail = vec![];
+      collect_tail_calls(body, true, is_void, &mut tail);
+      let is_tail = |call: &AST| tail.iter().any(|t| std::ptr::eq(*t, call));
+      let mut checked = vec![];
+      if ast.has_attr("tailcall") {
+        let mut calls = vec![];
+        collect_calls(body, name, &mut calls);
+        for call in calls {
+          if is_tail(call) {
+            checked.push(call);
+            continue;
+          }
+          diagnostics.push(
+            Diagnostic::error(
+              format!("recursive call to `{}` is not in tail position", name),
+              call.span,
+            )
+            .with_note(format!(
+              "`{}` is marked `@tailcall`, so every call to itself must be returned directly",
+              name
+            )),
+          );
+        }
+      }
+      let mut stmts = vec![];
+      collect_attributed_stmts(body, &mut stmts);
+      for stmt in stmts {
+        for attr in stmt.stmt_attrs() {
+          let attr_name = &attr.children[0];
+          if attr_name.value != "tailcall" || attr.children.len() > 1 {
+            diagnostics.push(
+              Diagnostic::error(
+                format!("`@{}` cannot be used on a statement", attr_name.value),
+                attr.span,
+              )
+              .with_note("only `@tailcall` can be placed on a statement".to_string()),
+            );
+          }
+        }
+        if !stmt.has_attr("tailcall") {
+          continue;
+        }
+        match stmt_call(&stmt.children[0]) {
+          Some(call) if is_tail(call) => {
+            if !checked.iter().any(|c| std::ptr::eq(*c, call)) {
+              checked.push(call);
+            }
+          }
+          _ => diagnostics.push(
+            Diagnostic::error(
+              "`@tailcall` statement is not a call in tail position".to_string(),
+              stmt.children[0].span,
+            )
+            .with_note(
+              "the call must be returned directly, or be the last statement of a void function"
+                .to_string(),
+            ),
+          ),
+        }
+      }
+      for call in checked {
+        let callee = &call.children[0].value;
+        let Some(sig) = signatures.get(callee) else {
+          diagnostics.push(Diagnostic::error(
+            format!("cannot tail call builtin function `{}`", callee),
+            call.span,
+          ));
+          continue;
+        };
+        let varargs = bind_args(sig, call).is_ok_and(|sources| {
+          sources
+            .iter()
+            .any(|source| matches!(source, ArgSource::Rest(args) if !args.is_empty()))
+        });
+        if varargs {
+          diagnostics.push(
+            Diagnostic::error(
+              format!("tail call to `{}` cannot pass variadic arguments", callee),
+              call.span,
+            )
+            .with_note("variadic arguments are stored in the caller's stack frame".to_string()),
+          );
+        }
+      }
+    }
+    "Pg" | "FnList" => {
+      for child in &ast.children {
+        check_tail_calls_helper(child, signatures, diagnostics);
+      }
+    }
+    _ => {}
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ir::parse_module;
+  use crate::lexer::Lexer;
+  use crate::parser::Parser;
+  use crate::signature::collect_signatures;
+  use crate::ssa::{into_ssa, verify};
+
+  fn optimize(text: &str) -> String {
+    let mut function = parse_module(text).unwrap().functions.remove(0);
+    into_ssa(&mut function);
+    eliminate_tail_calls(&mut function);
+    assert_eq!(verify(&function), Ok(()));
+    function.to_string()
+  }
+
+  #[test]
+  fn test_self_tail_call_becomes_loop() {
+    assert_eq!(
+      optimize(
+        "fn sum(n: int, acc: int) -> int {
+b0:
+  v0 = load int [n]
+  v1 = eq int v0, 0
+  br v1, b1, b2
+b1:
+  v2 = load int [acc]
+  ret v2
+b2:
+  v3 = load int [acc]
+  v4 = add int v3, v0
+  v5 = sub int v0, 1
+  v6 = call int sum(v5, v4)
+  ret v6
+}
+"
+      ),
+      "fn sum(n: int, acc: int) -> int {
+b0:
+  v7 = load int [n]
+  v8 = load int [acc]
+  jmp b3
+b1:
+  ret v10
+b2:
+  v4 = add int v10, v9
+  v5 = sub int v9, 1
+  jmp b3
+b3:
+  v9 = phi int [b0: v7], [b2: v5]
+  v10 = phi int [b0: v8], [b2: v4]
+  v1 = eq int v9, 0
+  br v1, b1, b2
+}
+"
+    );
+  }
+
+  #[test]
+  fn test_tail_calls_to_other_functions() {
+    assert_eq!(
+      optimize(
+        "fn f(n: int) -> int {
+b0:
+  v0 = load int [n]
+  v1 = gt int v0, 0
+  br v1, b1, b2
+b1:
+  v2 = call int g(v0)
+  jmp b3
+b2:
+  v3 = call int h(v0)
+  v4 = add int v3, 1
+  jmp b3
+b3:
+  v5 = phi int [b1: v2], [b2: v4]
+  ret v5
+}
+"
+      ),
+      "fn f(n: int) -> int {
+b0:
+  v6 = load int [n]
+  v1 = gt int v6, 0
+  br v1, b1, b2
+b1:
+  tailcall int g(v6)
+b2:
+  v3 = call int h(v6)
+  v4 = add int v3, 1
+  jmp b3
+b3:
+  v5 = phi int [b2: v4]
+  ret v5
+}
+"
+    );
+  }
+
+  #[test]
+  fn test_calls_that_are_not_tail_calls() {
+    let text = "fn f() -> int {
+  slot va0.0: int
+b0:
+  store [va0.0], 1
+  v0 = addr [va0.0]
+  v1 = call int sum(v0, 1)
+  ret v1
+}
+";
+    assert_eq!(optimize(text), parse_module(text).unwrap().to_string());
+    let text = "fn f() -> int {\nb0:\n  v0 = call int g()\n  ret 0\n}\n";
+    assert_eq!(optimize(text), text);
+    // void 函数忽略被调函数的返回值
+    assert_eq!(
+      optimize("fn f() -> void {\nb0:\n  v0 = call int g()\n  ret\n}\n"),
+      "fn f() -> void {\nb0:\n  tailcall int g()\n}\n"
+    );
+  }
+
+  fn check(source: &str) -> Vec<String> {
+    let ast = Parser::new(Lexer::new(source)).parse();
+    check_tail_calls(&ast, &collect_signatures(&ast))
+      .into_iter()
+      .map(|d| format!("{}: {}", d.message, &source[d.span.start..d.span.end]))
+      .collect()
+  }
+
+  #[test]
+  fn test_check_tail_calls() {
+    assert!(check(
+      "@tailcall\nint f(int n, int acc)\n  if n == 0\n    return acc\n  else\n    return f(n - 1, acc + n)\n"
+    )
+    .is_empty());
+    assert!(check(
+      "@tailcall\nvoid g(int n)\n  while n > 0\n    g(n - 1)\n    return\n  if n == 0\n    print(n)\n  else\n    g(n - 2)\n"
+    )
+    .is_empty());
+    assert_eq!(
+      check(
+        "@tailcall\nint f(int n)\n  if n == 0\n    return 1\n  else\n    return n * f(n - 1)\n"
+      ),
+      vec!["recursive call to `f` is not in tail position: f(n - 1)"]
+    );
+    assert_eq!(
+      check("@tailcall\nvoid g(int n)\n  while n > 0\n    g(n - 1)\n  print(n)\n"),
+      vec!["recursive call to `g` is not in tail position: g(n - 1)"]
+    );
+    // 没有 @tailcall 时不检查
+    assert!(check("int f(int n)\n  return n * f(n - 1)\n").is_empty());
+  }
+
+  #[test]
+  fn test_check_variadic_tail_call() {
+    assert_eq!(
+      check("@tailcall\nint f(int n, int... xs)\n  if n == 0\n    return 0\n  else\n    return f(n - 1, 1, 2)\n"),
+      vec!["tail call to `f` cannot pass variadic arguments: f(n - 1, 1, 2)"]
+    );
+  }
+
+  #[test]
+  fn test_check_tail_call_statements() {
+    // 带 @tailcall 的语句可以调用其他函数
+    assert!(check(
+      "bool even(int n)\n  if n == 0\n    return true\n  else\n    @tailcall\n    return odd(n - 1)\n\nbool odd(int n)\n  @tailcall\n  return even(n - 1)\n\nvoid g(int n)\n  print(n)\n  @tailcall\n  g(n - 1)\n"
+    )
+    .is_empty());
+    assert_eq!(
+      check("int f(int n)\n  @tailcall\n  int x = f(n)\n  @tailcall\n  return n + f(n)\n"),
+      vec![
+        "`@tailcall` statement is not a call in tail position: int x = f(n)",
+        "`@tailcall` statement is not a call in tail position: return n + f(n)"
+      ]
+    );
+    assert_eq!(
+      check("void g(int n)\n  @tailcall\n  g(n)\n  print(n)\n"),
+      vec!["`@tailcall` statement is not a call in tail position: g(n)"]
+    );
+    assert_eq!(
+      check("void g(int n)\n  @inline\n  @tailcall\n  print(n)\n"),
+      vec![
+        "`@inline` cannot be used on a statement: @inline",
+        "cannot tail call builtin function `print`: print(n)"
+      ]
+    );
+    assert_eq!(
+      check("int f(int... xs)\n  return 0\n\nint g()\n  @tailcall\n  return f(1)\n"),
+      vec!["tail call to `f` cannot pass variadic arguments: f(1)"]
+    );
+  }
+}
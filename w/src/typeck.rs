@@ -0,0 +1,694 @@
+//! 类型检查
+//!
+//! 在名字解析之后、代码生成之前运行。为每个表达式求出类型（int、float、bool、string
+//! 及切片），检查运算符、变量定义、赋值、return、条件和调用实参，不做隐式类型转换。
+//!
+//! 类型记录在 AST 节点的 `ty` 上：表达式节点是表达式的类型，运算符节点是操作数的类型，
+//! 代码生成器据此选择整数或浮点指令。
+//!
+//! `auto x = expr` 的类型由初始值推导，记录在 VarDef 的 `Type` 节点上。函数返回类型的推导
+//! （`auto f()`）需要显式开启：从函数体中第一个类型已知的 `return` 推导，递归函数可以从
+//! 不依赖自身结果的 `return` 推导出来。
+
+use crate::ast::{collect_chain_mut, fold_chain, AST};
+use crate::diagnostic::Diagnostic;
+use crate::flow::{fn_falls_through, missing_return};
+use crate::resolve::source_name;
+use crate::signature::{bind_args, ArgSource, FnSignature, Param, WRAPPING_FUNCTIONS};
+use crate::types::{collect_aliases, is_auto, Type, AUTO};
+use std::collections::{HashMap, HashSet};
+
+struct TypeChecker {
+  signatures: HashMap<String, FnSignature>,
+  aliases: HashMap<String, Type>,
+  /// 当前函数中变量的类型，名字解析之后每个名字在函数内唯一
+  vars: HashMap<String, Type>,
+  /// 全局常量的类型
+  globals: HashMap<String, Type>,
+  /// 当前函数中类型由 `auto` 推导出来的变量
+  inferred: HashSet<String>,
+  /// 推导返回类型时收集到的各 return 的类型
+  returns: Vec<Option<Type>>,
+  /// 当前函数的返回类型是推导出来的
+  returns_inferred: bool,
+  diagnostics: Vec<Diagnostic>,
+}
+
+/// 检查整个程序的类型，并在 AST 上标注类型。
+/// `infer_returns` 为 false 时 `auto` 返回类型是错误
+pub fn check_program(
+  ast: &mut AST,
+  signatures: &HashMap<String, FnSignature>,
+  infer_returns: bool,
+) -> Vec<Diagnostic> {
+  let aliases = collect_aliases(ast)
+    .into_iter()
+    .map(|(name, def)| (name, Type::from_ast(def)))
+    .collect();
+  let mut checker = TypeChecker {
+    signatures: signatures.clone(),
+    aliases,
+    vars: HashMap::new(),
+    globals: HashMap::new(),
+    inferred: HashSet::new(),
+    returns: vec![],
+    returns_inferred: false,
+    diagnostics: vec![],
+  };
+  // 常量的类型要在检查函数体之前确定
+  checker.check_consts(ast);
+  let mut fns = vec![];
+  collect_auto_fns(ast, &mut fns);
+  if infer_returns {
+    checker.infer_return_types(&mut fns);
+  } else {
+    for f in fns {
+      checker.diagnostics.push(
+        Diagnostic::error(
+          "return type inference is not enabled".to_string(),
+          f.children[0].span,
+        )
+        .with_note(format!(
+          "give `{}` an explicit return type, or pass `--infer-return-types`",
+          f.children[1].value
+        )),
+      );
+    }
+  }
+  checker.check_item(ast);
+  checker.diagnostics
+}
+
+fn collect_auto_fns<'a>(ast: &'a mut AST, fns: &mut Vec<&'a mut AST>) {
+  match ast.value.as_str() {
+    "Fn" if is_auto(&ast.children[0]) => fns.push(ast),
+    "Pg" | "FnList" => {
+      for child in &mut ast.children {
+        collect_auto_fns(child, fns);
+      }
+    }
+    _ => {}
+  }
+}
+
+fn auto() -> Type {
+  Type::Named(AUTO.to_string())
+}
+
+impl TypeChecker {
+  /// 反复检查返回类型尚未推导出来的函数，直到不再有进展。
+  /// 这一步只用于推导，诊断信息由之后的完整检查报告
+  fn infer_return_types(&mut self, fns: &mut [&mut AST]) {
+    loop {
+      let mut progress = false;
+      for f in fns.iter_mut() {
+        if f.children[0].ty.is_some() {
+          continue;
+        }
+        let saved = self.diagnostics.len();
+        self.returns.clear();
+        self.check_fn(&mut f.clone());
+        self.diagnostics.truncate(saved);
+        // 没有 return 语句的函数返回 void
+        let ty = if self.returns.is_empty() {
+          Some(Type::Void)
+        } else {
+          self.returns.iter().flatten().next().cloned()
+        };
+        if let Some(ty) = ty {
+          let name = &f.children[1].value;
+          self.signatures.get_mut(name).unwrap().ret = ty.clone();
+          f.children[0].ty = Some(ty);
+          progress = true;
+        }
+      }
+      if !progress {
+        break;
+      }
+    }
+    for f in fns.iter().filter(|f| f.children[0].ty.is_none()) {
+      self.diagnostics.push(
+        Diagnostic::error(
+          format!("cannot infer the return type of `{}`", f.children[1].value),
+          f.children[0].span,
+        )
+        .with_note(format!(
+          "every `return` in `{}` depends on a value whose type is not known yet",
+          f.children[1].value
+        )),
+      );
+    }
+  }
+
+  fn check_consts(&mut self, ast: &mut AST) {
+    match ast.value.as_str() {
+      "Const" => {
+        self.vars.clear();
+        let found = self.check_expr(&mut ast.children[2]);
+        let ty = if is_auto(&ast.children[0]) {
+          ast.children[0].ty = found.clone();
+          found
+        } else {
+          let ty = self.canonical(&Type::from_ast(&ast.children[0]));
+          self.expect(&ty, &found, &ast.children[2]);
+          Some(ty)
+        };
+        if let Some(ty) = ty {
+          self.globals.insert(ast.children[1].value.clone(), ty);
+        }
+      }
+      "Pg" | "FnList" => {
+        for child in &mut ast.children {
+          self.check_consts(child);
+        }
+      }
+      _ => {}
+    }
+  }
+
+  /// 展开类型别名
+  fn canonical(&self, ty: &Type) -> Type {
+    self.canonical_helper(ty, 0)
+  }
+
+  fn canonical_helper(&self, ty: &Type, depth: usize) -> Type {
+    match ty {
+      // 循环的别名已经由 check_types 报告，这里只需保证不会无限展开
+      Type::Named(name) if depth < 32 => match self.aliases.get(name) {
+        Some(def) => self.canonical_helper(def, depth + 1),
+        None => ty.clone(),
+      },
+      Type::Array(elem, len) => Type::Array(Box::new(self.canonical_helper(elem, depth)), *len),
+      Type::Slice(elem) => Type::Slice(Box::new(self.canonical_helper(elem, depth))),
+      Type::Fn(params, ret) => Type::Fn(
+        params
+          .iter()
+          .map(|p| self.canonical_helper(p, depth))
+          .collect(),
+        Box::new(self.canonical_helper(ret, depth)),
+      ),
+      _ => ty.clone(),
+    }
+  }
+
+  /// 检查表达式的类型是否为期望的类型；类型未知（此前已报错）时不再报告
+  fn expect(&mut self, expected: &Type, found: &Option<Type>, ast: &AST) -> bool {
+    match found {
+      Some(found) if found != expected => {
+        self.diagnostics.push(Diagnostic::error(
+          format!(
+            "mismatched types: expected `{}`, found `{}`",
+            expected, found
+          ),
+          ast.span,
+        ));
+        false
+      }
+      _ => true,
+    }
+  }
+
+  fn check_item(&mut self, ast: &mut AST) {
+    match ast.value.as_str() {
+      "Fn" => self.check_fn(ast),
+      "Pg" | "FnList" => {
+        for child in &mut ast.children {
+          self.check_item(child);
+        }
+      }
+      _ => {}
+    }
+  }
+
+  fn check_fn(&mut self, ast: &mut AST) {
+    self.vars.clear();
+    self.inferred.clear();
+    self.returns_inferred = is_auto(&ast.children[0]);
+    let ret = self.canonical(&Type::from_ast(&ast.children[0]));
+    let mut list = &mut ast.children[2].children[0];
+    while list.children.len() == 4 {
+      let ty = self.canonical(&Type::from_ast(&list.children[0]));
+      let default = &mut list.children[2].children[0];
+      if default.value != "ε" {
+        let found = self.check_expr(default);
+        self.expect(&ty, &found, default);
+      }
+      self.vars.insert(list.children[1].value.clone(), ty);
+      list = &mut list.children[3];
+    }
+    self.check_stmt_list(&mut ast.children[3].children[0], &ret);
+    let name = &ast.children[1];
+    if is_auto(&ast.children[0])
+      && ret != auto()
+      && ret != Type::Void
+      && name.value != "main"
+      && fn_falls_through(ast)
+    {
+      self.diagnostics.push(missing_return(name, &ret));
+    }
+  }
+
+  fn check_stmt_list(&mut self, ast: &mut AST, ret: &Type) {
+    let mut stmt_list = ast;
+    while stmt_list.children.len() == 2 {
+      let (stmt, rest) = stmt_list.children.split_at_mut(1);
+      self.check_stmt(&mut stmt[0].children[0], ret);
+      stmt_list = &mut rest[0];
+    }
+  }
+
+  fn check_stmt(&mut self, ast: &mut AST, ret: &Type) {
+    match ast.value.as_str() {
+      "VarDecl" => {
+        let ty = self.canonical(&Type::from_ast(&ast.children[0]));
+        self.vars.insert(ast.children[1].value.clone(), ty);
+      }
+      "VarDef" if is_auto(&ast.children[0]) => {
+        let found = self.check_expr(&mut ast.children[2]);
+        let name = ast.children[1].value.clone();
+        match found {
+          Some(Type::Void) => self.diagnostics.push(Diagnostic::error(
+            format!(
+              "cannot infer the type of `{}` from an expression of type `void`",
+              source_name(&name)
+            ),
+            ast.children[2].span,
+          )),
+          Some(ty) => {
+            ast.children[0].ty = Some(ty.clone());
+            self.vars.insert(name.clone(), ty);
+            self.inferred.insert(name);
+          }
+          // 初始值的错误已经报告过
+          None => {}
+        }
+      }
+      "VarDef" => {
+        let ty = self.canonical(&Type::from_ast(&ast.children[0]));
+        let found = self.check_expr(&mut ast.children[2]);
+        self.expect(&ty, &found, &ast.children[2]);
+        self.vars.insert(ast.children[1].value.clone(), ty);
+      }
+      "Assign" => {
+        let found = self.check_expr(&mut ast.children[1]);
+        let name = &ast.children[0].value;
+        if let Some(ty) = self.vars.get(name).cloned() {
+          if !self.expect(&ty, &found, &ast.children[1]) && self.inferred.contains(name) {
+            let diagnostic = self.diagnostics.pop().unwrap().with_note(format!(
+              "the type of `{}` was inferred as `{}` from its initializer",
+              source_name(name),
+              ty
+            ));
+            self.diagnostics.push(diagnostic);
+          }
+        }
+      }
+      "Return" if *ret == auto() => {
+        // 推导返回类型：只记录，不检查
+        let found = match ast.children.first_mut() {
+          Some(expr) => self.check_expr(expr),
+          None => Some(Type::Void),
+        };
+        self.returns.push(found);
+      }
+      "Return" => {
+        let inferred = self.returns_inferred;
+        let found = match ast.children.first_mut() {
+          Some(expr) => self.check_expr(expr),
+          None => Some(Type::Void),
+        };
+        // 显式返回类型的函数中 return 是否带值由 check_returns 检查
+        let checked = inferred || (*ret != Type::Void && !ast.children.is_empty());
+        if checked {
+          let node = ast.children.first().unwrap_or(&*ast);
+          if !self.expect(ret, &found, node) && inferred {
+            let diagnostic = self.diagnostics.pop().unwrap().with_note(format!(
+              "the return type of this function was inferred as `{}`",
+              ret
+            ));
+            self.diagnostics.push(diagnostic);
+          }
+        }
+      }
+      "BranchStmt" | "LoopStmt" => {
+        let keyword = if ast.value == "BranchStmt" {
+          "if"
+        } else {
+          "while"
+        };
+        let found = self.check_expr(&mut ast.children[0]);
+        if let Some(found) = found.filter(|ty| *ty != Type::Bool) {
+          self.diagnostics.push(Diagnostic::error(
+            format!(
+              "mismatched types: `{}` condition must be `bool`, found `{}`",
+              keyword, found
+            ),
+            ast.children[0].span,
+          ));
+        }
+        for child in &mut ast.children[1..] {
+          self.check_stmt_list(child, ret);
+        }
+      }
+      "Expr" => {
+        self.check_expr(ast);
+      }
+      _ => {}
+    }
+  }
+
+  /// 表达式：按运算符优先级（而不是语法树的右递归结构）求类型
+  fn check_expr(&mut self, ast: &mut AST) -> Option<Type> {
+    let mut operands = vec![];
+    let mut ops = vec![];
+    collect_chain_mut(ast, &mut operands, &mut ops);
+    let types: Vec<Option<Type>> = operands
+      .into_iter()
+      .map(|factor| self.check_factor(factor))
+      .collect();
+    let ty = fold_chain(ops, types, |op, lhs, rhs| self.check_binary(op, lhs, rhs));
+    ast.ty = ty.clone();
+    ty
+  }
+
+  fn check_binary(&mut self, op: &mut AST, lhs: Option<Type>, rhs: Option<Type>) -> Option<Type> {
+    let (Some(lhs), Some(rhs)) = (lhs, rhs) else {
+      return None;
+    };
+    let numeric = lhs == rhs && matches!(lhs, Type::Int | Type::Float);
+    let result = match op.value.as_str() {
+      "+" | "-" | "*" | "/" if numeric => Some(lhs.clone()),
+      "<" | ">" | "<=" | ">=" if numeric => Some(Type::Bool),
+      "==" | "!=" if lhs == rhs && matches!(lhs, Type::Int | Type::Float | Type::Bool) => {
+        Some(Type::Bool)
+      }
+      _ => None,
+    };
+    if result.is_none() {
+      self.diagnostics.push(Diagnostic::error(
+        format!("cannot apply `{}` to `{}` and `{}`", op.value, lhs, rhs),
+        op.span,
+      ));
+    }
+    op.ty = Some(lhs);
+    result
+  }
+
+  fn check_factor(&mut self, ast: &mut AST) -> Option<Type> {
+    let ty = if ast.children.len() == 3 {
+      // ( Expr )
+      self.check_expr(&mut ast.children[1])
+    } else {
+      let inner = &mut ast.children[0];
+      match inner.value.as_str() {
+        "FnCall" => self.check_call(inner),
+        "Index" => self.check_index(inner),
+        _ => {
+          let ty = self.leaf_type(&inner.value);
+          inner.ty = ty.clone();
+          ty
+        }
+      }
+    };
+    ast.ty = ty.clone();
+    ty
+  }
+
+  fn leaf_type(&self, token: &str) -> Option<Type> {
+    if token.starts_with('"') {
+      Some(Type::Str)
+    } else if token == "true" || token == "false" {
+      Some(Type::Bool)
+    } else if token.starts_with(|c: char| c.is_ascii_digit()) {
+      Some(if token.contains('.') {
+        Type::Float
+      } else {
+        Type::Int
+      })
+    } else {
+      // 未定义的变量已经由名字解析报告
+      self.vars.get(token).or(self.globals.get(token)).cloned()
+    }
+  }
+
+  fn check_call(&mut self, call: &mut AST) -> Option<Type> {
+    let mut arg_types = vec![];
+    for arg in &mut call.children[1..] {
+      let expr = if arg.value == "NamedArg" {
+        &mut arg.children[1]
+      } else {
+        arg
+      };
+      arg_types.push(self.check_expr(expr));
+    }
+    let name = call.children[0].value.clone();
+    let ty = match name.as_str() {
+      _ if self.signatures.contains_key(&name) => {
+        let sig = self.signatures[&name].clone();
+        // 实参个数等错误由 check_calls 报告
+        if let Ok(sources) = bind_args(&sig, call) {
+          for (param, source) in sig.params.iter().zip(sources) {
+            let expected = self.canonical(&param.ty);
+            match source {
+              ArgSource::Given(i) => self.expect_arg(&expected, &arg_types[i], call, i, param),
+              ArgSource::Rest(indices) => {
+                let Type::Slice(elem) = expected else {
+                  continue;
+                };
+                for i in indices {
+                  self.expect_arg(&elem, &arg_types[i], call, i, param);
+                }
+              }
+              ArgSource::Default(_) => {}
+            }
+          }
+        }
+        // 无法推导的返回类型已经报告过
+        Some(self.canonical(&sig.ret)).filter(|ty| *ty != auto())
+      }
+      "print" => {
+        for (i, ty) in arg_types.iter().enumerate() {
+          if *ty == Some(Type::Void) {
+            self.diagnostics.push(Diagnostic::error(
+              "cannot print a value of type `void`".to_string(),
+              call.children[i + 1].span,
+            ));
+          }
+        }
+        Some(Type::Void)
+      }
+      "scan" => Some(Type::Int),
+      _ if WRAPPING_FUNCTIONS.iter().any(|(f, _)| *f == name) => {
+        for (i, ty) in arg_types.iter().enumerate() {
+          self.expect(&Type::Int, ty, &call.children[i + 1]);
+        }
+        Some(Type::Int)
+      }
+      "size" => {
+        if let Some(Some(ty)) = arg_types.first() {
+          if !matches!(ty, Type::Slice(_) | Type::Array(..)) {
+            self.diagnostics.push(Diagnostic::error(
+              format!("`size` expects a slice, found `{}`", ty),
+              call.children[1].span,
+            ));
+          }
+        }
+        Some(Type::Int)
+      }
+      _ => None,
+    };
+    call.ty = ty.clone();
+    ty
+  }
+
+  /// 检查第 index 个实参，出错时指出对应的形参
+  fn expect_arg(
+    &mut self,
+    expected: &Type,
+    found: &Option<Type>,
+    call: &AST,
+    index: usize,
+    param: &Param,
+  ) {
+    let arg = &call.children[index + 1];
+    if !self.expect(expected, found, arg) {
+      let diagnostic = self.diagnostics.pop().unwrap().with_note(format!(
+        "parameter `{}` of `{}` has type `{}`",
+        param.name, call.children[0].value, param.ty
+      ));
+      self.diagnostics.push(diagnostic);
+    }
+  }
+
+  fn check_index(&mut self, ast: &mut AST) -> Option<Type> {
+    let base = self.vars.get(&ast.children[0].value).cloned();
+    ast.children[0].ty = base.clone();
+    let index = self.check_expr(&mut ast.children[1]);
+    self.expect(&Type::Int, &index, &ast.children[1]);
+    let ty = match base {
+      Some(Type::Slice(elem)) | Some(Type::Array(elem, _)) => Some(*elem),
+      Some(ty) => {
+        self.diagnostics.push(Diagnostic::error(
+          format!("cannot index into a value of type `{}`", ty),
+          ast.children[0].span,
+        ));
+        None
+      }
+      None => None,
+    };
+    ast.ty = ty.clone();
+    ty
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::parser::Parser;
+  use crate::signature::collect_signatures;
+
+  fn check(source: &str) -> (AST, Vec<String>) {
+    let mut ast = Parser::new(Lexer::new(source)).parse();
+    let signatures = collect_signatures(&ast);
+    let messages = check_program(&mut ast, &signatures, true)
+      .into_iter()
+      .map(|d| d.message)
+      .collect();
+    (ast, messages)
+  }
+
+  fn find<'a>(ast: &'a AST, value: &str) -> &'a AST {
+    if ast.value == value {
+      return ast;
+    }
+    ast
+      .children
+      .iter()
+      .map(|child| find(child, value))
+      .find(|node| node.value == value)
+      .unwrap_or(ast)
+  }
+
+  #[test]
+  fn test_well_typed_program() {
+    let (_, messages) = check(
+      "int main()\n  float x = 1.5 * 2.0\n  bool b = x > 1.0\n  string s = \"hi\"\n  if b == true\n    print(s, x)\n  else\n    pass\n  return 1 + 2 * 3\n",
+    );
+    assert!(messages.is_empty(), "{:?}", messages);
+  }
+
+  #[test]
+  fn test_operator_mismatch() {
+    let (_, messages) = check("int main()\n  int a = \"a\" + 1\n  return 1 + 2.0\n");
+    assert_eq!(
+      messages,
+      vec![
+        "cannot apply `+` to `string` and `int`",
+        "cannot apply `+` to `int` and `float`"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_condition_and_assignment() {
+    let (_, messages) = check(
+      "int main()\n  if 3.5\n    pass\n  else\n    pass\n  int a = 1\n  a = 2.5\n  string s = 1 < 2\n  return a\n",
+    );
+    assert_eq!(
+      messages,
+      vec![
+        "mismatched types: `if` condition must be `bool`, found `float`",
+        "mismatched types: expected `int`, found `float`",
+        "mismatched types: expected `string`, found `bool`"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_precedence_follows_operators() {
+    // 语法树是右递归的 1 + (2 < 3)，类型按优先级求得 (1 + 2) < 3
+    let (ast, messages) = check("bool f()\n  return 1 + 2 < 3\n");
+    assert!(messages.is_empty(), "{:?}", messages);
+    fn leaves(ast: &AST) -> Vec<&AST> {
+      if ast.children.is_empty() {
+        return vec![ast];
+      }
+      ast.children.iter().flat_map(leaves).collect()
+    }
+    let types: Vec<Option<Type>> = leaves(find(&ast, "Return"))
+      .into_iter()
+      .map(|leaf| leaf.ty.clone())
+      .collect();
+    assert_eq!(types, vec![Some(Type::Int); 5]);
+  }
+
+  #[test]
+  fn test_call_argument_types() {
+    let (_, messages) = check(
+      "int main()\n  return add(1, 2.0) + sum(1, \"x\")\n\nint add(int a, int b)\n  return a + b\n\nint sum(int... xs)\n  return size(xs)\n",
+    );
+    assert_eq!(
+      messages,
+      vec![
+        "mismatched types: expected `int`, found `float`",
+        "mismatched types: expected `int`, found `string`"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_auto_variables() {
+    let source = "int main()\n  auto x = 1.5 * 2.0\n  auto n = scan()\n  x = n\n  auto v = print(n)\n  return n\n";
+    let mut ast = Parser::new(Lexer::new(source)).parse();
+    let signatures = collect_signatures(&ast);
+    let diagnostics = check_program(&mut ast, &signatures, false);
+    let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+    assert_eq!(
+      messages,
+      vec![
+        "mismatched types: expected `float`, found `int`",
+        "cannot infer the type of `v` from an expression of type `void`"
+      ]
+    );
+    assert_eq!(
+      diagnostics[0].notes,
+      vec!["the type of `x` was inferred as `float` from its initializer"]
+    );
+    // 推导出的类型记录在 Type 节点上
+    assert_eq!(
+      Type::from_ast(&find(&ast, "VarDef").children[0]),
+      Type::Float
+    );
+  }
+
+  #[test]
+  fn test_return_type_inference() {
+    let source = "int main()\n  float f = half(3.0)\n  return fact(5)\n\nauto half(float x)\n  return x / 2.0\n\nauto fact(int n)\n  if n < 2\n    return 1\n  else\n    return n * fact(n - 1)\n";
+    let (ast, messages) = check(source);
+    assert!(messages.is_empty(), "{:?}", messages);
+    let signatures = collect_signatures(&ast);
+    assert_eq!(signatures["half"].ret, Type::Float);
+    assert_eq!(signatures["fact"].ret, Type::Int);
+
+    let (_, messages) = check("auto f(int n)\n  if n < 2\n    return 1\n  else\n    return 2.0\n");
+    assert_eq!(
+      messages,
+      vec!["mismatched types: expected `int`, found `float`"]
+    );
+    let (_, messages) = check("auto f()\n  return f()\n");
+    assert_eq!(messages, vec!["cannot infer the return type of `f`"]);
+  }
+
+  #[test]
+  fn test_return_type_inference_is_opt_in() {
+    let source = "auto f()\n  return 1\n";
+    let mut ast = Parser::new(Lexer::new(source)).parse();
+    let signatures = collect_signatures(&ast);
+    let diagnostics = check_program(&mut ast, &signatures, false);
+    assert_eq!(
+      diagnostics[0].message,
+      "return type inference is not enabled"
+    );
+  }
+}
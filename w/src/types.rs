@@ -0,0 +1,349 @@
+//! W 语言的类型
+//!
+//! 语法分析器把类型解析成 `Type` 子树：
+//! - `int` / `void` / `Meters`      → Type[int]
+//! - `[int; 4]`                     → Type[ArrayType[Type[int], 4]]
+//! - `[int]`                        → Type[SliceType[Type[int]]]
+//! - `fn(int, int) -> int`          → Type[FnType[TypeList[Type[int], Type[int]], Type[int]]]
+//! - 变长参数 `int...`               → Type[Variadic[Type[int]]]，在函数内是切片 `[int]`
+//!
+//! `check_types` 检查其中出现的名字都是基本类型或 `type Name = ...` 声明过的类型别名。
+//!
+//! `auto` 只能用作带初始值的变量和常量定义的类型（`auto x = 1`）和函数的返回类型，
+//! 具体类型由类型检查推导后记录在 `Type` 节点的 `ty` 上。
+
+use crate::ast::AST;
+use crate::diagnostic::Diagnostic;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+pub const PRIMITIVE_TYPES: [&str; 5] = ["int", "float", "bool", "string", "void"];
+
+/// 需要推导的类型
+pub const AUTO: &str = "auto";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Type {
+  Int,
+  Float,
+  Bool,
+  Str,
+  Void,
+  Array(Box<Type>, usize),
+  Slice(Box<Type>),
+  Fn(Vec<Type>, Box<Type>),
+  Named(String),
+}
+
+impl Type {
+  /// 由语法分析器产生的 `Type` 子树构造类型；`auto` 推导出的类型优先
+  pub fn from_ast(ast: &AST) -> Type {
+    if let Some(ty) = &ast.ty {
+      return ty.clone();
+    }
+    let inner = &ast.children[0];
+    match inner.value.as_str() {
+      "ArrayType" => Type::Array(
+        Box::new(Type::from_ast(&inner.children[0])),
+        inner.children[1].value.parse().unwrap(),
+      ),
+      "SliceType" | "Variadic" => Type::Slice(Box::new(Type::from_ast(&inner.children[0]))),
+      "FnType" => Type::Fn(
+        inner.children[0]
+          .children
+          .iter()
+          .map(Type::from_ast)
+          .collect(),
+        Box::new(Type::from_ast(&inner.children[1])),
+      ),
+      name => Type::from_name(name),
+    }
+  }
+
+  pub fn from_name(name: &str) -> Type {
+    match name {
+      "int" => Type::Int,
+      "float" => Type::Float,
+      "bool" => Type::Bool,
+      "string" => Type::Str,
+      "void" => Type::Void,
+      _ => Type::Named(name.to_string()),
+    }
+  }
+}
+
+impl fmt::Display for Type {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Type::Int => write!(f, "int"),
+      Type::Float => write!(f, "float"),
+      Type::Bool => write!(f, "bool"),
+      Type::Str => write!(f, "string"),
+      Type::Void => write!(f, "void"),
+      Type::Array(elem, len) => write!(f, "[{}; {}]", elem, len),
+      Type::Slice(elem) => write!(f, "[{}]", elem),
+      Type::Fn(params, ret) => {
+        let params: Vec<String> = params.iter().map(Type::to_string).collect();
+        write!(f, "fn({}) -> {}", params.join(", "), ret)
+      }
+      Type::Named(name) => write!(f, "{}", name),
+    }
+  }
+}
+
+/// `Type` 子树是否写的是 `auto`
+pub fn is_auto(ast: &AST) -> bool {
+  ast.children[0].value == AUTO && ast.children[0].children.is_empty()
+}
+
+/// 收集所有 `type Name = ...` 别名，名字 -> 别名右侧的 `Type` 子树
+pub fn collect_aliases(ast: &AST) -> HashMap<String, &AST> {
+  let mut aliases = HashMap::new();
+  collect_aliases_helper(ast, &mut aliases);
+  aliases
+}
+
+fn collect_aliases_helper<'a>(ast: &'a AST, aliases: &mut HashMap<String, &'a AST>) {
+  if ast.value == "TypeDef" {
+    aliases.insert(ast.children[0].value.clone(), &ast.children[1]);
+    return;
+  }
+  if ast.value == "Pg" || ast.value == "FnList" {
+    for child in &ast.children {
+      collect_aliases_helper(child, aliases);
+    }
+  }
+}
+
+/// 检查程序中所有类型标注，报告未知类型和循环的类型别名
+pub fn check_types(ast: &AST) -> Vec<Diagnostic> {
+  let aliases = collect_aliases(ast);
+  let mut diagnostics = vec![];
+  check_types_helper(ast, &aliases, &mut diagnostics);
+
+  let mut names: Vec<&String> = aliases.keys().collect();
+  names.sort();
+  for name in names {
+    let mut visiting = HashSet::new();
+    if alias_is_cyclic(name, &aliases, &mut visiting) {
+      let def = aliases[name];
+      diagnostics.push(Diagnostic::error(
+        format!("type alias `{}` refers to itself", name),
+        def.span,
+      ));
+    }
+  }
+  diagnostics
+}
+
+fn check_types_helper(
+  ast: &AST,
+  aliases: &HashMap<String, &AST>,
+  diagnostics: &mut Vec<Diagnostic>,
+) {
+  match ast.value.as_str() {
+    // 带初始值的变量定义和函数返回类型可以是 auto
+    "VarDef" | "Const" | "Fn" if is_auto(&ast.children[0]) => {
+      for child in &ast.children[1..] {
+        check_types_helper(child, aliases, diagnostics);
+      }
+      return;
+    }
+    "VarDecl" if is_auto(&ast.children[0]) => {
+      diagnostics.push(
+        Diagnostic::error(
+          format!(
+            "cannot infer the type of `{}` without an initializer",
+            ast.children[1].value
+          ),
+          ast.children[0].span,
+        )
+        .with_note(format!(
+          "write `auto {} = ...` or give `{}` an explicit type",
+          ast.children[1].value, ast.children[1].value
+        )),
+      );
+      return;
+    }
+    _ => {}
+  }
+  if ast.value == "Type" {
+    let inner = &ast.children[0];
+    if inner.children.is_empty() {
+      let name = inner.value.as_str();
+      if name == AUTO {
+        diagnostics.push(Diagnostic::error(
+          "`auto` is only allowed for initialized variables and return types".to_string(),
+          inner.span,
+        ));
+      } else if !PRIMITIVE_TYPES.contains(&name) && !aliases.contains_key(name) {
+        diagnostics.push(Diagnostic::error(
+          format!("unknown type `{}`", name),
+          inner.span,
+        ));
+      }
+      return;
+    }
+  }
+  for child in &ast.children {
+    check_types_helper(child, aliases, diagnostics);
+  }
+}
+
+/// 检查 return 语句与函数返回类型是否一致：
+/// void 函数不能返回值，非 void 函数的 return 必须带值
+pub fn check_returns(ast: &AST) -> Vec<Diagnostic> {
+  let mut diagnostics = vec![];
+  check_returns_helper(ast, None, &mut diagnostics);
+  diagnostics
+}
+
+fn check_returns_helper(
+  ast: &AST,
+  fn_sig: Option<(&str, &Type)>,
+  diagnostics: &mut Vec<Diagnostic>,
+) {
+  match ast.value.as_str() {
+    // 推导返回类型的函数由类型检查检查 return 语句
+    "Fn" if is_auto(&ast.children[0]) => return,
+    "Fn" => {
+      let ret = Type::from_ast(&ast.children[0]);
+      let name = ast.children[1].value.as_str();
+      check_returns_helper(&ast.children[3], Some((name, &ret)), diagnostics);
+      return;
+    }
+    "Return" => {
+      if let Some((name, ret)) = fn_sig {
+        if *ret == Type::Void && !ast.children.is_empty() {
+          diagnostics.push(Diagnostic::error(
+            format!("`return` with a value in void function `{}`", name),
+            ast.span,
+          ));
+        } else if *ret != Type::Void && ast.children.is_empty() {
+          diagnostics.push(Diagnostic::error(
+            format!(
+              "`return` without a value in function `{}` returning `{}`",
+              name, ret
+            ),
+            ast.span,
+          ));
+        }
+      }
+      return;
+    }
+    _ => {}
+  }
+  for child in &ast.children {
+    check_returns_helper(child, fn_sig, diagnostics);
+  }
+}
+
+fn alias_is_cyclic<'a>(
+  name: &'a str,
+  aliases: &HashMap<String, &'a AST>,
+  visiting: &mut HashSet<&'a str>,
+) -> bool {
+  if !visiting.insert(name) {
+    return true;
+  }
+  let Some(def) = aliases.get(name) else {
+    return false;
+  };
+  // 只有直接引用（而非数组、切片、函数类型中的引用）才会构成无限展开
+  let inner = &def.children[0];
+  let cyclic = inner.children.is_empty() && alias_is_cyclic(&inner.value, aliases, visiting);
+  visiting.remove(name);
+  cyclic
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::parser::Parser;
+
+  fn parse(source: &str) -> AST {
+    Parser::new(Lexer::new(source)).parse()
+  }
+
+  fn messages(source: &str) -> Vec<String> {
+    check_types(&parse(source))
+      .into_iter()
+      .map(|d| d.message)
+      .collect()
+  }
+
+  #[test]
+  fn test_type_from_ast() {
+    let ast = parse("fn(int, [float; 4]) -> [bool] main(Meters a)\n  return 0\n");
+    let ty = Type::from_ast(&ast.children[0].children[0]);
+    assert_eq!(
+      ty,
+      Type::Fn(
+        vec![Type::Int, Type::Array(Box::new(Type::Float), 4)],
+        Box::new(Type::Slice(Box::new(Type::Bool)))
+      )
+    );
+    assert_eq!(ty.to_string(), "fn(int, [float; 4]) -> [bool]");
+  }
+
+  #[test]
+  fn test_unknown_type() {
+    let source = "banana main()\n  return 0\n";
+    let diagnostics = check_types(&parse(source));
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "unknown type `banana`");
+    assert_eq!(
+      &source[diagnostics[0].span.start..diagnostics[0].span.end],
+      "banana"
+    );
+  }
+
+  #[test]
+  fn test_nested_unknown_type() {
+    assert_eq!(
+      messages("int main()\n  [apple; 2] xs\n  fn(pear) -> int f\n  return 0\n"),
+      vec!["unknown type `apple`", "unknown type `pear`"]
+    );
+  }
+
+  #[test]
+  fn test_type_alias() {
+    assert!(messages("int main()\n  Meters d = 3\n  return d\n\ntype Meters = int\n").is_empty());
+    assert_eq!(
+      messages("type A = B\ntype B = A\nint main()\n  return 0\n"),
+      vec![
+        "type alias `A` refers to itself",
+        "type alias `B` refers to itself"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_auto_positions() {
+    assert_eq!(
+      messages("auto main(auto a)\n  auto b = 1\n  auto c\n  [auto] d\n  return b\n"),
+      vec![
+        "`auto` is only allowed for initialized variables and return types",
+        "cannot infer the type of `c` without an initializer",
+        "`auto` is only allowed for initialized variables and return types"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_check_returns() {
+    let source = "void main()\n  return 1\n\nint f()\n  return\n\nvoid g()\n  return\n";
+    let diagnostics: Vec<String> = check_returns(&parse(source))
+      .into_iter()
+      .map(|d| d.message)
+      .collect();
+    assert_eq!(
+      diagnostics,
+      vec![
+        "`return` with a value in void function `main`",
+        "`return` without a value in function `f` returning `int`"
+      ]
+    );
+  }
+}